@@ -0,0 +1,42 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::wal::MAX_RECORD_SIZE;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Encode(bincode::Error),
+    /// A record before the end of a log, or a snapshot, is damaged.
+    Corrupt { path: PathBuf, offset: u64 },
+    /// A record is larger than `MAX_RECORD_SIZE`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Encode(e) => write!(f, "encoding error: {}", e),
+            Self::Corrupt { path, offset } => {
+                write!(f, "corrupt record in {} at offset {}", path.display(), offset)
+            }
+            Self::TooLarge(n) => {
+                write!(f, "record of {} bytes exceeds {}", n, MAX_RECORD_SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<bincode::Error> for StoreError {
+    fn from(e: bincode::Error) -> Self {
+        Self::Encode(e)
+    }
+}
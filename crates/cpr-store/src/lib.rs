@@ -1,14 +1,9 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+pub mod error;
+pub mod merkle;
+pub mod snapshot;
+pub mod wal;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use error::StoreError;
+pub use merkle::{Hash, InclusionProof, MerkleProof, MerkleTree, NonInclusionProof};
+pub use snapshot::SnapshotStore;
+pub use wal::{FsyncPolicy, Lsn, Wal, WalConfig};
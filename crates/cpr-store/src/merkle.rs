@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub type Hash = [u8; 32];
+
+/// Domain prefixes, so a leaf can never be passed off as an inner node or
+/// the root and vice versa.
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+const ROOT: u8 = 2;
+
+fn hash_leaf(key: &Hash, value: &[u8]) -> Hash {
+    let mut h = Sha256::new();
+    h.update([LEAF]);
+    h.update(key);
+    h.update(value);
+    h.finalize().into()
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut h = Sha256::new();
+    h.update([NODE]);
+    h.update(left);
+    h.update(right);
+    h.finalize().into()
+}
+
+fn hash_root(leaf_count: u64, top: &Hash) -> Hash {
+    let mut h = Sha256::new();
+    h.update([ROOT]);
+    h.update(leaf_count.to_le_bytes());
+    h.update(top);
+    h.finalize().into()
+}
+
+/// A Merkle tree over key/value pairs, with leaves sorted by key. Each
+/// level pairs up neighbouring hashes; an odd one out at the end of a level
+/// is carried up unchanged. The root also commits to the number of leaves,
+/// which fixes the shape of the tree.
+///
+/// Because leaves are sorted, a key's absence can be proven by showing the
+/// two adjacent leaves whose keys it falls between.
+#[derive(Clone, Debug, Default)]
+pub struct MerkleTree {
+    keys: Vec<Hash>,
+    values: Vec<Vec<u8>>,
+    /// `levels[0]` holds the leaf hashes, the last level the single top
+    /// hash.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: BTreeMap<Hash, Vec<u8>>) -> Self {
+        let (keys, values): (Vec<Hash>, Vec<Vec<u8>>) = leaves.into_iter().unzip();
+        let mut levels = vec![keys.iter().zip(values.iter()).map(|(k, v)| hash_leaf(k, v)).collect::<Vec<_>>()];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [l, r] => hash_node(l, r),
+                    [only] => *only,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { keys, values, levels }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn root(&self) -> Hash {
+        let top = self.levels[self.levels.len() - 1].first().copied().unwrap_or_default();
+        hash_root(self.len() as u64, &top)
+    }
+
+    pub fn get(&self, key: &Hash) -> Option<&[u8]> {
+        self.keys.binary_search(key).ok().map(|i| self.values[i].as_slice())
+    }
+
+    /// Prove `key` is or is not in the tree.
+    pub fn prove(&self, key: &Hash) -> MerkleProof {
+        match self.keys.binary_search(key) {
+            Ok(i) => MerkleProof::Included(self.inclusion(i)),
+            Err(i) => MerkleProof::Absent(NonInclusionProof {
+                key: *key,
+                leaf_count: self.len() as u64,
+                left: i.checked_sub(1).map(|l| self.inclusion(l)),
+                right: (i < self.len()).then(|| self.inclusion(i)),
+            }),
+        }
+    }
+
+    fn inclusion(&self, index: usize) -> InclusionProof {
+        let mut siblings = Vec::new();
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                siblings.push(level[sibling]);
+            }
+            i /= 2;
+        }
+        InclusionProof {
+            key: self.keys[index],
+            value: self.values[index].clone(),
+            index: index as u64,
+            leaf_count: self.len() as u64,
+            siblings,
+        }
+    }
+}
+
+/// Proof that `key` maps to `value` under some root.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub key: Hash,
+    pub value: Vec<u8>,
+    /// Position of the leaf among all leaves, in key order.
+    pub index: u64,
+    pub leaf_count: u64,
+    /// Sibling hashes from the leaf up, skipping levels where the leaf's
+    /// ancestor was carried up without one.
+    pub siblings: Vec<Hash>,
+}
+
+impl InclusionProof {
+    pub fn verify(&self, root: &Hash) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut hash = hash_leaf(&self.key, &self.value);
+        let mut siblings = self.siblings.iter();
+        let (mut i, mut n) = (self.index, self.leaf_count);
+        while n > 1 {
+            if i % 2 == 1 {
+                match siblings.next() {
+                    Some(s) => hash = hash_node(s, &hash),
+                    None => return false,
+                }
+            } else if i + 1 < n {
+                match siblings.next() {
+                    Some(s) => hash = hash_node(&hash, s),
+                    None => return false,
+                }
+            }
+            i /= 2;
+            n = n.div_ceil(2);
+        }
+        siblings.next().is_none() && hash_root(self.leaf_count, &hash) == *root
+    }
+}
+
+/// Proof that `key` is not in the tree under some root: the leaves directly
+/// before and after where it would sit, either of which is missing at the
+/// ends of the tree.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NonInclusionProof {
+    pub key: Hash,
+    pub leaf_count: u64,
+    pub left: Option<InclusionProof>,
+    pub right: Option<InclusionProof>,
+}
+
+impl NonInclusionProof {
+    pub fn verify(&self, root: &Hash) -> bool {
+        let n = self.leaf_count;
+        let side_ok = |p: &Option<InclusionProof>| {
+            p.as_ref().is_none_or(|p| p.leaf_count == n && p.verify(root))
+        };
+        if !side_ok(&self.left) || !side_ok(&self.right) {
+            return false;
+        }
+        match (&self.left, &self.right) {
+            (Some(l), Some(r)) => l.key < self.key && self.key < r.key && r.index == l.index + 1,
+            (Some(l), None) => l.key < self.key && l.index + 1 == n,
+            (None, Some(r)) => self.key < r.key && r.index == 0,
+            (None, None) => n == 0 && hash_root(0, &Hash::default()) == *root,
+        }
+    }
+}
+
+/// Either kind of proof, as returned by `MerkleTree::prove`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MerkleProof {
+    Included(InclusionProof),
+    Absent(NonInclusionProof),
+}
+
+impl MerkleProof {
+    pub fn key(&self) -> &Hash {
+        match self {
+            Self::Included(p) => &p.key,
+            Self::Absent(p) => &p.key,
+        }
+    }
+
+    /// The proven value, or `None` for a proof of absence.
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            Self::Included(p) => Some(&p.value),
+            Self::Absent(_) => None,
+        }
+    }
+
+    pub fn verify(&self, root: &Hash) -> bool {
+        match self {
+            Self::Included(p) => p.verify(root),
+            Self::Absent(p) => p.verify(root),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u8) -> Hash {
+        let mut k = Hash::default();
+        k[0] = i;
+        k
+    }
+
+    fn tree(keys: &[u8]) -> MerkleTree {
+        MerkleTree::new(keys.iter().map(|&k| (key(k), vec![k, k])).collect())
+    }
+
+    #[test]
+    fn proves_inclusion_for_every_shape() {
+        for n in 1..=9u8 {
+            let keys: Vec<u8> = (0..n).map(|i| i * 2 + 2).collect();
+            let t = tree(&keys);
+            for &k in &keys {
+                let proof = t.prove(&key(k));
+                assert_eq!(proof.value(), Some(&[k, k][..]));
+                assert!(proof.verify(&t.root()), "{} of {}", k, n);
+            }
+        }
+    }
+
+    #[test]
+    fn proves_absence() {
+        let t = tree(&[2, 4, 6, 8, 10]);
+        for k in [1, 3, 7, 11] {
+            let proof = t.prove(&key(k));
+            assert_eq!(proof.value(), None);
+            assert!(proof.verify(&t.root()));
+        }
+        let empty = tree(&[]);
+        assert!(empty.prove(&key(1)).verify(&empty.root()));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let t = tree(&[2, 4, 6, 8, 10]);
+        let mut proof = match t.prove(&key(6)) {
+            MerkleProof::Included(p) => p,
+            _ => unreachable!(),
+        };
+        proof.value = vec![0];
+        assert!(!proof.verify(&t.root()));
+
+        // Claiming 6 is absent by skipping over it.
+        let left = match t.prove(&key(4)) {
+            MerkleProof::Included(p) => p,
+            _ => unreachable!(),
+        };
+        let right = match t.prove(&key(8)) {
+            MerkleProof::Included(p) => p,
+            _ => unreachable!(),
+        };
+        let forged = NonInclusionProof {
+            key: key(6),
+            leaf_count: 5,
+            left: Some(left),
+            right: Some(right),
+        };
+        assert!(!forged.verify(&t.root()));
+        assert!(!t.prove(&key(6)).verify(&tree(&[2, 4, 6]).root()));
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{wal::sync_dir, Lsn, StoreError};
+
+/// Every snapshot starts `[magic][lsn: u64][crc32 of payload: u32]`, little
+/// endian, followed by the bincode payload.
+const MAGIC: &[u8; 8] = b"CPRSNAP1";
+const HEADER_LEN: usize = 20;
+const SNAPSHOT_EXT: &str = "snap";
+
+/// A directory of point-in-time snapshots, each tagged with the LSN of the
+/// last write-ahead log record it reflects. Booting from the newest snapshot
+/// and replaying the log after its LSN recovers the full state.
+///
+/// Snapshots are written to a temporary file, synced and then renamed into
+/// place, so a crash never leaves a partial snapshot under a real name; one
+/// that fails its checksum anyway is skipped in favour of the one before.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn open(dir: impl AsRef<Path>) -> Result<SnapshotStore, StoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(SnapshotStore { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// LSNs of every snapshot held, oldest first.
+    pub fn lsns(&self) -> Result<Vec<Lsn>, StoreError> {
+        let mut lsns = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SNAPSHOT_EXT) {
+                continue;
+            }
+            if let Some(lsn) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                lsns.push(lsn);
+            }
+        }
+        lsns.sort_unstable();
+        Ok(lsns)
+    }
+
+    fn path(&self, lsn: Lsn) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", lsn, SNAPSHOT_EXT))
+    }
+
+    /// Durably write `state` as the snapshot at `lsn`, replacing any
+    /// existing one.
+    pub fn save<T: Serialize>(&self, lsn: Lsn, state: &T) -> Result<PathBuf, StoreError> {
+        let payload = bincode::serialize(state)?;
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&lsn.to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        let path = self.path(lsn);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir);
+        Ok(path)
+    }
+
+    /// Read the snapshot at `lsn`.
+    pub fn load<T: DeserializeOwned>(&self, lsn: Lsn) -> Result<T, StoreError> {
+        let path = self.path(lsn);
+        let buf = fs::read(&path)?;
+        let corrupt = |offset| StoreError::Corrupt {
+            path: path.clone(),
+            offset,
+        };
+        if buf.len() < HEADER_LEN || &buf[..8] != MAGIC {
+            return Err(corrupt(0));
+        }
+        if u64::from_le_bytes(buf[8..16].try_into().unwrap()) != lsn {
+            return Err(corrupt(8));
+        }
+        let crc = u32::from_le_bytes(buf[16..20].try_into().unwrap());
+        let payload = &buf[HEADER_LEN..];
+        if crc32fast::hash(payload) != crc {
+            return Err(corrupt(HEADER_LEN as u64));
+        }
+        Ok(bincode::deserialize(payload)?)
+    }
+
+    /// The newest snapshot that reads back cleanly, with its LSN.
+    pub fn latest<T: DeserializeOwned>(&self) -> Result<Option<(Lsn, T)>, StoreError> {
+        for lsn in self.lsns()?.into_iter().rev() {
+            match self.load(lsn) {
+                Ok(state) => return Ok(Some((lsn, state))),
+                Err(e) => log::warn!("Skipping snapshot {}: {}", lsn, e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Delete all but the newest `keep` snapshots, returning the LSN of the
+    /// oldest one left.
+    pub fn retain(&self, keep: usize) -> Result<Option<Lsn>, StoreError> {
+        let lsns = self.lsns()?;
+        let cut = lsns.len().saturating_sub(keep.max(1));
+        for lsn in &lsns[..cut] {
+            fs::remove_file(self.path(*lsn))?;
+        }
+        if cut > 0 {
+            sync_dir(&self.dir);
+        }
+        Ok(lsns.get(cut).copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cpr-snap-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn latest_skips_corrupt() {
+        let dir = temp_dir("corrupt");
+        let store = SnapshotStore::open(&dir).unwrap();
+        store.save(5, &"five".to_string()).unwrap();
+        let newest = store.save(9, &"nine".to_string()).unwrap();
+        assert_eq!(store.latest::<String>().unwrap(), Some((9, "nine".to_string())));
+
+        let mut buf = fs::read(&newest).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        fs::write(&newest, buf).unwrap();
+        assert_eq!(store.latest::<String>().unwrap(), Some((5, "five".to_string())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retains_newest() {
+        let dir = temp_dir("retain");
+        let store = SnapshotStore::open(&dir).unwrap();
+        for lsn in [3, 1, 4, 2] {
+            store.save(lsn, &lsn).unwrap();
+        }
+        assert_eq!(store.retain(2).unwrap(), Some(3));
+        assert_eq!(store.lsns().unwrap(), vec![3, 4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,451 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::StoreError;
+
+/// Log sequence number: the position of a record in the log, starting at 1.
+pub type Lsn = u64;
+
+/// Size a segment may grow to before a new one is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+/// Largest record accepted. Anything claiming to be longer on recovery is
+/// treated as a torn write rather than allocated.
+pub const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Every record is `[len: u32][crc32 of payload: u32][payload]`, little
+/// endian.
+const HEADER_LEN: usize = 8;
+const SEGMENT_EXT: &str = "wal";
+
+/// When appended records are forced to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every append. Nothing acknowledged is ever lost.
+    Always,
+    /// On the first append at least this long after the last sync, or on
+    /// `sync`, rotation or drop. There is no timer: until something else is
+    /// appended, a crash loses everything since the last sync, however long
+    /// ago that was. Callers that go quiet should `sync` themselves.
+    Interval(Duration),
+    /// Only on `sync`, rotation or drop; otherwise left to the OS.
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalConfig {
+    pub segment_size: u64,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            fsync: FsyncPolicy::Always,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    first_lsn: Lsn,
+    path: PathBuf,
+}
+
+/// An append-only write-ahead log of bincode-encoded records, split across
+/// segment files in one directory. Each segment is named after the LSN of
+/// its first record, so the LSN of any record follows from its position.
+///
+/// Opening a log recovers it: every record is checked against its CRC, and
+/// a torn or corrupt tail on the last segment (what a crash mid-append
+/// leaves behind) is truncated away. Damage anywhere earlier is an error.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    config: WalConfig,
+    segments: Vec<Segment>,
+    file: File,
+    size: u64,
+    next_lsn: Lsn,
+    last_sync: Instant,
+    dirty: bool,
+}
+
+impl Wal {
+    /// Open the log in `dir`, creating it if needed, and recover it.
+    pub fn open(dir: impl AsRef<Path>, config: WalConfig) -> Result<Wal, StoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Some(first_lsn) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                segments.push(Segment { first_lsn, path });
+            }
+        }
+        segments.sort_by_key(|s| s.first_lsn);
+
+        let mut next_lsn = segments.first().map_or(1, |s| s.first_lsn);
+        let mut size = 0;
+        let last = segments.len().saturating_sub(1);
+        for (i, seg) in segments.iter().enumerate() {
+            if seg.first_lsn != next_lsn {
+                return Err(StoreError::Corrupt {
+                    path: seg.path.clone(),
+                    offset: 0,
+                });
+            }
+            let buf = fs::read(&seg.path)?;
+            let (records, valid) = decode(&buf);
+            if valid < buf.len() {
+                if i != last {
+                    return Err(StoreError::Corrupt {
+                        path: seg.path.clone(),
+                        offset: valid as u64,
+                    });
+                }
+                log::warn!(
+                    "Truncating torn wal tail in {} at offset {}",
+                    seg.path.display(),
+                    valid
+                );
+                let file = OpenOptions::new().write(true).open(&seg.path)?;
+                file.set_len(valid as u64)?;
+                file.sync_all()?;
+            }
+            next_lsn += records.len() as u64;
+            size = valid as u64;
+        }
+
+        if segments.is_empty() {
+            segments.push(Segment {
+                first_lsn: next_lsn,
+                path: segment_path(&dir, next_lsn),
+            });
+        }
+        let active = &segments[segments.len() - 1].path;
+        let file = OpenOptions::new().create(true).append(true).open(active)?;
+        sync_dir(&dir);
+        Ok(Wal {
+            dir,
+            config,
+            segments,
+            file,
+            size,
+            next_lsn,
+            last_sync: Instant::now(),
+            dirty: false,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn config(&self) -> WalConfig {
+        self.config
+    }
+
+    /// LSN the next appended record will get.
+    pub fn next_lsn(&self) -> Lsn {
+        self.next_lsn
+    }
+
+    /// LSN of the oldest record still held.
+    pub fn first_lsn(&self) -> Lsn {
+        self.segments[0].first_lsn
+    }
+
+    /// LSN of the last record written, or 0 for an empty log.
+    pub fn last_lsn(&self) -> Lsn {
+        self.next_lsn - 1
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Encode and append `record`, returning its LSN. It is on disk once
+    /// this returns if the fsync policy is `Always`.
+    pub fn append<T: Serialize>(&mut self, record: &T) -> Result<Lsn, StoreError> {
+        let payload = bincode::serialize(record)?;
+        self.append_bytes(&payload)
+    }
+
+    /// Append an already encoded record, returning its LSN.
+    pub fn append_bytes(&mut self, payload: &[u8]) -> Result<Lsn, StoreError> {
+        if payload.len() > MAX_RECORD_SIZE {
+            return Err(StoreError::TooLarge(payload.len()));
+        }
+        let len = (HEADER_LEN + payload.len()) as u64;
+        if self.size > 0 && self.size + len > self.config.segment_size {
+            self.rotate()?;
+        }
+        let mut buf = Vec::with_capacity(len as usize);
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        buf.extend_from_slice(payload);
+        if let Err(e) = self.file.write_all(&buf) {
+            // Cut off whatever part of the frame made it out, so the next
+            // append does not land after garbage that recovery would take
+            // for a torn tail.
+            let size = self.size;
+            let undone = self.file.set_len(size).and_then(|_| self.file.seek(SeekFrom::Start(size)));
+            if let Err(undo) = undone {
+                log::warn!("Could not cut a partial write from the wal: {}", undo);
+            }
+            return Err(e.into());
+        }
+        self.size += len;
+        let lsn = self.next_lsn;
+        self.next_lsn += 1;
+        self.dirty = true;
+        match self.config.fsync {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Interval(every) if self.last_sync.elapsed() >= every => self.sync()?,
+            _ => {}
+        }
+        Ok(lsn)
+    }
+
+    /// Force everything appended so far to disk.
+    pub fn sync(&mut self) -> Result<(), StoreError> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Close the current segment and start a new one at the next LSN.
+    fn rotate(&mut self) -> Result<(), StoreError> {
+        self.sync()?;
+        let path = segment_path(&self.dir, self.next_lsn);
+        self.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.segments.push(Segment {
+            first_lsn: self.next_lsn,
+            path,
+        });
+        self.size = 0;
+        sync_dir(&self.dir);
+        Ok(())
+    }
+
+    /// Delete every segment holding only records below `lsn`, e.g. once a
+    /// snapshot covers them. The active segment is always kept, so this may
+    /// leave some older records behind. Returns how many were removed.
+    pub fn truncate_before(&mut self, lsn: Lsn) -> Result<usize, StoreError> {
+        let mut removed = 0;
+        while self.segments.len() > 1 && self.segments[1].first_lsn <= lsn {
+            let seg = self.segments.remove(0);
+            fs::remove_file(&seg.path)?;
+            removed += 1;
+        }
+        if removed > 0 {
+            sync_dir(&self.dir);
+        }
+        Ok(removed)
+    }
+
+    /// Decode every record with an LSN of at least `from`, in order.
+    pub fn read_from<T: DeserializeOwned>(&self, from: Lsn) -> Result<Vec<(Lsn, T)>, StoreError> {
+        let mut out = Vec::new();
+        for (i, seg) in self.segments.iter().enumerate() {
+            let end = self.segments.get(i + 1).map_or(self.next_lsn, |s| s.first_lsn);
+            if end <= from {
+                continue;
+            }
+            let buf = fs::read(&seg.path)?;
+            let (records, _) = decode(&buf);
+            for (lsn, payload) in (seg.first_lsn..).zip(records) {
+                if lsn >= from {
+                    out.push((lsn, bincode::deserialize(payload)?));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Decode the records at each of `lsns`, in LSN order, reading every
+    /// segment they fall in once. LSNs not held are skipped.
+    pub fn read_many<T: DeserializeOwned>(&self, lsns: &[Lsn]) -> Result<Vec<(Lsn, T)>, StoreError> {
+        let mut wanted = lsns.to_vec();
+        wanted.sort_unstable();
+        wanted.dedup();
+        let mut out = Vec::with_capacity(wanted.len());
+        let mut rest = &wanted[..];
+        for (i, seg) in self.segments.iter().enumerate() {
+            let end = self.segments.get(i + 1).map_or(self.next_lsn, |s| s.first_lsn);
+            let skip = rest.iter().take_while(|l| **l < seg.first_lsn).count();
+            let take = rest[skip..].iter().take_while(|l| **l < end).count();
+            let (here, after) = rest[skip..].split_at(take);
+            rest = after;
+            if here.is_empty() {
+                continue;
+            }
+            let buf = fs::read(&seg.path)?;
+            let (records, _) = decode(&buf);
+            for lsn in here {
+                if let Some(payload) = records.get((lsn - seg.first_lsn) as usize) {
+                    out.push((*lsn, bincode::deserialize(payload)?));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+fn segment_path(dir: &Path, first_lsn: Lsn) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_lsn, SEGMENT_EXT))
+}
+
+/// Make a new or removed file's directory entry durable. Not every
+/// platform can open a directory, so failure is ignored.
+pub(crate) fn sync_dir(dir: &Path) {
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+}
+
+/// Split `buf` into record payloads, stopping at the first record that is
+/// incomplete or fails its checksum. Returns the payloads and how many bytes
+/// of `buf` they cover.
+fn decode(buf: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = Vec::new();
+    let mut off = 0;
+    while buf.len() - off >= HEADER_LEN {
+        let len = u32::from_le_bytes(buf[off..off + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buf[off + 4..off + 8].try_into().unwrap());
+        let start = off + HEADER_LEN;
+        if len > MAX_RECORD_SIZE || buf.len() - start < len {
+            break;
+        }
+        let payload = &buf[start..start + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        records.push(payload);
+        off = start + len;
+    }
+    (records, off)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cpr-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn replays_after_reopen() {
+        let dir = temp_dir("reopen");
+        {
+            let mut wal = Wal::open(&dir, WalConfig::default()).unwrap();
+            for i in 0..10u32 {
+                assert_eq!(wal.append(&i).unwrap(), i as u64 + 1);
+            }
+        }
+        let wal = Wal::open(&dir, WalConfig::default()).unwrap();
+        let records: Vec<(Lsn, u32)> = wal.read_from(4).unwrap();
+        assert_eq!(records.first(), Some(&(4, 3)));
+        assert_eq!(records.len(), 7);
+        assert_eq!(wal.next_lsn(), 11);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_segments() {
+        let dir = temp_dir("rotate");
+        let config = WalConfig {
+            segment_size: 64,
+            fsync: FsyncPolicy::Never,
+        };
+        let mut wal = Wal::open(&dir, config).unwrap();
+        for i in 0..20u64 {
+            wal.append(&i).unwrap();
+        }
+        assert!(wal.segment_count() > 1);
+        drop(wal);
+        let wal = Wal::open(&dir, config).unwrap();
+        let records: Vec<(Lsn, u64)> = wal.read_from(0).unwrap();
+        assert_eq!(records, (0..20).map(|i| (i + 1, i)).collect::<Vec<_>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_single_records() {
+        let dir = temp_dir("many");
+        let config = WalConfig {
+            segment_size: 64,
+            fsync: FsyncPolicy::Never,
+        };
+        let mut wal = Wal::open(&dir, config).unwrap();
+        for i in 0..20u64 {
+            wal.append(&i).unwrap();
+        }
+        assert!(wal.segment_count() > 1);
+        let records: Vec<(Lsn, u64)> = wal.read_many(&[17, 2, 0, 9, 2, 21]).unwrap();
+        assert_eq!(records, [(2, 1), (9, 8), (17, 16)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_covered_segments() {
+        let dir = temp_dir("truncate");
+        let config = WalConfig {
+            segment_size: 64,
+            fsync: FsyncPolicy::Never,
+        };
+        let mut wal = Wal::open(&dir, config).unwrap();
+        for i in 0..20u64 {
+            wal.append(&i).unwrap();
+        }
+        wal.truncate_before(10).unwrap();
+        assert!(wal.first_lsn() > 1 && wal.first_lsn() <= 10);
+        drop(wal);
+        let wal = Wal::open(&dir, config).unwrap();
+        let records: Vec<(Lsn, u64)> = wal.read_from(10).unwrap();
+        assert_eq!(records.first(), Some(&(10, 9)));
+        assert_eq!(wal.next_lsn(), 21);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_torn_tail() {
+        let dir = temp_dir("torn");
+        let mut wal = Wal::open(&dir, WalConfig::default()).unwrap();
+        wal.append(&"first".to_string()).unwrap();
+        wal.append(&"second".to_string()).unwrap();
+        drop(wal);
+        let path = segment_path(&dir, 1);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[12, 0, 0, 0, 1, 2, 3, 4, b'x']).unwrap();
+        drop(file);
+
+        let mut wal = Wal::open(&dir, WalConfig::default()).unwrap();
+        assert_eq!(wal.append(&"third".to_string()).unwrap(), 3);
+        let records: Vec<(Lsn, String)> = wal.read_from(0).unwrap();
+        let values: Vec<&str> = records.iter().map(|(_, s)| s.as_str()).collect();
+        assert_eq!(values, ["first", "second", "third"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,24 @@
 #![allow(clippy::unused_async)]
 #![allow(clippy::unimplemented)]
 
-use cpr::run;
+use cpr::node::{self, config::USAGE, NodeConfig};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let _ = run().await;
-    Ok(())
+    let config = match NodeConfig::from_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(config.log_level()?)
+        .parse_env("CPR_LOG")
+        .init();
+    node::start(config).await
 }
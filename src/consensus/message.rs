@@ -0,0 +1,355 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeSet, fmt};
+
+use crate::federation::genesis::hash_hex;
+use crate::federation::org::user::{KeyPair, PublicKey, Signature};
+use crate::store::proof::Hash;
+use crate::validate::ValidatorSet;
+use crate::TxId;
+
+const CONSENSUS_DOMAIN: &[u8] = b"cpr/consensus/v1";
+
+/// A point the federation agrees its DAG is final up to: the final
+/// transactions that no other final transaction approves, in id order,
+/// and the ledger state root they leave. Each checkpoint names the one
+/// committed before it, so the committed checkpoints form a chain back to
+/// the genesis.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u64,
+    /// Hash of the previous checkpoint, or of the genesis for the first.
+    pub parent: Hash,
+    pub tips: Vec<TxId>,
+    /// Root of the ledger once everything `tips` build on is applied; see
+    /// `CheckpointState`.
+    pub state_root: Hash,
+}
+
+impl Checkpoint {
+    pub fn new(height: u64, parent: Hash, tips: Vec<TxId>, state_root: Hash) -> Self {
+        let tips: BTreeSet<TxId> = tips.into_iter().collect();
+        Self {
+            height,
+            parent,
+            tips: tips.into_iter().collect(),
+            state_root,
+        }
+    }
+
+    pub fn hash(&self) -> Hash {
+        let bytes = bincode::serialize(self).expect("checkpoint is always serializable");
+        Sha256::digest(&bytes).into()
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checkpoint {} ({} tips, {})",
+            self.height,
+            self.tips.len(),
+            &hash_hex(&self.hash())[..16]
+        )
+    }
+}
+
+/// The two voting steps of a round.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A round's proposer putting `checkpoint` forward. `valid_round` is the
+/// round in which it last saw a quorum prevote for it, if it is re-proposing
+/// one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Proposal {
+    pub round: u32,
+    pub checkpoint: Checkpoint,
+    pub valid_round: Option<u32>,
+    pub proposer: String,
+    pub sig: Signature,
+}
+
+/// What proposals and votes sign, after `CONSENSUS_DOMAIN`. Like
+/// transaction votes, both cover the genesis hash of the federation they
+/// are made in without carrying it, so they cannot be replayed in another
+/// federation sharing the key, and the variant tells them apart.
+#[derive(Serialize)]
+enum SignedBody<'a> {
+    Proposal(ProposalBody<'a>),
+    Vote(ConsensusVoteBody<'a>),
+}
+
+impl SignedBody<'_> {
+    fn signing_bytes(&self) -> Vec<u8> {
+        let body = bincode::serialize(self).expect("consensus message body is always serializable");
+        [CONSENSUS_DOMAIN, &body].concat()
+    }
+}
+
+#[derive(Serialize)]
+struct ProposalBody<'a> {
+    genesis: &'a Hash,
+    round: u32,
+    checkpoint: &'a Checkpoint,
+    valid_round: Option<u32>,
+    proposer: &'a str,
+}
+
+impl Proposal {
+    pub fn new(
+        genesis: &Hash,
+        round: u32,
+        checkpoint: Checkpoint,
+        valid_round: Option<u32>,
+        proposer: &str,
+        key: &KeyPair,
+    ) -> Self {
+        let sig = key.sign(&Self::signing_bytes(genesis, round, &checkpoint, valid_round, proposer));
+        Self {
+            round,
+            checkpoint,
+            valid_round,
+            proposer: proposer.to_string(),
+            sig,
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        self.checkpoint.height
+    }
+
+    fn signing_bytes(
+        genesis: &Hash,
+        round: u32,
+        checkpoint: &Checkpoint,
+        valid_round: Option<u32>,
+        proposer: &str,
+    ) -> Vec<u8> {
+        SignedBody::Proposal(ProposalBody {
+            genesis,
+            round,
+            checkpoint,
+            valid_round,
+            proposer,
+        })
+        .signing_bytes()
+    }
+
+    /// Check the proposal is signed, in the federation started from
+    /// `genesis`, with the key its proposer is registered under in
+    /// `validators`.
+    pub fn verify(&self, genesis: &Hash, validators: &ValidatorSet) -> Result<(), ConsensusError> {
+        let key = registered_key(validators, &self.proposer)?;
+        let bytes = Self::signing_bytes(genesis, self.round, &self.checkpoint, self.valid_round, &self.proposer);
+        key.verify(&bytes, &self.sig)
+            .map_err(|_| ConsensusError::BadSignature(self.proposer.clone()))
+    }
+}
+
+/// A validator's prevote or precommit in one round, for the checkpoint with
+/// hash `checkpoint`, or for nothing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConsensusVote {
+    pub kind: VoteKind,
+    pub height: u64,
+    pub round: u32,
+    pub checkpoint: Option<Hash>,
+    pub validator: String,
+    pub sig: Signature,
+}
+
+#[derive(Serialize)]
+struct ConsensusVoteBody<'a> {
+    genesis: &'a Hash,
+    kind: VoteKind,
+    height: u64,
+    round: u32,
+    checkpoint: Option<&'a Hash>,
+    validator: &'a str,
+}
+
+impl ConsensusVote {
+    pub fn new(
+        genesis: &Hash,
+        kind: VoteKind,
+        height: u64,
+        round: u32,
+        checkpoint: Option<Hash>,
+        validator: &str,
+        key: &KeyPair,
+    ) -> Self {
+        let sig = key.sign(&Self::signing_bytes(
+            genesis,
+            kind,
+            height,
+            round,
+            checkpoint.as_ref(),
+            validator,
+        ));
+        Self {
+            kind,
+            height,
+            round,
+            checkpoint,
+            validator: validator.to_string(),
+            sig,
+        }
+    }
+
+    fn signing_bytes(
+        genesis: &Hash,
+        kind: VoteKind,
+        height: u64,
+        round: u32,
+        checkpoint: Option<&Hash>,
+        validator: &str,
+    ) -> Vec<u8> {
+        SignedBody::Vote(ConsensusVoteBody {
+            genesis,
+            kind,
+            height,
+            round,
+            checkpoint,
+            validator,
+        })
+        .signing_bytes()
+    }
+
+    /// Check the vote is signed, in the federation started from `genesis`,
+    /// with the key its validator is registered under in `validators`.
+    pub fn verify(&self, genesis: &Hash, validators: &ValidatorSet) -> Result<(), ConsensusError> {
+        let key = registered_key(validators, &self.validator)?;
+        let bytes = Self::signing_bytes(
+            genesis,
+            self.kind,
+            self.height,
+            self.round,
+            self.checkpoint.as_ref(),
+            &self.validator,
+        );
+        key.verify(&bytes, &self.sig)
+            .map_err(|_| ConsensusError::BadSignature(self.validator.clone()))
+    }
+}
+
+/// A committed checkpoint with the precommits that committed it, enough for
+/// anyone holding the validator set to check it was agreed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Commit {
+    pub round: u32,
+    pub checkpoint: Checkpoint,
+    pub precommits: Vec<ConsensusVote>,
+}
+
+impl Commit {
+    /// Check that validators holding a quorum of `validators`' weight
+    /// signed precommits for the checkpoint in the commit's round, in the
+    /// federation started from `genesis`.
+    pub fn verify(&self, genesis: &Hash, validators: &ValidatorSet) -> Result<(), ConsensusError> {
+        let hash = self.checkpoint.hash();
+        let mut signers = BTreeSet::new();
+        let mut weight = 0;
+        for vote in &self.precommits {
+            if vote.kind != VoteKind::Precommit
+                || vote.height != self.checkpoint.height
+                || vote.round != self.round
+                || vote.checkpoint != Some(hash)
+            {
+                return Err(ConsensusError::Mismatch(vote.validator.clone()));
+            }
+            vote.verify(genesis, validators)?;
+            if signers.insert(vote.validator.as_str()) {
+                weight += validators.get(&vote.validator).map_or(0, |v| v.weight() as u64);
+            }
+        }
+        if weight < validators.quorum() {
+            return Err(ConsensusError::NoQuorum {
+                weight,
+                quorum: validators.quorum(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Everything validators send one another to agree on checkpoints.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ConsensusMessage {
+    Proposal(Proposal),
+    Vote(ConsensusVote),
+    /// Sent once a checkpoint is committed, so validators that missed the
+    /// round can catch up.
+    Commit(Commit),
+}
+
+impl ConsensusMessage {
+    pub fn height(&self) -> u64 {
+        match self {
+            Self::Proposal(p) => p.height(),
+            Self::Vote(v) => v.height,
+            Self::Commit(c) => c.checkpoint.height,
+        }
+    }
+
+    /// Check the message's signatures against `validators`, as made in the
+    /// federation started from `genesis`.
+    pub fn verify(&self, genesis: &Hash, validators: &ValidatorSet) -> Result<(), ConsensusError> {
+        match self {
+            Self::Proposal(p) => p.verify(genesis, validators),
+            Self::Vote(v) => v.verify(genesis, validators),
+            Self::Commit(c) => c.verify(genesis, validators),
+        }
+    }
+}
+
+/// Why a consensus message was not accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsensusError {
+    UnknownValidator(String),
+    BadSignature(String),
+    /// The proposal did not come from the round's proposer.
+    WrongProposer {
+        expected: String,
+        got: String,
+    },
+    /// The validator already voted differently in the same step.
+    Equivocation(String),
+    /// A precommit in a commit is not for the committed checkpoint.
+    Mismatch(String),
+    NoQuorum {
+        weight: u64,
+        quorum: u64,
+    },
+    /// The message is for a height further ahead than is kept.
+    TooFarAhead(u64),
+}
+
+impl fmt::Display for ConsensusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownValidator(v) => write!(f, "{} is not a validator", v),
+            Self::BadSignature(v) => write!(f, "message from {} has an invalid signature", v),
+            Self::WrongProposer { expected, got } => {
+                write!(f, "proposal from {} but the proposer is {}", got, expected)
+            }
+            Self::Equivocation(v) => write!(f, "{} voted twice in the same step", v),
+            Self::Mismatch(v) => write!(f, "precommit from {} is not for the committed checkpoint", v),
+            Self::NoQuorum { weight, quorum } => write!(f, "signed by weight {} of the {} needed", weight, quorum),
+            Self::TooFarAhead(height) => write!(f, "message for height {} is too far ahead", height),
+        }
+    }
+}
+
+impl std::error::Error for ConsensusError {}
+
+fn registered_key(validators: &ValidatorSet, name: &str) -> Result<PublicKey, ConsensusError> {
+    validators
+        .get(name)
+        .and_then(|v| v.public_key().ok())
+        .ok_or_else(|| ConsensusError::UnknownValidator(name.to_string()))
+}
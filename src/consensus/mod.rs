@@ -0,0 +1,455 @@
+pub mod message;
+pub mod round;
+
+pub use message::{Checkpoint, Commit, ConsensusError, ConsensusMessage, ConsensusVote, Proposal, VoteKind};
+pub use round::{Consensus, Step, TimeoutConfig};
+
+use crossbeam_channel::Sender;
+use std::sync::Arc;
+
+use crate::store::proof::Hash;
+use crate::validate::ValidatorSet;
+use crate::{StreamingDAG, TxId};
+
+/// Where checkpoints come from: what a validator proposes, and what it
+/// checks others' proposals against.
+pub trait CheckpointSource {
+    /// The tips the DAG is final up to here.
+    fn final_tips(&self) -> Vec<TxId>;
+
+    /// Whether every one of `tips` is final here. A validator that has not
+    /// seen a tip yet votes against the checkpoint until it has.
+    fn is_final(&self, tips: &[TxId]) -> bool;
+
+    /// The state root a checkpoint of `tips` leaves, building on the last
+    /// one committed, or `None` if something they build on is missing.
+    fn state_root(&self, tips: &[TxId]) -> Option<Hash>;
+
+    /// Called with every commit, in order, once its tips are final here.
+    fn committed(&self, _commit: &Commit) {}
+}
+
+impl CheckpointSource for StreamingDAG {
+    fn final_tips(&self) -> Vec<TxId> {
+        self.dag.final_tips()
+    }
+
+    fn is_final(&self, tips: &[TxId]) -> bool {
+        tips.iter().all(|id| self.dag.is_final(id))
+    }
+
+    fn state_root(&self, tips: &[TxId]) -> Option<Hash> {
+        self.checkpoint_root(tips)
+    }
+
+    fn committed(&self, commit: &Commit) {
+        if let Err(e) = self.record_commit(commit.clone()) {
+            log::error!("Could not record {}: {}", commit.checkpoint, e);
+        }
+    }
+}
+
+impl<S: CheckpointSource + ?Sized> CheckpointSource for Arc<S> {
+    fn final_tips(&self) -> Vec<TxId> {
+        (**self).final_tips()
+    }
+
+    fn is_final(&self, tips: &[TxId]) -> bool {
+        (**self).is_final(tips)
+    }
+
+    fn state_root(&self, tips: &[TxId]) -> Option<Hash> {
+        (**self).state_root(tips)
+    }
+
+    fn committed(&self, commit: &Commit) {
+        (**self).committed(commit)
+    }
+}
+
+/// How consensus messages reach the other validators.
+pub trait Transport {
+    /// Send `msg` to every other validator. Delivery may fail or be late;
+    /// rounds that do not complete in time are retried.
+    fn broadcast(&self, msg: ConsensusMessage);
+}
+
+/// Delivers messages to validators running in the same process, each
+/// reading its own channel.
+pub struct ChannelTransport {
+    pub peers: Vec<Sender<ConsensusMessage>>,
+}
+
+impl ChannelTransport {
+    pub fn new(peers: Vec<Sender<ConsensusMessage>>) -> Self {
+        Self { peers }
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn broadcast(&self, msg: ConsensusMessage) {
+        for peer in &self.peers {
+            // A validator that has gone away is no different from one
+            // that is down.
+            let _ = peer.send(msg.clone());
+        }
+    }
+}
+
+/// The validator proposing in `round` at `height`. Proposers take turns in
+/// the set's order, each for as many rounds as its weight, so that a failed
+/// round passes to the next one.
+pub fn proposer(validators: &ValidatorSet, height: u64, round: u32) -> Option<&str> {
+    let total = validators.total_weight();
+    if total == 0 {
+        return None;
+    }
+    let mut slot = (height + round as u64) % total;
+    for v in validators.iter() {
+        let weight = v.weight() as u64;
+        if slot < weight {
+            return Some(v.name());
+        }
+        slot -= weight;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{user::KeyPair, OrgId};
+    use crate::validate::Validator;
+    use crossbeam_channel::{unbounded, Receiver};
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const GENESIS: [u8; 32] = [7; 32];
+    const GOOD: TxId = TxId([1; 32]);
+    const BAD: TxId = TxId([2; 32]);
+    const ROOT: Hash = [3; 32];
+
+    /// How each in-process validator behaves.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Role {
+        Honest,
+        /// Never runs.
+        Down,
+        /// Starts after a while, with every message sent meanwhile queued.
+        Late(Duration),
+        /// Proposes tips no one else holds final.
+        Faulty,
+    }
+
+    /// Only `GOOD` is final, though a faulty validator claims `BAD` is.
+    struct TestSource {
+        tips: Vec<TxId>,
+    }
+
+    impl CheckpointSource for TestSource {
+        fn final_tips(&self) -> Vec<TxId> {
+            self.tips.clone()
+        }
+
+        fn is_final(&self, tips: &[TxId]) -> bool {
+            tips.iter().all(|t| *t == GOOD)
+        }
+
+        fn state_root(&self, _tips: &[TxId]) -> Option<Hash> {
+            Some(ROOT)
+        }
+    }
+
+    fn timeouts() -> TimeoutConfig {
+        TimeoutConfig {
+            propose: Duration::from_millis(150),
+            prevote: Duration::from_millis(50),
+            precommit: Duration::from_millis(50),
+            delta: Duration::from_millis(50),
+            commit: Duration::from_millis(10),
+        }
+    }
+
+    /// Run one equally weighted validator per role until every running one
+    /// has committed `heights` checkpoints, or `limit` has passed. Returns
+    /// the commits of each validator, empty for those that were down.
+    fn run(roles: &[Role], heights: usize, limit: Duration) -> Vec<Vec<Commit>> {
+        let keys: Vec<KeyPair> = roles.iter().map(|_| KeyPair::generate()).collect();
+        let validators = ValidatorSet::new(
+            keys.iter()
+                .enumerate()
+                .map(|(i, k)| Validator::new(&format!("v{}", i), OrgId::new("org"), 1, k.public().to_string()))
+                .collect(),
+        );
+        let channels: Vec<(Sender<ConsensusMessage>, Receiver<ConsensusMessage>)> =
+            roles.iter().map(|_| unbounded()).collect();
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut running = Vec::new();
+        for (i, role) in roles.iter().enumerate() {
+            if *role == Role::Down {
+                continue;
+            }
+            let tips = if *role == Role::Faulty { vec![BAD] } else { vec![GOOD] };
+            let (commit_tx, commit_rx) = unbounded();
+            let mut node = Consensus::new(
+                &format!("v{}", i),
+                keys[i].clone(),
+                validators.clone(),
+                TestSource { tips },
+                GENESIS,
+            )
+            .with_timeouts(timeouts())
+            .with_commit_channel(commit_tx);
+            let inbox = channels[i].1.clone();
+            let peers = channels
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, (tx, _))| tx.clone());
+            let transport = ChannelTransport::new(peers.collect());
+            let (role, stop) = (*role, Arc::clone(&stop));
+            let handle = thread::spawn(move || {
+                if let Role::Late(delay) = role {
+                    thread::sleep(delay);
+                }
+                node.run(&inbox, &transport, &stop);
+                node.commits().to_vec()
+            });
+            running.push((i, handle, commit_rx));
+        }
+        let deadline = Instant::now() + limit;
+        let mut counts = vec![0; roles.len()];
+        while Instant::now() < deadline && running.iter().any(|(i, _, _)| counts[*i] < heights) {
+            for (i, _, commits) in &running {
+                counts[*i] += commits.try_iter().count();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        stop.store(true, Ordering::SeqCst);
+        let mut commits = vec![Vec::new(); roles.len()];
+        for (i, handle, _) in running {
+            commits[i] = handle.join().unwrap();
+        }
+        commits
+    }
+
+    /// Every validator committed the same valid chain of checkpoints as far
+    /// as it got, and all got at least `heights` far.
+    fn assert_agree(commits: &[Vec<Commit>], validators: usize, heights: usize) {
+        let longest = commits.iter().max_by_key(|c| c.len()).unwrap();
+        let mut parent = GENESIS;
+        for (height, commit) in longest.iter().enumerate() {
+            assert_eq!(commit.checkpoint.height, height as u64);
+            assert_eq!(commit.checkpoint.parent, parent);
+            assert_eq!(commit.checkpoint.tips, vec![GOOD]);
+            assert!(commit.precommits.len() * 3 > validators * 2);
+            parent = commit.checkpoint.hash();
+        }
+        for c in commits.iter().filter(|c| !c.is_empty()) {
+            assert!(c.len() >= heights);
+            let checkpoints = |c: &[Commit]| c.iter().map(|c| c.checkpoint.clone()).collect::<Vec<_>>();
+            assert_eq!(checkpoints(c), checkpoints(&longest[..c.len()]));
+        }
+    }
+
+    #[test]
+    fn proposers_take_turns_by_weight() {
+        let validators = ValidatorSet::new(
+            [("a", 2), ("b", 1)]
+                .iter()
+                .map(|(name, weight)| {
+                    Validator::new(
+                        name,
+                        OrgId::new("org"),
+                        *weight,
+                        KeyPair::generate().public().to_string(),
+                    )
+                })
+                .collect(),
+        );
+        let turns: Vec<&str> = (0..6).map(|r| proposer(&validators, 0, r).unwrap()).collect();
+        assert_eq!(turns, ["a", "a", "b", "a", "a", "b"]);
+        assert_eq!(proposer(&validators, 2, 0), Some("b"));
+        assert_eq!(proposer(&ValidatorSet::default(), 0, 0), None);
+    }
+
+    #[test]
+    fn commits_the_same_checkpoints() {
+        let commits = run(&[Role::Honest; 4], 3, Duration::from_secs(10));
+        assert_agree(&commits, 4, 3);
+        assert!(commits[0].iter().all(|c| c.round == 0));
+    }
+
+    #[test]
+    fn changes_view_past_a_crashed_proposer() {
+        // v1 proposes at height 1, so that height needs a second round.
+        let commits = run(
+            &[Role::Honest, Role::Down, Role::Honest, Role::Honest],
+            3,
+            Duration::from_secs(10),
+        );
+        assert_agree(&commits, 4, 3);
+        assert!(commits[0][1].round > 0);
+    }
+
+    #[test]
+    fn changes_view_past_a_faulty_proposer() {
+        let commits = run(
+            &[Role::Faulty, Role::Honest, Role::Honest, Role::Honest],
+            2,
+            Duration::from_secs(10),
+        );
+        // The faulty validator follows the others' commits, but never gets
+        // its own checkpoint committed.
+        assert_agree(&commits, 4, 2);
+        assert!(commits[1][0].round > 0);
+    }
+
+    #[test]
+    fn halts_without_a_quorum() {
+        let commits = run(
+            &[Role::Honest, Role::Honest, Role::Down, Role::Down],
+            1,
+            Duration::from_millis(800),
+        );
+        assert!(commits.iter().all(|c| c.is_empty()));
+    }
+
+    #[test]
+    fn late_validator_catches_up() {
+        let roles = [
+            Role::Honest,
+            Role::Honest,
+            Role::Honest,
+            Role::Late(Duration::from_millis(300)),
+        ];
+        let commits = run(&roles, 4, Duration::from_secs(10));
+        assert_agree(&commits, 4, 4);
+    }
+
+    #[test]
+    fn buffers_only_signed_messages_close_ahead() {
+        let keys = [KeyPair::generate(), KeyPair::generate()];
+        let validators = ValidatorSet::new(
+            keys.iter()
+                .enumerate()
+                .map(|(i, k)| Validator::new(&format!("v{}", i), OrgId::new("org"), 1, k.public().to_string()))
+                .collect(),
+        );
+        let source = TestSource { tips: vec![GOOD] };
+        let mut node = Consensus::new("v0", keys[0].clone(), validators, source, GENESIS);
+        let vote = |height: u64, key: &KeyPair| {
+            ConsensusMessage::Vote(ConsensusVote::new(&GENESIS, VoteKind::Prevote, height, 0, None, "v1", key))
+        };
+        node.receive(vote(1, &KeyPair::generate()));
+        node.receive(vote(round::MAX_FUTURE_HEIGHTS + 1, &keys[1]));
+        // Signed by v1, but in another federation.
+        let replayed = ConsensusVote::new(&[8; 32], VoteKind::Prevote, 1, 0, None, "v1", &keys[1]);
+        node.receive(ConsensusMessage::Vote(replayed));
+        assert_eq!(node.buffered(), 0);
+        node.receive(vote(1, &keys[1]));
+        node.receive(vote(round::MAX_FUTURE_HEIGHTS, &keys[1]));
+        assert_eq!(node.buffered(), 2);
+    }
+
+    /// Holds `GOOD` final only once `caught_up` is set, and records every
+    /// commit it is told of.
+    struct LaggingSource {
+        caught_up: Arc<AtomicBool>,
+        recorded: Arc<std::sync::Mutex<Vec<Commit>>>,
+    }
+
+    impl CheckpointSource for LaggingSource {
+        fn final_tips(&self) -> Vec<TxId> {
+            Vec::new()
+        }
+
+        fn is_final(&self, tips: &[TxId]) -> bool {
+            self.caught_up.load(Ordering::SeqCst) && tips.iter().all(|t| *t == GOOD)
+        }
+
+        fn state_root(&self, _tips: &[TxId]) -> Option<Hash> {
+            Some(ROOT)
+        }
+
+        fn committed(&self, commit: &Commit) {
+            self.recorded.lock().unwrap().push(commit.clone());
+        }
+    }
+
+    #[test]
+    fn follows_commits_before_their_tips_are_final() {
+        let key = KeyPair::generate();
+        let validators = ValidatorSet::new(vec![Validator::new(
+            "v0",
+            OrgId::new("org"),
+            1,
+            key.public().to_string(),
+        )]);
+        let source = LaggingSource {
+            caught_up: Arc::new(AtomicBool::new(false)),
+            recorded: Arc::default(),
+        };
+        let (caught_up, recorded) = (Arc::clone(&source.caught_up), Arc::clone(&source.recorded));
+        let mut node = Consensus::new("v1", KeyPair::generate(), validators, source, GENESIS);
+        let checkpoint = Checkpoint::new(0, GENESIS, vec![GOOD], ROOT);
+        let precommit = ConsensusVote::new(&GENESIS, VoteKind::Precommit, 0, 0, Some(checkpoint.hash()), "v0", &key);
+        node.receive(ConsensusMessage::Commit(Commit {
+            round: 0,
+            checkpoint,
+            precommits: vec![precommit],
+        }));
+        assert_eq!(node.height(), 1);
+        assert_eq!(node.unrecorded(), 1);
+        assert!(recorded.lock().unwrap().is_empty());
+
+        caught_up.store(true, Ordering::SeqCst);
+        node.on_timeout();
+        assert_eq!(node.unrecorded(), 0);
+        assert_eq!(recorded.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn commits_verify_against_the_validator_set() {
+        let key = KeyPair::generate();
+        let validators = ValidatorSet::new(vec![Validator::new(
+            "v0",
+            OrgId::new("org"),
+            1,
+            key.public().to_string(),
+        )]);
+        let checkpoint = Checkpoint::new(0, GENESIS, vec![GOOD], ROOT);
+        let vote = |c: &Checkpoint, key: &KeyPair| {
+            ConsensusVote::new(&GENESIS, VoteKind::Precommit, 0, 0, Some(c.hash()), "v0", key)
+        };
+        let commit = Commit {
+            round: 0,
+            checkpoint: checkpoint.clone(),
+            precommits: vec![vote(&checkpoint, &key)],
+        };
+        assert_eq!(commit.verify(&GENESIS, &validators), Ok(()));
+        assert_eq!(
+            commit.verify(&[8; 32], &validators),
+            Err(ConsensusError::BadSignature("v0".to_string()))
+        );
+        let forged = Commit {
+            precommits: vec![vote(&checkpoint, &KeyPair::generate())],
+            ..commit.clone()
+        };
+        assert_eq!(
+            forged.verify(&GENESIS, &validators),
+            Err(ConsensusError::BadSignature("v0".to_string()))
+        );
+        let other = Commit {
+            checkpoint: Checkpoint::new(0, GENESIS, vec![BAD], ROOT),
+            ..commit
+        };
+        assert_eq!(
+            other.verify(&GENESIS, &validators),
+            Err(ConsensusError::Mismatch("v0".to_string()))
+        );
+    }
+}
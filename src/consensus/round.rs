@@ -0,0 +1,661 @@
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use super::message::{Checkpoint, Commit, ConsensusError, ConsensusMessage, ConsensusVote, Proposal, VoteKind};
+use super::{proposer, CheckpointSource, Transport};
+use crate::federation::org::user::KeyPair;
+use crate::store::proof::Hash;
+use crate::validate::ValidatorSet;
+
+pub static DEFAULT_PROPOSE_TIMEOUT: Duration = Duration::from_secs(3);
+pub static DEFAULT_PREVOTE_TIMEOUT: Duration = Duration::from_secs(1);
+pub static DEFAULT_PRECOMMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Added to each step's timeout for every round already failed at a
+/// height, so a slow network eventually gets enough time.
+pub static DEFAULT_TIMEOUT_DELTA: Duration = Duration::from_millis(500);
+/// Wait after a commit before starting the next height.
+pub static DEFAULT_COMMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Messages for heights not reached yet that are kept until they are.
+pub static MAX_FUTURE_MESSAGES: usize = 10_000;
+/// How far above the current height proposals and votes are kept for.
+/// Commits carry a quorum's signatures, so are kept whatever their height,
+/// and a validator further behind catches up from those.
+pub static MAX_FUTURE_HEIGHTS: u64 = 4;
+/// Longest `Consensus::run` waits without checking whether to stop.
+pub static POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeoutConfig {
+    pub propose: Duration,
+    pub prevote: Duration,
+    pub precommit: Duration,
+    pub delta: Duration,
+    pub commit: Duration,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            propose: DEFAULT_PROPOSE_TIMEOUT,
+            prevote: DEFAULT_PREVOTE_TIMEOUT,
+            precommit: DEFAULT_PRECOMMIT_TIMEOUT,
+            delta: DEFAULT_TIMEOUT_DELTA,
+            commit: DEFAULT_COMMIT_TIMEOUT,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    fn for_round(&self, base: Duration, round: u32) -> Duration {
+        base + self.delta * round
+    }
+}
+
+/// Where a validator is within a round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    /// Waiting for the round's proposal.
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Timeout {
+    Propose,
+    Prevote,
+    Precommit,
+    /// The pause after a commit is over; start round 0.
+    Commit,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    at: Instant,
+    timeout: Timeout,
+    height: u64,
+    round: u32,
+}
+
+/// One validator's side of the Tendermint round protocol, agreeing with the
+/// rest of `validators` on one checkpoint per height.
+///
+/// Each round, the round's proposer (see `proposer`) proposes a checkpoint
+/// of the tips its `CheckpointSource` reports final. Validators prevote for
+/// it if their own source agrees the tips are final and they are not locked
+/// on another checkpoint, and precommit for it, locking on it, once a quorum
+/// of the validator weight has prevoted for it. A quorum of precommits
+/// commits it. A round in which that does not happen in time is abandoned
+/// for the next, which has another proposer; so is one a third of the
+/// weight has already moved past. As long as validators holding more than
+/// two thirds of the weight are honest, no two of them commit different
+/// checkpoints at the same height.
+///
+/// A commit signed by a quorum is followed even when the tips it names are
+/// not final here yet, so a validator that lags behind the DAG does not get
+/// stuck at its height. The source only hears of it once they are.
+///
+/// The state machine is driven by `receive` and `on_timeout`, and what it
+/// has to send is collected for `drain_outbox`. `run` does both over a
+/// channel and a `Transport`.
+pub struct Consensus<S: CheckpointSource> {
+    pub name: String,
+    key: KeyPair,
+    pub validators: ValidatorSet,
+    pub source: S,
+    pub timeouts: TimeoutConfig,
+    height: u64,
+    round: u32,
+    step: Step,
+    /// Whether the current height's first round has begun.
+    started: bool,
+    /// Hash of the genesis, which every signature covers.
+    genesis: Hash,
+    /// Hash of the last committed checkpoint, or the genesis.
+    last: Hash,
+    locked: Option<(u32, Checkpoint)>,
+    valid: Option<(u32, Checkpoint)>,
+    proposals: BTreeMap<u32, Proposal>,
+    votes: BTreeMap<(u32, VoteKind), BTreeMap<String, ConsensusVote>>,
+    /// Rounds whose prevote and precommit timers were started, and those
+    /// in which a quorum prevote for the proposal was acted on.
+    prevote_timers: BTreeSet<u32>,
+    precommit_timers: BTreeSet<u32>,
+    polka_rounds: BTreeSet<u32>,
+    /// Checkpoints the source has agreed to, by hash.
+    known_valid: HashSet<Hash>,
+    future: Vec<ConsensusMessage>,
+    timers: Vec<Timer>,
+    pending: VecDeque<ConsensusMessage>,
+    outbox: Vec<ConsensusMessage>,
+    commits: Vec<Commit>,
+    /// Commits followed whose tips are not final in the source yet, oldest
+    /// first.
+    unrecorded: VecDeque<Commit>,
+    on_commit: Option<Sender<Commit>>,
+}
+
+impl<S: CheckpointSource> Consensus<S> {
+    /// A validator named `name`, signing with `key`, that builds on
+    /// `genesis` from height 0.
+    pub fn new(name: &str, key: KeyPair, validators: ValidatorSet, source: S, genesis: Hash) -> Self {
+        Self {
+            name: name.to_string(),
+            key,
+            validators,
+            source,
+            timeouts: TimeoutConfig::default(),
+            height: 0,
+            round: 0,
+            step: Step::Propose,
+            started: false,
+            genesis,
+            last: genesis,
+            locked: None,
+            valid: None,
+            proposals: BTreeMap::new(),
+            votes: BTreeMap::new(),
+            prevote_timers: BTreeSet::new(),
+            precommit_timers: BTreeSet::new(),
+            polka_rounds: BTreeSet::new(),
+            known_valid: HashSet::new(),
+            future: Vec::new(),
+            timers: Vec::new(),
+            pending: VecDeque::new(),
+            outbox: Vec::new(),
+            commits: Vec::new(),
+            unrecorded: VecDeque::new(),
+            on_commit: None,
+        }
+    }
+
+    pub fn with_timeouts(self, timeouts: TimeoutConfig) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    /// Send every commit on `on_commit` as well.
+    pub fn with_commit_channel(self, on_commit: Sender<Commit>) -> Self {
+        Self {
+            on_commit: Some(on_commit),
+            ..self
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    /// Every checkpoint committed so far, in order.
+    pub fn commits(&self) -> &[Commit] {
+        &self.commits
+    }
+
+    /// How many commits the source has not been told of yet, waiting for
+    /// their tips to be final there.
+    pub fn unrecorded(&self) -> usize {
+        self.unrecorded.len()
+    }
+
+    /// How many messages are held for heights not reached yet.
+    pub fn buffered(&self) -> usize {
+        self.future.len()
+    }
+
+    /// Whether this node votes, rather than only following the commits.
+    pub fn is_validator(&self) -> bool {
+        self.validators
+            .get(&self.name)
+            .is_some_and(|v| v.public_key().ok() == Some(self.key.public()))
+    }
+
+    /// Begin the current height's first round, if not already begun.
+    pub fn start(&mut self) {
+        if !self.started {
+            self.start_round(0);
+            self.progress();
+        }
+    }
+
+    /// Messages to send to every other validator, oldest first.
+    pub fn drain_outbox(&mut self) -> Vec<ConsensusMessage> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// When `on_timeout` next has something to do.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().map(|t| t.at).min()
+    }
+
+    /// Take in a message from another validator.
+    pub fn receive(&mut self, msg: ConsensusMessage) {
+        self.pending.push_back(msg);
+        self.process();
+    }
+
+    /// Act on every timer that has run out, and pass the source the
+    /// commits whose tips it has finalized since.
+    pub fn on_timeout(&mut self) {
+        self.record_final();
+        let now = Instant::now();
+        let (due, rest): (Vec<Timer>, Vec<Timer>) = self.timers.drain(..).partition(|t| t.at <= now);
+        self.timers = rest;
+        for timer in due {
+            if timer.height != self.height {
+                continue;
+            }
+            match timer.timeout {
+                Timeout::Propose if timer.round == self.round && self.step == Step::Propose => {
+                    log::debug!("{}: no proposal at {}/{}", self.name, self.height, self.round);
+                    self.vote(VoteKind::Prevote, None);
+                    self.step = Step::Prevote;
+                }
+                Timeout::Prevote if timer.round == self.round && self.step == Step::Prevote => {
+                    self.vote(VoteKind::Precommit, None);
+                    self.step = Step::Precommit;
+                }
+                Timeout::Precommit if timer.round == self.round => {
+                    log::debug!("{}: round {}/{} timed out", self.name, self.height, self.round);
+                    self.start_round(self.round + 1);
+                }
+                Timeout::Commit if !self.started => self.start_round(0),
+                _ => {}
+            }
+            self.progress();
+        }
+        self.process();
+    }
+
+    /// Follow the protocol until `stop` is set or `inbox` is closed:
+    /// take messages from `inbox`, act on timeouts, and send everything
+    /// produced over `transport`.
+    pub fn run(&mut self, inbox: &Receiver<ConsensusMessage>, transport: &dyn Transport, stop: &AtomicBool) {
+        self.start();
+        while !stop.load(Ordering::SeqCst) {
+            for msg in self.drain_outbox() {
+                transport.broadcast(msg);
+            }
+            let wait = self
+                .next_deadline()
+                .map_or(POLL_INTERVAL, |at| at.saturating_duration_since(Instant::now()))
+                .min(POLL_INTERVAL);
+            match inbox.recv_timeout(wait) {
+                Ok(msg) => self.receive(msg),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.on_timeout();
+        }
+        for msg in self.drain_outbox() {
+            transport.broadcast(msg);
+        }
+    }
+
+    fn process(&mut self) {
+        while let Some(msg) = self.pending.pop_front() {
+            if let Err(e) = self.accept(msg) {
+                log::warn!("{}: ignoring consensus message: {}", self.name, e);
+            }
+            self.progress();
+        }
+    }
+
+    /// Check and store a message, without acting on it yet.
+    fn accept(&mut self, msg: ConsensusMessage) -> Result<(), ConsensusError> {
+        if msg.height() < self.height {
+            return Ok(());
+        }
+        if msg.height() > self.height {
+            // Checked now, so that only validators can fill the buffer.
+            let commit = matches!(msg, ConsensusMessage::Commit(_));
+            if !commit && msg.height() > self.height + MAX_FUTURE_HEIGHTS {
+                return Err(ConsensusError::TooFarAhead(msg.height()));
+            }
+            msg.verify(&self.genesis, &self.validators)?;
+            if self.future.len() < MAX_FUTURE_MESSAGES {
+                self.future.push(msg);
+            }
+            return Ok(());
+        }
+        match msg {
+            ConsensusMessage::Proposal(p) => {
+                p.verify(&self.genesis, &self.validators)?;
+                let expected = proposer(&self.validators, self.height, p.round);
+                if expected != Some(p.proposer.as_str()) {
+                    return Err(ConsensusError::WrongProposer {
+                        expected: expected.unwrap_or_default().to_string(),
+                        got: p.proposer,
+                    });
+                }
+                match self.proposals.get(&p.round) {
+                    Some(existing) if existing.checkpoint != p.checkpoint => {
+                        return Err(ConsensusError::Equivocation(p.proposer))
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.proposals.insert(p.round, p);
+                    }
+                }
+            }
+            ConsensusMessage::Vote(v) => {
+                v.verify(&self.genesis, &self.validators)?;
+                let votes = self.votes.entry((v.round, v.kind)).or_default();
+                match votes.get(&v.validator) {
+                    Some(existing) if existing.checkpoint != v.checkpoint => {
+                        return Err(ConsensusError::Equivocation(v.validator))
+                    }
+                    Some(_) => {}
+                    None => {
+                        votes.insert(v.validator.clone(), v);
+                    }
+                }
+            }
+            ConsensusMessage::Commit(c) => {
+                c.verify(&self.genesis, &self.validators)?;
+                if c.checkpoint.parent == self.last {
+                    log::info!("{}: caught up to {}", self.name, c.checkpoint);
+                    self.commit(c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the protocol's rules to what has been received until none
+    /// applies any more.
+    fn progress(&mut self) {
+        while self.started && self.step_once() {}
+    }
+
+    /// Apply the first rule that applies, returning whether one did.
+    fn step_once(&mut self) -> bool {
+        let (height, round) = (self.height, self.round);
+
+        // A third of the weight is in a later round: skip ahead to it.
+        let later = self.votes.keys().map(|(r, _)| *r).chain(self.proposals.keys().copied());
+        let skip = later.filter(|r| *r > round).collect::<BTreeSet<u32>>();
+        let skip_weight = self.validators.total_weight().saturating_sub(self.validators.quorum());
+        if let Some(r) = skip.into_iter().rev().find(|r| self.round_weight(*r) > skip_weight) {
+            log::debug!("{}: skipping to round {}/{}", self.name, height, r);
+            self.start_round(r);
+            return true;
+        }
+
+        // A proposal with a quorum of precommits, in any round: commit it.
+        let decided = self.proposals.iter().find_map(|(r, p)| {
+            let hash = p.checkpoint.hash();
+            (self.weight(*r, VoteKind::Precommit, Some(hash)) >= self.validators.quorum())
+                .then(|| (*r, p.checkpoint.clone()))
+        });
+        if let Some((r, checkpoint)) = decided {
+            if checkpoint.height == height && checkpoint.parent == self.last {
+                let hash = checkpoint.hash();
+                let precommits = self.votes[&(r, VoteKind::Precommit)]
+                    .values()
+                    .filter(|v| v.checkpoint == Some(hash))
+                    .cloned()
+                    .collect();
+                let commit = Commit {
+                    round: r,
+                    checkpoint,
+                    precommits,
+                };
+                self.outbox.push(ConsensusMessage::Commit(commit.clone()));
+                self.commit(commit);
+                return true;
+            }
+        }
+
+        let proposal = self
+            .proposals
+            .get(&round)
+            .map(|p| (p.checkpoint.clone(), p.valid_round));
+        if self.step == Step::Propose {
+            if let Some((checkpoint, valid_round)) = &proposal {
+                let hash = checkpoint.hash();
+                let acceptable = match valid_round {
+                    None => Some(self.locked.as_ref().is_none_or(|(_, c)| c == checkpoint)),
+                    // A re-proposal of a checkpoint that had a quorum of
+                    // prevotes in an earlier round, once those prevotes
+                    // are here too.
+                    Some(vr)
+                        if *vr < round
+                            && self.weight(*vr, VoteKind::Prevote, Some(hash)) >= self.validators.quorum() =>
+                    {
+                        Some(self.locked.as_ref().is_none_or(|(lr, c)| lr <= vr || c == checkpoint))
+                    }
+                    Some(_) => None,
+                };
+                if let Some(acceptable) = acceptable {
+                    let vote = (acceptable && self.is_valid(checkpoint)).then_some(hash);
+                    self.vote(VoteKind::Prevote, vote);
+                    self.step = Step::Prevote;
+                    return true;
+                }
+            }
+        }
+
+        if self.step == Step::Prevote
+            && !self.prevote_timers.contains(&round)
+            && self.step_weight(round, VoteKind::Prevote) >= self.validators.quorum()
+        {
+            self.prevote_timers.insert(round);
+            self.schedule(Timeout::Prevote, self.timeouts.for_round(self.timeouts.prevote, round));
+            return true;
+        }
+
+        if let Some((checkpoint, _)) = &proposal {
+            let hash = checkpoint.hash();
+            if self.step >= Step::Prevote
+                && !self.polka_rounds.contains(&round)
+                && self.weight(round, VoteKind::Prevote, Some(hash)) >= self.validators.quorum()
+                && self.is_valid(checkpoint)
+            {
+                self.polka_rounds.insert(round);
+                if self.step == Step::Prevote {
+                    self.locked = Some((round, checkpoint.clone()));
+                    self.vote(VoteKind::Precommit, Some(hash));
+                    self.step = Step::Precommit;
+                }
+                self.valid = Some((round, checkpoint.clone()));
+                return true;
+            }
+        }
+
+        if self.step == Step::Prevote && self.weight(round, VoteKind::Prevote, None) >= self.validators.quorum() {
+            self.vote(VoteKind::Precommit, None);
+            self.step = Step::Precommit;
+            return true;
+        }
+
+        if !self.precommit_timers.contains(&round)
+            && self.step_weight(round, VoteKind::Precommit) >= self.validators.quorum()
+        {
+            self.precommit_timers.insert(round);
+            self.schedule(
+                Timeout::Precommit,
+                self.timeouts.for_round(self.timeouts.precommit, round),
+            );
+            return true;
+        }
+
+        false
+    }
+
+    fn start_round(&mut self, round: u32) {
+        self.round = round;
+        self.step = Step::Propose;
+        self.started = true;
+        let proposing = proposer(&self.validators, self.height, round) == Some(self.name.as_str());
+        let proposal = match &self.valid {
+            _ if !proposing || !self.is_validator() => None,
+            Some((r, checkpoint)) => Some((checkpoint.clone(), Some(*r))),
+            None => self.new_checkpoint().map(|c| (c, None)),
+        };
+        match proposal {
+            Some((checkpoint, valid_round)) => {
+                log::debug!("{}: proposing {} in round {}", self.name, checkpoint, round);
+                let proposal = Proposal::new(
+                    &self.genesis,
+                    round,
+                    checkpoint,
+                    valid_round,
+                    &self.name,
+                    &self.key,
+                );
+                self.broadcast(ConsensusMessage::Proposal(proposal));
+            }
+            None => self.schedule(Timeout::Propose, self.timeouts.for_round(self.timeouts.propose, round)),
+        }
+    }
+
+    /// A checkpoint of the source's final tips at the current height, or
+    /// `None` if the source cannot tell the state they leave yet.
+    fn new_checkpoint(&self) -> Option<Checkpoint> {
+        let tips = self.source.final_tips();
+        let state_root = self.source.state_root(&tips)?;
+        Some(Checkpoint::new(self.height, self.last, tips, state_root))
+    }
+
+    fn commit(&mut self, commit: Commit) {
+        log::info!(
+            "{}: committed {} in round {}",
+            self.name,
+            commit.checkpoint,
+            commit.round
+        );
+        self.last = commit.checkpoint.hash();
+        self.height += 1;
+        self.round = 0;
+        self.step = Step::Propose;
+        self.started = false;
+        self.locked = None;
+        self.valid = None;
+        self.proposals.clear();
+        self.votes.clear();
+        self.prevote_timers.clear();
+        self.precommit_timers.clear();
+        self.polka_rounds.clear();
+        self.known_valid.clear();
+        self.timers.clear();
+        self.unrecorded.push_back(commit.clone());
+        self.record_final();
+        if let Some(tx) = &self.on_commit {
+            let _ = tx.send(commit.clone());
+        }
+        self.commits.push(commit);
+        self.schedule(Timeout::Commit, self.timeouts.commit);
+        let (now, later): (Vec<_>, Vec<_>) = self.future.drain(..).partition(|m| m.height() == self.height);
+        self.future = later;
+        self.pending.extend(now);
+    }
+
+    /// Pass the source each commit, in order, as soon as its tips are final
+    /// there.
+    fn record_final(&mut self) {
+        while let Some(commit) = self.unrecorded.front() {
+            if !self.source.is_final(&commit.checkpoint.tips) {
+                break;
+            }
+            self.source.committed(commit);
+            self.unrecorded.pop_front();
+        }
+    }
+
+    /// Sign and send a vote in the current round, if this node votes.
+    fn vote(&mut self, kind: VoteKind, checkpoint: Option<Hash>) {
+        if self.is_validator() {
+            let vote = ConsensusVote::new(
+                &self.genesis,
+                kind,
+                self.height,
+                self.round,
+                checkpoint,
+                &self.name,
+                &self.key,
+            );
+            self.broadcast(ConsensusMessage::Vote(vote));
+        }
+    }
+
+    /// Send `msg` to the others, and take it in here as if received.
+    fn broadcast(&mut self, msg: ConsensusMessage) {
+        self.outbox.push(msg.clone());
+        self.pending.push_back(msg);
+    }
+
+    fn schedule(&mut self, timeout: Timeout, after: Duration) {
+        self.timers.push(Timer {
+            at: Instant::now() + after,
+            timeout,
+            height: self.height,
+            round: self.round,
+        });
+    }
+
+    fn is_valid(&mut self, checkpoint: &Checkpoint) -> bool {
+        let hash = checkpoint.hash();
+        if self.known_valid.contains(&hash) {
+            return true;
+        }
+        let valid = checkpoint.height == self.height
+            && checkpoint.parent == self.last
+            && self.source.is_final(&checkpoint.tips)
+            && self.source.state_root(&checkpoint.tips) == Some(checkpoint.state_root);
+        if valid {
+            self.known_valid.insert(hash);
+        }
+        valid
+    }
+
+    fn weight_of<'a>(&self, names: impl Iterator<Item = &'a String>) -> u64 {
+        names
+            .filter_map(|n| self.validators.get(n))
+            .map(|v| v.weight() as u64)
+            .sum()
+    }
+
+    /// Weight of the `kind` votes in `round` for `checkpoint`.
+    fn weight(&self, round: u32, kind: VoteKind, checkpoint: Option<Hash>) -> u64 {
+        self.votes.get(&(round, kind)).map_or(0, |votes| {
+            self.weight_of(
+                votes
+                    .values()
+                    .filter(|v| v.checkpoint == checkpoint)
+                    .map(|v| &v.validator),
+            )
+        })
+    }
+
+    /// Weight of the `kind` votes in `round`, whatever they are for.
+    fn step_weight(&self, round: u32, kind: VoteKind) -> u64 {
+        self.votes
+            .get(&(round, kind))
+            .map_or(0, |votes| self.weight_of(votes.keys()))
+    }
+
+    /// Weight of the validators heard from in `round` at all.
+    fn round_weight(&self, round: u32) -> u64 {
+        let mut names: BTreeSet<&String> = BTreeSet::new();
+        for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+            if let Some(votes) = self.votes.get(&(round, kind)) {
+                names.extend(votes.keys());
+            }
+        }
+        if let Some(p) = self.proposals.get(&round) {
+            names.insert(&p.proposer);
+        }
+        self.weight_of(names.into_iter())
+    }
+}
@@ -0,0 +1,555 @@
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashSet, fs, path::Path, str::FromStr};
+
+use super::{
+    id::FedId,
+    org::{
+        user::{key::KEY_LEN, OrgUser, OrgUserId, PublicKey},
+        Org, OrgId, OrgRules,
+    },
+    Federation,
+};
+use crate::models::amount::{Amount, DEFAULT_DECIMALS, MAX_DECIMALS};
+use crate::store::{
+    dag::graph::{DEFAULT_CONFIRMATION_THRESHOLD, DEFAULT_FINALITY_THRESHOLD},
+    proof::Hash,
+    FinalityConfig, StreamingDAG,
+};
+use crate::validate::Validator;
+
+/// Characters generated ids are drawn from, as for random ones.
+static ID_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// Length of ids derived for entries that do not give one.
+static DERIVED_ID_LEN: usize = 2;
+
+/// Everything a federation starts out with, so that every node built from
+/// the same file agrees on it:
+///
+/// ```toml
+/// [federation]
+/// handle = "test"
+///
+/// [params]
+/// decimals = 2
+/// finality_threshold = 6
+///
+/// [[orgs]]
+/// handle = "aliceorg"
+/// symbol = "ALICE"
+/// decimals = 2
+/// users = [{ handle = "jordan", key = "3b6a27bc...bd2b" }]
+/// rules = { tx_limit = { ALICE = "500.00" } }
+///
+/// [[validators]]
+/// name = "alice-1"
+/// org = "aliceorg"
+/// weight = 3
+/// key = "d75a9801...511a"
+///
+/// [[balances]]
+/// org = "aliceorg"
+/// user = "jordan"
+/// symbol = "ALICE"
+/// amount = "100.00"
+/// ```
+///
+/// Ids left out are derived from the handles, never generated at random.
+/// Nodes compare `hash()` during the handshake and refuse peers started
+/// from a different genesis.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Genesis {
+    pub federation: GenesisFederation,
+    #[serde(default)]
+    pub params: ProtocolParams,
+    #[serde(default)]
+    pub orgs: Vec<GenesisOrg>,
+    #[serde(default)]
+    pub validators: Vec<GenesisValidator>,
+    #[serde(default)]
+    pub balances: Vec<GenesisBalance>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisFederation {
+    pub handle: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Settings every node in the federation must share.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolParams {
+    /// Decimal places of symbols whose org does not set its own.
+    pub decimals: u8,
+    pub confirmation_threshold: u64,
+    pub finality_threshold: u64,
+    /// Whether approvals count for their org's validator weight.
+    pub validator_weighted: bool,
+}
+
+impl Default for ProtocolParams {
+    fn default() -> Self {
+        Self {
+            decimals: DEFAULT_DECIMALS,
+            confirmation_threshold: DEFAULT_CONFIRMATION_THRESHOLD,
+            finality_threshold: DEFAULT_FINALITY_THRESHOLD,
+            validator_weighted: true,
+        }
+    }
+}
+
+impl ProtocolParams {
+    pub fn finality(&self) -> FinalityConfig {
+        FinalityConfig {
+            confirmation_threshold: self.confirmation_threshold,
+            finality_threshold: self.finality_threshold,
+            validator_weighted: self.validator_weighted,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisOrg {
+    pub handle: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    pub symbol: String,
+    /// Decimal places amounts of `symbol` are written with, or
+    /// `params.decimals`.
+    #[serde(default)]
+    pub decimals: Option<u8>,
+    #[serde(default)]
+    pub users: Vec<GenesisUser>,
+    #[serde(default)]
+    pub rules: OrgRules,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisUser {
+    pub handle: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Hex-encoded Ed25519 public key the user signs with.
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisValidator {
+    pub name: String,
+    /// Handle of the org the validator approves for.
+    pub org: String,
+    pub weight: usize,
+    /// Hex-encoded Ed25519 public key the validator signs with.
+    pub key: String,
+}
+
+/// An amount credited to a user before any transaction.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisBalance {
+    pub org: String,
+    pub user: String,
+    pub symbol: String,
+    /// A decimal string with at most as many places as `symbol` has.
+    pub amount: String,
+}
+
+impl FromStr for Genesis {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let genesis: Genesis = toml::from_str(s).map_err(|e| anyhow::anyhow!("{}", e.to_string().trim_end()))?;
+        genesis.validate()?;
+        Ok(genesis)
+    }
+}
+
+impl Genesis {
+    /// A federation of the one org `handle`, issuing a symbol of the same
+    /// name, for a node run without a genesis file.
+    pub fn standalone(handle: &str) -> Self {
+        Self {
+            federation: GenesisFederation {
+                handle: handle.to_string(),
+                ..Default::default()
+            },
+            orgs: vec![GenesisOrg {
+                handle: handle.to_string(),
+                symbol: handle.to_uppercase(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Read and validate the genesis file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Could not read genesis file {}: {}", path.display(), e))?;
+        text.parse()
+            .map_err(|e| anyhow::anyhow!("Invalid genesis file {}: {}", path.display(), e))
+    }
+
+    /// SHA-256 of the parsed contents, so formatting and comments do not
+    /// change it but any setting does.
+    pub fn hash(&self) -> Hash {
+        let bytes = bincode::serialize(self).expect("genesis is always serializable");
+        Sha256::digest(&bytes).into()
+    }
+
+    /// Check that every entry is well formed and refers only to orgs and
+    /// users defined in the file, naming the first one that is not.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.federation.handle.is_empty() {
+            anyhow::bail!("federation.handle: must not be empty");
+        }
+        let params = &self.params;
+        if params.decimals > MAX_DECIMALS {
+            anyhow::bail!("params.decimals: must be at most {}", MAX_DECIMALS);
+        }
+        if params.confirmation_threshold > params.finality_threshold {
+            anyhow::bail!("params.confirmation_threshold: must not exceed finality_threshold");
+        }
+        let mut handles = HashSet::new();
+        let mut symbols = HashSet::new();
+        for (i, org) in self.orgs.iter().enumerate() {
+            if org.handle.is_empty() {
+                anyhow::bail!("orgs[{}].handle: must not be empty", i);
+            }
+            if !handles.insert(org.handle.as_str()) {
+                anyhow::bail!("orgs[{}].handle: {} is defined twice", i, org.handle);
+            }
+            if org.symbol.is_empty() {
+                anyhow::bail!("orgs[{}].symbol: must not be empty", i);
+            }
+            if !symbols.insert(org.symbol.to_uppercase()) {
+                anyhow::bail!("orgs[{}].symbol: {} is used by another org", i, org.symbol);
+            }
+            if org.decimals.is_some_and(|d| d > MAX_DECIMALS) {
+                anyhow::bail!("orgs[{}].decimals: must be at most {}", i, MAX_DECIMALS);
+            }
+            let mut users = HashSet::new();
+            for (j, user) in org.users.iter().enumerate() {
+                if user.handle.is_empty() {
+                    anyhow::bail!("orgs[{}].users[{}].handle: must not be empty", i, j);
+                }
+                if !users.insert(user.handle.as_str()) {
+                    anyhow::bail!("orgs[{}].users[{}].handle: {} is defined twice", i, j, user.handle);
+                }
+                if let Some(key) = &user.key {
+                    parse_key(key).map_err(|e| anyhow::anyhow!("orgs[{}].users[{}].key: {}", i, j, e))?;
+                }
+            }
+        }
+        for (i, org) in self.orgs.iter().enumerate() {
+            let rules = &org.rules;
+            for (field, limits) in [
+                ("tx_limit", &rules.tx_limit),
+                ("daily_limit", &rules.daily_limit),
+                ("min_balance", &rules.min_balance),
+            ] {
+                for (symbol, amt) in limits.iter() {
+                    let decimals = self.decimals(symbol);
+                    if amt.decimals() > decimals {
+                        anyhow::bail!("orgs[{}].rules.{}.{}: {} has more than {} decimals", i, field, symbol, amt, decimals);
+                    }
+                }
+            }
+        }
+        let mut names = HashSet::new();
+        for (i, v) in self.validators.iter().enumerate() {
+            if v.name.is_empty() {
+                anyhow::bail!("validators[{}].name: must not be empty", i);
+            }
+            if !names.insert(v.name.as_str()) {
+                anyhow::bail!("validators[{}].name: {} is defined twice", i, v.name);
+            }
+            if self.org(&v.org).is_none() {
+                anyhow::bail!("validators[{}].org: no org {}", i, v.org);
+            }
+            if v.weight == 0 {
+                anyhow::bail!("validators[{}].weight: must be at least 1", i);
+            }
+            parse_key(&v.key).map_err(|e| anyhow::anyhow!("validators[{}].key: {}", i, e))?;
+        }
+        for (i, b) in self.balances.iter().enumerate() {
+            let org = match self.org(&b.org) {
+                Some(org) => org,
+                None => anyhow::bail!("balances[{}].org: no org {}", i, b.org),
+            };
+            if !org.users.iter().any(|u| u.handle == b.user) {
+                anyhow::bail!("balances[{}].user: no user {} in {}", i, b.user, b.org);
+            }
+            if !symbols.contains(&b.symbol.to_uppercase()) {
+                anyhow::bail!("balances[{}].symbol: no org issues {}", i, b.symbol);
+            }
+            Amount::parse_with_decimals(&b.amount, self.decimals(&b.symbol))
+                .map_err(|e| anyhow::anyhow!("balances[{}].amount: {}", i, e))?;
+        }
+        Ok(())
+    }
+
+    fn org(&self, handle: &str) -> Option<&GenesisOrg> {
+        self.orgs.iter().find(|o| o.handle == handle)
+    }
+
+    /// Decimal places of `symbol`: those of the org issuing it, if it sets
+    /// any, or else `params.decimals`.
+    pub fn decimals(&self, symbol: &str) -> u8 {
+        self.orgs
+            .iter()
+            .find(|o| o.symbol.eq_ignore_ascii_case(symbol))
+            .and_then(|o| o.decimals)
+            .unwrap_or(self.params.decimals)
+    }
+
+    /// Build the federation described, tagged with this genesis' hash.
+    pub fn federation(&self) -> anyhow::Result<Federation> {
+        self.validate()?;
+        let fed_id = FedId {
+            id: self
+                .federation
+                .id
+                .clone()
+                .unwrap_or_else(|| derive_id(&[&self.federation.handle])),
+            handle: self.federation.handle.clone(),
+            tags: self.federation.tags.clone(),
+            metadata: None,
+        };
+        let mut fed = Federation::new(&fed_id.handle);
+        fed.id = fed_id.clone();
+        fed.genesis = self.hash();
+        for org in self.orgs.iter() {
+            let org_id = self.org_id(&fed_id, org);
+            let users = org
+                .users
+                .iter()
+                .map(|u| {
+                    let id = OrgUserId {
+                        id: u.id.clone().unwrap_or_else(|| derive_id(&[&org_id.id, &u.handle])),
+                        handle: u.handle.clone(),
+                        org_id: org_id.clone(),
+                    };
+                    let key = u.key.as_deref().map(parse_key).transpose()?;
+                    Ok(OrgUser { id, balances: Vec::new(), key })
+                })
+                .collect::<anyhow::Result<Vec<OrgUser>>>()?;
+            fed.register_org(Org {
+                id: org_id,
+                symbol: org.symbol.to_uppercase(),
+                decimals: self.decimals(&org.symbol),
+                users,
+                rules: org.rules.clone(),
+            });
+        }
+        for v in self.validators.iter() {
+            let org_id = match fed.orgs.iter().find(|o| o.id.handle == v.org) {
+                Some(org) => org.id.clone(),
+                None => anyhow::bail!("validators: no org {}", v.org),
+            };
+            fed.register_validator(Validator::new(&v.name, org_id, v.weight, v.key.clone()));
+        }
+        Ok(fed)
+    }
+
+    fn org_id(&self, fed_id: &FedId, org: &GenesisOrg) -> OrgId {
+        OrgId {
+            id: org.id.clone().unwrap_or_else(|| derive_id(&[&fed_id.id, &org.handle])),
+            fed_id: fed_id.clone(),
+            handle: org.handle.clone(),
+        }
+    }
+
+    /// Credit the initial balances to `dag`, unless it already holds state,
+    /// e.g. from an earlier run whose log has been replayed.
+    pub fn mint(&self, dag: &StreamingDAG) -> anyhow::Result<bool> {
+        if !dag.ledger.lock().unwrap().is_empty() || !dag.dag.is_empty() || dag.dag.has_pruned() {
+            return Ok(false);
+        }
+        for b in self.balances.iter() {
+            let user = dag
+                .federation
+                .orgs
+                .iter()
+                .find(|o| o.id.handle == b.org)
+                .and_then(|o| o.users.iter().find(|u| u.id.handle == b.user))
+                .map(|u| u.id.clone())
+                .ok_or_else(|| anyhow::anyhow!("balances: no user {} in {}", b.user, b.org))?;
+            let amt = Amount::parse_with_decimals(&b.amount, self.decimals(&b.symbol))?;
+            dag.mint(&user, &b.symbol.to_uppercase(), amt)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Hex form of a genesis hash, for logs and errors.
+pub fn hash_hex(hash: &Hash) -> String {
+    HEXLOWER.encode(hash)
+}
+
+fn parse_key(hex: &str) -> anyhow::Result<PublicKey> {
+    hex.parse()
+        .map_err(|e| anyhow::anyhow!("{}, expected {} hex characters", e, KEY_LEN * 2))
+}
+
+/// An id of the usual length and alphabet, taken from a hash of `parts`.
+fn derive_id(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .take(DERIVED_ID_LEN)
+        .map(|b| ID_CHARS[*b as usize % ID_CHARS.len()] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TWO_ORGS: &str = r#"
+        [federation]
+        handle = "test"
+
+        [params]
+        decimals = 4
+
+        [[orgs]]
+        handle = "aliceorg"
+        symbol = "ALICE"
+        decimals = 0
+        users = [{ handle = "jordan" }]
+
+        [[orgs]]
+        handle = "boborg"
+        symbol = "BOB"
+        users = [{ handle = "sam" }]
+    "#;
+
+    #[test]
+    fn precision_is_per_symbol() {
+        let genesis: Genesis = TWO_ORGS.parse().unwrap();
+        assert_eq!(genesis.decimals("alice"), 0);
+        assert_eq!(genesis.decimals("BOB"), 4);
+        let fed = genesis.federation().unwrap();
+        assert_eq!(fed.decimals("ALICE"), Some(0));
+        assert_eq!(fed.decimals("BOB"), Some(4));
+
+        let balance = |symbol: &str, amount: &str| {
+            format!("{}\n[[balances]]\norg = \"aliceorg\"\nuser = \"jordan\"\nsymbol = \"{}\"\namount = \"{}\"\n", TWO_ORGS, symbol, amount)
+        };
+        assert!(balance("ALICE", "5").parse::<Genesis>().is_ok());
+        assert!(balance("ALICE", "5.5").parse::<Genesis>().is_err());
+        assert!(balance("BOB", "5.5").parse::<Genesis>().is_ok());
+        let limited = TWO_ORGS.replacen("decimals = 0", "decimals = 0\nrules = { tx_limit = { ALICE = \"1.5\" } }", 1);
+        assert!(limited.parse::<Genesis>().unwrap_err().to_string().starts_with("orgs[0].rules.tx_limit.ALICE"));
+    }
+
+    /// `TWO_ORGS` with keys for jordan and a validator, and a balance.
+    fn full() -> Genesis {
+        use crate::federation::org::user::KeyPair;
+        let mut genesis: Genesis = TWO_ORGS.parse().unwrap();
+        genesis.orgs[0].users[0].key = Some(KeyPair::generate().public().to_string());
+        genesis.validators.push(GenesisValidator {
+            name: "v0".to_string(),
+            org: "aliceorg".to_string(),
+            weight: 1,
+            key: KeyPair::generate().public().to_string(),
+        });
+        genesis.balances.push(GenesisBalance {
+            org: "aliceorg".to_string(),
+            user: "jordan".to_string(),
+            symbol: "ALICE".to_string(),
+            amount: "5".to_string(),
+        });
+        genesis.validate().unwrap();
+        genesis
+    }
+
+    #[test]
+    fn validate_names_the_first_bad_entry() {
+        let base = full();
+        let check = |field: &str, f: &dyn Fn(&mut Genesis)| {
+            let mut genesis = base.clone();
+            f(&mut genesis);
+            let err = genesis.validate().unwrap_err().to_string();
+            assert!(err.starts_with(field), "{}: {}", field, err);
+        };
+        check("federation.handle", &|g| g.federation.handle.clear());
+        check("params.decimals", &|g| g.params.decimals = MAX_DECIMALS + 1);
+        check("params.confirmation_threshold", &|g| g.params.confirmation_threshold = g.params.finality_threshold + 1);
+        check("orgs[1].handle", &|g| g.orgs[1].handle.clear());
+        check("orgs[1].handle: aliceorg is defined twice", &|g| g.orgs[1].handle = "aliceorg".to_string());
+        check("orgs[1].symbol", &|g| g.orgs[1].symbol.clear());
+        check("orgs[1].symbol: alice is used", &|g| g.orgs[1].symbol = "alice".to_string());
+        check("orgs[1].decimals", &|g| g.orgs[1].decimals = Some(MAX_DECIMALS + 1));
+        check("orgs[0].users[0].handle", &|g| g.orgs[0].users[0].handle.clear());
+        check("orgs[1].users[1].handle: sam is defined twice", &|g| {
+            let sam = g.orgs[1].users[0].clone();
+            g.orgs[1].users.push(sam);
+        });
+        check("orgs[0].users[0].key", &|g| g.orgs[0].users[0].key = Some("beef".to_string()));
+        check("validators[0].name", &|g| g.validators[0].name.clear());
+        check("validators[1].name: v0 is defined twice", &|g| {
+            let v0 = g.validators[0].clone();
+            g.validators.push(v0);
+        });
+        check("validators[0].org", &|g| g.validators[0].org = "carolorg".to_string());
+        check("validators[0].weight", &|g| g.validators[0].weight = 0);
+        check("validators[0].key", &|g| g.validators[0].key = "zz".to_string());
+        check("balances[0].org", &|g| g.balances[0].org = "carolorg".to_string());
+        check("balances[0].user", &|g| g.balances[0].user = "sam".to_string());
+        check("balances[0].symbol", &|g| g.balances[0].symbol = "CAROL".to_string());
+        check("balances[0].amount", &|g| g.balances[0].amount = "five".to_string());
+        assert!("[federation]\nhandle = \"test\"\ncolour = \"blue\"".parse::<Genesis>().is_err());
+    }
+
+    #[test]
+    fn the_hash_depends_only_on_the_settings() {
+        let reformatted = format!("# The test federation\n{}", TWO_ORGS.replace("        ", "").replace(" = ", "="));
+        let (a, b): (Genesis, Genesis) = (TWO_ORGS.parse().unwrap(), reformatted.parse().unwrap());
+        assert_eq!(a.hash(), b.hash());
+        let mut changed = a.clone();
+        changed.params.finality_threshold += 1;
+        assert_ne!(changed.hash(), a.hash());
+
+        // Every id is derived, so two builds agree on all of them.
+        let (x, y) = (a.federation().unwrap(), b.federation().unwrap());
+        assert_eq!(x.genesis, a.hash());
+        assert_eq!(x.id, y.id);
+        for (o, p) in x.orgs.iter().zip(y.orgs.iter()) {
+            assert_eq!(o.id, p.id);
+            let ids = |org: &Org| org.users.iter().map(|u| u.id.clone()).collect::<Vec<_>>();
+            assert_eq!(ids(o), ids(p));
+        }
+    }
+
+    #[test]
+    fn minting_happens_once() {
+        let genesis = full();
+        let dag = StreamingDAG::new_with_federation(10, genesis.federation().unwrap());
+        let jordan = dag.federation.orgs[0].users[0].id.clone();
+        let five = Some(Amount::from_whole(5, 0).unwrap());
+        assert!(genesis.mint(&dag).unwrap());
+        let minted = dag.state_root();
+        assert_eq!(dag.ledger.lock().unwrap().balance(&jordan, "ALICE"), five);
+        assert!(!genesis.mint(&dag).unwrap());
+        assert_eq!(dag.ledger.lock().unwrap().balance(&jordan, "ALICE"), five);
+        assert_eq!(dag.state_root(), minted);
+    }
+}
@@ -1,23 +1,42 @@
+pub mod genesis;
 pub mod id;
 pub mod org;
 
-use self::{id::FedId, org::OrgId};
-use super::Transaction;
+use self::{
+    id::FedId,
+    org::{
+        user::{OrgUser, OrgUserId},
+        OrgId,
+    },
+};
+use super::{
+    store::proof::Hash,
+    validate::{Decision, ValidationError, ValidationOutcome, Validator, ValidatorSet, Voter},
+    Transaction,
+};
 pub use super::models::HasIdentifier;
+pub use genesis::Genesis;
 pub use org::Org;
 
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
+    sync::Arc,
     time::SystemTime,
 };
-use tokio::time::Duration;
+use tokio::{task::JoinSet, time::Duration};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde()]
 pub struct Federation {
     pub id: FedId,
     pub orgs: Vec<Org>,
+    #[serde(default)]
+    pub validators: ValidatorSet,
+    /// Hash of the genesis the federation was built from, or zeros if it
+    /// was built in code.
+    #[serde(default)]
+    pub genesis: Hash,
 }
 
 impl Clone for Federation {
@@ -32,6 +51,8 @@ impl Default for Federation {
         Self {
             id: fid.clone(),
             orgs: Vec::new(),
+            validators: ValidatorSet::default(),
+            genesis: Hash::default(),
         }
     }
 }
@@ -41,6 +62,8 @@ impl Federation {
         Self {
             id: FedId::new(handle.into()),
             orgs: Vec::<Org>::new(),
+            validators: ValidatorSet::default(),
+            genesis: Hash::default(),
         }
     }
 
@@ -54,40 +77,260 @@ impl Federation {
 
     }
 
-    pub fn validate_tx(&self, tx: &Transaction, org_id: OrgId) -> Option<String> {
-        let orgs = self.orgs.as_slice();
-        match orgs.into_iter().find(|o| o.id.handle == org_id.handle) {
-            Some(o) => {
-                // check validation
-                let is_valid = true;
-                if is_valid {
-                    return Some(format!("{}: {:?}", o, tx));
-                } else {
-                    return None;
-                }
+    pub fn register_validator(&mut self, validator: Validator) {
+        self.validators.insert(validator);
+    }
+
+    /// How much an approval from `org_id` counts for: the combined weight of
+    /// the org's validators, or 1 for an org without any.
+    pub fn approval_weight(&self, org_id: &OrgId) -> u64 {
+        let weight: usize = self
+            .validators
+            .iter()
+            .filter(|v| &v.org_id == org_id)
+            .map(|v| v.weight())
+            .sum();
+        weight.max(1) as u64
+    }
+
+    /// Look up a registered user across every org in the federation.
+    pub fn find_user(&self, id: &OrgUserId) -> Option<&OrgUser> {
+        self.orgs
+            .iter()
+            .find(|o| o.id == id.org_id)
+            .and_then(|o| o.find_user(id))
+    }
+
+    /// Decimal places of `symbol`, as set by the org issuing it, if any org
+    /// does.
+    pub fn decimals(&self, symbol: &str) -> Option<u8> {
+        self.orgs
+            .iter()
+            .find(|o| o.symbol.eq_ignore_ascii_case(symbol))
+            .map(|o| o.decimals)
+    }
+
+    /// Check `tx` on behalf of the org `org_id`: the org must belong to this
+    /// federation, the transaction's id must match its contents, its amount
+    /// must be written with the precision of its symbol, and it must carry a
+    /// valid signature from the key its sender registered with their org.
+    pub fn validate_tx(&self, tx: &Transaction, org_id: OrgId) -> Result<(), ValidationError> {
+        if !self.orgs.iter().any(|o| o.id == org_id) {
+            return Err(ValidationError::UnknownOrg(Box::new(org_id)));
+        }
+        if !tx.verify_id() {
+            return Err(ValidationError::IdMismatch);
+        }
+        if let Some(expected) = self.decimals(&tx.amt.symbol) {
+            let found = tx.amt.amt.decimals();
+            if found != expected {
+                return Err(ValidationError::WrongPrecision { expected, found });
             }
-            None => return None,
         }
+        let sender = self
+            .find_user(&tx.send.id)
+            .ok_or_else(|| ValidationError::UnknownSender(Box::new(tx.send.id.clone())))?;
+        let key = sender
+            .key
+            .ok_or_else(|| ValidationError::MissingKey(Box::new(tx.send.id.clone())))?;
+        if tx.sig.is_none() {
+            return Err(ValidationError::MissingSignature);
+        }
+        tx.verify_sig(&key).map_err(|_| ValidationError::BadSignature)
     }
 
-    pub async fn validate_tx_distributed(&self, tx: &Transaction) -> bool {
-        let (mut valid, mut invalid) = (0, 0);
-        let req_validations = self.orgs.len() / 2 + 1;
-        for o in self.orgs.iter() {
-            let orgc = o.clone();
-            let txc = tx.clone();
-            let validation_res = tokio::spawn(async move {
-                orgc.validate_tx(&txc).await
-            });
-            if let Ok(v) = validation_res.await {
-                if v { valid += 1; } else { invalid += 1 };
+    /// Ask `voters` to vote on `tx`, all at once, and count their votes
+    /// against the validator set as they arrive. Counting stops as soon as
+    /// the outcome is decided either way, and the votes still outstanding
+    /// are cancelled. Votes that fail to verify are dropped, and validators
+    /// that give no vote within `timeout` are listed as timed out.
+    pub async fn validate_tx_distributed(
+        &self,
+        tx: &Transaction,
+        voters: &[Arc<dyn Voter>],
+        timeout: Duration,
+    ) -> ValidationOutcome {
+        let mut outcome = ValidationOutcome::new(tx.id, self.genesis, &self.validators);
+        let mut pending = JoinSet::new();
+        for voter in voters.iter() {
+            if self.validators.get(voter.validator()).is_none() {
+                continue;
             }
-            if valid >= req_validations {
-                return true;
-            } else if invalid >= req_validations {
-                return false;
+            let (voter, tx) = (Arc::clone(voter), tx.clone());
+            pending.spawn(async move {
+                let vote = tokio::time::timeout(timeout, voter.vote(&tx)).await;
+                (voter.validator().to_string(), vote)
+            });
+        }
+        while let Some(res) = pending.join_next().await {
+            let (validator, vote) = match res {
+                Ok(res) => res,
+                Err(e) => {
+                    log::warn!("Vote on {} failed: {}", tx.id, e);
+                    continue;
+                }
+            };
+            match vote {
+                Ok(Ok(vote)) => match outcome.record(&self.validators, vote) {
+                    Ok(Decision::Undecided) => {}
+                    Ok(_) => break,
+                    Err(e) => log::warn!("Ignoring vote on {}: {}", tx.id, e),
+                },
+                Ok(Err(e)) => log::warn!("Validator {} did not vote on {}: {}", validator, tx.id, e),
+                Err(_) => log::warn!("Validator {} did not vote on {} within {:?}", validator, tx.id, timeout),
             }
         }
-        false
+        pending.abort_all();
+        outcome.finish(&self.validators);
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{user::KeyPair, RejectReason};
+    use crate::models::Amount;
+    use crate::validate::Vote;
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A validator that takes `delay` to answer, then approves, rejects or
+    /// fails, and records whether it got to answer at all.
+    struct TestVoter {
+        name: String,
+        key: KeyPair,
+        delay: Duration,
+        approve: bool,
+        fail: bool,
+        answered: Arc<AtomicBool>,
+    }
+
+    impl Voter for TestVoter {
+        fn validator(&self) -> &str {
+            &self.name
+        }
+
+        fn vote<'a>(&'a self, tx: &'a Transaction) -> BoxFuture<'a, anyhow::Result<Vote>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                self.answered.store(true, Ordering::SeqCst);
+                if self.fail {
+                    anyhow::bail!("{} is down", self.name);
+                }
+                let reasons = if self.approve {
+                    Vec::new()
+                } else {
+                    vec![RejectReason::SymbolNotAllowed(tx.amt.symbol.clone())]
+                };
+                Ok(Vote::new(&Hash::default(), &self.name, tx.id, reasons, &self.key))
+            })
+        }
+    }
+
+    type Setup = (Federation, Transaction, Vec<Arc<dyn Voter>>, Vec<Arc<AtomicBool>>);
+
+    /// (delay in ms, approve, fail) for each of a set of equally weighted
+    /// validators.
+    fn setup(voters: &[(u64, bool, bool)]) -> Setup {
+        let mut fed = Federation::new("test");
+        let mut org = Org::new_from(fed.id.clone(), "testorg", "test", Vec::new());
+        let send = org.new_user("alice".to_string());
+        let recv = org.new_user("bob".to_string());
+        let tx = Transaction::new(send, recv, "TEST", Amount::from_whole(1, 2).unwrap(), 1);
+        let (mut dyn_voters, mut answered) = (Vec::new(), Vec::new());
+        for (i, &(delay, approve, fail)) in voters.iter().enumerate() {
+            let key = KeyPair::generate();
+            let name = format!("v{}", i);
+            fed.register_validator(Validator::new(&name, org.id.clone(), 1, key.public().to_string()));
+            let flag = Arc::new(AtomicBool::new(false));
+            answered.push(Arc::clone(&flag));
+            dyn_voters.push(Arc::new(TestVoter {
+                name,
+                key,
+                delay: Duration::from_millis(delay),
+                approve,
+                fail,
+                answered: flag,
+            }) as Arc<dyn Voter>);
+        }
+        fed.register_org(org);
+        (fed, tx, dyn_voters, answered)
+    }
+
+    #[test]
+    fn orgs_are_matched_by_their_whole_id() {
+        let (fed, tx, _, _) = setup(&[]);
+        let elsewhere = OrgId::with_fed_id(FedId::new("elsewhere".to_string()), "testorg");
+        assert_eq!(elsewhere.handle, tx.send.id.org_id.handle);
+        assert_eq!(
+            fed.validate_tx(&tx, elsewhere.clone()),
+            Err(ValidationError::UnknownOrg(Box::new(elsewhere)))
+        );
+    }
+
+    #[test]
+    fn amounts_must_have_the_precision_of_their_symbol() {
+        let (fed, tx, _, _) = setup(&[]);
+        let org_id = tx.send.id.org_id.clone();
+        assert_eq!(fed.validate_tx(&tx, org_id.clone()), Err(ValidationError::UnknownSender(Box::new(tx.send.id.clone()))));
+        let finer = Transaction::new(tx.send.clone(), tx.recv.clone(), "test", Amount::from_whole(1, 3).unwrap(), 1);
+        assert_eq!(
+            fed.validate_tx(&finer, org_id),
+            Err(ValidationError::WrongPrecision { expected: 2, found: 3 })
+        );
+    }
+
+    // The clock is paused in these, and only moves when every task is
+    // waiting on it, so the delays are exact.
+
+    #[tokio::test(start_paused = true)]
+    async fn votes_concurrently() {
+        let (fed, tx, voters, _) = setup(&[(200, true, false); 4]);
+        // One after another, the votes would take 600ms to reach quorum.
+        let voting = fed.validate_tx_distributed(&tx, &voters, Duration::from_secs(5));
+        let outcome = tokio::time::timeout(Duration::from_millis(300), voting).await.unwrap();
+        assert_eq!(outcome.decision(), Decision::Approved);
+        assert_eq!(outcome.approve_weight, 3);
+        assert_eq!(outcome.timed_out.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_once_approved_and_cancels_the_rest() {
+        let (fed, tx, voters, answered) = setup(&[(10, true, false), (10, true, false), (10, true, false), (500, true, false)]);
+        let outcome = fed.validate_tx_distributed(&tx, &voters, Duration::from_secs(5)).await;
+        assert!(outcome.is_approved());
+        assert_eq!(outcome.timed_out, vec!["v3".to_string()]);
+        tokio::time::advance(Duration::from_millis(700)).await;
+        tokio::task::yield_now().await;
+        assert!(!answered[3].load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_once_rejected() {
+        let (fed, tx, voters, answered) = setup(&[(10, false, false), (10, false, false), (500, true, false), (500, true, false)]);
+        let outcome = fed.validate_tx_distributed(&tx, &voters, Duration::from_secs(5)).await;
+        assert_eq!(outcome.decision(), Decision::Rejected);
+        assert_eq!(outcome.verdict(), Err(vec![RejectReason::SymbolNotAllowed("TEST".to_string())]));
+        assert_eq!(outcome.timed_out, vec!["v2".to_string(), "v3".to_string()]);
+        tokio::time::advance(Duration::from_millis(700)).await;
+        tokio::task::yield_now().await;
+        assert!(!answered[2].load(Ordering::SeqCst) && !answered[3].load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_slow_and_failing_validators() {
+        let (fed, tx, voters, _) = setup(&[(10, true, false), (10, true, false), (10, true, true), (10_000, true, false)]);
+        let outcome = fed.validate_tx_distributed(&tx, &voters, Duration::from_millis(100)).await;
+        assert_eq!(outcome.decision(), Decision::Undecided);
+        assert_eq!(outcome.approve_weight, 2);
+        assert_eq!(outcome.timed_out, vec!["v2".to_string(), "v3".to_string()]);
+        assert_eq!(
+            outcome.verdict(),
+            Err(vec![RejectReason::NoQuorum {
+                approve_weight: 2,
+                quorum: 3
+            }])
+        );
     }
 }
@@ -1,4 +1,4 @@
-use std::{ops::{Deref, DerefMut}, str::FromStr, mem::discriminant};
+use std::{hash::{Hash, Hasher}, str::FromStr};
 use self::super::Org;
 use crate::models::ident::Id;
 pub use crate::models::Balance;
@@ -152,6 +152,15 @@ impl PartialEq for OrgId {
     }
 }
 
+/// Hashes the same fields `PartialEq` compares, so ids that differ only in
+/// how much of their federation is known still land in the same bucket.
+impl Hash for OrgId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+        self.handle.hash(state);
+    }
+}
+
 impl OrgId {
 
     pub fn new(handle: &str) -> OrgId {
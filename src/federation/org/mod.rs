@@ -1,12 +1,14 @@
 pub mod id;
+pub mod rules;
 pub mod user;
 
 pub use self::id::OrgId;
+pub use self::rules::{OrgRules, RejectReason, RuleContext};
 use super::{FedId, Federation, HasIdentifier};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fmt, ops::DerefMut};
-use user::OrgUser;
-use crate::{models::Balance, Transaction};
+use user::{OrgUser, OrgUserId, PublicKey};
+use crate::{models::{amount::DEFAULT_DECIMALS, Balance}, Transaction};
 
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -15,8 +17,18 @@ pub struct Org {
     pub id: OrgId,
     #[serde(rename = "uppercase")]
     pub symbol: String,
+    /// Decimal places amounts of `symbol` are written with.
+    #[serde(default = "default_decimals")]
+    pub decimals: u8,
     #[serde()]
     pub users: Vec<OrgUser>,
+    /// What the org's validators require of the transactions they approve.
+    #[serde(default)]
+    pub rules: OrgRules,
+}
+
+fn default_decimals() -> u8 {
+    DEFAULT_DECIMALS
 }
 
 // impl Clone for Org {
@@ -32,7 +44,9 @@ impl Default for Org {
         Self {
             id: oid.clone(),
             symbol: oid.to_string(),
+            decimals: DEFAULT_DECIMALS,
             users: Vec::new(),
+            rules: OrgRules::default(),
         }
     }
 }
@@ -43,10 +57,19 @@ impl Org {
             id: OrgId::new(name),
             users: Vec::new(),
             symbol: name.to_uppercase().into(),
+            decimals: DEFAULT_DECIMALS,
+            rules: OrgRules::default(),
         }
     }
-    pub async fn validate_tx(&self, t: &Transaction) -> bool {
-        return true
+    pub fn with_rules(self, rules: OrgRules) -> Self {
+        Self { rules, ..self }
+    }
+    pub fn with_decimals(self, decimals: u8) -> Self {
+        Self { decimals, ..self }
+    }
+    /// Check `tx` against the org's rules, returning every one it breaks.
+    pub fn validate_tx(&self, t: &Transaction, ctx: &RuleContext) -> Result<(), Vec<RejectReason>> {
+        self.rules.check(&self.id, t, ctx)
     }
     pub fn with_fed_id(fed_id: FedId, name: &str) -> Self {
         Self {
@@ -79,6 +102,15 @@ impl Org {
         self.users.push(OrgUser::new(self.id.clone(), handle.clone()));
         return ou;
     }
+    /// Register a user whose transactions will be verified against `key`.
+    pub fn new_user_with_key(&mut self, handle: String, key: PublicKey) -> OrgUser {
+        let ou = OrgUser::new_with_key(self.id.clone(), handle, key);
+        self.users.push(ou.clone());
+        ou
+    }
+    pub fn find_user(&self, id: &OrgUserId) -> Option<&OrgUser> {
+        self.users.iter().find(|u| &u.id == id)
+    }
     // pub fn get_users(self) -> Vec<OrgUser> {
     //     return Vec::from(self.users);
     // }
@@ -110,6 +142,8 @@ impl Org {
             id: OrgId::with_fed_id(fed_id, name),
             users,
             symbol: symbol.to_uppercase().into(),
+            decimals: DEFAULT_DECIMALS,
+            rules: OrgRules::default(),
         }
     }
 }
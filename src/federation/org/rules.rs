@@ -0,0 +1,443 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use super::{user::OrgUserId, OrgId};
+use crate::federation::Federation;
+use crate::models::{Amount, Symbol};
+use crate::validate::ValidationError;
+use crate::Transaction;
+
+/// Length of the days `OrgRules::daily_limit` is counted over. Days are
+/// counted from the Unix epoch, so they run midnight to midnight UTC.
+pub static DAILY_LIMIT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How far a transaction's timestamp may be from the validator's clock.
+/// Spending is counted on the day a transaction is dated, so it must not be
+/// dated onto another.
+pub static MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+
+/// The day `at` falls on, in days since the Unix epoch.
+pub fn day_of(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / DAILY_LIMIT_WINDOW.as_secs())
+}
+
+/// What an org requires of the transactions its validators approve. The
+/// defaults only require both parties to be registered users.
+///
+/// Symbols are matched case-insensitively, and amounts written as decimal
+/// strings, so the rules can be given in a genesis file:
+///
+/// ```toml
+/// [orgs.rules]
+/// allowed_symbols = ["ALICE", "BOB"]
+/// blocklist = ["boborg/mallory"]
+/// tx_limit = { ALICE = "500.00" }
+/// daily_limit = { ALICE = "2000.00" }
+/// min_balance = { ALICE = "10.00" }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrgRules {
+    /// Sender and receiver must be registered users of their orgs.
+    pub registered_only: bool,
+    /// Sender and receiver must both be users of this org.
+    pub internal_only: bool,
+    /// Symbols that may be transferred, or any if empty.
+    pub allowed_symbols: Vec<Symbol>,
+    /// Users, as `org/user` handles, who may neither send nor receive.
+    pub blocklist: Vec<String>,
+    /// Most a single transaction may transfer, per symbol.
+    #[serde(with = "amounts")]
+    pub tx_limit: BTreeMap<Symbol, Amount>,
+    /// Most one sender may transfer over `DAILY_LIMIT_WINDOW`, per symbol.
+    #[serde(with = "amounts")]
+    pub daily_limit: BTreeMap<Symbol, Amount>,
+    /// Least a sender must have left afterwards, per symbol.
+    #[serde(with = "amounts")]
+    pub min_balance: BTreeMap<Symbol, Amount>,
+}
+
+impl Default for OrgRules {
+    fn default() -> Self {
+        Self {
+            registered_only: true,
+            internal_only: false,
+            allowed_symbols: Vec::new(),
+            blocklist: Vec::new(),
+            tx_limit: BTreeMap::new(),
+            daily_limit: BTreeMap::new(),
+            min_balance: BTreeMap::new(),
+        }
+    }
+}
+
+/// What a validator knows about the state a transaction would apply to.
+pub struct RuleContext<'a> {
+    pub fed: &'a Federation,
+    /// The sender's current balance in the transaction's symbol.
+    pub balance: Option<Amount>,
+    /// What the sender has transferred in the symbol on the day the
+    /// transaction was made (see `day_of`), not counting it.
+    pub spent_today: Option<Amount>,
+    /// The validator's clock, which the transaction must be dated within
+    /// `MAX_CLOCK_SKEW` of.
+    pub now: SystemTime,
+}
+
+impl<'a> RuleContext<'a> {
+    pub fn new(fed: &'a Federation) -> Self {
+        Self {
+            fed,
+            balance: None,
+            spent_today: None,
+            now: SystemTime::now(),
+        }
+    }
+}
+
+/// Why a validator voted against a transaction.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The transaction failed the federation's own checks, e.g. its
+    /// signature.
+    Invalid(ValidationError),
+    SenderNotMember(OrgUserId),
+    ReceiverNotMember(OrgUserId),
+    SymbolNotAllowed(Symbol),
+    Blocklisted(OrgUserId),
+    /// Dated further than `MAX_CLOCK_SKEW` from the validator's clock, by
+    /// this many seconds ahead, or behind if negative.
+    BadTimestamp { offset_secs: i64 },
+    OverTxLimit { limit: Amount, amt: Amount },
+    OverDailyLimit { limit: Amount, spent: Amount },
+    BelowMinBalance { min: Amount, remaining: Amount },
+    /// Not enough validator weight approved it.
+    NoQuorum { approve_weight: u64, quorum: u64 },
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(e) => write!(f, "{}", e),
+            Self::SenderNotMember(u) => write!(f, "sender {} is not a member", u.handle),
+            Self::ReceiverNotMember(u) => write!(f, "receiver {} is not a member", u.handle),
+            Self::SymbolNotAllowed(s) => write!(f, "symbol {} is not allowed", s),
+            Self::Blocklisted(u) => write!(f, "{} is blocklisted", u.handle),
+            Self::BadTimestamp { offset_secs } if *offset_secs < 0 => {
+                write!(f, "dated {}s behind the validator's clock", -offset_secs)
+            }
+            Self::BadTimestamp { offset_secs } => write!(f, "dated {}s ahead of the validator's clock", offset_secs),
+            Self::OverTxLimit { limit, amt } => write!(f, "{} exceeds the per-transaction limit of {}", amt, limit),
+            Self::OverDailyLimit { limit, spent } => {
+                write!(f, "{} sent that day would exceed the daily limit of {}", spent, limit)
+            }
+            Self::BelowMinBalance { min, remaining } => {
+                write!(f, "{} left would be below the minimum balance of {}", remaining, min)
+            }
+            Self::NoQuorum { approve_weight, quorum } => {
+                write!(f, "approved by weight {} of the {} needed", approve_weight, quorum)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
+impl OrgRules {
+    fn limit<'a>(limits: &'a BTreeMap<Symbol, Amount>, symbol: &str) -> Option<&'a Amount> {
+        limits
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(symbol))
+            .map(|(_, amt)| amt)
+    }
+
+    fn blocks(&self, user: &OrgUserId) -> bool {
+        self.blocklist
+            .iter()
+            .any(|b| b.split_once('/') == Some((user.org_id.handle.as_str(), user.handle.as_str())))
+    }
+
+    /// Check `tx` on behalf of org `org_id`, returning every rule it breaks.
+    pub fn check(&self, org_id: &OrgId, tx: &Transaction, ctx: &RuleContext) -> Result<(), Vec<RejectReason>> {
+        let mut reasons = Vec::new();
+        let offset = match tx.timestamp.duration_since(ctx.now) {
+            Ok(ahead) => ahead.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        if offset.unsigned_abs() > MAX_CLOCK_SKEW.as_secs() {
+            reasons.push(RejectReason::BadTimestamp { offset_secs: offset });
+        }
+        let (send, recv) = (&tx.send.id, &tx.recv.id);
+        if self.registered_only {
+            if ctx.fed.find_user(send).is_none() {
+                reasons.push(RejectReason::SenderNotMember(send.clone()));
+            }
+            if ctx.fed.find_user(recv).is_none() {
+                reasons.push(RejectReason::ReceiverNotMember(recv.clone()));
+            }
+        }
+        if self.internal_only {
+            if &send.org_id != org_id && !reasons.contains(&RejectReason::SenderNotMember(send.clone())) {
+                reasons.push(RejectReason::SenderNotMember(send.clone()));
+            }
+            if &recv.org_id != org_id && !reasons.contains(&RejectReason::ReceiverNotMember(recv.clone())) {
+                reasons.push(RejectReason::ReceiverNotMember(recv.clone()));
+            }
+        }
+        let symbol = &tx.amt.symbol;
+        if !self.allowed_symbols.is_empty() && !self.allowed_symbols.iter().any(|s| s.eq_ignore_ascii_case(symbol)) {
+            reasons.push(RejectReason::SymbolNotAllowed(symbol.clone()));
+        }
+        for user in [send, recv] {
+            if self.blocks(user) {
+                reasons.push(RejectReason::Blocklisted(user.clone()));
+            }
+        }
+        let amt = tx.amt.amt;
+        if let Some(limit) = Self::limit(&self.tx_limit, symbol) {
+            if compare(&amt, limit) == Ordering::Greater {
+                reasons.push(RejectReason::OverTxLimit { limit: *limit, amt });
+            }
+        }
+        if let Some(limit) = Self::limit(&self.daily_limit, symbol) {
+            let spent = ctx.spent_today.unwrap_or(amt.zeroed());
+            let total = spent.checked_add(amt).unwrap_or(amt.saturated());
+            if compare(&total, limit) == Ordering::Greater {
+                reasons.push(RejectReason::OverDailyLimit { limit: *limit, spent: total });
+            }
+        }
+        if let Some(min) = Self::limit(&self.min_balance, symbol) {
+            let balance = ctx.balance.unwrap_or(amt.zeroed());
+            let remaining = balance.checked_sub(amt).unwrap_or(amt.zeroed());
+            if compare(&remaining, min) == Ordering::Less {
+                reasons.push(RejectReason::BelowMinBalance { min: *min, remaining });
+            }
+        }
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(reasons)
+        }
+    }
+}
+
+/// Compare amounts of any precision by value.
+fn compare(a: &Amount, b: &Amount) -> Ordering {
+    let scale = |amt: &Amount, to: u8| amt.units() as u128 * 10u128.pow((to - amt.decimals()) as u32);
+    let to = a.decimals().max(b.decimals());
+    scale(a, to).cmp(&scale(b, to))
+}
+
+/// Amounts keyed by symbol, as decimal strings.
+mod amounts {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    use crate::models::{Amount, Symbol};
+
+    pub fn serialize<S: Serializer>(map: &BTreeMap<Symbol, Amount>, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_map(map.iter().map(|(k, v)| (k, v.to_string())))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<BTreeMap<Symbol, Amount>, D::Error> {
+        BTreeMap::<Symbol, String>::deserialize(d)?
+            .into_iter()
+            .map(|(k, v)| match v.parse() {
+                Ok(amt) => Ok((k.to_uppercase(), amt)),
+                Err(e) => Err(D::Error::custom(format!("{}: {}", k, e))),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{
+        user::{KeyPair, OrgUser},
+        Org,
+    };
+
+    struct Users {
+        alice: OrgUser,
+        bob: OrgUser,
+        carol: OrgUser,
+        eve: OrgUser,
+    }
+
+    /// Alice and bob of `org`, carol of `other`, and eve, who never
+    /// registered with `org`.
+    fn federation() -> (Federation, OrgId, Users) {
+        let (mut org, mut other) = (Org::new("org"), Org::new("other"));
+        let user = |org: &mut Org, handle: &str| {
+            org.new_user_with_key(handle.to_string(), KeyPair::generate().public())
+        };
+        let users = Users {
+            alice: user(&mut org, "alice"),
+            bob: user(&mut org, "bob"),
+            carol: user(&mut other, "carol"),
+            eve: OrgUser::new(org.id.clone(), "eve".to_string()),
+        };
+        let org_id = org.id.clone();
+        let mut fed = Federation::new("test");
+        fed.register_org(org);
+        fed.register_org(other);
+        (fed, org_id, users)
+    }
+
+    fn amt(whole: u64) -> Amount {
+        Amount::from_whole(whole, 2).unwrap()
+    }
+
+    fn pay(from: &OrgUser, to: &OrgUser, symbol: &str, whole: u64) -> Transaction {
+        Transaction::new(from.clone(), to.clone(), symbol, amt(whole), 1)
+    }
+
+    fn limits(symbol: &str, whole: u64) -> BTreeMap<Symbol, Amount> {
+        BTreeMap::from([(symbol.to_string(), amt(whole))])
+    }
+
+    #[test]
+    fn registered_only_requires_both_parties_to_be_users() {
+        let (fed, org_id, u) = federation();
+        let ctx = RuleContext::new(&fed);
+        let rules = OrgRules::default();
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.carol, "TEST", 1), &ctx), Ok(()));
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.eve, &u.alice, "TEST", 1), &ctx),
+            Err(vec![RejectReason::SenderNotMember(u.eve.id.clone())])
+        );
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.eve, "TEST", 1), &ctx),
+            Err(vec![RejectReason::ReceiverNotMember(u.eve.id.clone())])
+        );
+        let open = OrgRules {
+            registered_only: false,
+            ..OrgRules::default()
+        };
+        assert_eq!(open.check(&org_id, &pay(&u.eve, &u.alice, "TEST", 1), &ctx), Ok(()));
+    }
+
+    #[test]
+    fn internal_only_keeps_both_parties_in_the_org() {
+        let (fed, org_id, u) = federation();
+        let ctx = RuleContext::new(&fed);
+        let rules = OrgRules {
+            internal_only: true,
+            ..OrgRules::default()
+        };
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 1), &ctx), Ok(()));
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.carol, "TEST", 1), &ctx),
+            Err(vec![RejectReason::ReceiverNotMember(u.carol.id.clone())])
+        );
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.carol, &u.bob, "TEST", 1), &ctx),
+            Err(vec![RejectReason::SenderNotMember(u.carol.id.clone())])
+        );
+    }
+
+    #[test]
+    fn only_allowed_symbols_may_be_sent() {
+        let (fed, org_id, u) = federation();
+        let ctx = RuleContext::new(&fed);
+        let rules = OrgRules {
+            allowed_symbols: vec!["test".to_string()],
+            ..OrgRules::default()
+        };
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 1), &ctx), Ok(()));
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.bob, "OTHER", 1), &ctx),
+            Err(vec![RejectReason::SymbolNotAllowed("OTHER".to_string())])
+        );
+    }
+
+    #[test]
+    fn blocklisted_users_may_neither_send_nor_receive() {
+        let (fed, org_id, u) = federation();
+        let ctx = RuleContext::new(&fed);
+        let rules = OrgRules {
+            blocklist: vec!["org/bob".to_string(), "other/alice".to_string()],
+            ..OrgRules::default()
+        };
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.carol, "TEST", 1), &ctx), Ok(()));
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 1), &ctx),
+            Err(vec![RejectReason::Blocklisted(u.bob.id.clone())])
+        );
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.bob, &u.alice, "TEST", 1), &ctx),
+            Err(vec![RejectReason::Blocklisted(u.bob.id.clone())])
+        );
+    }
+
+    #[test]
+    fn senders_keep_the_min_balance() {
+        let (fed, org_id, u) = federation();
+        let mut ctx = RuleContext::new(&fed);
+        let rules = OrgRules {
+            min_balance: limits("TEST", 2),
+            ..OrgRules::default()
+        };
+        ctx.balance = Some(amt(10));
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 8), &ctx), Ok(()));
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 9), &ctx),
+            Err(vec![RejectReason::BelowMinBalance { min: amt(2), remaining: amt(1) }])
+        );
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.bob, "OTHER", 9), &ctx), Ok(()));
+        // Nothing held counts as nothing left.
+        ctx.balance = None;
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 1), &ctx),
+            Err(vec![RejectReason::BelowMinBalance { min: amt(2), remaining: amt(0) }])
+        );
+    }
+
+    #[test]
+    fn daily_limits_count_what_was_sent_that_day() {
+        let (fed, org_id, u) = federation();
+        let mut ctx = RuleContext::new(&fed);
+        let rules = OrgRules {
+            daily_limit: limits("TEST", 10),
+            ..OrgRules::default()
+        };
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 10), &ctx), Ok(()));
+        ctx.spent_today = Some(amt(8));
+        assert_eq!(rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 2), &ctx), Ok(()));
+        assert_eq!(
+            rules.check(&org_id, &pay(&u.alice, &u.bob, "TEST", 3), &ctx),
+            Err(vec![RejectReason::OverDailyLimit { limit: amt(10), spent: amt(11) }])
+        );
+    }
+
+    #[test]
+    fn transactions_are_dated_near_the_validators_clock() {
+        let (fed, org_id, u) = federation();
+        let ctx = RuleContext::new(&fed);
+        let rules = OrgRules::default();
+        let dated = |at: SystemTime| {
+            let mut tx = pay(&u.alice, &u.bob, "TEST", 1);
+            tx.timestamp = at;
+            tx
+        };
+        let minute = Duration::from_secs(60);
+        assert_eq!(rules.check(&org_id, &dated(ctx.now + minute), &ctx), Ok(()));
+        assert_eq!(rules.check(&org_id, &dated(ctx.now - minute), &ctx), Ok(()));
+        let offset = DAILY_LIMIT_WINDOW.as_secs() as i64;
+        assert_eq!(
+            rules.check(&org_id, &dated(ctx.now + DAILY_LIMIT_WINDOW), &ctx),
+            Err(vec![RejectReason::BadTimestamp { offset_secs: offset }])
+        );
+        assert_eq!(
+            rules.check(&org_id, &dated(ctx.now - DAILY_LIMIT_WINDOW), &ctx),
+            Err(vec![RejectReason::BadTimestamp { offset_secs: -offset }])
+        );
+    }
+}
@@ -12,7 +12,7 @@ use super::super::{FedId, Federation};
 use rand::{distributions::Alphanumeric, Rng, RngCore};
 pub const ORG_USER_DISCRIMINATOR: &str = "OU";
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Hash)]
 #[serde()]
 pub struct OrgUserId {
     pub id: String,
@@ -115,6 +115,18 @@ impl Default for OrgUserId {
     }
 }
 
+/// The parts of an `OrgUserId` that identify the user, as `PartialEq` and
+/// `Hash` see them: the org's federation is left out, since the same id may
+/// arrive with more or less of it filled in. Anything hashed or signed
+/// encodes this instead of the id itself.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CanonicalUserId<'a> {
+    pub org_id: &'a str,
+    pub org_handle: &'a str,
+    pub id: &'a str,
+    pub handle: &'a str,
+}
+
 impl OrgUserId {
     pub fn new(org_id: OrgId, handle: String) -> OrgUserId {
         OrgUserId {
@@ -123,6 +135,15 @@ impl OrgUserId {
             ..OrgUserId::default()
         }
     }
+
+    pub fn canonical(&self) -> CanonicalUserId<'_> {
+        CanonicalUserId {
+            org_id: &self.org_id.id,
+            org_handle: &self.org_id.handle,
+            id: &self.id,
+            handle: &self.handle,
+        }
+    }
 }
 // impl Deref for OrgUserId {
     // type Target = String;
@@ -0,0 +1,178 @@
+use data_encoding::HEXLOWER_PERMISSIVE;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Length in bytes of an encoded public key or secret key.
+pub const KEY_LEN: usize = 32;
+
+/// The public half of a user's key pair. This is what an `OrgUser` carries
+/// around and what other parties verify transaction signatures against.
+/// Keys of small order, which would accept signatures anyone can forge,
+/// are rejected however they are read.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "VerifyingKey")]
+pub struct PublicKey(VerifyingKey);
+
+/// An Ed25519 signature over some canonical message bytes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature(ed25519_dalek::Signature);
+
+/// A user's signing key. The secret half never leaves whoever generated it;
+/// only `public()` is ever registered with an org.
+#[derive(Clone)]
+pub struct KeyPair(SigningKey);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// The bytes do not encode a valid Ed25519 key.
+    Malformed,
+    /// The key is of small order, so signatures from it prove nothing.
+    Weak,
+    /// The signature does not match the message for this key.
+    BadSignature,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed key"),
+            Self::Weak => write!(f, "weak key"),
+            Self::BadSignature => write!(f, "signature verification failed"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn from_bytes(secret: &[u8; KEY_LEN]) -> Self {
+        Self(SigningKey::from_bytes(secret))
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.0.to_bytes()
+    }
+
+    pub fn public(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        Signature(self.0.sign(msg))
+    }
+}
+
+/// Only the public half is printed.
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KeyPair").field(&self.public()).finish()
+    }
+}
+
+impl PublicKey {
+    pub fn from_bytes(bytes: &[u8; KEY_LEN]) -> Result<Self, KeyError> {
+        VerifyingKey::from_bytes(bytes)
+            .map_err(|_| KeyError::Malformed)?
+            .try_into()
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_LEN] {
+        self.0.to_bytes()
+    }
+
+    /// Check `sig` over `msg`, rejecting the malleable and small-order
+    /// signatures plain Ed25519 verification lets through.
+    pub fn verify(&self, msg: &[u8], sig: &Signature) -> Result<(), KeyError> {
+        self.0
+            .verify_strict(msg, &sig.0)
+            .map_err(|_| KeyError::BadSignature)
+    }
+}
+
+impl TryFrom<VerifyingKey> for PublicKey {
+    type Error = KeyError;
+    fn try_from(key: VerifyingKey) -> Result<Self, Self::Error> {
+        if key.is_weak() {
+            return Err(KeyError::Weak);
+        }
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.to_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads back the hex form `Display` prints.
+impl FromStr for PublicKey {
+    type Err = KeyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; KEY_LEN] = HEXLOWER_PERMISSIVE
+            .decode(s.as_bytes())
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or(KeyError::Malformed)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Reads a secret key written as hex, e.g. in a validator's key file.
+impl FromStr for KeyPair {
+    type Err = KeyError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; KEY_LEN] = HEXLOWER_PERMISSIVE
+            .decode(s.trim().as_bytes())
+            .ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or(KeyError::Malformed)?;
+        Ok(Self::from_bytes(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The identity point, which has order one.
+    const IDENTITY: [u8; KEY_LEN] = {
+        let mut b = [0; KEY_LEN];
+        b[0] = 1;
+        b
+    };
+
+    #[test]
+    fn round_trips() {
+        let key = KeyPair::generate();
+        let public = key.public();
+        assert_eq!(public.to_string().parse::<PublicKey>(), Ok(public));
+        assert_eq!(PublicKey::from_bytes(&public.to_bytes()), Ok(public));
+        let bytes = bincode::serialize(&public).unwrap();
+        assert_eq!(bincode::deserialize::<PublicKey>(&bytes).unwrap(), public);
+        let sig = key.sign(b"msg");
+        assert_eq!(public.verify(b"msg", &sig), Ok(()));
+        assert_eq!(public.verify(b"other", &sig), Err(KeyError::BadSignature));
+    }
+
+    #[test]
+    fn rejects_weak_keys() {
+        assert_eq!(PublicKey::from_bytes(&IDENTITY), Err(KeyError::Weak));
+        let hex: String = IDENTITY.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex.parse::<PublicKey>(), Err(KeyError::Weak));
+        let strong = bincode::serialize(&KeyPair::generate().public()).unwrap();
+        let mut weak = strong.clone();
+        let at = weak.len() - KEY_LEN;
+        weak[at..].copy_from_slice(&IDENTITY);
+        assert!(bincode::deserialize::<PublicKey>(&weak).is_err());
+    }
+}
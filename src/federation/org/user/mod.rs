@@ -2,27 +2,22 @@ pub mod id;
 pub mod key;
 
 use serde::{Deserialize, Serialize};
-use std::{ops::DerefMut, str::FromStr};
+use std::str::FromStr;
 
-use crate::Balance;
+use crate::{models::{Amount, AmountError}, Balance};
 
 use super::{HasIdentifier, OrgId};
 pub use id::OrgUserId;
+pub use key::{KeyError, KeyPair, PublicKey, Signature};
 
-#[derive(Debug, PartialEq, Serialize, Clone, Deserialize)]
+#[derive(Debug, Default, PartialEq, Serialize, Clone, Deserialize)]
 #[serde()]
 pub struct OrgUser {
     pub id: OrgUserId,
     pub balances: Vec<Balance>,
-}
-
-impl Default for OrgUser {
-    fn default() -> Self {
-        Self {
-            id: OrgUserId::default(),
-            balances: vec![],
-        }
-    }
+    /// Key the user signs their transactions with, if one is registered.
+    #[serde(default)]
+    pub key: Option<PublicKey>,
 }
 
 impl OrgUser {
@@ -30,6 +25,13 @@ impl OrgUser {
         Self {
             id: OrgUserId::new(org_id, handle),
             balances: vec![],
+            key: None,
+        }
+    }
+    pub fn new_with_key(org_id: OrgId, handle: String, key: PublicKey) -> Self {
+        Self {
+            key: Some(key),
+            ..Self::new(org_id, handle)
         }
     }
     pub fn get_global_identifier(self) -> String {
@@ -56,49 +58,37 @@ impl OrgUser {
     }
     pub fn new_with_identifier(org_identifier: String, handle: String) -> Self {
         let org_id = OrgId::from_str(&org_identifier.as_str()).unwrap_or_default();
-        Self {
-            id: OrgUserId::new(org_id, handle),
-            balances: vec![],
-        }
+        Self::new(org_id, handle)
     }
 
     pub fn get_balance(&self, symbol: &str) -> Option<&Balance> {
-        let b = self.balances.as_slice().into_iter().find_map(|b| {
-            if b.symbol == symbol {
-                Some(b)
-            } else {
-                None
-            }
-        });
-        return b;
+        self.balances.iter().find(|b| b.symbol == symbol)
     }
 
     pub fn get_balance_mut(&mut self, symbol: &str) -> Option<&mut Balance> {
-        let b = self.balances.deref_mut().into_iter().find_map(|b| {
-            if b.symbol == symbol {
-                Some(b)
-            } else {
-                None
-            }
-        });
-        return b;
+        self.balances.iter_mut().find(|b| b.symbol == symbol)
     }
 
     pub fn get_org_id(&self) -> OrgId {
         self.id.org_id.clone()
     }
 
-    pub fn add_balance(&mut self, symbol: String, amt: usize) {
-        if let Some(b) = self.get_balance_mut(&symbol) {
-            b.add(amt);
-        } else {
-            self.balances.push(Balance::new(symbol, amt));
+    pub fn add_balance(&mut self, symbol: String, amt: Amount) -> Result<(), AmountError> {
+        match self.get_balance_mut(&symbol) {
+            Some(b) => b.add(amt),
+            None => {
+                self.balances.push(Balance::new(symbol, amt));
+                Ok(())
+            }
         }
     }
 
-    pub fn sub_balance(&mut self, symbol: &str, amt: usize) {
-        if let Some(b) = self.get_balance_mut(symbol) {
-            b.sub(amt);
+    /// Debit `amt` of `symbol`. A user holding no balance in that symbol has
+    /// nothing to spend, so this fails with `InsufficientFunds`.
+    pub fn sub_balance(&mut self, symbol: &str, amt: Amount) -> Result<(), AmountError> {
+        match self.get_balance_mut(symbol) {
+            Some(b) => b.sub(amt),
+            None => amt.zeroed().checked_sub(amt).map(|_| ()),
         }
     }
 }
@@ -1,3 +1,4 @@
+pub mod consensus;
 pub mod federation;
 pub mod models;
 pub mod msg;
@@ -7,12 +8,12 @@ pub mod validate;
 
 pub use validate::{Validator};
 pub use federation::{Federation, Org};
-pub use models::{Balance, Balances};
+pub use models::{Amount, Balance, Balances};
 pub use msg::{Transaction, TxId};
 pub use node::Node;
 pub use store::{StreamingDAG, DAG};
 
-use models::{HasIdentifier, Id};
+use models::{amount::DEFAULT_DECIMALS, HasIdentifier, Id};
 
 use rand::Rng;
 use std::{
@@ -27,18 +28,77 @@ use std::{
 };
 use tokio::sync::Mutex;
 
-use crate::federation::{org::user::OrgUser, id::FedId};
+use crate::federation::{
+    genesis::{Genesis, GenesisFederation, GenesisOrg, GenesisUser},
+    org::user::{KeyPair, OrgUser, OrgUserId},
+    id::FedId,
+};
+use crate::store::dag::DEFAULT_PARENTS;
 
 pub async fn run() {
     println!("RUNNING");
-    let mut fed = Federation::new("test");
-    let fid = fed.id.clone();
-    let mut o1: Org = Org::new_from(fid.clone(), "aliceorg", "alice", Vec::new());
-    let mut o2: Org = Org::new_from(fid.clone(), "boborg", "bob", Vec::new());
-    let mut o3: Org = Org::new_from(fid.clone(), "jimorg", "jim", Vec::new());
-    let mut o4: Org = Org::new_from(fid.clone(), "lucyorg", "lucy", Vec::new());
-    fed.register_orgs(&[o1.clone(), o2.clone(), o3.clone(), o4.clone()]);
-    let streamdag = StreamingDAG::new_arc_with_federation(10, fed);
+    let mut genesis = Genesis {
+        federation: GenesisFederation {
+            handle: "test".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut secrets: HashMap<String, KeyPair> = HashMap::new();
+    for (org, symbol, name) in [
+        ("aliceorg", "alice", "Jordan"),
+        ("boborg", "bob", "Tom"),
+        ("jimorg", "jim", "Lester"),
+        ("lucyorg", "lucy", "Irina"),
+    ] {
+        let users = (1..=10)
+            .map(|i| {
+                let key = KeyPair::generate();
+                let handle = format!("{}{}", name, i);
+                let user = GenesisUser {
+                    handle: handle.clone(),
+                    id: None,
+                    key: Some(key.public().to_string()),
+                };
+                secrets.insert(handle, key);
+                user
+            })
+            .collect();
+        genesis.orgs.push(GenesisOrg {
+            handle: org.to_string(),
+            symbol: symbol.to_string(),
+            users,
+            ..Default::default()
+        });
+    }
+    let fed = match genesis.federation() {
+        Ok(fed) => fed,
+        Err(e) => {
+            println!("Invalid genesis: {}", e);
+            return;
+        }
+    };
+    let (o1, o2, o3, o4) = (fed.orgs[0].clone(), fed.orgs[1].clone(), fed.orgs[2].clone(), fed.orgs[3].clone());
+    let pools: Vec<Vec<OrgUser>> = fed.orgs.iter().map(|o| o.users.clone()).collect();
+    let mut keys: HashMap<OrgUserId, KeyPair> = HashMap::new();
+    for user in pools.iter().flatten() {
+        if let Some(key) = secrets.remove(&user.id.handle) {
+            keys.insert(user.id.clone(), key);
+        }
+    }
+    let streamdag = match StreamingDAG::new_with_federation(10, fed)
+        .with_storage(
+            store::wal::DEFAULT_DATA_DIR,
+            store::WalConfig::default(),
+            store::SnapshotConfig::default(),
+        )
+    {
+        Ok(streamdag) => Arc::new(streamdag),
+        Err(e) => {
+            println!("Could not open storage: {}", e);
+            return;
+        }
+    };
     // streamdag.federation = fed;
     let stop = Arc::new(AtomicBool::new(false));
     // let _proc_thread = thread::spawn(move || {
@@ -46,22 +106,13 @@ pub async fn run() {
     // });
     let mut rng = rand::thread_rng();
     loop {
-        let u1 = format!("Jordan{}", rng.gen_range(1..=10));
-        let u2 = format!("Tom{}", rng.gen_range(1..=10));
-        let u3 = format!("Lester{}", rng.gen_range(1..=10));
-        let u4 = format!("Irina{}", rng.gen_range(1..=10));
-        let us1 = OrgUser::new(o1.clone().id, u1);
-        let us2 = OrgUser::new(o2.clone().id, u2);
-        let us3 = OrgUser::new(o3.clone().id, u3);
-        let us4 = OrgUser::new(o4.clone().id, u4);
+        let us1 = pools[0][rng.gen_range(0..10)].clone();
+        let us2 = pools[1][rng.gen_range(0..10)].clone();
+        let us3 = pools[2][rng.gen_range(0..10)].clone();
+        let us4 = pools[3][rng.gen_range(0..10)].clone();
 
-        o1.clone().new_user(us1.clone().id.handle);
-        o2.clone().new_user(us2.clone().id.handle);
-        o3.clone().new_user(us3.clone().id.handle);
-        o4.clone().new_user(us4.clone().id.handle);
-
-        let amt = rng.gen_range(1..=100);
-        let (mut recv, mut send) = if rng.gen_bool(0.5) {
+        let amt = Amount::from_whole(rng.gen_range(1..=100), DEFAULT_DECIMALS).unwrap();
+        let (recv, send) = if rng.gen_bool(0.5) {
             if rng.gen_bool(0.5) {
                 (us1.clone(), us2.clone())
             } else {
@@ -89,7 +140,10 @@ pub async fn run() {
             }
         };
 
-        let tx = Transaction::new(send.clone(), recv.clone(), &symbol, amt);
+        let nonce = streamdag.ledger.lock().unwrap().next_nonce(&send.id);
+        let mut tx = Transaction::new(send.clone(), recv.clone(), &symbol, amt, nonce);
+        tx.attach(streamdag.select_tips(DEFAULT_PARENTS)).expect("not signed yet");
+        tx.sign(&keys[&send.id]);
         println!(
             "IN \x1b[32;1m{}\x1b[0m: \x1b[33;1m{}\x1b[0m PAID \x1b[34;1m{}\x1b[0m \x1b[35;1m{}{}\x1b[0m",
             // org.clone().handle,
@@ -101,12 +155,28 @@ pub async fn run() {
             // recvid.clone().org_id.to_string(),
             // recvid.clone().handle,
             // recvid.clone().to_string(),
-            amt.clone(),
+            amt,
             symbol.clone(),
         );
-        println!("TX QUEUE LEN: {} DAG LEN: {}", &streamdag.clone().tx_queue.lock().unwrap().len(), &streamdag.clone().dag.nodes.lock().unwrap().len() );
-        recv.add_balance(symbol.clone(), amt.clone());
-        streamdag.push_tx(tx, org).await;
+        println!("TX QUEUE LEN: {} DAG LEN: {}", &streamdag.clone().tx_queue.lock().unwrap().len(), streamdag.dag.len());
+        // Top up the sender so the demo never runs dry.
+        let allowance = Amount::from_whole(100, DEFAULT_DECIMALS).unwrap();
+        if let Err(e) = streamdag.mint(&send.id, &symbol, allowance) {
+            println!("Could not fund {}: {}", send.id.handle, e);
+        }
+        match streamdag.push_tx(tx, org).await {
+            Ok(_) => {
+                let ledger = streamdag.ledger.lock().unwrap();
+                println!(
+                    "LEDGER HEIGHT: {} {} BALANCE: {}{}",
+                    ledger.height(),
+                    recv.id.handle,
+                    ledger.balance(&recv.id, &symbol).unwrap_or_default(),
+                    symbol,
+                );
+            }
+            Err(e) => println!("REJECTED: {}", e),
+        }
 
         // println!(
         //     "DAG [{}]: {:#?}",
@@ -0,0 +1,287 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// Number of decimal places used for a symbol when none is configured.
+pub const DEFAULT_DECIMALS: u8 = 2;
+
+/// Upper bound on decimal places so `10^decimals` always fits in a `u64`.
+pub const MAX_DECIMALS: u8 = 18;
+
+/// A non-negative fixed-point quantity of some symbol. The value is held as
+/// an integer count of the smallest unit (`units`), with `decimals` giving
+/// the position of the decimal point, so `Amount::new(1250, 2)` is `12.50`.
+///
+/// Arithmetic is always checked: amounts never wrap, and two amounts can only
+/// be combined when they share the same precision.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(try_from = "RawAmount")]
+pub struct Amount {
+    units: u64,
+    decimals: u8,
+}
+
+/// An amount as received, checked by `Amount::new` before it is used.
+#[derive(Deserialize)]
+struct RawAmount {
+    units: u64,
+    decimals: u8,
+}
+
+impl TryFrom<RawAmount> for Amount {
+    type Error = AmountError;
+    fn try_from(raw: RawAmount) -> Result<Self, Self::Error> {
+        Self::new(raw.units, raw.decimals)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AmountError {
+    /// The result does not fit in the underlying integer.
+    Overflow,
+    /// Subtracting `requested` from `available` would go below zero.
+    InsufficientFunds { available: Amount, requested: Amount },
+    /// The operands were expressed with different decimal precision.
+    PrecisionMismatch { expected: u8, found: u8 },
+    /// More decimal places than `MAX_DECIMALS` were asked for.
+    TooManyDecimals(u8),
+    /// A decimal string could not be parsed into an amount.
+    Parse(String),
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "amount overflow"),
+            Self::InsufficientFunds { available, requested } => write!(
+                f,
+                "insufficient funds: {} available, {} requested",
+                available, requested
+            ),
+            Self::PrecisionMismatch { expected, found } => write!(
+                f,
+                "precision mismatch: expected {} decimals, found {}",
+                expected, found
+            ),
+            Self::TooManyDecimals(d) => write!(f, "{} decimals exceeds {}", d, MAX_DECIMALS),
+            Self::Parse(s) => write!(f, "invalid amount: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+impl Amount {
+    /// Construct an amount directly from its smallest units. At most
+    /// `MAX_DECIMALS` places are supported.
+    pub fn new(units: u64, decimals: u8) -> Result<Self, AmountError> {
+        if decimals > MAX_DECIMALS {
+            return Err(AmountError::TooManyDecimals(decimals));
+        }
+        Ok(Self { units, decimals })
+    }
+
+    pub fn zero(decimals: u8) -> Result<Self, AmountError> {
+        Self::new(0, decimals)
+    }
+
+    /// Nothing, in the same precision as this amount.
+    pub fn zeroed(&self) -> Self {
+        Self {
+            units: 0,
+            decimals: self.decimals,
+        }
+    }
+
+    /// The most this amount's precision can hold.
+    pub fn saturated(&self) -> Self {
+        Self {
+            units: u64::MAX,
+            decimals: self.decimals,
+        }
+    }
+
+    /// Construct an amount from a whole number of tokens, e.g. `from_whole(5, 2)`
+    /// is `5.00` (500 units).
+    pub fn from_whole(whole: u64, decimals: u8) -> Result<Self, AmountError> {
+        if decimals > MAX_DECIMALS {
+            return Err(AmountError::TooManyDecimals(decimals));
+        }
+        let units = whole
+            .checked_mul(Self::scale(decimals))
+            .ok_or(AmountError::Overflow)?;
+        Self::new(units, decimals)
+    }
+
+    /// Parse a decimal string like `"12.5"` into an amount with exactly
+    /// `decimals` places. More fractional digits than `decimals` is an error
+    /// rather than a silent truncation.
+    pub fn parse_with_decimals(s: &str, decimals: u8) -> Result<Self, AmountError> {
+        if decimals > MAX_DECIMALS {
+            return Err(AmountError::TooManyDecimals(decimals));
+        }
+        let (whole, frac) = match s.split_once('.') {
+            Some((w, f)) => (w, f),
+            None => (s, ""),
+        };
+        let all_digits = |p: &str| p.bytes().all(|b| b.is_ascii_digit());
+        if whole.is_empty() || !all_digits(whole) || !all_digits(frac) {
+            return Err(AmountError::Parse(s.to_string()));
+        }
+        if frac.len() > decimals as usize {
+            return Err(AmountError::Parse(format!(
+                "{} has more than {} decimal places",
+                s, decimals
+            )));
+        }
+        let whole: u64 = whole.parse().map_err(|_| AmountError::Overflow)?;
+        let padded = format!("{:0<width$}", frac, width = decimals as usize);
+        let frac: u64 = if padded.is_empty() { 0 } else { padded.parse().map_err(|_| AmountError::Overflow)? };
+        let units = whole
+            .checked_mul(Self::scale(decimals))
+            .and_then(|u| u.checked_add(frac))
+            .ok_or(AmountError::Overflow)?;
+        Self::new(units, decimals)
+    }
+
+    pub fn units(&self) -> u64 {
+        self.units
+    }
+
+    pub fn decimals(&self) -> u8 {
+        self.decimals
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0
+    }
+
+    pub fn checked_add(&self, other: Amount) -> Result<Amount, AmountError> {
+        self.same_precision(&other)?;
+        let units = self.units.checked_add(other.units).ok_or(AmountError::Overflow)?;
+        Ok(Self { units, ..*self })
+    }
+
+    pub fn checked_sub(&self, other: Amount) -> Result<Amount, AmountError> {
+        self.same_precision(&other)?;
+        let units = self.units.checked_sub(other.units).ok_or(AmountError::InsufficientFunds {
+            available: *self,
+            requested: other,
+        })?;
+        Ok(Self { units, ..*self })
+    }
+
+    fn same_precision(&self, other: &Amount) -> Result<(), AmountError> {
+        if self.decimals != other.decimals {
+            return Err(AmountError::PrecisionMismatch {
+                expected: self.decimals,
+                found: other.decimals,
+            });
+        }
+        Ok(())
+    }
+
+    fn scale(decimals: u8) -> u64 {
+        10u64.pow(decimals as u32)
+    }
+}
+
+/// Amounts of different precision are not comparable.
+impl PartialOrd for Amount {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.decimals != other.decimals {
+            return None;
+        }
+        Some(self.units.cmp(&other.units))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.decimals == 0 {
+            return write!(f, "{}", self.units);
+        }
+        let scale = Self::scale(self.decimals);
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.units / scale,
+            self.units % scale,
+            width = self.decimals as usize
+        )
+    }
+}
+
+/// Parses a decimal string, taking the precision from the number of
+/// fractional digits given (`"1.50"` has 2 decimals, `"3"` has 0).
+impl FromStr for Amount {
+    type Err = AmountError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let decimals = s.split_once('.').map(|(_, f)| f.len()).unwrap_or(0);
+        if decimals > MAX_DECIMALS as usize {
+            return Err(AmountError::Parse(s.to_string()));
+        }
+        Self::parse_with_decimals(s, decimals as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_too_many_decimals() {
+        assert_eq!(Amount::new(1, MAX_DECIMALS).unwrap().decimals(), MAX_DECIMALS);
+        assert_eq!(Amount::new(1, MAX_DECIMALS + 1), Err(AmountError::TooManyDecimals(19)));
+        assert_eq!(Amount::zero(u8::MAX), Err(AmountError::TooManyDecimals(u8::MAX)));
+        assert_eq!(Amount::from_whole(1, 19), Err(AmountError::TooManyDecimals(19)));
+        assert_eq!(Amount::parse_with_decimals("1", 19), Err(AmountError::TooManyDecimals(19)));
+        let raw = bincode::serialize(&(1u64, 19u8)).unwrap();
+        assert!(bincode::deserialize::<Amount>(&raw).is_err());
+        let raw = bincode::serialize(&(1u64, 18u8)).unwrap();
+        assert_eq!(bincode::deserialize::<Amount>(&raw).unwrap(), Amount::new(1, 18).unwrap());
+    }
+
+    #[test]
+    fn checked_add_overflows() {
+        let max = Amount::new(u64::MAX, 2).unwrap();
+        let one = Amount::new(1, 2).unwrap();
+        assert_eq!(max.checked_add(one), Err(AmountError::Overflow));
+        assert_eq!(max.checked_add(one.zeroed()), Ok(max));
+        assert_eq!(Amount::from_whole(u64::MAX / 10, 2), Err(AmountError::Overflow));
+    }
+
+    #[test]
+    fn checked_sub_underflows() {
+        let one = Amount::new(1, 2).unwrap();
+        let two = Amount::new(2, 2).unwrap();
+        assert_eq!(two.checked_sub(one), Ok(one));
+        assert_eq!(
+            one.checked_sub(two),
+            Err(AmountError::InsufficientFunds {
+                available: one,
+                requested: two,
+            })
+        );
+        assert_eq!(one.checked_sub(one).map(|a| a.is_zero()), Ok(true));
+    }
+
+    #[test]
+    fn does_not_mix_decimals() {
+        let cents = Amount::new(100, 2).unwrap();
+        let whole = Amount::new(1, 0).unwrap();
+        let mismatch = Err(AmountError::PrecisionMismatch { expected: 2, found: 0 });
+        assert_eq!(cents.checked_add(whole), mismatch);
+        assert_eq!(cents.checked_sub(whole), mismatch);
+        assert_eq!(cents.partial_cmp(&whole), None);
+        assert_ne!(cents, whole);
+    }
+
+    #[test]
+    fn parses_and_displays() {
+        assert_eq!("12.50".parse(), Amount::new(1250, 2));
+        assert_eq!(Amount::parse_with_decimals("12.5", 2), Amount::new(1250, 2));
+        assert!(Amount::parse_with_decimals("12.501", 2).is_err());
+        assert_eq!(Amount::new(1250, 2).unwrap().to_string(), "12.50");
+        assert_eq!(Amount::new(7, 0).unwrap().to_string(), "7");
+    }
+}
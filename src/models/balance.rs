@@ -1,52 +1,33 @@
 use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicUsize, Ordering},
-};
+
+use super::amount::{Amount, AmountError};
 
 pub type Symbol = String;
 pub type Balances = Vec<Balance>;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
 pub struct Balance {
     // org_id: String,
     pub symbol: Symbol,
-    pub amt: AtomicUsize,
-}
-impl PartialEq for Balance {
-    fn eq(&self, other: &Self) -> bool {
-        if self.symbol == other.symbol {
-            if self.amt.load(Ordering::Relaxed) == other.amt.load(Ordering::Relaxed) {
-                return true;
-            }
-        }
-        false
-    }
-}
-impl Clone for Balance {
-    fn clone(&self) -> Self {
-        Self {
-            symbol: self.symbol.clone(),
-            amt: AtomicUsize::new(self.amt.load(Ordering::Relaxed)),
-        }
-    }
+    pub amt: Amount,
 }
 
 impl Balance {
-    pub fn new(symbol: String, amt: usize) -> Self {
-        Self {
-            symbol,
-            amt: AtomicUsize::new(amt),
-        }
+    pub fn new(symbol: String, amt: Amount) -> Self {
+        Self { symbol, amt }
     }
-    pub fn get(&self) -> usize {
-        self.amt.load(Ordering::Relaxed)
+    pub fn get(&self) -> Amount {
+        self.amt
     }
-    pub fn add(&mut self, amt: usize) {
-        self.amt.load(Ordering::Relaxed).wrapping_add(amt);
+    /// Credit `amt`, leaving the balance untouched if the result would overflow.
+    pub fn add(&mut self, amt: Amount) -> Result<(), AmountError> {
+        self.amt = self.amt.checked_add(amt)?;
+        Ok(())
     }
-    pub fn sub(&mut self, amt: usize) {
-        self.amt.fetch_sub(amt, Ordering::Relaxed);
+    /// Debit `amt`, leaving the balance untouched if it would go negative.
+    pub fn sub(&mut self, amt: Amount) -> Result<(), AmountError> {
+        self.amt = self.amt.checked_sub(amt)?;
+        Ok(())
     }
     pub fn zero() -> Self {
         Self::default()
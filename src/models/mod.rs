@@ -1,5 +1,7 @@
+pub mod amount;
 pub mod balance;
 pub mod ident;
 
-pub use balance::{Balance, Balances};
+pub use amount::{Amount, AmountError};
+pub use balance::{Balance, Balances, Symbol};
 pub use ident::{HasIdentifier, Id};
@@ -0,0 +1,158 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use super::NetworkMessage;
+
+/// Version of the wire format spoken by this build. A peer sending any
+/// other version gets an `UnsupportedVersion` error back.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Identifies a message within one connection, so replies can name the
+/// request they answer.
+pub type MsgId = u64;
+
+/// Why a request could not be handled.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The frame could not be decoded.
+    Malformed,
+    UnsupportedVersion,
+    /// The message was understood but is not something this node handles.
+    Unsupported,
+    /// The request was refused, e.g. an invalid transaction.
+    Rejected,
+    /// A message other than `Hello` arrived before the handshake finished.
+    HandshakeRequired,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed"),
+            Self::UnsupportedVersion => write!(f, "unsupported version"),
+            Self::Unsupported => write!(f, "unsupported"),
+            Self::Rejected => write!(f, "rejected"),
+            Self::HandshakeRequired => write!(f, "handshake required"),
+        }
+    }
+}
+
+/// The fields every frame starts with, whatever its version. Decoded on
+/// their own first so even a frame with an unknown body can be answered.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u16,
+    id: MsgId,
+    reply_to: Option<MsgId>,
+}
+
+/// A `NetworkMessage` as sent over the wire: one bincode-encoded envelope
+/// per length-delimited frame.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Envelope {
+    pub version: u16,
+    pub id: MsgId,
+    /// The message this one answers, if it is a reply.
+    pub reply_to: Option<MsgId>,
+    pub body: NetworkMessage,
+}
+
+/// A frame that could not be turned into an `Envelope`, and the error to
+/// send back for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    /// The id of the offending frame, if even that much could be read.
+    pub id: Option<MsgId>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Envelope {
+    pub fn new(id: MsgId, body: NetworkMessage) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            reply_to: None,
+            body,
+        }
+    }
+
+    pub fn reply(id: MsgId, reply_to: MsgId, body: NetworkMessage) -> Self {
+        Self {
+            reply_to: Some(reply_to),
+            ..Self::new(id, body)
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let header: Header = bincode::deserialize(bytes).map_err(|e| DecodeError {
+            id: None,
+            code: ErrorCode::Malformed,
+            message: e.to_string(),
+        })?;
+        if header.version != PROTOCOL_VERSION {
+            return Err(DecodeError {
+                id: Some(header.id),
+                code: ErrorCode::UnsupportedVersion,
+                message: format!(
+                    "got version {}, this node speaks {}",
+                    header.version, PROTOCOL_VERSION
+                ),
+            });
+        }
+        bincode::deserialize(bytes).map_err(|e| DecodeError {
+            id: Some(header.id),
+            code: ErrorCode::Malformed,
+            message: e.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let bytes = Envelope::reply(7, 3, NetworkMessage::Pong).encode().unwrap();
+        let envelope = Envelope::decode(&bytes).unwrap();
+        assert_eq!((envelope.version, envelope.id, envelope.reply_to), (PROTOCOL_VERSION, 7, Some(3)));
+        assert!(matches!(envelope.body, NetworkMessage::Pong));
+    }
+
+    #[test]
+    fn names_the_frame_with_another_version() {
+        let mut envelope = Envelope::new(9, NetworkMessage::Ping);
+        envelope.version = PROTOCOL_VERSION + 1;
+        let err = Envelope::decode(&envelope.encode().unwrap()).unwrap_err();
+        assert_eq!((err.id, err.code), (Some(9), ErrorCode::UnsupportedVersion));
+    }
+
+    #[test]
+    fn reports_malformed_frames() {
+        let err = Envelope::decode(&[1, 2]).unwrap_err();
+        assert_eq!((err.id, err.code), (None, ErrorCode::Malformed));
+        // A readable header with a body that is not a message.
+        let mut bytes = bincode::serialize(&Header {
+            version: PROTOCOL_VERSION,
+            id: 4,
+            reply_to: None,
+        })
+        .unwrap();
+        bytes.extend([0xff; 4]);
+        let err = Envelope::decode(&bytes).unwrap_err();
+        assert_eq!((err.id, err.code), (Some(4), ErrorCode::Malformed));
+    }
+}
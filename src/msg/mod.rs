@@ -1,11 +1,86 @@
+pub mod envelope;
 pub mod tx;
 
 use serde::{Serialize, Deserialize};
+use crate::consensus::Commit;
+use crate::federation::org::user::Signature;
+use crate::node::peer::Hello;
+use crate::store::{proof::Hash, SnapshotHead, SnapshotPage, SnapshotPart};
+use crate::validate::Vote;
+pub use envelope::{Envelope, ErrorCode, MsgId, PROTOCOL_VERSION};
 pub use tx::{TxId, Transaction};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NetworkMessage {
+    /// Opens the handshake; the peer answers with its own `Hello`.
+    Hello(Hello),
+    /// Closes the handshake: the sender's `Hello` signed together with the
+    /// peer's nonce. The dialer sends its own once it has accepted the
+    /// reply to its `Hello`, and the peer answers with its own.
+    HelloProof(Signature),
+    /// A transaction without votes, which the receiver puts to the
+    /// validators before confirming it.
     Tx(Transaction),
+    /// Asks for the votes of the validators the peer runs; answered with
+    /// `ValidationRes`.
     ValidationReq(Transaction),
-    ValidationRes(Transaction, bool),
+    /// Signed votes on the transaction. Sent in reply to `ValidationReq`,
+    /// and unasked to share the votes that approved a transaction, which
+    /// the receiver confirms once they reach its own quorum and relays on.
+    ValidationRes(Transaction, Vec<Vote>),
+    /// Reply to `Tx`: the transaction was stored under this id.
+    TxAccepted(TxId),
+    /// Announces transactions the sender has; fetch any unseen ones with
+    /// `GetTx`.
+    Inv(Vec<TxId>),
+    /// Asks for transactions by id; answered with `Txs`.
+    GetTx(Vec<TxId>),
+    /// Whichever of the requested transactions the sender holds.
+    Txs(Vec<Transaction>),
+    /// Asks for the sender's tips; answered with `Tips`.
+    GetTips,
+    Tips {
+        tips: Vec<TxId>,
+        /// Height of the highest transaction, or `None` for an empty DAG.
+        max_height: Option<u64>,
+        /// Whether history has been pruned, so it can only be had from a
+        /// snapshot.
+        pruned: bool,
+        /// The sender's last commit, which vouches for everything its tips
+        /// build on.
+        commit: Option<Box<Commit>>,
+    },
+    /// Asks for `id` and up to `limit` of its nearest ancestors, not going
+    /// past any in `known`; answered with `Ancestors`, parents first.
+    GetAncestors { id: TxId, known: Vec<TxId>, limit: u32 },
+    Ancestors(Vec<SyncedTx>),
+    /// Asks for transactions with heights in `from..to`; answered with
+    /// `Range`.
+    GetRange { from: u64, to: u64, limit: u32 },
+    /// Transactions by height, parents first, and the height to ask for
+    /// next if the range was cut short.
+    Range { txs: Vec<SyncedTx>, next: Option<u64> },
+    /// Asks for the sender's state as of its last commit; answered with
+    /// `SnapshotHead`, or an error if it offers none.
+    GetSnapshot,
+    SnapshotHead(Box<SnapshotHead>),
+    /// Asks for up to `limit` items of `part` of the snapshot at
+    /// `checkpoint`, from the `from`th on; answered with `SnapshotPage`, or
+    /// an error once the sender has moved on to a later checkpoint.
+    GetSnapshotPage { checkpoint: Hash, part: SnapshotPart, from: u64, limit: u32 },
+    SnapshotPage(SnapshotPage),
+    /// Keepalive; answered with `Pong`.
+    Ping,
+    Pong,
+    /// Reply to any request that could not be handled.
+    Error { code: ErrorCode, message: String },
+}
+
+/// A transaction sent to a peer that is syncing, with the votes that
+/// approved it. They are left out where the sender no longer has them, in
+/// which case only a commit covering the transaction vouches for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedTx {
+    pub tx: Transaction,
+    pub votes: Vec<Vote>,
 }
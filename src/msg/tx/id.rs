@@ -1,41 +1,84 @@
-use std::ops::Deref;
+use std::{fmt, str::FromStr};
 
-use rand::{Rng, RngCore, distributions::Alphanumeric};
-use serde::{Serialize, Deserialize};
+use data_encoding::{BASE32_NOPAD, HEXLOWER_PERMISSIVE};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
-pub struct TxId(pub String);
+/// Length in bytes of a transaction id.
+pub const TX_ID_LEN: usize = 32;
 
-impl Default for TxId {
-    fn default() -> Self {
-        let mut rng = rand::thread_rng();
-        let rid: String =  (0..16).map(|_| (&mut rng).sample(Alphanumeric) as char)
-            .collect::<String>();
-        Self(rid)
-    }
-}
+/// Domain separator so a transaction hash can never collide with a hash of
+/// some other structure that happens to share the same encoding.
+const TX_ID_DOMAIN: &[u8] = b"cpr/tx/v1";
+
+/// The identity of a transaction: the SHA-256 of its canonical body. Every
+/// node hashing the same transaction arrives at the same id, so ids can be
+/// compared across the network and duplicates are detectable.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TxId(pub [u8; TX_ID_LEN]);
 
 impl TxId {
-    pub fn new() -> TxId {
-        TxId::default()
+    /// Hash a canonical transaction body into its id.
+    pub fn digest(body: &[u8]) -> TxId {
+        let mut hasher = Sha256::new();
+        hasher.update(TX_ID_DOMAIN);
+        hasher.update(body);
+        TxId(hasher.finalize().into())
     }
-}
-impl Deref<> for TxId {
-    type Target = String;
-    fn deref(&self) -> &Self::Target {
+
+    pub fn as_bytes(&self) -> &[u8; TX_ID_LEN] {
         &self.0
     }
+
+    pub fn to_hex(&self) -> String {
+        data_encoding::HEXLOWER.encode(&self.0)
+    }
+
+    pub fn to_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.0)
+    }
 }
-impl ToString for TxId {
-    fn to_string(&self) -> String {
-        self.0.to_owned()
+
+/// Lowercase hex, the same form `FromStr` reads back.
+impl fmt::Display for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
     }
 }
 
-impl Into<String> for TxId {
-    fn into(self) -> String {
-        self.0
+impl fmt::Debug for TxId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TxId({})", self.to_hex())
     }
 }
 
+/// Accepts either the 64 character hex form or the 52 character unpadded
+/// base32 form, telling them apart by length.
+impl FromStr for TxId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex_len = HEXLOWER_PERMISSIVE.encode_len(TX_ID_LEN);
+        let b32_len = BASE32_NOPAD.encode_len(TX_ID_LEN);
+        let bytes = if s.len() == hex_len {
+            HEXLOWER_PERMISSIVE.decode(s.as_bytes())?
+        } else if s.len() == b32_len {
+            BASE32_NOPAD.decode(s.to_ascii_uppercase().as_bytes())?
+        } else {
+            return Err(anyhow::anyhow!(
+                "TxId must be {} hex or {} base32 characters, got {}",
+                hex_len,
+                b32_len,
+                s.len()
+            ));
+        };
+        let mut id = [0u8; TX_ID_LEN];
+        id.copy_from_slice(&bytes);
+        Ok(TxId(id))
+    }
+}
 
+impl From<TxId> for String {
+    fn from(id: TxId) -> Self {
+        id.to_string()
+    }
+}
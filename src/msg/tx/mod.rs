@@ -4,55 +4,182 @@ use tokio::time::Duration;
 use std::time::SystemTime;
 pub use id::TxId;
 
-use crate::{federation::org::user::OrgUser, Balance};
+use serde::Serialize;
+
+use crate::{
+    federation::org::user::{id::CanonicalUserId, key::KeyError, KeyPair, OrgUser, PublicKey, Signature},
+    models::Amount,
+    Balance,
+};
 
 
 /// 
-#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
     pub id: TxId,
     pub send: OrgUser,
     pub recv: OrgUser,
     pub amt: Balance,
     pub timestamp: SystemTime,
-    pub sig: Option<String>,
+    /// Per-sender sequence number; each transaction a user sends must use a
+    /// higher nonce than the last one the ledger accepted from them.
+    pub nonce: u64,
+    /// Earlier transactions this one approves. Empty only for a DAG root.
+    pub parents: Vec<TxId>,
+    pub sig: Option<Signature>,
     pub contract: Option<Vec<u8>>,
 }
-impl Clone for Transaction {
-    fn clone(&self) -> Self {
-        return Self {
-            id: self.id.clone(),
-            send: OrgUser::from(self.send.clone()),
-            recv: OrgUser::from(self.recv.clone()),
-            amt: self.amt.clone(),
-            timestamp: self.timestamp,
-            sig: self.sig.clone(),
-            contract: self.contract.clone(),
-        }
-    }
-}
 impl Default for Transaction {
     fn default() -> Self {
-        Self {
-            id: TxId::new(),
-            amt: Balance::new("".into(), 0),
+        let mut tx = Self {
+            id: TxId::default(),
+            send: OrgUser::default(),
+            recv: OrgUser::default(),
+            amt: Balance::default(),
             timestamp: SystemTime::now(),
-            sig: None, ..Default::default()
-        }
+            nonce: 0,
+            parents: Vec::new(),
+            sig: None,
+            contract: None,
+        };
+        tx.id = tx.compute_id();
+        tx
     }
 }
 
 impl Transaction {
-    pub fn new(send: OrgUser, recv: OrgUser, symbol: &str, amt: usize) -> Self {
-        Self {
-            id: TxId::new(),
+    pub fn new(send: OrgUser, recv: OrgUser, symbol: &str, amt: Amount, nonce: u64) -> Self {
+        let mut tx = Self {
+            id: TxId::default(),
             send,
             recv,
             amt: Balance::new(symbol.into(), amt),
             timestamp: SystemTime::now(),
+            nonce,
+            parents: Vec::new(),
             sig: None,
             contract: None,
+        };
+        tx.id = tx.compute_id();
+        tx
+    }
+
+    /// The content address of this transaction, derived from its canonical
+    /// body, parents included. Two nodes holding the same transaction
+    /// always agree on it.
+    pub fn compute_id(&self) -> TxId {
+        TxId::digest(&self.signing_bytes())
+    }
+
+    /// Set the parents this transaction approves and re-derive its id.
+    /// Parents are signed along with everything else, so they have to be
+    /// chosen first: attaching a signed transaction fails rather than
+    /// silently invalidating its signature.
+    pub fn attach(&mut self, parents: Vec<TxId>) -> anyhow::Result<()> {
+        if self.sig.is_some() {
+            anyhow::bail!("Transaction {} is already signed; attach it before signing", self.id);
         }
+        self.parents = parents;
+        self.id = self.compute_id();
+        Ok(())
+    }
+
+    /// Whether `id` is really the hash of this transaction's contents,
+    /// i.e. it was not tampered with after being addressed.
+    pub fn verify_id(&self) -> bool {
+        self.id == self.compute_id()
+    }
+
+    /// The canonical encoding of everything the sender authorizes: who
+    /// pays whom, how much, when, at which nonce, which transactions it
+    /// approves, and any contract payload. The id and signature are excluded, so this is stable
+    /// across signing, and users are encoded in their canonical form, so
+    /// it does not depend on how much of their federation is known.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&TxBody {
+            send: self.send.id.canonical(),
+            recv: self.recv.id.canonical(),
+            amt: &self.amt,
+            timestamp: &self.timestamp,
+            nonce: self.nonce,
+            parents: &self.parents,
+            contract: &self.contract,
+        })
+        .expect("transaction body is always serializable")
+    }
+
+    /// Sign the transaction as its sender.
+    pub fn sign(&mut self, key: &KeyPair) {
+        self.sig = Some(key.sign(&self.signing_bytes()));
     }
+
+    /// Check the signature against the sender's public key.
+    pub fn verify_sig(&self, key: &PublicKey) -> Result<(), KeyError> {
+        match &self.sig {
+            Some(sig) => key.verify(&self.signing_bytes(), sig),
+            None => Err(KeyError::BadSignature),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TxBody<'a> {
+    send: CanonicalUserId<'a>,
+    recv: CanonicalUserId<'a>,
+    amt: &'a Balance,
+    timestamp: &'a SystemTime,
+    nonce: u64,
+    parents: &'a [TxId],
+    contract: &'a Option<Vec<u8>>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::{id::FedId, org::OrgId};
+
+    /// The same transaction, with its users' org carrying another idea of
+    /// their federation.
+    fn refederated(tx: &Transaction) -> Transaction {
+        let mut other = tx.clone();
+        for user in [&mut other.send, &mut other.recv] {
+            user.id.org_id.fed_id = FedId::new("elsewhere".to_string());
+        }
+        other
+    }
+
+    fn tx() -> Transaction {
+        let org = OrgId::new("org");
+        let send = OrgUser::new(org.clone(), "alice".to_string());
+        let recv = OrgUser::new(org, "bob".to_string());
+        Transaction::new(send, recv, "TEST", Amount::from_whole(1, 2).unwrap(), 1)
+    }
+
+    #[test]
+    fn id_ignores_federation_of_users() {
+        let key = KeyPair::generate();
+        let mut tx = tx();
+        tx.sign(&key);
+        let other = refederated(&tx);
+        assert_eq!(other.send.id, tx.send.id);
+        assert!(other.verify_id());
+        assert_eq!(other.verify_sig(&key.public()), Ok(()));
+    }
+
+    #[test]
+    fn signature_covers_parents() {
+        let key = KeyPair::generate();
+        let mut tx = tx();
+        tx.attach(vec![TxId([1; 32])]).unwrap();
+        tx.sign(&key);
+        assert_eq!(tx.verify_sig(&key.public()), Ok(()));
+        assert!(tx.attach(vec![TxId([2; 32])]).is_err());
+        assert_eq!(tx.parents, vec![TxId([1; 32])]);
+
+        let mut moved = tx.clone();
+        moved.parents = vec![TxId([2; 32])];
+        moved.id = moved.compute_id();
+        assert_ne!(moved.id, tx.id);
+        assert_eq!(moved.verify_sig(&key.public()), Err(KeyError::BadSignature));
+    }
+}
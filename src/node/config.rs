@@ -0,0 +1,400 @@
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use super::DEFAULT_LISTEN_ADDR;
+use crate::store::wal::DEFAULT_DATA_DIR;
+
+/// Where `cprd` looks for its configuration when `--config` is not given.
+/// A missing file there is not an error; the defaults are used instead.
+pub static DEFAULT_CONFIG_PATH: &str = "cprd.toml";
+/// Transactions the `StreamingDAG` queues before processing.
+pub static DEFAULT_WINDOW_SIZE: usize = 10;
+pub static DEFAULT_LOG_LEVEL: &str = "info";
+/// Handle of the org a node without a genesis file acts for.
+pub static DEFAULT_ORG: &str = "local";
+
+/// Usage printed for `cprd --help`.
+pub static USAGE: &str = "\
+Usage: cprd [OPTIONS]
+
+Options:
+  -c, --config <PATH>       Configuration file [default: cprd.toml]
+  -l, --listen <ADDR>       Address to accept peers on
+  -p, --peer <ADDR>         Peer to dial; may be repeated, adds to the file's
+  -d, --data-dir <PATH>     Where the write-ahead log and snapshots are kept
+  -g, --genesis <PATH>      Federation genesis file
+  -o, --org <HANDLE>        Org this node acts for
+  -k, --validator-key <PATH>
+                            Secret key of a validator this node votes as
+  -w, --window-size <N>     Transactions queued before processing
+  -v, --log-level <LEVEL>   off, error, warn, info, debug or trace
+  -h, --help                Print this message";
+
+/// Everything `cprd` needs to start a node, read from a TOML file:
+///
+/// ```toml
+/// listen = "0.0.0.0:8787"
+/// peers = ["10.0.0.2:8787", "node3.example.org:8787"]
+/// data_dir = "/var/lib/cprd"
+/// genesis = "genesis.toml"
+/// org = "aliceorg"
+/// validator_key = "validator.key"
+/// window_size = 10
+/// log_level = "info"
+/// ```
+///
+/// Every key is optional. Relative paths are taken relative to the
+/// directory `cprd` runs in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    /// Address to accept peer connections on.
+    pub listen: String,
+    /// Addresses of peers to keep connected to, as `host:port`.
+    pub peers: Vec<String>,
+    pub data_dir: PathBuf,
+    /// The genesis file the federation is built from. Without one the node
+    /// runs a federation of its own org alone.
+    pub genesis: Option<PathBuf>,
+    /// Handle of the org this node acts for.
+    pub org: String,
+    /// File holding the hex secret key of one of the org's validators,
+    /// for the node to vote as when peers ask it to validate.
+    pub validator_key: Option<PathBuf>,
+    pub window_size: usize,
+    pub log_level: String,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            listen: DEFAULT_LISTEN_ADDR.to_string(),
+            peers: Vec::new(),
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            genesis: None,
+            org: DEFAULT_ORG.to_string(),
+            validator_key: None,
+            window_size: DEFAULT_WINDOW_SIZE,
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+        }
+    }
+}
+
+/// A configuration that could not be read or is not valid. `key` names the
+/// setting at fault, as written in the file, or the file itself if it
+/// could not be read or parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration: {}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl FromStr for NodeConfig {
+    type Err = ConfigError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: NodeConfig = toml::from_str(s).map_err(|e| ConfigError::new("config", e.to_string().trim_end()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl NodeConfig {
+    /// Read and validate the configuration at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let config = Self::read(path.as_ref())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read the file at `path` without validating it, so settings given on
+    /// the command line can still replace bad ones.
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let key = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::new(&key, e.to_string()))?;
+        toml::from_str(&text).map_err(|e| ConfigError::new(key, e.to_string().trim_end()))
+    }
+
+    /// Build the configuration from command-line arguments (without the
+    /// program name): read the file named by `--config`, or
+    /// `DEFAULT_CONFIG_PATH` if it exists, then let the other options
+    /// override it. Returns `None` if `--help` was asked for.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, ConfigError> {
+        let overrides = match Overrides::parse(args)? {
+            Some(overrides) => overrides,
+            None => return Ok(None),
+        };
+        let mut config = match &overrides.config {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::read(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default(),
+        };
+        config.apply(overrides);
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    /// Replace settings with those given on the command line. Peers are
+    /// added to the file's rather than replacing them.
+    pub fn apply(&mut self, overrides: Overrides) {
+        if let Some(listen) = overrides.listen {
+            self.listen = listen;
+        }
+        self.peers.extend(overrides.peers);
+        if let Some(data_dir) = overrides.data_dir {
+            self.data_dir = data_dir;
+        }
+        if let Some(genesis) = overrides.genesis {
+            self.genesis = Some(genesis);
+        }
+        if let Some(org) = overrides.org {
+            self.org = org;
+        }
+        if let Some(validator_key) = overrides.validator_key {
+            self.validator_key = Some(validator_key);
+        }
+        if let Some(window_size) = overrides.window_size {
+            self.window_size = window_size;
+        }
+        if let Some(log_level) = overrides.log_level {
+            self.log_level = log_level;
+        }
+    }
+
+    /// Check every setting, returning the first bad one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.listen_addr()?;
+        for (i, peer) in self.peers.iter().enumerate() {
+            let port = peer.rsplit_once(':').map(|(host, port)| (host, port.parse::<u16>()));
+            match port {
+                Some((host, Ok(port))) if !host.is_empty() && port != 0 => {}
+                _ => {
+                    return Err(ConfigError::new(
+                        format!("peers[{}]", i),
+                        format!("expected host:port, got {:?}", peer),
+                    ))
+                }
+            }
+        }
+        if self.data_dir.as_os_str().is_empty() {
+            return Err(ConfigError::new("data_dir", "must not be empty"));
+        }
+        if let Some(genesis) = &self.genesis {
+            if !genesis.is_file() {
+                return Err(ConfigError::new("genesis", format!("no such file {}", genesis.display())));
+            }
+        }
+        if self.org.is_empty() {
+            return Err(ConfigError::new("org", "must not be empty"));
+        }
+        if let Some(key) = &self.validator_key {
+            if !key.is_file() {
+                return Err(ConfigError::new("validator_key", format!("no such file {}", key.display())));
+            }
+        }
+        if self.window_size == 0 {
+            return Err(ConfigError::new("window_size", "must be at least 1"));
+        }
+        self.log_level()?;
+        Ok(())
+    }
+
+    pub fn listen_addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.listen
+            .parse()
+            .map_err(|_| ConfigError::new("listen", format!("expected ip:port, got {:?}", self.listen)))
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, ConfigError> {
+        self.log_level.parse().map_err(|_| {
+            ConfigError::new(
+                "log_level",
+                format!("expected off, error, warn, info, debug or trace, got {:?}", self.log_level),
+            )
+        })
+    }
+}
+
+/// Settings given on the command line, each replacing the one in the file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides {
+    pub config: Option<PathBuf>,
+    pub listen: Option<String>,
+    pub peers: Vec<String>,
+    pub data_dir: Option<PathBuf>,
+    pub genesis: Option<PathBuf>,
+    pub org: Option<String>,
+    pub validator_key: Option<PathBuf>,
+    pub window_size: Option<usize>,
+    pub log_level: Option<String>,
+}
+
+impl Overrides {
+    /// Parse `cprd`'s options, as listed in `USAGE`. Both `--key value` and
+    /// `--key=value` are accepted. Returns `None` for `--help`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, ConfigError> {
+        let mut overrides = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                return Ok(None);
+            }
+            let key = match flag.as_str() {
+                "-c" | "--config" => "config",
+                "-l" | "--listen" => "listen",
+                "-p" | "--peer" => "peers",
+                "-d" | "--data-dir" => "data_dir",
+                "-g" | "--genesis" => "genesis",
+                "-o" | "--org" => "org",
+                "-k" | "--validator-key" => "validator_key",
+                "-w" | "--window-size" => "window_size",
+                "-v" | "--log-level" => "log_level",
+                _ => return Err(ConfigError::new(flag, "unknown option, see --help")),
+            };
+            let value = match inline.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(ConfigError::new(flag, "expected a value")),
+            };
+            match key {
+                "config" => overrides.config = Some(value.into()),
+                "listen" => overrides.listen = Some(value),
+                "peers" => overrides.peers.push(value),
+                "data_dir" => overrides.data_dir = Some(value.into()),
+                "genesis" => overrides.genesis = Some(value.into()),
+                "org" => overrides.org = Some(value),
+                "validator_key" => overrides.validator_key = Some(value.into()),
+                "window_size" => {
+                    let n = value
+                        .parse()
+                        .map_err(|_| ConfigError::new(key, format!("expected a number, got {:?}", value)))?;
+                    overrides.window_size = Some(n);
+                }
+                _ => overrides.log_level = Some(value),
+            }
+        }
+        Ok(Some(overrides))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Write `text` to a file of its own in the temp dir.
+    fn file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cpr-config-{}-{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_every_option() {
+        let parsed = Overrides::parse(args(&[
+            "-c", "a.toml", "--listen=127.0.0.1:1", "-p", "x:1", "--peer", "y:2", "-d", "data", "--genesis=g.toml",
+            "-o", "org", "-k", "v.key", "-w", "3", "--log-level", "debug",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            parsed,
+            Overrides {
+                config: Some("a.toml".into()),
+                listen: Some("127.0.0.1:1".to_string()),
+                peers: args(&["x:1", "y:2"]),
+                data_dir: Some("data".into()),
+                genesis: Some("g.toml".into()),
+                org: Some("org".to_string()),
+                validator_key: Some("v.key".into()),
+                window_size: Some(3),
+                log_level: Some("debug".to_string()),
+            }
+        );
+        assert_eq!(Overrides::parse(args(&["-w", "1", "--help"])), Ok(None));
+        assert_eq!(Overrides::parse(args(&["--bogus"])).unwrap_err().key, "--bogus");
+        assert_eq!(Overrides::parse(args(&["-l"])).unwrap_err().key, "-l");
+        assert_eq!(Overrides::parse(args(&["-w", "many"])).unwrap_err().key, "window_size");
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_file() {
+        let path = file("overrides.toml", "listen = \"0.0.0.0:1\"\npeers = [\"a:1\"]\norg = \"aliceorg\"\nwindow_size = 0\n");
+        let config_arg = path.display().to_string();
+        // The file's window size is invalid, but replaced before checking.
+        let config = NodeConfig::from_args(args(&["-c", &config_arg, "-l", "127.0.0.1:2", "--peer=b:2", "-w", "5"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.listen, "127.0.0.1:2");
+        assert_eq!(config.peers, args(&["a:1", "b:2"]));
+        assert_eq!(config.org, "aliceorg");
+        assert_eq!(config.window_size, 5);
+        assert_eq!(config.log_level, DEFAULT_LOG_LEVEL);
+        assert_eq!(NodeConfig::from_args(args(&["-c", &config_arg])).unwrap_err().key, "window_size");
+        assert_eq!(NodeConfig::load(&path).unwrap_err().key, "window_size");
+        assert_eq!(NodeConfig::from_args(args(&["-h"])), Ok(None));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(NodeConfig::from_args(args(&["-c", &config_arg])).unwrap_err().key, config_arg);
+
+        let mut config = NodeConfig::default();
+        config.apply(Overrides::default());
+        assert_eq!(config, NodeConfig::default());
+    }
+
+    #[test]
+    fn validate_names_the_bad_setting() {
+        assert_eq!(NodeConfig::default().validate(), Ok(()));
+        let key = file("validator.key", "");
+        let bad = |f: &dyn Fn(&mut NodeConfig)| {
+            let mut config = NodeConfig::default();
+            f(&mut config);
+            config.validate().unwrap_err().key
+        };
+        assert_eq!(bad(&|c| c.listen = "localhost".to_string()), "listen");
+        assert_eq!(bad(&|c| c.peers = args(&["a:1", "host"])), "peers[1]");
+        assert_eq!(bad(&|c| c.peers = args(&["a:0"])), "peers[0]");
+        assert_eq!(bad(&|c| c.peers = args(&[":80"])), "peers[0]");
+        assert_eq!(bad(&|c| c.data_dir = PathBuf::new()), "data_dir");
+        assert_eq!(bad(&|c| c.genesis = Some("no-such-genesis.toml".into())), "genesis");
+        assert_eq!(bad(&|c| c.org = String::new()), "org");
+        assert_eq!(bad(&|c| c.validator_key = Some(key.with_extension("missing"))), "validator_key");
+        assert_eq!(bad(&|c| c.window_size = 0), "window_size");
+        assert_eq!(bad(&|c| c.log_level = "loud".to_string()), "log_level");
+        let config = NodeConfig {
+            peers: args(&["node3.example.org:8787", "[::1]:8787"]),
+            validator_key: Some(key.clone()),
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!("colour = \"blue\"".parse::<NodeConfig>().unwrap_err().key, "config");
+        fs::remove_file(&key).unwrap();
+    }
+}
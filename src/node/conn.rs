@@ -0,0 +1,161 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::io;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::msg::{envelope::DecodeError, Envelope, ErrorCode, MsgId, NetworkMessage};
+use crate::store::dag::MAX_MIN_MSG_SIZE;
+
+/// One end of a peer connection: envelopes framed by a length prefix, at
+/// most `MAX_MIN_MSG_SIZE` bytes each.
+///
+/// Ids are assigned per connection, starting at 1, and a reply carries the
+/// id of the request it answers in `reply_to`.
+pub struct Connection {
+    framed: Framed<TcpStream, LengthDelimitedCodec>,
+    next_id: MsgId,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Self {
+        let codec = LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_MIN_MSG_SIZE)
+            .new_codec();
+        Self {
+            framed: Framed::new(stream, codec),
+            next_id: 1,
+        }
+    }
+
+    pub async fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.framed.get_ref().peer_addr()
+    }
+
+    fn next_id(&mut self) -> MsgId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    async fn write(&mut self, envelope: Envelope) -> anyhow::Result<MsgId> {
+        let id = envelope.id;
+        self.framed.send(Bytes::from(envelope.encode()?)).await?;
+        Ok(id)
+    }
+
+    /// Send `body` as a new message, returning its id.
+    pub async fn send(&mut self, body: NetworkMessage) -> anyhow::Result<MsgId> {
+        let id = self.next_id();
+        self.write(Envelope::new(id, body)).await
+    }
+
+    /// Send `body` in reply to the message `to`.
+    pub async fn reply(&mut self, to: MsgId, body: NetworkMessage) -> anyhow::Result<MsgId> {
+        let id = self.next_id();
+        self.write(Envelope::reply(id, to, body)).await
+    }
+
+    /// The next well-formed envelope, or `None` once the peer hangs up.
+    ///
+    /// Frames that do not decode are answered with an `Error` and skipped.
+    /// A frame over the size limit cannot be skipped, so after answering
+    /// it the connection is given up with an error.
+    pub async fn recv(&mut self) -> anyhow::Result<Option<Envelope>> {
+        loop {
+            let frame = match self.framed.next().await {
+                None => return Ok(None),
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                    let body = NetworkMessage::Error {
+                        code: ErrorCode::Malformed,
+                        message: e.to_string(),
+                    };
+                    let _ = self.send(body).await;
+                    return Err(e.into());
+                }
+                Some(Err(e)) => return Err(e.into()),
+            };
+            match Envelope::decode(&frame) {
+                Ok(envelope) => return Ok(Some(envelope)),
+                Err(DecodeError { id, code, message }) => {
+                    log::warn!("Bad frame from {:?}: {}: {}", self.peer_addr().ok(), code, message);
+                    let body = NetworkMessage::Error { code, message };
+                    match id {
+                        Some(id) => self.reply(id, body).await?,
+                        None => self.send(body).await?,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Send `body` and wait for the reply to it. Anything else that
+    /// arrives in the meantime is dropped.
+    pub async fn request(&mut self, body: NetworkMessage) -> anyhow::Result<NetworkMessage> {
+        let id = self.send(body).await?;
+        while let Some(envelope) = self.recv().await? {
+            if envelope.reply_to == Some(id) {
+                return Ok(envelope.body);
+            }
+            log::debug!("Dropping message {} while awaiting reply to {}", envelope.id, id);
+        }
+        anyhow::bail!("connection closed before reply to {}", id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    /// A connection to a listener, and the raw stream accepted there.
+    async fn pair() -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (conn, accepted) = tokio::join!(Connection::connect(addr), listener.accept());
+        (conn.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn replies_name_the_request() {
+        let (mut client, stream) = pair().await;
+        let mut server = Connection::new(stream);
+        let serve = tokio::spawn(async move {
+            let ping = server.recv().await.unwrap().unwrap();
+            server.send(NetworkMessage::Ping).await.unwrap();
+            server.reply(ping.id, NetworkMessage::Pong).await.unwrap();
+        });
+        assert!(matches!(client.request(NetworkMessage::Ping).await.unwrap(), NetworkMessage::Pong));
+        serve.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_and_skips_undecodable_frames() {
+        let (client, stream) = pair().await;
+        let mut server = Connection::new(stream);
+        let mut raw = Framed::new(client.framed.into_inner(), LengthDelimitedCodec::new());
+        raw.send(Bytes::from_static(&[1, 2])).await.unwrap();
+        raw.send(Bytes::from(Envelope::new(5, NetworkMessage::Ping).encode().unwrap())).await.unwrap();
+        let serve = tokio::spawn(async move { server.recv().await.unwrap().unwrap() });
+        let error = Envelope::decode(&raw.next().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(error.body, NetworkMessage::Error { code: ErrorCode::Malformed, .. }));
+        assert_eq!(serve.await.unwrap().id, 5);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_oversized_frames() {
+        let (client, stream) = pair().await;
+        let mut server = Connection::new(stream);
+        let mut raw = client.framed.into_inner();
+        raw.write_all(&((MAX_MIN_MSG_SIZE + 1) as u32).to_be_bytes()).await.unwrap();
+        assert!(server.recv().await.is_err());
+        let mut raw = Framed::new(raw, LengthDelimitedCodec::new());
+        let error = Envelope::decode(&raw.next().await.unwrap().unwrap()).unwrap();
+        assert!(matches!(error.body, NetworkMessage::Error { code: ErrorCode::Malformed, .. }));
+    }
+}
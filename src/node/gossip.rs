@@ -0,0 +1,406 @@
+use rand::seq::SliceRandom;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use super::{manager::PeerManager, peer::FEATURE_INV, NodeId};
+use crate::federation::org::OrgId;
+use crate::msg::{ErrorCode, NetworkMessage};
+use crate::store::dag::DEFAULT_PARENTS;
+use crate::validate::Vote;
+use crate::{Transaction, TxId};
+
+/// Peers a new transaction is relayed to.
+pub static DEFAULT_FANOUT: usize = 8;
+/// Transaction ids remembered as seen, network-wide and per peer.
+pub static DEFAULT_SEEN_CAPACITY: usize = 100_000;
+pub static DEFAULT_INVENTORY_CAPACITY: usize = 10_000;
+/// Encoded size above which a transaction is announced rather than pushed.
+pub static DEFAULT_ANNOUNCE_THRESHOLD: usize = 4096;
+/// Most ids one `Inv` or `GetTx` may carry.
+pub static MAX_INV_LEN: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    pub fanout: usize,
+    pub seen_capacity: usize,
+    pub inventory_capacity: usize,
+    /// Transactions carrying a contract payload, or encoding to more than
+    /// this many bytes, are announced with `Inv` to peers that support it
+    /// and only sent to those that ask with `GetTx`.
+    pub announce_threshold: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            fanout: DEFAULT_FANOUT,
+            seen_capacity: DEFAULT_SEEN_CAPACITY,
+            inventory_capacity: DEFAULT_INVENTORY_CAPACITY,
+            announce_threshold: DEFAULT_ANNOUNCE_THRESHOLD,
+        }
+    }
+}
+
+/// A set of transaction ids that forgets the oldest once full.
+#[derive(Debug, Clone, Default)]
+pub struct SeenSet {
+    ids: HashSet<TxId>,
+    order: VecDeque<TxId>,
+    capacity: usize,
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn contains(&self, id: &TxId) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Returns true if `id` was not already in the set.
+    pub fn insert(&mut self, id: TxId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Relays transactions this node accepts to its peers, together with the
+/// validator votes that approved them.
+///
+/// Each transaction is sent at most once per peer: the node remembers which
+/// ids it has seen, and for each peer which ids it is known to have, either
+/// because it sent or announced them or because they were sent to it.
+pub struct Gossip {
+    pub config: GossipConfig,
+    seen: Mutex<SeenSet>,
+    inventory: Mutex<HashMap<NodeId, SeenSet>>,
+    /// Ids requested with `GetTx` and not yet received.
+    in_flight: Mutex<HashSet<TxId>>,
+}
+
+impl Gossip {
+    pub fn new(config: GossipConfig) -> Self {
+        Self {
+            seen: Mutex::new(SeenSet::new(config.seen_capacity)),
+            inventory: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashSet::new()),
+            config,
+        }
+    }
+
+    pub fn has_seen(&self, id: &TxId) -> bool {
+        self.seen.lock().unwrap().contains(id)
+    }
+
+    /// Record `id` as seen without relaying it, e.g. for transactions
+    /// fetched while syncing.
+    pub fn mark_seen(&self, id: TxId) {
+        self.seen.lock().unwrap().insert(id);
+    }
+
+    /// Record that `peer` has `id`.
+    pub fn mark_known(&self, peer: &NodeId, id: TxId) {
+        let capacity = self.config.inventory_capacity;
+        self.inventory
+            .lock()
+            .unwrap()
+            .entry(*peer)
+            .or_insert_with(|| SeenSet::new(capacity))
+            .insert(id);
+    }
+
+    pub fn peer_knows(&self, peer: &NodeId, id: &TxId) -> bool {
+        self.inventory.lock().unwrap().get(peer).is_some_and(|inv| inv.contains(id))
+    }
+
+    /// Forget what a disconnected peer had.
+    pub fn forget_peer(&self, peer: &NodeId) {
+        self.inventory.lock().unwrap().remove(peer);
+    }
+
+    /// Whether `tx` should be announced rather than pushed.
+    pub fn should_announce(&self, tx: &Transaction) -> bool {
+        tx.contract.is_some()
+            || bincode::serialized_size(tx).map_or(true, |n| n as usize > self.config.announce_threshold)
+    }
+
+    /// Accept `tx` into the local DAG on behalf of `org_id` and relay it.
+    /// Nothing is confirmed without a quorum of the validator set: `votes`
+    /// that came with the transaction are counted as they are, and one
+    /// that came without is checked with `StreamingDAG::check_tx`, so no
+    /// validator is asked about a transaction that cannot pass, and then
+    /// put to the validators with `PeerManager::validate`. A transaction
+    /// already seen is neither re-applied nor relayed again.
+    pub async fn accept(
+        &self,
+        manager: &Arc<PeerManager>,
+        mut tx: Transaction,
+        votes: Option<Vec<Vote>>,
+        org_id: OrgId,
+        from: Option<NodeId>,
+    ) -> anyhow::Result<TxId> {
+        if from.is_none() && tx.parents.is_empty() && tx.sig.is_none() {
+            let tips = manager.node.dag.select_tips(DEFAULT_PARENTS);
+            if !tips.is_empty() {
+                tx.attach(tips)?;
+            }
+        }
+        if let Some(peer) = &from {
+            self.mark_known(peer, tx.id);
+        }
+        if manager.node.dag.dag.contains(&tx.id) {
+            self.seen.lock().unwrap().insert(tx.id);
+            return Ok(tx.id);
+        }
+        if self.has_seen(&tx.id) {
+            anyhow::bail!("Transaction {} already seen", tx.id);
+        }
+        let dag = &manager.node.dag.dag;
+        let missing: Vec<TxId> = tx.parents.iter().filter(|p| !dag.knows(p)).copied().collect();
+        if let (false, Some(peer)) = (missing.is_empty(), from) {
+            // Fetch the parents from whoever sent it.
+            manager.sync.fetch_parents(manager, &missing, peer).await;
+            if !tx.parents.iter().all(|p| dag.knows(p)) {
+                anyhow::bail!("Transaction {} is missing parents {:?}", tx.id, missing);
+            }
+        }
+        let outcome = match votes {
+            Some(votes) => {
+                let outcome = manager.node.confirm_approved(&tx, votes).await?;
+                if outcome.is_approved() {
+                    self.relay(manager, &tx, &outcome.approved);
+                }
+                outcome
+            }
+            None => {
+                manager.node.dag.check_tx(&tx, &org_id)?;
+                manager.validate(&tx).await?
+            }
+        };
+        if let Err(reasons) = outcome.verdict() {
+            let reasons: Vec<String> = reasons.iter().map(|r| r.to_string()).collect();
+            anyhow::bail!("Transaction {} not approved: {}", tx.id, reasons.join("; "));
+        }
+        Ok(tx.id)
+    }
+
+    /// Send `tx` and the `votes` that approved it to up to `fanout` peers
+    /// picked at random from those not known to have it yet, returning how
+    /// many it went to.
+    pub fn relay(&self, manager: &PeerManager, tx: &Transaction, votes: &[Vote]) -> usize {
+        self.seen.lock().unwrap().insert(tx.id);
+        let mut peers: Vec<_> = manager
+            .peers()
+            .into_iter()
+            .filter(|p| !self.peer_knows(&p.node_id, &tx.id))
+            .collect();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(self.config.fanout);
+        let announce = self.should_announce(tx);
+        let mut sent = 0;
+        for peer in peers {
+            let body = if announce && peer.supports(FEATURE_INV) {
+                NetworkMessage::Inv(vec![tx.id])
+            } else {
+                NetworkMessage::ValidationRes(tx.clone(), votes.to_vec())
+            };
+            if manager.send_to(&peer.node_id, body).is_ok() {
+                self.mark_known(&peer.node_id, tx.id);
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// Handle an `Inv` from `from`: fetch the announced transactions not
+    /// seen yet, and accept those that arrive. They come without votes, so
+    /// each is put to the validators.
+    pub async fn on_inv(&self, manager: &Arc<PeerManager>, from: NodeId, ids: Vec<TxId>) {
+        let wanted: Vec<TxId> = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            ids.into_iter()
+                .take(MAX_INV_LEN)
+                .filter(|id| {
+                    self.mark_known(&from, *id);
+                    !self.has_seen(id) && in_flight.insert(*id)
+                })
+                .collect()
+        };
+        if wanted.is_empty() {
+            return;
+        }
+        let resp = manager.request(&from, NetworkMessage::GetTx(wanted.clone())).await;
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            for id in &wanted {
+                in_flight.remove(id);
+            }
+        }
+        let txs = match resp {
+            Ok(NetworkMessage::Txs(txs)) => txs,
+            Ok(other) => {
+                log::warn!("Unexpected reply to GetTx from {}: {:?}", from, other);
+                return;
+            }
+            Err(e) => {
+                log::warn!("Could not fetch announced transactions: {}", e);
+                return;
+            }
+        };
+        for tx in txs {
+            if !wanted.contains(&tx.id) {
+                continue;
+            }
+            let org_id = tx.send.id.org_id.clone();
+            if let Err(e) = self.accept(manager, tx, None, org_id, Some(from)).await {
+                log::debug!("{}", e);
+            }
+        }
+    }
+
+    /// Answer a `GetTx`: whichever of `ids` the local DAG holds.
+    pub fn on_get_tx(&self, manager: &PeerManager, ids: Vec<TxId>) -> NetworkMessage {
+        let dag = &manager.node.dag.dag;
+        NetworkMessage::Txs(ids.iter().take(MAX_INV_LEN).filter_map(|id| dag.get_tx(id)).collect())
+    }
+
+    /// Handle a `Tx` from `from`, which carries no votes, returning the
+    /// reply.
+    pub async fn on_tx(&self, manager: &Arc<PeerManager>, from: NodeId, tx: Transaction) -> NetworkMessage {
+        let org_id = tx.send.id.org_id.clone();
+        if org_id.fed_id != tx.recv.id.org_id.fed_id {
+            return NetworkMessage::Error {
+                code: ErrorCode::Unsupported,
+                message: "Transaction between federations not supported yet.".to_string(),
+            };
+        }
+        match self.accept(manager, tx, None, org_id, Some(from)).await {
+            Ok(id) => NetworkMessage::TxAccepted(id),
+            Err(e) => NetworkMessage::Error {
+                code: ErrorCode::Rejected,
+                message: e.to_string(),
+            },
+        }
+    }
+
+    /// Handle a `ValidationRes` from `from`: the transaction and the votes
+    /// that approved it, confirmed and relayed on once they reach quorum.
+    pub async fn on_approved(&self, manager: &Arc<PeerManager>, from: NodeId, tx: Transaction, votes: Vec<Vote>) {
+        let org_id = tx.send.id.org_id.clone();
+        if let Err(e) = self.accept(manager, tx, Some(votes), org_id, Some(from)).await {
+            log::info!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{
+        user::{KeyPair, OrgUser},
+        Org,
+    };
+    use crate::node::{manager::PeerManagerConfig, Node};
+    use crate::validate::{LocalVoter, Validator, Voter};
+    use crate::{Amount, Federation, StreamingDAG};
+
+    /// A peer manager for a node of an org with one validator, v0, signing
+    /// with `validator`, and alice funded. The node runs v0 itself if
+    /// `local` is set.
+    fn manager(validator: &KeyPair, local: bool) -> (Arc<PeerManager>, OrgUser, OrgUser, KeyPair) {
+        let mut fed = Federation::new("test");
+        let mut org = Org::new_from(fed.id.clone(), "testorg", "test", Vec::new());
+        let key = KeyPair::generate();
+        let alice = org.new_user_with_key("alice".to_string(), key.public());
+        let bob = org.new_user_with_key("bob".to_string(), KeyPair::generate().public());
+        let org_id = org.id.clone();
+        fed.register_validator(Validator::new("v0", org_id.clone(), 1, validator.public().to_string()));
+        fed.register_org(org);
+        let dag = Arc::new(StreamingDAG::new_with_federation(10, fed));
+        dag.mint(&alice.id, "TEST", Amount::from_whole(100, 2).unwrap()).unwrap();
+        let mut node = Node::new(org_id.clone(), Arc::clone(&dag));
+        if local {
+            let voter: Arc<dyn Voter> = Arc::new(LocalVoter::new("v0", org_id, validator.clone(), dag));
+            node = node.with_voters(vec![voter]);
+        }
+        (PeerManager::new(Arc::new(node), PeerManagerConfig::default()), alice, bob, key)
+    }
+
+    fn pay(manager: &PeerManager, from: &OrgUser, to: &OrgUser, key: &KeyPair) -> Transaction {
+        let dag = &manager.node.dag;
+        let nonce = dag.ledger.lock().unwrap().next_nonce(&from.id);
+        let mut tx = Transaction::new(from.clone(), to.clone(), "TEST", Amount::from_whole(1, 2).unwrap(), nonce);
+        tx.attach(dag.select_tips(DEFAULT_PARENTS)).unwrap();
+        tx.sign(key);
+        tx
+    }
+
+    #[tokio::test]
+    async fn transactions_without_votes_are_put_to_the_validators() {
+        let validator = KeyPair::generate();
+        let peer = NodeId::of(&KeyPair::generate().public());
+        // v0 runs elsewhere and cannot be reached, so nothing approves it.
+        let (remote, alice, bob, key) = manager(&validator, false);
+        let tx = pay(&remote, &alice, &bob, &key);
+        let reply = remote.gossip.on_tx(&remote, peer, tx.clone()).await;
+        assert!(matches!(reply, NetworkMessage::Error { code: ErrorCode::Rejected, .. }), "{:?}", reply);
+        assert!(!remote.node.dag.dag.contains(&tx.id));
+        assert!(!remote.gossip.has_seen(&tx.id));
+
+        let (local, alice, bob, key) = manager(&validator, true);
+        let tx = pay(&local, &alice, &bob, &key);
+        let reply = local.gossip.on_tx(&local, peer, tx.clone()).await;
+        assert!(matches!(reply, NetworkMessage::TxAccepted(id) if id == tx.id), "{:?}", reply);
+        assert!(local.node.dag.dag.contains(&tx.id));
+        assert!(local.gossip.has_seen(&tx.id));
+    }
+
+    #[tokio::test]
+    async fn relayed_votes_must_reach_quorum() {
+        let validator = KeyPair::generate();
+        let peer = NodeId::of(&KeyPair::generate().public());
+        let (manager, alice, bob, key) = manager(&validator, false);
+        let tx = pay(&manager, &alice, &bob, &key);
+        let org_id = alice.id.org_id.clone();
+        let genesis = manager.node.fed.genesis;
+
+        let forged = Vote::new(&genesis, "v0", tx.id, Vec::new(), &KeyPair::generate());
+        let res = manager.gossip.accept(&manager, tx.clone(), Some(vec![forged]), org_id.clone(), Some(peer)).await;
+        assert!(res.is_err());
+        manager.gossip.on_approved(&manager, peer, tx.clone(), Vec::new()).await;
+        assert!(!manager.node.dag.dag.contains(&tx.id));
+        // Cast by the right key, but in another federation.
+        let replayed = Vote::new(&[9; 32], "v0", tx.id, Vec::new(), &validator);
+        manager.gossip.on_approved(&manager, peer, tx.clone(), vec![replayed]).await;
+        assert!(!manager.node.dag.dag.contains(&tx.id));
+
+        let vote = Vote::new(&genesis, "v0", tx.id, Vec::new(), &validator);
+        manager.gossip.on_approved(&manager, peer, tx.clone(), vec![vote]).await;
+        assert!(manager.node.dag.dag.contains(&tx.id));
+        assert!(manager.gossip.has_seen(&tx.id));
+        assert!(manager.gossip.peer_knows(&peer, &tx.id));
+    }
+}
@@ -0,0 +1,768 @@
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, watch, OwnedSemaphorePermit, Semaphore,
+    },
+};
+
+use super::{
+    handle, Connection, Direction, Gossip, GossipConfig, Node, NodeId, Peer, RemoteVoter, SyncReport, Syncer,
+};
+use crate::federation::org::OrgId;
+use crate::msg::{Envelope, MsgId, NetworkMessage};
+use crate::validate::{ValidationOutcome, Voter};
+use crate::{Transaction, TxId};
+
+pub static DEFAULT_MIN_BACKOFF: Duration = Duration::from_millis(500);
+pub static DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+pub static DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(15);
+/// A peer silent for this long is disconnected.
+pub static DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+pub static DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+pub static DEFAULT_QUEUE_LEN: usize = 256;
+pub static DEFAULT_MAX_HANDLERS: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct PeerManagerConfig {
+    /// Addresses to keep a connection open to.
+    pub peers: Vec<String>,
+    /// Wait before the first redial; doubles with every failure in a row.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Messages that may wait to be sent to one peer. Sends to a peer whose
+    /// queue is full fail with `PeerError::Busy`; requests wait for room.
+    pub queue_len: usize,
+    /// Messages from one peer that may be handled at once. The peer's
+    /// connection is not read from while this many are in hand.
+    pub max_handlers: usize,
+    pub gossip: GossipConfig,
+    /// Catch up with each peer as soon as a session with it opens.
+    pub sync_on_connect: bool,
+}
+
+impl Default for PeerManagerConfig {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            min_backoff: DEFAULT_MIN_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            queue_len: DEFAULT_QUEUE_LEN,
+            max_handlers: DEFAULT_MAX_HANDLERS,
+            gossip: GossipConfig::default(),
+            sync_on_connect: true,
+        }
+    }
+}
+
+impl PeerManagerConfig {
+    pub fn with_peers(self, peers: Vec<String>) -> Self {
+        Self { peers, ..self }
+    }
+
+    /// How long to wait before redialing after `failures` failures in a
+    /// row, with up to a quarter added at random so peers that dropped
+    /// together do not all redial together.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let base = self
+            .min_backoff
+            .saturating_mul(1 << failures.min(16))
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0..=base.as_millis() as u64 / 4);
+        base + Duration::from_millis(jitter)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerError {
+    /// No open session with the peer.
+    NotConnected(NodeId),
+    /// The session closed before the message could be sent or answered.
+    Closed(NodeId),
+    /// Too many messages are already waiting to be sent to the peer.
+    Busy(NodeId),
+    Timeout(NodeId),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotConnected(id) => write!(f, "Not connected to peer {}", id),
+            Self::Closed(id) => write!(f, "Connection to peer {} closed", id),
+            Self::Busy(id) => write!(f, "Too many messages queued for peer {}", id),
+            Self::Timeout(id) => write!(f, "Peer {} did not reply in time", id),
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+/// How a peer's session is faring.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerHealth {
+    pub connected: bool,
+    /// Sessions opened with the peer, counting the current one.
+    pub sessions: u64,
+    /// When the last message from the peer arrived.
+    pub last_seen: Option<SystemTime>,
+    /// Round trip of the last answered ping.
+    pub rtt: Option<Duration>,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// Requests that timed out or went unanswered, and connection errors.
+    pub errors: u64,
+}
+
+/// Progress dialing one configured address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DialState {
+    /// The node last reached at the address.
+    pub node_id: Option<NodeId>,
+    /// Failed attempts since the last successful one.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    /// When the next attempt is due, while backing off.
+    pub next_attempt: Option<SystemTime>,
+}
+
+enum Outgoing {
+    Send(NetworkMessage),
+    Request(NetworkMessage, oneshot::Sender<NetworkMessage>),
+}
+
+struct Session {
+    /// Tells a replaced session apart from the one that replaced it.
+    serial: u64,
+    peer: Peer,
+    outgoing: mpsc::Sender<Outgoing>,
+    /// Changes to an error once the session's task has finished.
+    closed: watch::Receiver<()>,
+}
+
+/// Keeps connections open to a configured list of peers, redialing with
+/// exponential backoff, and routes messages to and from every handshaken
+/// peer, inbound or outbound.
+///
+/// There is at most one session per peer. When two nodes dial each other at
+/// once, both keep the connection dialed by the node with the lower id.
+pub struct PeerManager {
+    pub node: Arc<Node>,
+    pub config: PeerManagerConfig,
+    pub gossip: Gossip,
+    pub sync: Syncer,
+    sessions: Mutex<HashMap<NodeId, Session>>,
+    health: Mutex<HashMap<NodeId, PeerHealth>>,
+    dials: Mutex<HashMap<String, DialState>>,
+    next_serial: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl PeerManager {
+    pub fn new(node: Arc<Node>, config: PeerManagerConfig) -> Arc<Self> {
+        Arc::new(Self {
+            node,
+            gossip: Gossip::new(config.gossip.clone()),
+            sync: Syncer::new(),
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            health: Mutex::new(HashMap::new()),
+            dials: Mutex::new(HashMap::new()),
+            next_serial: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Start dialing every configured peer.
+    pub fn start(self: &Arc<Self>) {
+        for addr in self.config.peers.clone() {
+            self.dial(addr);
+        }
+    }
+
+    /// Keep a connection open to `addr` from now on. Returns false if the
+    /// address is already being dialed.
+    pub fn dial(self: &Arc<Self>, addr: String) -> bool {
+        {
+            let mut dials = self.dials.lock().unwrap();
+            if dials.contains_key(&addr) {
+                return false;
+            }
+            dials.insert(addr.clone(), DialState::default());
+        }
+        let manager = Arc::clone(self);
+        tokio::spawn(async move { manager.dial_loop(addr).await });
+        true
+    }
+
+    /// Close every session and stop redialing.
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Dropping the senders ends each session's task.
+        self.sessions.lock().unwrap().clear();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.sessions.lock().unwrap().values().map(|s| s.peer.clone()).collect()
+    }
+
+    pub fn is_connected(&self, id: &NodeId) -> bool {
+        self.sessions.lock().unwrap().contains_key(id)
+    }
+
+    pub fn health(&self, id: &NodeId) -> Option<PeerHealth> {
+        self.health.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn dial_state(&self, addr: &str) -> Option<DialState> {
+        self.dials.lock().unwrap().get(addr).cloned()
+    }
+
+    /// Send `body` to one peer without waiting for a reply.
+    pub fn send_to(&self, id: &NodeId, body: NetworkMessage) -> Result<(), PeerError> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id).ok_or(PeerError::NotConnected(*id))?;
+        session.outgoing.try_send(Outgoing::Send(body)).map_err(|e| match e {
+            TrySendError::Full(_) => PeerError::Busy(*id),
+            TrySendError::Closed(_) => PeerError::Closed(*id),
+        })
+    }
+
+    /// Send `body` to every connected peer, returning how many it went to.
+    pub fn broadcast(&self, body: NetworkMessage) -> usize {
+        self.broadcast_except(body, None)
+    }
+
+    /// Send `body` to every connected peer but `except`. Peers whose queue
+    /// is full are skipped.
+    pub fn broadcast_except(&self, body: NetworkMessage, except: Option<&NodeId>) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .iter()
+            .filter(|(id, _)| Some(*id) != except)
+            .filter(|(_, s)| s.outgoing.try_send(Outgoing::Send(body.clone())).is_ok())
+            .count()
+    }
+
+    /// Send `body` to one peer and wait for its reply, waiting first for
+    /// room in the peer's queue if it is full.
+    pub async fn request(&self, id: &NodeId, body: NetworkMessage) -> Result<NetworkMessage, PeerError> {
+        let outgoing = {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions.get(id).ok_or(PeerError::NotConnected(*id))?;
+            session.outgoing.clone()
+        };
+        let (tx, rx) = oneshot::channel();
+        let res = tokio::time::timeout(self.config.request_timeout, async {
+            outgoing
+                .send(Outgoing::Request(body, tx))
+                .await
+                .map_err(|_| PeerError::Closed(*id))?;
+            rx.await.map_err(|_| PeerError::Closed(*id))
+        })
+        .await
+        .unwrap_or(Err(PeerError::Timeout(*id)));
+        if res.is_err() {
+            self.update_health(id, |h| h.errors += 1);
+        }
+        res
+    }
+
+    /// Catch up with `peer`; see `Syncer`.
+    pub async fn sync_with(&self, peer: &NodeId) -> anyhow::Result<SyncReport> {
+        self.sync.sync_with(self, peer).await
+    }
+
+    /// Put a transaction submitted to this node to the validators, and
+    /// confirm and relay it to peers if they approve it.
+    pub async fn submit(self: &Arc<Self>, tx: Transaction, org_id: OrgId) -> anyhow::Result<TxId> {
+        self.gossip.accept(self, tx, None, org_id, None).await
+    }
+
+    /// Voters for every validator in the federation: those this node runs
+    /// itself, and a `RemoteVoter` for each of the others.
+    pub fn voters(self: &Arc<Self>) -> Vec<Arc<dyn Voter>> {
+        let mut voters = self.node.voters.clone();
+        for v in self.node.fed.validators.iter() {
+            if !voters.iter().any(|local| local.validator() == v.name()) {
+                voters.push(Arc::new(RemoteVoter::new(v.name(), v.org_id.clone(), Arc::clone(self))));
+            }
+        }
+        voters
+    }
+
+    /// Collect votes on `tx` from the whole validator set, here and at
+    /// peers. If they approve it, `tx` is confirmed and relayed with the
+    /// approving votes, so each peer can confirm it too.
+    pub async fn validate(self: &Arc<Self>, tx: &Transaction) -> anyhow::Result<ValidationOutcome> {
+        let outcome = self
+            .node
+            .fed
+            .validate_tx_distributed(tx, &self.voters(), self.node.vote_timeout)
+            .await;
+        if outcome.is_approved() {
+            self.node.confirm_approved(tx, outcome.approved.clone()).await?;
+            self.gossip.relay(self, tx, &outcome.approved);
+        }
+        Ok(outcome)
+    }
+
+    /// Accept connections on `listener` until it fails or the manager is
+    /// shut down.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            if self.is_stopped() {
+                return Ok(());
+            }
+            let manager = Arc::clone(&self);
+            tokio::spawn(async move { manager.accept(stream).await });
+        }
+    }
+
+    /// Handshake with an inbound connection and run its session.
+    pub async fn accept(self: Arc<Self>, stream: TcpStream) {
+        let mut conn = Connection::new(stream);
+        match self.node.accept_handshake(&mut conn).await {
+            Ok(peer) => {
+                self.run_session(conn, peer).await;
+            }
+            Err(e) => log::warn!("Handshake with {:?} failed: {}", conn.peer_addr().ok(), e),
+        }
+    }
+
+    async fn dial_loop(self: Arc<Self>, addr: String) {
+        let mut failures = 0;
+        while !self.is_stopped() {
+            // Already connected to whoever is at this address, perhaps
+            // because they dialed us: wait for that session to end.
+            let known = self.dials.lock().unwrap().get(&addr).and_then(|d| d.node_id);
+            if let Some(mut closed) = known.and_then(|id| self.closed(&id)) {
+                let _ = closed.changed().await;
+                continue;
+            }
+            let res = async {
+                let mut conn = Connection::connect(addr.as_str()).await?;
+                let peer = self.node.handshake(&mut conn).await?;
+                anyhow::Ok((conn, peer))
+            }
+            .await;
+            let err = match res {
+                Ok((conn, peer)) => {
+                    self.update_dial(&addr, |d| d.node_id = Some(peer.node_id));
+                    // Runs until the session ends, or returns at once if
+                    // an existing session with the peer won out.
+                    let started = Instant::now();
+                    if !self.clone().run_session(conn, peer).await || self.is_stopped() {
+                        continue;
+                    }
+                    // A session that did not outlast one keepalive round
+                    // counts as a failure, so a flapping peer backs off too.
+                    if started.elapsed() >= self.config.ping_interval {
+                        failures = 0;
+                        self.update_dial(&addr, |d| {
+                            d.failures = 0;
+                            d.next_attempt = None;
+                        });
+                        continue;
+                    }
+                    "session closed right after opening".to_string()
+                }
+                Err(e) => e.to_string(),
+            };
+            failures += 1;
+            let wait = self.config.backoff(failures - 1);
+            log::warn!("Could not connect to {}: {}; retrying in {:?}", addr, err, wait);
+            self.update_dial(&addr, |d| {
+                d.failures = failures;
+                d.last_error = Some(err);
+                d.next_attempt = Some(SystemTime::now() + wait);
+            });
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn update_dial(&self, addr: &str, f: impl FnOnce(&mut DialState)) {
+        if let Some(d) = self.dials.lock().unwrap().get_mut(addr) {
+            f(d);
+        }
+    }
+
+    fn update_health(&self, id: &NodeId, f: impl FnOnce(&mut PeerHealth)) {
+        f(self.health.lock().unwrap().entry(*id).or_default());
+    }
+
+    fn closed(&self, id: &NodeId) -> Option<watch::Receiver<()>> {
+        self.sessions.lock().unwrap().get(id).map(|s| s.closed.clone())
+    }
+
+    /// Whether a connection with `peer` in `direction` is the one to keep
+    /// when there are two: the one dialed by the lower node id.
+    fn preferred(&self, peer: &NodeId, direction: Direction) -> bool {
+        let dialer = match direction {
+            Direction::Outbound => self.node.id,
+            Direction::Inbound => *peer,
+        };
+        dialer == self.node.id.min(*peer)
+    }
+
+    /// Register a session, replacing any existing one with the same peer
+    /// unless the existing one is preferred. Both have proven they hold the
+    /// peer's key, so a replacement comes from the same node, e.g. one that
+    /// reconnected before its old session timed out; one with a different
+    /// key is refused all the same. Returns false if it was not registered.
+    fn register(&self, session: Session) -> bool {
+        let id = session.peer.node_id;
+        let mut sessions = self.sessions.lock().unwrap();
+        if self.is_stopped() {
+            return false;
+        }
+        if let Some(existing) = sessions.get(&id) {
+            if existing.peer.key != session.peer.key {
+                log::warn!("Refusing second session with peer {} under another key", id);
+                return false;
+            }
+            if existing.peer.direction != session.peer.direction
+                && self.preferred(&id, existing.peer.direction)
+            {
+                return false;
+            }
+            log::debug!("Replacing session with peer {}", id);
+        }
+        self.node.add_peer(session.peer.clone());
+        sessions.insert(id, session);
+        drop(sessions);
+        self.update_health(&id, |h| {
+            h.connected = true;
+            h.sessions += 1;
+            h.last_seen = Some(SystemTime::now());
+        });
+        true
+    }
+
+    fn unregister(&self, id: &NodeId, serial: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(id).is_some_and(|s| s.serial == serial) {
+            sessions.remove(id);
+            self.node.remove_peer(id);
+            drop(sessions);
+            self.gossip.forget_peer(id);
+            self.update_health(id, |h| h.connected = false);
+            log::info!("Peer {} disconnected", id);
+        }
+    }
+
+    /// Run a session over a handshaken connection until either side closes
+    /// it, the peer goes quiet, or it is replaced. Returns false without
+    /// running if another session with the peer is preferred.
+    async fn run_session(self: Arc<Self>, mut conn: Connection, peer: Peer) -> bool {
+        let id = peer.node_id;
+        let serial = self.next_serial.fetch_add(1, Ordering::SeqCst);
+        let (outgoing, mut outgoing_rx) = mpsc::channel(self.config.queue_len.max(1));
+        let (_closed_tx, closed) = watch::channel(());
+        let session = Session { serial, peer, outgoing, closed };
+        if !self.register(session) {
+            return false;
+        }
+        if self.config.sync_on_connect {
+            let manager = Arc::clone(&self);
+            tokio::spawn(async move {
+                match manager.sync_with(&id).await {
+                    Ok(report) if report == SyncReport::default() => {}
+                    Ok(report) => log::info!("Synced with peer {}: {:?}", id, report),
+                    Err(e) => log::warn!("Could not sync with peer {}: {}", id, e),
+                }
+            });
+        }
+
+        // Replies from handlers spawned below come back on their own
+        // channel, so `outgoing` closes as soon as the session is dropped
+        // from the map. Each handler holds a permit until it has replied,
+        // and the connection is only read while one is free.
+        let max_handlers = self.config.max_handlers.max(1);
+        let (replies, mut replies_rx) = mpsc::channel::<(MsgId, NetworkMessage)>(max_handlers);
+        let handlers = Arc::new(Semaphore::new(max_handlers));
+        let mut permit: Option<OwnedSemaphorePermit> = None;
+        let mut pending: HashMap<MsgId, oneshot::Sender<NetworkMessage>> = HashMap::new();
+        let mut ping: Option<(MsgId, Instant)> = None;
+        let mut last_seen = Instant::now();
+        let mut ticker = tokio::time::interval(self.config.ping_interval);
+
+        loop {
+            let sent = tokio::select! {
+                out = outgoing_rx.recv() => match out {
+                    Some(Outgoing::Send(body)) => conn.send(body).await,
+                    Some(Outgoing::Request(body, tx)) => conn.send(body).await.inspect(|&msg_id| {
+                        pending.insert(msg_id, tx);
+                    }),
+                    None => break,
+                },
+                Some((to, body)) = replies_rx.recv() => conn.reply(to, body).await,
+                acquired = Arc::clone(&handlers).acquire_owned(), if permit.is_none() => {
+                    permit = acquired.ok();
+                    continue;
+                },
+                _ = ticker.tick() => {
+                    if last_seen.elapsed() > self.config.idle_timeout {
+                        log::warn!("Peer {} timed out", id);
+                        break;
+                    }
+                    pending.retain(|_, tx| !tx.is_closed());
+                    conn.send(NetworkMessage::Ping).await.inspect(|&msg_id| {
+                        ping = Some((msg_id, Instant::now()));
+                    })
+                },
+                incoming = conn.recv(), if permit.is_some() => {
+                    let envelope = match incoming {
+                        Ok(Some(envelope)) => envelope,
+                        Ok(None) => break,
+                        Err(e) => {
+                            log::warn!("Connection to peer {} failed: {}", id, e);
+                            self.update_health(&id, |h| h.errors += 1);
+                            break;
+                        }
+                    };
+                    last_seen = Instant::now();
+                    self.update_health(&id, |h| {
+                        h.messages_in += 1;
+                        h.last_seen = Some(SystemTime::now());
+                    });
+                    if let Some(to) = envelope.reply_to {
+                        if let Some((ping_id, at)) = ping {
+                            if ping_id == to {
+                                ping = None;
+                                self.update_health(&id, |h| h.rtt = Some(at.elapsed()));
+                                continue;
+                            }
+                        }
+                        if let Some(tx) = pending.remove(&to) {
+                            let _ = tx.send(envelope.body);
+                            continue;
+                        }
+                    }
+                    let manager = Arc::clone(&self);
+                    let replies = replies.clone();
+                    let permit = permit.take();
+                    tokio::spawn(async move {
+                        let msg_id = envelope.id;
+                        if let Some(resp) = manager.dispatch(id, envelope).await {
+                            let _ = replies.send((msg_id, resp)).await;
+                        }
+                        drop(permit);
+                    });
+                    continue;
+                },
+            };
+            match sent {
+                Ok(_) => self.update_health(&id, |h| h.messages_out += 1),
+                Err(e) => {
+                    log::warn!("Could not send to peer {}: {}", id, e);
+                    self.update_health(&id, |h| h.errors += 1);
+                    break;
+                }
+            }
+        }
+        self.unregister(&id, serial);
+        true
+    }
+
+    /// Act on a message from peer `from`, returning the reply, if any.
+    async fn dispatch(self: &Arc<Self>, from: NodeId, envelope: Envelope) -> Option<NetworkMessage> {
+        match envelope.body {
+            NetworkMessage::Tx(tx) => Some(self.gossip.on_tx(self, from, tx).await),
+            NetworkMessage::ValidationRes(tx, votes) => {
+                self.gossip.on_approved(self, from, tx, votes).await;
+                None
+            }
+            NetworkMessage::Inv(ids) => {
+                self.gossip.on_inv(self, from, ids).await;
+                None
+            }
+            NetworkMessage::GetTx(ids) => Some(self.gossip.on_get_tx(self, ids)),
+            body => match self.sync.on_request(self, &body) {
+                Some(reply) => Some(reply),
+                None => handle(Envelope { body, ..envelope }, &self.node).await,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::genesis::Genesis;
+    use crate::federation::org::user::KeyPair;
+    use crate::StreamingDAG;
+
+    fn manager(key: KeyPair, config: PeerManagerConfig) -> Arc<PeerManager> {
+        let fed = Genesis::standalone("org").federation().unwrap();
+        let org_id = fed.orgs[0].id.clone();
+        let node = Node::new(org_id, Arc::new(StreamingDAG::new_with_federation(10, fed))).with_key(key);
+        PeerManager::new(Arc::new(node), config)
+    }
+
+    fn peer(manager: &PeerManager, key: &KeyPair, direction: Direction) -> Peer {
+        Peer {
+            node_id: NodeId::of(&key.public()),
+            key: key.public(),
+            addr: "127.0.0.1:1".parse().unwrap(),
+            direction,
+            protocol_version: crate::msg::PROTOCOL_VERSION,
+            fed_id: manager.node.fed.id.clone(),
+            org_id: manager.node.org_id.clone(),
+            features: Default::default(),
+            connected_at: SystemTime::now(),
+        }
+    }
+
+    /// A session nothing reads from, and the receiving end of its queue.
+    fn session(manager: &PeerManager, peer: Peer, queue_len: usize) -> (Session, mpsc::Receiver<Outgoing>) {
+        let (outgoing, rx) = mpsc::channel(queue_len);
+        let (_closed_tx, closed) = watch::channel(());
+        let serial = manager.next_serial.fetch_add(1, Ordering::SeqCst);
+        (Session { serial, peer, outgoing, closed }, rx)
+    }
+
+    /// Two keys, the one with the lower node id first.
+    fn keys() -> (KeyPair, KeyPair) {
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        if NodeId::of(&a.public()) < NodeId::of(&b.public()) {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    async fn eventually(what: &str, mut f: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if f() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_with_up_to_a_quarter_added() {
+        let config = PeerManagerConfig {
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            ..Default::default()
+        };
+        for failures in 0..40 {
+            let base = Duration::from_millis(100 * (1u64 << failures.min(16))).min(config.max_backoff);
+            let waits: Vec<Duration> = (0..50).map(|_| config.backoff(failures)).collect();
+            assert!(waits.iter().all(|w| *w >= base && *w <= base + base / 4), "{:?}", waits);
+            // Jittered, so peers that failed together spread out.
+            assert!(waits.iter().any(|w| *w != waits[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_dialed_by_the_lower_id_are_kept() {
+        let (low, high) = keys();
+        let mine = manager(low.clone(), PeerManagerConfig::default());
+        let theirs = NodeId::of(&high.public());
+        assert!(mine.preferred(&theirs, Direction::Outbound));
+        assert!(!mine.preferred(&theirs, Direction::Inbound));
+
+        let (inbound, _rx1) = session(&mine, peer(&mine, &high, Direction::Inbound), 1);
+        assert!(mine.register(inbound));
+        // Our own dial wins over theirs...
+        let (outbound, _rx2) = session(&mine, peer(&mine, &high, Direction::Outbound), 1);
+        assert!(mine.register(outbound));
+        assert_eq!(mine.peers()[0].direction, Direction::Outbound);
+        // ...and is not given up for it.
+        let (inbound, _rx3) = session(&mine, peer(&mine, &high, Direction::Inbound), 1);
+        assert!(!mine.register(inbound));
+        assert_eq!(mine.peers()[0].direction, Direction::Outbound);
+        // A reconnect in the same direction replaces the old session.
+        let (again, _rx4) = session(&mine, peer(&mine, &high, Direction::Outbound), 1);
+        let serial = again.serial;
+        assert!(mine.register(again));
+        assert_eq!(mine.sessions.lock().unwrap()[&theirs].serial, serial);
+        assert_eq!(mine.health(&theirs).unwrap().sessions, 3);
+        // The same id under another key is refused.
+        let mut impostor = peer(&mine, &low, Direction::Outbound);
+        impostor.node_id = theirs;
+        let (impostor, _rx5) = session(&mine, impostor, 1);
+        assert!(!mine.register(impostor));
+
+        // Seen from the other side, their dial is the one to keep.
+        let other = manager(high, PeerManagerConfig::default());
+        let (outbound, _rx6) = session(&other, peer(&other, &low, Direction::Outbound), 1);
+        assert!(other.register(outbound));
+        let (inbound, _rx7) = session(&other, peer(&other, &low, Direction::Inbound), 1);
+        assert!(other.register(inbound));
+        assert_eq!(other.peers()[0].direction, Direction::Inbound);
+    }
+
+    #[tokio::test]
+    async fn sends_to_a_full_queue_fail() {
+        let (low, high) = keys();
+        let mine = manager(low, PeerManagerConfig::default());
+        let theirs = NodeId::of(&high.public());
+        assert_eq!(mine.send_to(&theirs, NetworkMessage::Ping), Err(PeerError::NotConnected(theirs)));
+        let (session, rx) = session(&mine, peer(&mine, &high, Direction::Outbound), 1);
+        assert!(mine.register(session));
+        assert_eq!(mine.send_to(&theirs, NetworkMessage::Ping), Ok(()));
+        assert_eq!(mine.send_to(&theirs, NetworkMessage::Ping), Err(PeerError::Busy(theirs)));
+        assert_eq!(mine.broadcast(NetworkMessage::Ping), 0);
+        drop(rx);
+        assert_eq!(mine.send_to(&theirs, NetworkMessage::Ping), Err(PeerError::Closed(theirs)));
+    }
+
+    #[tokio::test]
+    async fn health_follows_the_session() {
+        let config = PeerManagerConfig {
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            ping_interval: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let dialer = manager(KeyPair::generate(), config.clone());
+        let server = manager(KeyPair::generate(), config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        assert!(dialer.dial(addr.clone()));
+        assert!(!dialer.dial(addr.clone()));
+
+        let id = server.node.id;
+        eventually("a ping to be answered", || dialer.health(&id).is_some_and(|h| h.rtt.is_some())).await;
+        let health = dialer.health(&id).unwrap();
+        assert!(health.connected);
+        assert_eq!(health.sessions, 1);
+        assert!(health.last_seen.is_some());
+        assert!(health.messages_in > 0 && health.messages_out > 0);
+        assert_eq!(dialer.dial_state(&addr).unwrap().node_id, Some(id));
+        assert!(server.health(&dialer.node.id).unwrap().connected);
+
+        // Once the server is gone, the dialer notices and backs off.
+        server.shutdown();
+        eventually("the session to close", || !dialer.health(&id).unwrap().connected).await;
+        eventually("a failed redial", || dialer.dial_state(&addr).unwrap().failures > 0).await;
+        assert!(dialer.dial_state(&addr).unwrap().last_error.is_some());
+        dialer.shutdown();
+    }
+}
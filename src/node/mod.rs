@@ -1,70 +1,510 @@
-use std::sync::Arc;
-use tokio::io::{AsyncWrite, AsyncWriteExt, AsyncRead};
-use tokio::net::{TcpStream, TcpListener};
-use tokio::io::AsyncReadExt;
-use crate::msg::NetworkMessage;
-use crate::{StreamingDAG, DAG, Federation};
-use tokio_util::codec::{
-    Framed, LengthDelimitedCodec,
+pub mod config;
+pub mod conn;
+pub mod gossip;
+pub mod manager;
+pub mod peer;
+pub mod sync;
+pub mod validation;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::net::TcpListener;
+use crate::federation::org::{
+    user::{KeyError, KeyPair},
+    OrgId,
 };
+use crate::msg::{Envelope, ErrorCode, MsgId, NetworkMessage, PROTOCOL_VERSION};
+use crate::store::{SnapshotConfig, WalConfig};
+use crate::federation::genesis::{hash_hex, Genesis};
+use crate::validate::{LocalVoter, ValidationOutcome, Vote, Voter, DEFAULT_VOTE_TIMEOUT};
+use crate::{Federation, StreamingDAG, Transaction};
+
+pub use config::{ConfigError, NodeConfig, Overrides};
+pub use conn::Connection;
+pub use gossip::{Gossip, GossipConfig, SeenSet};
+pub use manager::{DialState, PeerError, PeerHealth, PeerManager, PeerManagerConfig};
+pub use peer::{Direction, HandshakeError, Hello, NodeId, Peer};
+pub use sync::{Approvals, OrphanPool, SyncReport, Syncer};
+pub use validation::RemoteVoter;
+
+/// Where a node listens unless configured otherwise.
+pub static DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8787";
+
+/// How long a new connection has to complete the handshake.
+pub static HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A node serving the DAG to its peers on behalf of one org.
+pub struct Node {
+    pub id: NodeId,
+    /// What the node proves its id with during handshakes.
+    key: KeyPair,
+    pub org_id: OrgId,
+    pub dag: Arc<StreamingDAG>,
+    pub fed: Arc<Federation>,
+    /// Peers with an open, handshaken connection.
+    pub peers: Mutex<HashMap<NodeId, Peer>>,
+    /// Validators this node runs, voting on `ValidationReq`s from peers.
+    pub voters: Vec<Arc<dyn Voter>>,
+    /// How long each of `voters` is given to vote.
+    pub vote_timeout: Duration,
+    /// Votes that approved the transactions confirmed lately, sent along
+    /// with them to peers that sync.
+    pub approvals: Mutex<Approvals>,
+}
+
+impl Node {
+    /// A node with a fresh key, and so a fresh id.
+    pub fn new(org_id: OrgId, dag: Arc<StreamingDAG>) -> Self {
+        let key = KeyPair::generate();
+        Self {
+            id: NodeId::of(&key.public()),
+            key,
+            org_id,
+            fed: Arc::clone(&dag.federation),
+            dag,
+            peers: Mutex::new(HashMap::new()),
+            voters: Vec::new(),
+            vote_timeout: DEFAULT_VOTE_TIMEOUT,
+            approvals: Mutex::new(Approvals::new(sync::DEFAULT_APPROVAL_CAPACITY)),
+        }
+    }
+
+    /// Identify as the holder of `key`, e.g. to keep the same id across
+    /// restarts, or to prove the node's org with a validator's key.
+    pub fn with_key(self, key: KeyPair) -> Self {
+        Self {
+            id: NodeId::of(&key.public()),
+            key,
+            ..self
+        }
+    }
+
+    pub fn with_voters(self, voters: Vec<Arc<dyn Voter>>) -> Self {
+        Self { voters, ..self }
+    }
+
+    pub fn with_vote_timeout(self, vote_timeout: Duration) -> Self {
+        Self { vote_timeout, ..self }
+    }
+
+    /// A `Hello` for this node to introduce itself with, with a fresh
+    /// nonce.
+    pub fn hello(&self) -> Hello {
+        Hello::new(self.key.public(), self.fed.id.clone(), self.fed.genesis, self.org_id.clone())
+    }
+
+    /// Check a peer's `Hello`: it must speak this protocol version, belong
+    /// to this federation started from the same genesis, act for one of its
+    /// orgs, have the id its key gives, and not be this node. A peer using
+    /// a validator's key must act for that validator's org. Whether it
+    /// holds the key is checked with its `HelloProof`.
+    pub fn check_hello(&self, hello: &Hello) -> Result<(), HandshakeError> {
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(hello.protocol_version));
+        }
+        if hello.fed_id.id != self.fed.id.id {
+            return Err(HandshakeError::ForeignFederation(Box::new(hello.fed_id.clone())));
+        }
+        if hello.genesis != self.fed.genesis {
+            return Err(HandshakeError::GenesisMismatch(hello.genesis));
+        }
+        if !self.fed.orgs.iter().any(|o| o.id == hello.org_id) {
+            return Err(HandshakeError::UnknownOrg(Box::new(hello.org_id.clone())));
+        }
+        if hello.node_id != NodeId::of(&hello.key) {
+            return Err(HandshakeError::ForeignId(hello.node_id));
+        }
+        if let Some(v) = self.fed.validators.by_key(&hello.key) {
+            if v.org_id != hello.org_id {
+                return Err(HandshakeError::WrongOrg(Box::new(v.org_id.clone())));
+            }
+        }
+        if hello.node_id == self.id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(())
+    }
+
+    /// Open the handshake on an outbound connection: send our `Hello`,
+    /// check the one that comes back, then trade proofs that each side
+    /// holds its key, ours first.
+    pub async fn handshake(&self, conn: &mut Connection) -> anyhow::Result<Peer> {
+        let addr = conn.peer_addr()?;
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let ours = self.hello();
+            let hello = match conn.request(NetworkMessage::Hello(ours.clone())).await? {
+                NetworkMessage::Hello(hello) => hello,
+                NetworkMessage::Error { code, message } => anyhow::bail!("Handshake refused: {}: {}", code, message),
+                _ => return Err(HandshakeError::Expected("Hello").into()),
+            };
+            self.check_hello(&hello)?;
+            let proof = ours.prove(&self.key, &hello.nonce);
+            match conn.request(NetworkMessage::HelloProof(proof)).await? {
+                NetworkMessage::HelloProof(sig) => hello.verify(&ours.nonce, &sig)?,
+                NetworkMessage::Error { code, message } => anyhow::bail!("Handshake refused: {}: {}", code, message),
+                _ => return Err(HandshakeError::Expected("HelloProof").into()),
+            }
+            Ok(Peer::new(hello, addr, Direction::Outbound))
+        })
+        .await?
+    }
 
-pub struct Node;
-
-pub(crate) async fn conn_handler(
-    stream: TcpStream, 
-    str_dag: Arc<StreamingDAG>,
-    fed: Arc<Federation>,
-) {
-    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
-    let mut buf =  [0; 1024];
-    while let tx_bytes = framed.read_buffer() {
-        let m: NetworkMessage = bincode::deserialize(&tx_bytes).unwrap();
-        match m {
-            NetworkMessage::Tx(t) => {
-                let sender_id = t.send.clone().id;
-                let sender_org = sender_id.org_id;
-                let sender_fed = sender_org.clone().fed_id;
-
-                let receiver_id = t.recv.clone().id;
-                let receiver_org = receiver_id.org_id;
-                let receiver_fed = receiver_org.fed_id;
-
-                if sender_fed == receiver_fed {
-                    str_dag.push_tx(t, sender_org).await;
-                } else {
-                    println!("Transaction between federations not supported yet.");
-                    continue;
+    /// Answer the handshake on an inbound connection: wait for the peer's
+    /// `Hello` and reply with ours, then wait for its proof and reply with
+    /// ours. An error is sent back instead at the first step that fails.
+    pub async fn accept_handshake(&self, conn: &mut Connection) -> anyhow::Result<Peer> {
+        let addr = conn.peer_addr()?;
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            let ours = self.hello();
+            let envelope = recv_handshake(conn).await?;
+            let hello = match envelope.body {
+                NetworkMessage::Hello(hello) => self.check_hello(&hello).map(|_| hello),
+                _ => Err(HandshakeError::Expected("Hello")),
+            };
+            let hello = refuse_on_error(conn, envelope.id, hello).await?;
+            conn.reply(envelope.id, NetworkMessage::Hello(ours.clone())).await?;
+            let envelope = recv_handshake(conn).await?;
+            let proven = match envelope.body {
+                NetworkMessage::HelloProof(sig) => hello.verify(&ours.nonce, &sig),
+                _ => Err(HandshakeError::Expected("HelloProof")),
+            };
+            refuse_on_error(conn, envelope.id, proven).await?;
+            let proof = ours.prove(&self.key, &hello.nonce);
+            conn.reply(envelope.id, NetworkMessage::HelloProof(proof)).await?;
+            Ok(Peer::new(hello, addr, Direction::Inbound))
+        })
+        .await?
+    }
+
+    pub fn add_peer(&self, peer: Peer) {
+        log::info!("Peer {} ({}) connected from {}", peer.node_id, peer.org_id.handle, peer.addr);
+        self.peers.lock().unwrap().insert(peer.node_id, peer);
+    }
+
+    pub fn remove_peer(&self, id: &NodeId) -> Option<Peer> {
+        self.peers.lock().unwrap().remove(id)
+    }
+
+    pub fn peer(&self, id: &NodeId) -> Option<Peer> {
+        self.peers.lock().unwrap().get(id).cloned()
+    }
+
+    /// Votes on `tx` from every validator this node runs, cast at once.
+    /// Those that fail or take longer than `vote_timeout` are left out.
+    pub async fn votes(&self, tx: &Transaction) -> Vec<Vote> {
+        let votes = self.voters.iter().map(|voter| async move {
+            match tokio::time::timeout(self.vote_timeout, voter.vote(tx)).await {
+                Ok(Ok(vote)) => Some(vote),
+                Ok(Err(e)) => {
+                    log::warn!("Validator {} did not vote on {}: {}", voter.validator(), tx.id, e);
+                    None
                 }
-            },
-            NetworkMessage::ValidationReq(transaction) => {
-                let validation = fed.validate_tx_distributed(&transaction).await;
-                let resp = NetworkMessage::ValidationRes(transaction, validation);
-                let respbytes = bincode::serialize(&resp).unwrap();
-                // framed.write_buffer_mut().join(&respbytes.into());
-            },
-            NetworkMessage::ValidationRes(t, validation) => {
-                if validation {
-                    str_dag.confirm_tx(&t).await;
-                } else {
-                    // Handle invalid tx
+                Err(_) => {
+                    log::warn!("Validator {} did not vote on {} in time", voter.validator(), tx.id);
+                    None
                 }
             }
+        });
+        futures::future::join_all(votes).await.into_iter().flatten().collect()
+    }
+
+    /// Count `votes` on `tx` against the federation's validators. Votes
+    /// that do not verify are dropped.
+    pub fn tally(&self, tx: &Transaction, votes: Vec<Vote>) -> ValidationOutcome {
+        let mut outcome = ValidationOutcome::new(tx.id, self.fed.genesis, &self.fed.validators);
+        for vote in votes {
+            if let Err(e) = outcome.record(&self.fed.validators, vote) {
+                log::warn!("Ignoring vote on {}: {}", tx.id, e);
+            }
+        }
+        outcome
+    }
+
+    /// `tally` the `votes` on `tx`, and confirm `tx` if they approve it and
+    /// it is not in the DAG yet. The approving votes are kept in
+    /// `approvals`.
+    pub async fn confirm_approved(&self, tx: &Transaction, votes: Vec<Vote>) -> anyhow::Result<ValidationOutcome> {
+        if !tx.verify_id() {
+            anyhow::bail!("Transaction {}: id does not match contents", tx.id);
         }
-        let tx: crate::Transaction = bincode::deserialize(tx_bytes).unwrap();
-        let org_id = tx.clone().send.id.org_id;
-        let (send, recv, amt) = (tx.clone().send, tx.clone().recv, tx.clone().amt);
-        str_dag.push_tx(tx, org_id).await;
+        let outcome = self.tally(tx, votes);
+        if outcome.is_approved() {
+            if !self.dag.dag.contains(&tx.id) {
+                self.dag.confirm_tx(tx, &tx.send.id.org_id).await?;
+            }
+            self.approvals.lock().unwrap().insert(tx.id, outcome.approved.clone());
+        }
+        Ok(outcome)
+    }
+}
+
+async fn recv_handshake(conn: &mut Connection) -> anyhow::Result<Envelope> {
+    match conn.recv().await? {
+        Some(envelope) => Ok(envelope),
+        None => anyhow::bail!("Connection closed during handshake"),
+    }
+}
+
+/// Pass a handshake step's result on, telling the peer why if it failed.
+async fn refuse_on_error<T>(
+    conn: &mut Connection,
+    to: MsgId,
+    checked: Result<T, HandshakeError>,
+) -> anyhow::Result<T> {
+    match checked {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            let body = NetworkMessage::Error {
+                code: e.code(),
+                message: e.to_string(),
+            };
+            conn.reply(to, body).await?;
+            Err(e.into())
+        }
+    }
+}
+
+/// Act on one message, returning the reply to send back, if any.
+pub(crate) async fn handle(envelope: Envelope, node: &Node) -> Option<NetworkMessage> {
+    let str_dag = &node.dag;
+    match envelope.body {
+        NetworkMessage::Hello(_) | NetworkMessage::HelloProof(_) => Some(NetworkMessage::Error {
+            code: ErrorCode::Unsupported,
+            message: "Handshake already complete".to_string(),
+        }),
+        NetworkMessage::Tx(t) => {
+            let sender_org = t.send.id.org_id.clone();
+            if sender_org.fed_id != t.recv.id.org_id.fed_id {
+                return Some(NetworkMessage::Error {
+                    code: ErrorCode::Unsupported,
+                    message: "Transaction between federations not supported yet.".to_string(),
+                });
+            }
+            // Only the validators this node runs vote here; the peer
+            // manager puts transactions to the whole set.
+            let approved = match str_dag.check_tx(&t, &sender_org) {
+                Ok(()) => node.confirm_approved(&t, node.votes(&t).await).await,
+                Err(e) => Err(e),
+            };
+            let message = match approved.map(|outcome| outcome.verdict()) {
+                Ok(Ok(())) => return Some(NetworkMessage::TxAccepted(t.id)),
+                Ok(Err(reasons)) => {
+                    let reasons: Vec<String> = reasons.iter().map(|r| r.to_string()).collect();
+                    format!("Transaction {} not approved: {}", t.id, reasons.join("; "))
+                }
+                Err(e) => e.to_string(),
+            };
+            Some(NetworkMessage::Error {
+                code: ErrorCode::Rejected,
+                message,
+            })
+        },
+        NetworkMessage::ValidationReq(transaction) => {
+            let votes = node.votes(&transaction).await;
+            Some(NetworkMessage::ValidationRes(transaction, votes))
+        },
+        NetworkMessage::ValidationRes(t, votes) => {
+            match node.confirm_approved(&t, votes).await {
+                Ok(outcome) => {
+                    if let Err(reasons) = outcome.verdict() {
+                        let reasons: Vec<String> = reasons.iter().map(|r| r.to_string()).collect();
+                        log::info!("Transaction {} not approved: {}", t.id, reasons.join("; "));
+                    }
+                }
+                Err(e) => log::warn!("{}", e),
+            }
+            None
+        },
+        NetworkMessage::Inv(_)
+        | NetworkMessage::GetTx(_)
+        | NetworkMessage::GetTips
+        | NetworkMessage::GetAncestors { .. }
+        | NetworkMessage::GetRange { .. }
+        | NetworkMessage::GetSnapshot
+        | NetworkMessage::GetSnapshotPage { .. } => Some(NetworkMessage::Error {
+            code: ErrorCode::Unsupported,
+            message: "Gossip and sync are handled by the peer manager".to_string(),
+        }),
+        NetworkMessage::Ping => Some(NetworkMessage::Pong),
+        NetworkMessage::TxAccepted(_)
+        | NetworkMessage::Txs(_)
+        | NetworkMessage::Ancestors(_)
+        | NetworkMessage::Tips { .. }
+        | NetworkMessage::Range { .. }
+        | NetworkMessage::SnapshotHead(_)
+        | NetworkMessage::SnapshotPage(_)
+        | NetworkMessage::Pong => None,
+        NetworkMessage::Error { code, message } => {
+            log::warn!("Peer error in reply to {:?}: {}: {}", envelope.reply_to, code, message);
+            None
+        },
     }
 }
 
-pub async fn server_start(stream_dag: Arc<StreamingDAG>, fed: Arc<Federation>) {
-    let listener = TcpListener::bind("127.0.0.1:8787").await.unwrap();
-    loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        let strdagc = Arc::clone(&stream_dag);
-        let fedc = Arc::clone(&fed);
-        tokio::spawn(async move {
-            conn_handler(stream, strdagc, fedc).await;
+pub async fn server_start(manager: Arc<PeerManager>, addr: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Node {} listening on {}", manager.node.id, listener.local_addr()?);
+    manager.start();
+    manager.serve(listener).await
+}
+
+/// Open the DAG in `config.data_dir`, then serve it on `config.listen` and
+/// keep connected to `config.peers` until the listener fails.
+pub async fn start(config: NodeConfig) -> anyhow::Result<()> {
+    config.validate()?;
+    let genesis = match &config.genesis {
+        Some(path) => Genesis::load(path)?,
+        None => Genesis::standalone(&config.org),
+    };
+    let fed = genesis.federation()?;
+    let org_id = match fed.orgs.iter().find(|o| o.id.handle == config.org) {
+        Some(org) => org.id.clone(),
+        None => return Err(ConfigError::new("org", format!("{} is not an org of the federation", config.org)).into()),
+    };
+    log::info!("Federation {} from genesis {}", fed.id.handle, hash_hex(&fed.genesis));
+    let dag = StreamingDAG::new_with_federation(config.window_size, fed)
+        .with_finality(genesis.params.finality())
+        .with_storage(&config.data_dir, WalConfig::default(), SnapshotConfig::default())?;
+    if genesis.mint(&dag)? {
+        log::info!("Credited {} genesis balances", genesis.balances.len());
+    }
+    let dag = Arc::new(dag);
+    let mut voters: Vec<Arc<dyn Voter>> = Vec::new();
+    // A validator's node proves its org with the validator's key.
+    let mut validator_key = None;
+    if let Some(path) = &config.validator_key {
+        let key: KeyPair = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::new("validator_key", e.to_string()))?
+            .parse()
+            .map_err(|e: KeyError| ConfigError::new("validator_key", e.to_string()))?;
+        let validator = match dag.federation.validators.by_key(&key.public()) {
+            Some(v) if v.org_id == org_id => v,
+            _ => {
+                return Err(ConfigError::new(
+                    "validator_key",
+                    format!("{} is not the key of a validator of {}", key.public(), org_id.handle),
+                )
+                .into())
+            }
+        };
+        log::info!("Voting as validator {}", validator.name());
+        voters.push(Arc::new(LocalVoter::new(validator.name(), org_id.clone(), key.clone(), Arc::clone(&dag))));
+        validator_key = Some(key);
+    }
+    let mut node = Node::new(org_id, dag);
+    if let Some(key) = validator_key {
+        node = node.with_key(key);
+    }
+    let node = node.with_voters(voters);
+    let manager = PeerManager::new(Arc::new(node), PeerManagerConfig::default().with_peers(config.peers.clone()));
+    server_start(manager, config.listen_addr()?).await
+}
+
+/// Dial `addr` and complete the handshake, returning the connection and
+/// the peer on the other end.
+pub async fn connect(node: &Node, addr: SocketAddr) -> anyhow::Result<(Connection, Peer)> {
+    let mut conn = Connection::connect(addr).await?;
+    let peer = node.handshake(&mut conn).await?;
+    Ok((conn, peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> Node {
+        let fed = Genesis::standalone("org").federation().unwrap();
+        let org_id = fed.orgs[0].id.clone();
+        Node::new(org_id, Arc::new(StreamingDAG::new_with_federation(10, fed)))
+    }
+
+    /// A listening node, and the outcome of its handshake with whoever
+    /// connects first.
+    async fn listen() -> (SocketAddr, tokio::task::JoinHandle<anyhow::Result<Peer>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            node().accept_handshake(&mut Connection::new(stream)).await
         });
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn handshakes_prove_node_ids() {
+        let (addr, accepted) = listen().await;
+        let dialer = node();
+        let (_conn, peer) = connect(&dialer, addr).await.unwrap();
+        let inbound = accepted.await.unwrap().unwrap();
+        assert_eq!(inbound.node_id, dialer.id);
+        assert_eq!(inbound.key, dialer.key.public());
+        assert_eq!(peer.node_id, NodeId::of(&peer.key));
+    }
+
+    #[test]
+    fn checks_hellos_against_the_federation() {
+        let node = node();
+        let peer = Node::new(node.org_id.clone(), Arc::clone(&node.dag));
+        assert_eq!(node.check_hello(&peer.hello()), Ok(()));
+        let hello = || peer.hello();
+        let newer = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..hello()
+        };
+        assert_eq!(node.check_hello(&newer), Err(HandshakeError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+        let foreign = Genesis::standalone("elsewhere").federation().unwrap();
+        let abroad = Hello {
+            fed_id: foreign.id.clone(),
+            ..hello()
+        };
+        assert_eq!(node.check_hello(&abroad), Err(HandshakeError::ForeignFederation(Box::new(foreign.id.clone()))));
+        let restarted = Hello {
+            genesis: foreign.genesis,
+            ..hello()
+        };
+        assert_eq!(node.check_hello(&restarted), Err(HandshakeError::GenesisMismatch(foreign.genesis)));
+        let unknown = Hello {
+            org_id: foreign.orgs[0].id.clone(),
+            ..hello()
+        };
+        assert_eq!(node.check_hello(&unknown), Err(HandshakeError::UnknownOrg(Box::new(foreign.orgs[0].id.clone()))));
+        assert_eq!(node.check_hello(&node.hello()), Err(HandshakeError::SelfConnection));
+    }
+
+    #[tokio::test]
+    async fn refuses_claimed_ids() {
+        let (addr, accepted) = listen().await;
+        let (victim, dialer) = (node(), node());
+        let mut conn = Connection::connect(addr).await.unwrap();
+        let hello = Hello {
+            node_id: victim.id,
+            ..dialer.hello()
+        };
+        let resp = conn.request(NetworkMessage::Hello(hello)).await.unwrap();
+        assert!(matches!(resp, NetworkMessage::Error { code: ErrorCode::Rejected, .. }));
+        let err = accepted.await.unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&HandshakeError::ForeignId(victim.id)));
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_it_cannot_prove() {
+        let (addr, accepted) = listen().await;
+        let (victim, dialer) = (node(), node());
+        let mut conn = Connection::connect(addr).await.unwrap();
+        // The victim's key and id, but signed with another key.
+        let ours = victim.hello();
+        let theirs = match conn.request(NetworkMessage::Hello(ours.clone())).await.unwrap() {
+            NetworkMessage::Hello(hello) => hello,
+            other => panic!("expected a Hello, got {:?}", other),
+        };
+        let proof = ours.prove(&dialer.key, &theirs.nonce);
+        let resp = conn.request(NetworkMessage::HelloProof(proof)).await.unwrap();
+        assert!(matches!(resp, NetworkMessage::Error { .. }));
+        let err = accepted.await.unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&HandshakeError::BadProof));
     }
 }
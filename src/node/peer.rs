@@ -0,0 +1,267 @@
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeSet,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::federation::{
+    genesis::hash_hex,
+    id::FedId,
+    org::{
+        user::{KeyPair, PublicKey, Signature},
+        OrgId,
+    },
+};
+use crate::msg::{ErrorCode, PROTOCOL_VERSION};
+use crate::store::proof::Hash;
+
+/// Length in bytes of a node id.
+pub const NODE_ID_LEN: usize = 16;
+/// Length in bytes of the challenge in a `Hello`.
+pub const NONCE_LEN: usize = 32;
+
+/// Handles `ValidationReq` messages.
+pub static FEATURE_VALIDATION: &str = "validation";
+
+/// Understands `Inv` and `GetTx`, so large transactions can be announced
+/// instead of pushed.
+pub static FEATURE_INV: &str = "inv";
+
+/// Features this build advertises in its `Hello`.
+pub static DEFAULT_FEATURES: &[&str] = &[FEATURE_VALIDATION, FEATURE_INV];
+
+/// Identifies a running node, so the same node is recognised whichever
+/// address it connects from. Derived from the node's key, which it proves
+/// it holds during the handshake.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub [u8; NODE_ID_LEN]);
+
+impl NodeId {
+    /// The id of the node holding `key`: the start of its SHA-256 hash.
+    pub fn of(key: &PublicKey) -> NodeId {
+        let hash = Sha256::digest(key.to_bytes());
+        let mut id = [0; NODE_ID_LEN];
+        id.copy_from_slice(&hash[..NODE_ID_LEN]);
+        NodeId(id)
+    }
+}
+
+/// Lowercase hex, the same form `FromStr` reads back.
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&HEXLOWER.encode(&self.0))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = HEXLOWER_PERMISSIVE.decode(s.as_bytes())?;
+        let id = bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("node id must be {} hex characters, got {}", NODE_ID_LEN * 2, s))?;
+        Ok(NodeId(id))
+    }
+}
+
+/// The first message each side sends on a new connection, saying who it is
+/// and what it can do. Each side then signs the other's `nonce` together
+/// with its own `Hello`, proving it holds the key its node id is derived
+/// from and that it sent what it claims. Nothing else is handled until
+/// both sides have accepted the other's.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub node_id: NodeId,
+    /// The node's key. If it is a validator's, the validator must belong
+    /// to `org_id`; otherwise the org is taken on the node's word.
+    pub key: PublicKey,
+    /// Fresh for every connection, for the peer to sign.
+    pub nonce: [u8; NONCE_LEN],
+    pub fed_id: FedId,
+    /// Hash of the genesis the node's federation was built from.
+    pub genesis: Hash,
+    /// The org the node acts for.
+    pub org_id: OrgId,
+    /// Names of optional features the node supports. Unknown names are
+    /// ignored, so new features can be added without a version bump.
+    pub features: BTreeSet<String>,
+}
+
+#[derive(Serialize)]
+struct HelloProof<'a> {
+    hello: &'a Hello,
+    challenge: &'a [u8; NONCE_LEN],
+}
+
+impl Hello {
+    pub fn new(key: PublicKey, fed_id: FedId, genesis: Hash, org_id: OrgId) -> Self {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            node_id: NodeId::of(&key),
+            key,
+            nonce,
+            fed_id,
+            genesis,
+            org_id,
+            features: DEFAULT_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    fn proof_bytes(&self, challenge: &[u8; NONCE_LEN]) -> Vec<u8> {
+        bincode::serialize(&HelloProof { hello: self, challenge }).expect("hello is always serializable")
+    }
+
+    /// Sign this `Hello` together with the peer's `challenge`.
+    pub fn prove(&self, key: &KeyPair, challenge: &[u8; NONCE_LEN]) -> Signature {
+        key.sign(&self.proof_bytes(challenge))
+    }
+
+    /// Check `sig` is this `Hello`'s key signing it together with
+    /// `challenge`, the nonce we sent.
+    pub fn verify(&self, challenge: &[u8; NONCE_LEN], sig: &Signature) -> Result<(), HandshakeError> {
+        self.key
+            .verify(&self.proof_bytes(challenge), sig)
+            .map_err(|_| HandshakeError::BadProof)
+    }
+}
+
+/// Why a peer's `Hello` was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    UnsupportedVersion(u16),
+    /// The peer belongs to a different federation.
+    ForeignFederation(Box<FedId>),
+    /// The peer's federation was started from a different genesis.
+    GenesisMismatch(Hash),
+    /// The peer acts for an org that is not part of this federation.
+    UnknownOrg(Box<OrgId>),
+    /// The peer is this node.
+    SelfConnection,
+    /// The peer's node id is not the one its key gives.
+    ForeignId(NodeId),
+    /// The peer's key is a validator's of a different org than it claims.
+    WrongOrg(Box<OrgId>),
+    /// The peer's signature over our nonce did not verify.
+    BadProof,
+    /// The first message was not a `Hello`, or the second not a
+    /// `HelloProof`.
+    Expected(&'static str),
+}
+
+impl HandshakeError {
+    /// The error code to send back to the peer.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            Self::Expected(_) => ErrorCode::HandshakeRequired,
+            _ => ErrorCode::Rejected,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(v) => {
+                write!(f, "Protocol version {} not supported, this node speaks {}", v, PROTOCOL_VERSION)
+            }
+            Self::ForeignFederation(fed) => write!(f, "Peer belongs to foreign federation {}", fed.to_string()),
+            Self::GenesisMismatch(hash) => write!(f, "Peer was started from a different genesis {}", hash_hex(hash)),
+            Self::UnknownOrg(org) => write!(f, "Peer org {} is not part of this federation", org.to_string()),
+            Self::SelfConnection => write!(f, "Peer is this node"),
+            Self::ForeignId(id) => write!(f, "Peer id {} does not match its key", id),
+            Self::WrongOrg(org) => write!(f, "Peer key belongs to a validator of {}", org.to_string()),
+            Self::BadProof => write!(f, "Peer did not prove it holds its key"),
+            Self::Expected(what) => write!(f, "Expected {}", what),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Which side opened a connection.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// What the node knows about a peer it has completed a handshake with.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub node_id: NodeId,
+    pub key: PublicKey,
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    pub protocol_version: u16,
+    pub fed_id: FedId,
+    pub org_id: OrgId,
+    pub features: BTreeSet<String>,
+    pub connected_at: SystemTime,
+}
+
+impl Peer {
+    pub fn new(hello: Hello, addr: SocketAddr, direction: Direction) -> Self {
+        Self {
+            node_id: hello.node_id,
+            key: hello.key,
+            addr,
+            direction,
+            protocol_version: hello.protocol_version,
+            fed_id: hello.fed_id,
+            org_id: hello.org_id,
+            features: hello.features,
+            connected_at: SystemTime::now(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_ids_read_back_what_they_print() {
+        let id = NodeId::of(&KeyPair::generate().public());
+        assert_eq!(id.to_string().parse::<NodeId>().unwrap(), id);
+        assert_eq!(id.to_string().to_uppercase().parse::<NodeId>().unwrap(), id);
+        assert!("abcd".parse::<NodeId>().is_err());
+    }
+
+    #[test]
+    fn proofs_cover_the_hello_and_the_challenge() {
+        let key = KeyPair::generate();
+        let org_id = OrgId::new("org");
+        let hello = Hello::new(key.public(), org_id.fed_id.clone(), Hash::default(), org_id);
+        let challenge = [7; NONCE_LEN];
+        let proof = hello.prove(&key, &challenge);
+        assert_eq!(hello.verify(&challenge, &proof), Ok(()));
+        assert_eq!(hello.verify(&[8; NONCE_LEN], &proof), Err(HandshakeError::BadProof));
+        let altered = Hello {
+            features: BTreeSet::new(),
+            ..hello.clone()
+        };
+        assert_eq!(altered.verify(&challenge, &proof), Err(HandshakeError::BadProof));
+        let forged = hello.prove(&KeyPair::generate(), &challenge);
+        assert_eq!(hello.verify(&challenge, &forged), Err(HandshakeError::BadProof));
+    }
+}
@@ -0,0 +1,950 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use super::{gossip::SeenSet, manager::PeerManager, NodeId};
+use crate::consensus::{Commit, ConsensusError};
+use crate::msg::{ErrorCode, NetworkMessage, SyncedTx};
+use crate::store::dag::{DAGNode, MAX_MIN_MSG_SIZE};
+use crate::store::{proof::Hash, LedgerEntry, Snapshot, SnapshotHead, SnapshotPage, SnapshotPart};
+use crate::validate::Vote;
+use crate::{StreamingDAG, Transaction, TxId};
+
+/// Most transactions asked for in one `GetRange` or `GetAncestors`.
+pub static MAX_SYNC_BATCH: u32 = 500;
+/// Encoded size a batch of transactions sent in reply is kept under,
+/// leaving room for the envelope around it.
+pub static MAX_SYNC_BYTES: usize = MAX_MIN_MSG_SIZE / 2;
+/// Most items of a snapshot asked for in one `GetSnapshotPage`.
+pub static MAX_SNAPSHOT_PAGE: u32 = 10_000;
+/// Requests one sync may make before giving up.
+pub static MAX_SYNC_ROUNDS: usize = 1000;
+/// Transactions held waiting for their parents.
+pub static DEFAULT_ORPHAN_CAPACITY: usize = 10_000;
+/// Transactions whose approving votes are kept to send to syncing peers.
+pub static DEFAULT_APPROVAL_CAPACITY: usize = 100_000;
+/// Ids a commit vouches for that are remembered until they arrive.
+pub static DEFAULT_VOUCHED_CAPACITY: usize = 100_000;
+
+/// The votes that approved the transactions confirmed lately. The oldest
+/// are forgotten once full; by then a commit should cover them.
+#[derive(Debug, Default)]
+pub struct Approvals {
+    votes: HashMap<TxId, Vec<Vote>>,
+    order: VecDeque<TxId>,
+    capacity: usize,
+}
+
+impl Approvals {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.votes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.votes.is_empty()
+    }
+
+    /// Keep `votes` as what approved `id`, in place of any kept before.
+    pub fn insert(&mut self, id: TxId, votes: Vec<Vote>) {
+        if self.votes.insert(id, votes).is_some() {
+            return;
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.votes.remove(&old);
+            }
+        }
+    }
+
+    /// The votes that approved `id`, or none if they are not kept.
+    pub fn get(&self, id: &TxId) -> Vec<Vote> {
+        self.votes.get(id).cloned().unwrap_or_default()
+    }
+}
+
+/// Transactions received before their parents, held until the parents
+/// arrive. The oldest are dropped once full.
+#[derive(Debug, Default)]
+pub struct OrphanPool {
+    txs: HashMap<TxId, SyncedTx>,
+    /// Orphans waiting on each missing parent.
+    waiting: HashMap<TxId, HashSet<TxId>>,
+    order: VecDeque<TxId>,
+    capacity: usize,
+}
+
+impl OrphanPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn contains(&self, id: &TxId) -> bool {
+        self.txs.contains_key(id)
+    }
+
+    /// Hold `tx` until every id in `missing` has arrived.
+    pub fn add(&mut self, tx: SyncedTx, missing: &[TxId]) {
+        let id = tx.tx.id;
+        if self.txs.insert(id, tx).is_some() {
+            return;
+        }
+        for p in missing {
+            self.waiting.entry(*p).or_default().insert(id);
+        }
+        self.order.push_back(id);
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.remove(&old);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: &TxId) -> Option<SyncedTx> {
+        let tx = self.txs.remove(id)?;
+        for p in tx.tx.parents.iter() {
+            if let Some(set) = self.waiting.get_mut(p) {
+                set.remove(id);
+                if set.is_empty() {
+                    self.waiting.remove(p);
+                }
+            }
+        }
+        Some(tx)
+    }
+
+    /// Take the orphans that were waiting on `parent`, now that it has
+    /// arrived. They may still be missing other parents.
+    pub fn release(&mut self, parent: &TxId) -> Vec<SyncedTx> {
+        let ids = self.waiting.remove(parent).unwrap_or_default();
+        let mut txs: Vec<SyncedTx> = ids.iter().filter_map(|id| self.remove(id)).collect();
+        self.order.retain(|id| !ids.contains(id));
+        txs.sort_by_key(|tx| tx.tx.id);
+        txs
+    }
+
+    /// Parents some orphan is waiting on that are not orphans themselves,
+    /// i.e. the ones still to be fetched.
+    pub fn missing(&self) -> Vec<TxId> {
+        let mut ids: Vec<TxId> = self.waiting.keys().filter(|id| !self.txs.contains_key(id)).copied().collect();
+        ids.sort();
+        ids
+    }
+}
+
+/// The last snapshot offered to peers, taken apart so every page of it is
+/// cut from the same state.
+struct Exported {
+    checkpoint: Hash,
+    head: SnapshotHead,
+    entries: Vec<LedgerEntry>,
+    pruned: Vec<(TxId, u64)>,
+    nodes: Vec<DAGNode>,
+}
+
+impl Exported {
+    /// Up to `limit` items of `part` from the `from`th on, cut to fit in a
+    /// message.
+    fn page(&self, part: SnapshotPart, from: u64, limit: usize) -> SnapshotPage {
+        match part {
+            SnapshotPart::Ledger => SnapshotPage::Ledger(page(&self.entries, from, limit)),
+            SnapshotPart::Pruned => SnapshotPage::Pruned(page(&self.pruned, from, limit)),
+            SnapshotPart::Nodes => SnapshotPage::Nodes(page(&self.nodes, from, limit)),
+        }
+    }
+}
+
+/// What one sync with a peer achieved.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncReport {
+    /// Whether the peer's snapshot was installed first.
+    pub snapshot: bool,
+    pub inserted: usize,
+    pub rejected: usize,
+    /// Parents still missing when the sync gave up.
+    pub missing: usize,
+}
+
+/// Brings the local DAG up to date with peers', and answers their requests
+/// to do the same.
+///
+/// A node with an empty DAG starts from a peer's snapshot if the peer has
+/// pruned history or offers one; peers only offer one at a committed
+/// checkpoint, which the snapshot has to prove. It arrives in pages, the
+/// ledger's entries, pruned ids and transactions, and is only installed
+/// once whole and matching the commit's state root. What the peer's last
+/// commit covers is then fetched by walking back from its tips, the
+/// transactions above the local DAG's height by height, and any still
+/// missing by walking back from the peer's tips. Transactions whose
+/// parents have not arrived wait in an `OrphanPool`.
+///
+/// Nothing synced is confirmed on the peer's word alone: a transaction
+/// must either come with votes approving it from a quorum of the
+/// validators, or be one a verified commit's tips build on. The latter are
+/// still checked with `StreamingDAG::import_tx`.
+pub struct Syncer {
+    orphans: Mutex<OrphanPool>,
+    /// Ids a verified commit's tips build on, whether held yet or not.
+    vouched: Mutex<SeenSet>,
+    exported: Mutex<Option<Arc<Exported>>>,
+}
+
+impl Default for Syncer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Syncer {
+    pub fn new() -> Self {
+        Self {
+            orphans: Mutex::new(OrphanPool::new(DEFAULT_ORPHAN_CAPACITY)),
+            vouched: Mutex::new(SeenSet::new(DEFAULT_VOUCHED_CAPACITY)),
+            exported: Mutex::new(None),
+        }
+    }
+
+    /// The snapshot to offer peers, at `dag`'s last commit. It is taken
+    /// again only once a later checkpoint is committed.
+    fn export(&self, dag: &StreamingDAG) -> anyhow::Result<Arc<Exported>> {
+        let last = dag.checkpoint.lock().unwrap().commit.as_ref().map(|c| c.checkpoint.hash());
+        let mut exported = self.exported.lock().unwrap();
+        if let Some(e) = exported.as_ref().filter(|e| Some(e.checkpoint) == last) {
+            return Ok(e.clone());
+        }
+        let (head, entries, pruned, nodes) = match dag.export_snapshot()?.split() {
+            Some(parts) => parts,
+            None => anyhow::bail!("No checkpoint has been committed yet"),
+        };
+        let e = Arc::new(Exported {
+            checkpoint: head.commit.checkpoint.hash(),
+            head,
+            entries,
+            pruned,
+            nodes,
+        });
+        *exported = Some(e.clone());
+        Ok(e)
+    }
+
+    pub fn orphans(&self) -> usize {
+        self.orphans.lock().unwrap().len()
+    }
+
+    /// Answer a sync request, or return `None` for any other message.
+    pub fn on_request(&self, manager: &PeerManager, body: &NetworkMessage) -> Option<NetworkMessage> {
+        let streamdag = &manager.node.dag;
+        let dag = &streamdag.dag;
+        let reply = match body {
+            NetworkMessage::GetTips => NetworkMessage::Tips {
+                tips: dag.tips(),
+                max_height: dag.max_height(),
+                pruned: dag.has_pruned(),
+                commit: streamdag.checkpoint.lock().unwrap().commit.clone().map(Box::new),
+            },
+            NetworkMessage::GetAncestors { id, known, limit } => {
+                let known: HashSet<TxId> = known.iter().copied().collect();
+                let limit = (*limit).min(MAX_SYNC_BATCH) as usize;
+                let mut txs = with_votes(manager, dag.ancestry(id, &known, limit));
+                txs.truncate(fit(&txs));
+                NetworkMessage::Ancestors(txs)
+            }
+            NetworkMessage::GetRange { from, to, limit } => {
+                // Ranges are cut at height boundaries, so halve the limit
+                // until the reply fits.
+                let mut limit = (*limit).clamp(1, MAX_SYNC_BATCH) as usize;
+                loop {
+                    let (txs, next) = dag.range(*from, *to, limit);
+                    let txs = with_votes(manager, txs);
+                    if limit == 1 || fit(&txs) == txs.len() {
+                        break NetworkMessage::Range { txs, next };
+                    }
+                    limit /= 2;
+                }
+            }
+            NetworkMessage::GetSnapshot => match self.export(streamdag) {
+                Ok(exported) => NetworkMessage::SnapshotHead(Box::new(exported.head.clone())),
+                Err(e) => NetworkMessage::Error {
+                    code: ErrorCode::Unsupported,
+                    message: e.to_string(),
+                },
+            },
+            NetworkMessage::GetSnapshotPage {
+                checkpoint,
+                part,
+                from,
+                limit,
+            } => match self.export(streamdag) {
+                Ok(exported) if exported.checkpoint == *checkpoint => {
+                    let limit = (*limit).clamp(1, MAX_SNAPSHOT_PAGE) as usize;
+                    NetworkMessage::SnapshotPage(exported.page(*part, *from, limit))
+                }
+                _ => NetworkMessage::Error {
+                    code: ErrorCode::Rejected,
+                    message: "That snapshot is no longer offered".to_string(),
+                },
+            },
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    /// Take `commit`'s word for every transaction its tips build on, once
+    /// it verifies against the federation's validators.
+    pub fn vouch(&self, manager: &PeerManager, commit: &Commit) -> Result<(), ConsensusError> {
+        let fed = &manager.node.fed;
+        commit.verify(&fed.genesis, &fed.validators)?;
+        let mut vouched = self.vouched.lock().unwrap();
+        for id in commit.checkpoint.tips.iter() {
+            vouched.insert(*id);
+        }
+        Ok(())
+    }
+
+    /// Insert `txs`, parents first, holding back any whose parents are not
+    /// known yet and releasing orphans whose parents arrive. One a commit
+    /// vouches for is checked with `StreamingDAG::check_tx` before it is
+    /// confirmed; any other needs its votes to approve it.
+    pub async fn ingest(&self, manager: &PeerManager, txs: Vec<SyncedTx>, report: &mut SyncReport) {
+        let dag = &manager.node.dag;
+        {
+            // Parents come first, so a commit's word is passed down from
+            // the back.
+            let mut vouched = self.vouched.lock().unwrap();
+            for synced in txs.iter().rev() {
+                if vouched.contains(&synced.tx.id) && synced.tx.verify_id() {
+                    for p in synced.tx.parents.iter() {
+                        vouched.insert(*p);
+                    }
+                }
+            }
+        }
+        let mut queue: VecDeque<SyncedTx> = txs.into();
+        while let Some(synced) = queue.pop_front() {
+            let tx = &synced.tx;
+            if dag.dag.knows(&tx.id) || !tx.verify_id() {
+                continue;
+            }
+            let vouched = self.vouched.lock().unwrap().contains(&tx.id);
+            if !vouched && !manager.node.tally(tx, synced.votes.clone()).is_approved() {
+                report.rejected += 1;
+                log::warn!("Rejected synced transaction {}: not approved and no commit covers it", tx.id);
+                continue;
+            }
+            let missing: Vec<TxId> = tx.parents.iter().filter(|p| !dag.dag.knows(p)).copied().collect();
+            if !missing.is_empty() {
+                self.orphans.lock().unwrap().add(synced, &missing);
+                continue;
+            }
+            let confirmed = match vouched {
+                true => dag.import_tx(tx).await.map(|_| ()),
+                false => manager.node.confirm_approved(tx, synced.votes.clone()).await.map(|_| ()),
+            };
+            match confirmed {
+                Ok(()) => {
+                    report.inserted += 1;
+                    manager.gossip.mark_seen(tx.id);
+                    queue.extend(self.orphans.lock().unwrap().release(&tx.id));
+                }
+                Err(e) => {
+                    report.rejected += 1;
+                    log::warn!("Rejected synced transaction {}: {}", tx.id, e);
+                }
+            }
+        }
+    }
+
+    /// Fetch the `missing` parents of a transaction from `from`, along with
+    /// whatever they build on that the local DAG lacks.
+    pub async fn fetch_parents(&self, manager: &PeerManager, missing: &[TxId], from: NodeId) -> SyncReport {
+        let mut report = SyncReport::default();
+        self.fetch_ancestors(manager, &from, missing.to_vec(), &mut report).await;
+        report.missing = self.orphans.lock().unwrap().missing().len();
+        report
+    }
+
+    /// Walk back from `ids` on `peer` until nothing is missing, no progress
+    /// is made or the round limit is hit.
+    async fn fetch_ancestors(&self, manager: &PeerManager, peer: &NodeId, ids: Vec<TxId>, report: &mut SyncReport) {
+        let dag = &manager.node.dag.dag;
+        let mut targets = ids;
+        for _ in 0..MAX_SYNC_ROUNDS {
+            targets.retain(|id| !dag.knows(id) && !self.orphans.lock().unwrap().contains(id));
+            targets.extend(self.orphans.lock().unwrap().missing());
+            targets.sort();
+            targets.dedup();
+            let id = match targets.pop() {
+                Some(id) => id,
+                None => return,
+            };
+            let req = NetworkMessage::GetAncestors {
+                id,
+                known: dag.tips(),
+                limit: MAX_SYNC_BATCH,
+            };
+            let txs = match manager.request(peer, req).await {
+                Ok(NetworkMessage::Ancestors(txs)) => txs,
+                Ok(other) => {
+                    log::warn!("Unexpected reply to GetAncestors from {}: {:?}", peer, other);
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("Could not fetch ancestors of {}: {}", id, e);
+                    return;
+                }
+            };
+            let before = (report.inserted, self.orphans());
+            if txs.is_empty() {
+                // The peer does not have it either.
+                continue;
+            }
+            self.ingest(manager, txs, report).await;
+            if (report.inserted, self.orphans()) == before {
+                return;
+            }
+        }
+    }
+
+    /// Fetch every page of the snapshot `head` opens from `peer`.
+    async fn fetch_snapshot(
+        &self,
+        manager: &PeerManager,
+        peer: &NodeId,
+        head: SnapshotHead,
+    ) -> anyhow::Result<Snapshot> {
+        let checkpoint = head.commit.checkpoint.hash();
+        let (mut entries, mut pruned, mut nodes) = (Vec::new(), Vec::new(), Vec::new());
+        let mut rounds = 0;
+        for part in [SnapshotPart::Ledger, SnapshotPart::Pruned, SnapshotPart::Nodes] {
+            loop {
+                let (have, want) = match part {
+                    SnapshotPart::Ledger => (entries.len(), head.entries),
+                    SnapshotPart::Pruned => (pruned.len(), head.pruned),
+                    SnapshotPart::Nodes => (nodes.len(), head.nodes),
+                };
+                if have as u64 >= want {
+                    break;
+                }
+                rounds += 1;
+                if rounds > MAX_SYNC_ROUNDS {
+                    anyhow::bail!("Snapshot of {} took more than {} requests", peer, MAX_SYNC_ROUNDS);
+                }
+                let req = NetworkMessage::GetSnapshotPage {
+                    checkpoint,
+                    part,
+                    from: have as u64,
+                    limit: MAX_SNAPSHOT_PAGE,
+                };
+                let page = match manager.request(peer, req).await? {
+                    NetworkMessage::SnapshotPage(page) if page.part() == part && !page.is_empty() => page,
+                    other => anyhow::bail!("Unexpected reply to GetSnapshotPage: {:?}", other),
+                };
+                match page {
+                    SnapshotPage::Ledger(page) => entries.extend(page),
+                    SnapshotPage::Pruned(page) => pruned.extend(page),
+                    SnapshotPage::Nodes(page) => nodes.extend(page),
+                }
+            }
+        }
+        Ok(Snapshot::join(head, entries, pruned, nodes))
+    }
+
+    /// Catch up with `peer` until the local DAG holds everything the
+    /// peer's tips build on.
+    pub async fn sync_with(&self, manager: &PeerManager, peer: &NodeId) -> anyhow::Result<SyncReport> {
+        let streamdag = &manager.node.dag;
+        let dag = &streamdag.dag;
+        let mut report = SyncReport::default();
+        let (tips, max_height, pruned, commit) = match manager.request(peer, NetworkMessage::GetTips).await? {
+            NetworkMessage::Tips {
+                tips,
+                max_height,
+                pruned,
+                commit,
+            } => (tips, max_height, pruned, commit),
+            other => anyhow::bail!("Unexpected reply to GetTips: {:?}", other),
+        };
+        if tips.iter().all(|id| dag.knows(id)) {
+            return Ok(report);
+        }
+
+        if dag.is_empty() && !dag.has_pruned() {
+            match manager.request(peer, NetworkMessage::GetSnapshot).await? {
+                NetworkMessage::SnapshotHead(head) => {
+                    let snapshot = self.fetch_snapshot(manager, peer, *head).await?;
+                    streamdag.install_snapshot(snapshot)?;
+                    report.snapshot = true;
+                    for id in dag.tips() {
+                        manager.gossip.mark_seen(id);
+                    }
+                }
+                NetworkMessage::Error { message, .. } if !pruned => {
+                    log::info!("Peer {} sent no snapshot ({}); fetching by height", peer, message);
+                }
+                NetworkMessage::Error { message, .. } => {
+                    anyhow::bail!("Peer {} has pruned history and sent no snapshot: {}", peer, message)
+                }
+                other => anyhow::bail!("Unexpected reply to GetSnapshot: {:?}", other),
+            }
+        }
+
+        if let Some(commit) = commit {
+            match self.vouch(manager, &commit) {
+                Ok(()) => {
+                    self.fetch_ancestors(manager, peer, commit.checkpoint.tips.clone(), &mut report).await;
+                    let last = streamdag.checkpoint.lock().unwrap().commit.as_ref().map(|c| c.checkpoint.hash());
+                    if last != Some(commit.checkpoint.hash()) {
+                        if let Err(e) = streamdag.record_commit(*commit) {
+                            log::debug!("Could not record the commit of {}: {}", peer, e);
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Commit from {} does not verify: {}", peer, e),
+            }
+        }
+
+        if let Some(max_height) = max_height {
+            let mut from = dag.max_height().map_or(0, |h| h + 1);
+            let mut rounds = 0;
+            while from <= max_height && rounds < MAX_SYNC_ROUNDS {
+                rounds += 1;
+                let req = NetworkMessage::GetRange {
+                    from,
+                    to: max_height + 1,
+                    limit: MAX_SYNC_BATCH,
+                };
+                let (txs, next) = match manager.request(peer, req).await? {
+                    NetworkMessage::Range { txs, next } => (txs, next),
+                    other => anyhow::bail!("Unexpected reply to GetRange: {:?}", other),
+                };
+                self.ingest(manager, txs, &mut report).await;
+                match next {
+                    Some(next) if next > from => from = next,
+                    _ => break,
+                }
+            }
+        }
+
+        self.fetch_ancestors(manager, peer, tips, &mut report).await;
+        report.missing = self.orphans.lock().unwrap().missing().len();
+        Ok(report)
+    }
+}
+
+/// `txs` with whatever votes approved each, to send to a syncing peer.
+fn with_votes(manager: &PeerManager, txs: Vec<Transaction>) -> Vec<SyncedTx> {
+    let approvals = manager.node.approvals.lock().unwrap();
+    txs.into_iter()
+        .map(|tx| SyncedTx {
+            votes: approvals.get(&tx.id),
+            tx,
+        })
+        .collect()
+}
+
+/// Up to `limit` of `items` from the `from`th on, as many as fit in
+/// `MAX_SYNC_BYTES` but at least one so every page gets somewhere.
+fn page<T: Serialize + Clone>(items: &[T], from: u64, limit: usize) -> Vec<T> {
+    let items = usize::try_from(from).ok().and_then(|from| items.get(from..)).unwrap_or_default();
+    let items = &items[..items.len().min(limit)];
+    items[..fit(items).max(1).min(items.len())].to_vec()
+}
+
+/// How many of `txs`, from the front, fit in `MAX_SYNC_BYTES`. The batch
+/// ends before any transaction that cannot be encoded.
+fn fit<T: Serialize>(txs: &[T]) -> usize {
+    let mut total: usize = 0;
+    for (i, tx) in txs.iter().enumerate() {
+        total = total.saturating_add(bincode::serialized_size(tx).map_or(usize::MAX, |n| n as usize));
+        if total > MAX_SYNC_BYTES {
+            return i;
+        }
+    }
+    txs.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::genesis::Genesis;
+    use crate::federation::org::user::{KeyPair, OrgUser};
+    use crate::node::{manager::PeerManagerConfig, Node};
+    use crate::consensus::{Checkpoint, Commit, ConsensusVote, VoteKind};
+    use crate::store::{dag::DEFAULT_PARENTS, proof::Hash, SnapshotConfig, WalConfig};
+    use crate::{Amount, StreamingDAG};
+    use std::{path::Path, sync::Arc, time::Duration};
+    use tokio::net::TcpListener;
+
+    /// One org, with alice holding `key` and 100 TEST, bob, and one
+    /// validator, v0, signing with `validator`. Every transaction is final
+    /// at once.
+    fn genesis(key: &KeyPair, validator: &KeyPair) -> Genesis {
+        format!(
+            r#"
+            [federation]
+            handle = "test"
+
+            [params]
+            confirmation_threshold = 1
+            finality_threshold = 1
+            validator_weighted = false
+
+            [[orgs]]
+            handle = "org"
+            symbol = "TEST"
+            users = [{{ handle = "alice", key = "{}" }}, {{ handle = "bob" }}]
+
+            [[validators]]
+            name = "v0"
+            org = "org"
+            weight = 1
+            key = "{}"
+
+            [[balances]]
+            org = "org"
+            user = "alice"
+            symbol = "TEST"
+            amount = "100"
+            "#,
+            key.public(),
+            validator.public()
+        )
+        .parse()
+        .unwrap()
+    }
+
+    fn manager(genesis: &Genesis) -> Arc<PeerManager> {
+        manager_at(genesis, None)
+    }
+
+    /// A node's peer manager, keeping its DAG under `dir` if given, with
+    /// a snapshot only when asked for.
+    fn manager_at(genesis: &Genesis, dir: Option<&Path>) -> Arc<PeerManager> {
+        let fed = genesis.federation().unwrap();
+        let org_id = fed.orgs[0].id.clone();
+        let mut dag = StreamingDAG::new_with_federation(10, fed).with_finality(genesis.params.finality());
+        if let Some(dir) = dir {
+            let config = SnapshotConfig { every: 0, keep: 2 };
+            dag = dag.with_storage(dir, WalConfig::default(), config).unwrap();
+        }
+        genesis.mint(&dag).unwrap();
+        let config = PeerManagerConfig {
+            sync_on_connect: false,
+            ..Default::default()
+        };
+        PeerManager::new(Arc::new(Node::new(org_id, Arc::new(dag))), config)
+    }
+
+    fn user(manager: &PeerManager, handle: &str) -> OrgUser {
+        manager.node.fed.orgs[0].users.iter().find(|u| u.id.handle == handle).unwrap().clone()
+    }
+
+    /// `n` payments from alice to bob, each confirmed in `manager`'s DAG,
+    /// with v0's vote if given its key and unapproved otherwise.
+    async fn payments(manager: &PeerManager, key: &KeyPair, validator: Option<&KeyPair>, n: usize) -> Vec<Transaction> {
+        let dag = &manager.node.dag;
+        let (alice, bob) = (user(manager, "alice"), user(manager, "bob"));
+        let mut txs = Vec::new();
+        for _ in 0..n {
+            let nonce = dag.ledger.lock().unwrap().next_nonce(&alice.id);
+            let mut tx = Transaction::new(alice.clone(), bob.clone(), "TEST", Amount::from_whole(1, 2).unwrap(), nonce);
+            tx.attach(dag.select_tips(DEFAULT_PARENTS)).unwrap();
+            tx.sign(key);
+            match validator {
+                Some(validator) => {
+                    let vote = Vote::new(&dag.federation.genesis, "v0", tx.id, Vec::new(), validator);
+                    assert!(manager.node.confirm_approved(&tx, vec![vote]).await.unwrap().is_approved());
+                }
+                None => {
+                    dag.import_tx(&tx).await.unwrap();
+                }
+            }
+            txs.push(tx);
+        }
+        txs
+    }
+
+    /// A checkpoint of `manager`'s final tips at `height`, committed by v0.
+    fn commit(manager: &PeerManager, validator: &KeyPair, height: u64, parent: Hash) -> Commit {
+        let dag = &manager.node.dag;
+        let tips = dag.dag.final_tips();
+        let checkpoint = Checkpoint::new(height, parent, tips.clone(), dag.checkpoint_root(&tips).unwrap());
+        let genesis = &dag.federation.genesis;
+        let precommit =
+            ConsensusVote::new(genesis, VoteKind::Precommit, height, 0, Some(checkpoint.hash()), "v0", validator);
+        Commit {
+            round: 0,
+            checkpoint,
+            precommits: vec![precommit],
+        }
+    }
+
+    /// Serve `server` on a local port and connect `client` to it.
+    async fn connect(server: &Arc<PeerManager>, client: &Arc<PeerManager>) -> NodeId {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(Arc::clone(server).serve(listener));
+        client.dial(addr);
+        let id = server.node.id;
+        for _ in 0..200 {
+            if client.is_connected(&id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        id
+    }
+
+    /// `tx` as `manager` sends it to a syncing peer.
+    fn synced(manager: &PeerManager, tx: &Transaction) -> SyncedTx {
+        with_votes(manager, vec![tx.clone()]).remove(0)
+    }
+
+    fn orphan(tx: &Transaction) -> SyncedTx {
+        SyncedTx {
+            tx: tx.clone(),
+            votes: Vec::new(),
+        }
+    }
+
+    fn tx(contract: usize) -> Transaction {
+        let key = KeyPair::generate();
+        let genesis = genesis(&key, &KeyPair::generate());
+        let fed = genesis.federation().unwrap();
+        let users = &fed.orgs[0].users;
+        let mut tx = Transaction::new(users[0].clone(), users[1].clone(), "TEST", Amount::from_whole(1, 2).unwrap(), 0);
+        tx.contract = Some(vec![0; contract]);
+        tx
+    }
+
+    #[test]
+    fn orphans_are_released_once_every_parent_arrives() {
+        let (a, b) = (tx(0).id, tx(0).id);
+        let mut tx = self::tx(0);
+        tx.attach(vec![a, b]).unwrap();
+        let mut pool = OrphanPool::new(10);
+        pool.add(orphan(&tx), &[a, b]);
+        pool.add(orphan(&tx), &[a, b]);
+        assert_eq!(pool.len(), 1);
+        let mut missing = vec![a, b];
+        missing.sort();
+        assert_eq!(pool.missing(), missing);
+        // Released on the first parent; the caller holds it back again
+        // for the other.
+        assert_eq!(pool.release(&a).iter().map(|t| t.tx.id).collect::<Vec<_>>(), vec![tx.id]);
+        assert!(pool.is_empty());
+        assert!(pool.missing().is_empty());
+        assert!(pool.release(&b).is_empty());
+        pool.add(orphan(&tx), &[b]);
+        assert_eq!(pool.release(&b).len(), 1);
+        assert!(pool.order.is_empty());
+    }
+
+    #[test]
+    fn the_pool_drops_the_oldest_once_full() {
+        let parents: Vec<TxId> = (0..3).map(|_| tx(0).id).collect();
+        let orphans: Vec<Transaction> = parents
+            .iter()
+            .map(|p| {
+                let mut t = tx(0);
+                t.attach(vec![*p]).unwrap();
+                t
+            })
+            .collect();
+        let mut pool = OrphanPool::new(2);
+        for (t, p) in orphans.iter().zip(&parents) {
+            pool.add(orphan(t), &[*p]);
+        }
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&orphans[0].id));
+        assert!(!pool.missing().contains(&parents[0]));
+        // A released orphan no longer takes up room, so one added back
+        // later is not dropped in place of an older one.
+        assert_eq!(pool.release(&parents[1]).len(), 1);
+        pool.add(orphan(&orphans[1]), &[parents[1]]);
+        assert_eq!(pool.len(), 2);
+        assert!(pool.contains(&orphans[1].id) && pool.contains(&orphans[2].id));
+    }
+
+    #[test]
+    fn batches_fit_in_a_message() {
+        let small: Vec<Transaction> = (0..10).map(|_| tx(0)).collect();
+        assert_eq!(fit(&small), small.len());
+        assert_eq!(fit::<Transaction>(&[]), 0);
+        let big: Vec<Transaction> = (0..4).map(|_| tx(MAX_SYNC_BYTES / 3)).collect();
+        assert_eq!(fit(&big), 2);
+        assert_eq!(fit(&[tx(MAX_SYNC_BYTES)]), 0);
+    }
+
+    #[tokio::test]
+    async fn syncing_fetches_what_the_peer_has() {
+        let (key, validator) = (KeyPair::generate(), KeyPair::generate());
+        let genesis = genesis(&key, &validator);
+        let (server, client) = (manager(&genesis), manager(&genesis));
+        let txs = payments(&server, &key, Some(&validator), 5).await;
+
+        // Out of order, children wait for their parents.
+        let mut report = SyncReport::default();
+        let reversed: Vec<SyncedTx> = txs.iter().rev().map(|t| synced(&server, t)).collect();
+        client.sync.ingest(&client, reversed[..4].to_vec(), &mut report).await;
+        assert_eq!((report.inserted, client.sync.orphans()), (0, 4));
+
+        let id = connect(&server, &client).await;
+        let report = client.sync_with(&id).await.unwrap();
+        assert_eq!(report.inserted, 5);
+        assert_eq!((report.rejected, report.missing), (0, 0));
+        assert_eq!(client.sync.orphans(), 0);
+        assert!(txs.iter().all(|t| client.node.dag.dag.contains(&t.id)));
+        assert_eq!(client.node.dag.state_root(), server.node.dag.state_root());
+        assert_eq!(client.sync_with(&id).await.unwrap(), SyncReport::default());
+        client.shutdown();
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn syncing_from_a_pruned_peer_starts_from_its_snapshot() {
+        let dir = std::env::temp_dir().join(format!("cpr-sync-pruned-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (key, validator) = (KeyPair::generate(), KeyPair::generate());
+        let genesis = genesis(&key, &validator);
+        let (server, client) = (manager_at(&genesis, Some(&dir)), manager(&genesis));
+        let covered = payments(&server, &key, None, 5).await;
+        let dag = &server.node.dag;
+        dag.record_commit(commit(&server, &validator, 0, dag.federation.genesis)).unwrap();
+        let tail = payments(&server, &key, Some(&validator), 2).await;
+        dag.snapshot().unwrap();
+        dag.snapshot().unwrap();
+        assert!(dag.dag.has_pruned());
+        assert!(!dag.dag.contains(&covered[0].id));
+
+        let id = connect(&server, &client).await;
+        let report = client.sync_with(&id).await.unwrap();
+        assert!(report.snapshot);
+        assert_eq!((report.inserted, report.rejected, report.missing), (tail.len(), 0, 0));
+        assert!(tail.iter().all(|t| client.node.dag.dag.contains(&t.id)));
+        assert_eq!(client.node.dag.state_root(), dag.state_root());
+        client.shutdown();
+        server.shutdown();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_sent_in_pages_checked_against_the_commit() {
+        let (key, validator) = (KeyPair::generate(), KeyPair::generate());
+        let genesis = genesis(&key, &validator);
+        let (server, client) = (manager(&genesis), manager(&genesis));
+        let txs = payments(&server, &key, Some(&validator), 5).await;
+        let dag = &server.node.dag;
+        dag.record_commit(commit(&server, &validator, 0, dag.federation.genesis)).unwrap();
+        let head = match server.sync.on_request(&server, &NetworkMessage::GetSnapshot) {
+            Some(NetworkMessage::SnapshotHead(head)) => *head,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(head.nodes, txs.len() as u64);
+
+        // Two at a time, so the ledger and the transactions take more than
+        // one page each.
+        let checkpoint = head.commit.checkpoint.hash();
+        let mut pages = Vec::new();
+        for part in [SnapshotPart::Ledger, SnapshotPart::Pruned, SnapshotPart::Nodes] {
+            let mut from = 0;
+            loop {
+                let req = NetworkMessage::GetSnapshotPage { checkpoint, part, from, limit: 2 };
+                let page = match server.sync.on_request(&server, &req) {
+                    Some(NetworkMessage::SnapshotPage(page)) => page,
+                    other => panic!("unexpected reply {:?}", other),
+                };
+                assert!(page.len() <= 2);
+                if page.is_empty() {
+                    break;
+                }
+                from += page.len() as u64;
+                pages.push(page);
+            }
+        }
+        assert!(pages.len() > 3);
+        let (mut entries, mut pruned, mut nodes) = (Vec::new(), Vec::new(), Vec::new());
+        for page in pages {
+            match page {
+                SnapshotPage::Ledger(page) => entries.extend(page),
+                SnapshotPage::Pruned(page) => pruned.extend(page),
+                SnapshotPage::Nodes(page) => nodes.extend(page),
+            }
+        }
+        assert_eq!(entries.len() as u64, head.entries);
+
+        // A page that does not add up to the commit's state root is caught.
+        let mut forged = entries.clone();
+        let nonce = forged.iter_mut().find_map(|e| match e {
+            LedgerEntry::Nonce(_, nonce) => Some(nonce),
+            _ => None,
+        });
+        *nonce.unwrap() += 1;
+        let snapshot = Snapshot::join(head.clone(), forged, pruned.clone(), nodes.clone());
+        assert!(client.node.dag.install_snapshot(snapshot).is_err());
+        client.node.dag.install_snapshot(Snapshot::join(head, entries, pruned, nodes)).unwrap();
+        assert!(txs.iter().all(|t| client.node.dag.dag.contains(&t.id)));
+        assert_eq!(client.node.dag.state_root(), dag.state_root());
+
+        // Pages are only cut from the snapshot at the last commit.
+        let req = NetworkMessage::GetSnapshotPage {
+            checkpoint: [0; 32],
+            part: SnapshotPart::Ledger,
+            from: 0,
+            limit: 2,
+        };
+        let reply = server.sync.on_request(&server, &req);
+        assert!(matches!(reply, Some(NetworkMessage::Error { code: ErrorCode::Rejected, .. })));
+        client.shutdown();
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn synced_transactions_need_votes_or_a_commit() {
+        let (key, validator) = (KeyPair::generate(), KeyPair::generate());
+        let genesis = genesis(&key, &validator);
+        let (server, client) = (manager(&genesis), manager(&genesis));
+        payments(&server, &key, Some(&validator), 1).await;
+        let id = connect(&server, &client).await;
+        assert_eq!(client.sync_with(&id).await.unwrap().inserted, 1);
+
+        // Neither approved nor covered, so not taken on the peer's word.
+        let unapproved = payments(&server, &key, None, 3).await;
+        let report = client.sync_with(&id).await.unwrap();
+        assert_eq!(report.inserted, 0);
+        assert!(report.rejected > 0);
+        assert!(!unapproved.iter().any(|t| client.node.dag.dag.knows(&t.id)));
+
+        let dag = &server.node.dag;
+        dag.record_commit(commit(&server, &validator, 0, dag.federation.genesis)).unwrap();
+        let report = client.sync_with(&id).await.unwrap();
+        assert!(!report.snapshot);
+        assert_eq!((report.inserted, report.missing), (unapproved.len(), 0));
+        assert_eq!(client.node.dag.state_root(), dag.state_root());
+        let last = |dag: &StreamingDAG| dag.checkpoint.lock().unwrap().commit.clone().map(|c| c.checkpoint);
+        assert_eq!(last(&client.node.dag), last(dag));
+        client.shutdown();
+        server.shutdown();
+    }
+}
@@ -0,0 +1,184 @@
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+use super::{manager::PeerManager, peer::FEATURE_VALIDATION};
+use crate::federation::org::OrgId;
+use crate::msg::NetworkMessage;
+use crate::validate::{Vote, Voter};
+use crate::Transaction;
+
+/// A validator run by another node, reached through the peers acting for
+/// its org. Each is asked in turn with `ValidationReq` until one answers
+/// with a vote from the validator; the vote is returned as received, to be
+/// checked against the validator set when it is counted.
+pub struct RemoteVoter {
+    pub name: String,
+    pub org_id: OrgId,
+    manager: Arc<PeerManager>,
+}
+
+impl RemoteVoter {
+    pub fn new(name: &str, org_id: OrgId, manager: Arc<PeerManager>) -> Self {
+        Self {
+            name: name.to_string(),
+            org_id,
+            manager,
+        }
+    }
+}
+
+impl Voter for RemoteVoter {
+    fn validator(&self) -> &str {
+        &self.name
+    }
+
+    fn vote<'a>(&'a self, tx: &'a Transaction) -> BoxFuture<'a, anyhow::Result<Vote>> {
+        Box::pin(async move {
+            let peers = self
+                .manager
+                .peers()
+                .into_iter()
+                .filter(|p| p.org_id == self.org_id && p.supports(FEATURE_VALIDATION));
+            let mut asked = 0;
+            for peer in peers {
+                asked += 1;
+                let req = NetworkMessage::ValidationReq(tx.clone());
+                match self.manager.request(&peer.node_id, req).await {
+                    Ok(NetworkMessage::ValidationRes(_, votes)) => {
+                        if let Some(vote) = votes.into_iter().find(|v| v.validator == self.name) {
+                            return Ok(vote);
+                        }
+                    }
+                    Ok(other) => log::debug!("Peer {} answered a validation request with {:?}", peer.node_id, other),
+                    Err(e) => log::debug!("Validation request to {} failed: {}", peer.node_id, e),
+                }
+            }
+            anyhow::bail!("none of {} peers of {} had a vote from {}", asked, self.org_id.handle, self.name)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::genesis::Genesis;
+    use crate::federation::org::user::{KeyPair, OrgUser};
+    use crate::node::{manager::PeerManagerConfig, Node};
+    use crate::store::dag::DEFAULT_PARENTS;
+    use crate::validate::LocalVoter;
+    use crate::{Amount, StreamingDAG};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// One org with alice holding `alice` and 100 TEST, bob, and one
+    /// validator, v0, signing with `validator`.
+    fn genesis(alice: &KeyPair, validator: &KeyPair) -> Genesis {
+        format!(
+            r#"
+            [federation]
+            handle = "test"
+
+            [[orgs]]
+            handle = "org"
+            symbol = "TEST"
+            users = [{{ handle = "alice", key = "{}" }}, {{ handle = "bob" }}]
+
+            [[validators]]
+            name = "v0"
+            org = "org"
+            weight = 1
+            key = "{}"
+
+            [[balances]]
+            org = "org"
+            user = "alice"
+            symbol = "TEST"
+            amount = "100"
+            "#,
+            alice.public(),
+            validator.public()
+        )
+        .parse()
+        .unwrap()
+    }
+
+    /// A node's peer manager, voting as v0 if given its key.
+    fn manager(genesis: &Genesis, validator: Option<&KeyPair>) -> Arc<PeerManager> {
+        let fed = genesis.federation().unwrap();
+        let org_id = fed.orgs[0].id.clone();
+        let dag = StreamingDAG::new_with_federation(10, fed);
+        genesis.mint(&dag).unwrap();
+        let dag = Arc::new(dag);
+        let mut node = Node::new(org_id.clone(), Arc::clone(&dag));
+        if let Some(key) = validator {
+            let voter: Arc<dyn Voter> = Arc::new(LocalVoter::new("v0", org_id, key.clone(), dag));
+            node = node.with_key(key.clone()).with_voters(vec![voter]);
+        }
+        let config = PeerManagerConfig {
+            sync_on_connect: false,
+            ..Default::default()
+        };
+        PeerManager::new(Arc::new(node), config)
+    }
+
+    async fn eventually(what: &str, f: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if f() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for {}", what);
+    }
+
+    fn pay(manager: &PeerManager, key: &KeyPair) -> Transaction {
+        let dag = &manager.node.dag;
+        let users = &manager.node.fed.orgs[0].users;
+        let find = |handle: &str| -> OrgUser { users.iter().find(|u| u.id.handle == handle).unwrap().clone() };
+        let (alice, bob) = (find("alice"), find("bob"));
+        let nonce = dag.ledger.lock().unwrap().next_nonce(&alice.id);
+        let mut tx = Transaction::new(alice, bob, "TEST", Amount::from_whole(1, 2).unwrap(), nonce);
+        tx.attach(dag.select_tips(DEFAULT_PARENTS)).unwrap();
+        tx.sign(key);
+        tx
+    }
+
+    #[tokio::test]
+    async fn remote_validators_vote_through_their_org_peers() {
+        let (alice, validator) = (KeyPair::generate(), KeyPair::generate());
+        let genesis = genesis(&alice, &validator);
+        let (server, client) = (manager(&genesis, Some(&validator)), manager(&genesis, None));
+        let org_id = client.node.org_id.clone();
+        let tx = pay(&client, &alice);
+
+        // No peers yet to ask.
+        let remote = RemoteVoter::new("v0", org_id.clone(), Arc::clone(&client));
+        assert!(remote.vote(&tx).await.is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(Arc::clone(&server).serve(listener));
+        client.dial(addr);
+        let id = server.node.id;
+        eventually("the handshake", || client.is_connected(&id)).await;
+
+        let vote = remote.vote(&tx).await.unwrap();
+        assert_eq!((vote.validator.as_str(), vote.tx_id), ("v0", tx.id));
+        assert!(vote.approves());
+        assert_eq!(vote.verify(&genesis.hash()), Ok(()));
+        // The peer runs no validator of that name.
+        let missing = RemoteVoter::new("v1", org_id, Arc::clone(&client));
+        assert!(missing.vote(&tx).await.is_err());
+
+        // The client runs no validator, so every vote comes from the peer;
+        // once approved, the peer confirms it from the relayed votes.
+        let voters = client.voters();
+        assert_eq!(voters.iter().map(|v| v.validator()).collect::<Vec<_>>(), vec!["v0"]);
+        let outcome = client.validate(&tx).await.unwrap();
+        assert!(outcome.is_approved());
+        assert!(client.node.dag.dag.contains(&tx.id));
+        eventually("the peer to confirm it", || server.node.dag.dag.contains(&tx.id)).await;
+        client.shutdown();
+        server.shutdown();
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashMap, path::Path};
+
+use super::{
+    dag::DAGNode,
+    index::TxIndex,
+    wal::{Lsn, Wal, WalConfig},
+};
+use crate::TxId;
+
+/// Final transactions pruned from the DAG, kept on disk so that queries
+/// over the `TxIndex` still find them. Pruned nodes are appended at every
+/// snapshot and never removed. Only where each one is held is kept in
+/// memory.
+#[derive(Debug)]
+pub struct Archive {
+    wal: Wal,
+    at: HashMap<TxId, Lsn>,
+}
+
+impl Archive {
+    /// Open the archive in `dir`, creating it if needed, and add every
+    /// archived transaction to `index`.
+    pub fn open(dir: impl AsRef<Path>, config: WalConfig, index: &mut TxIndex) -> anyhow::Result<Self> {
+        let wal = Wal::open(dir, config)?;
+        let mut at = HashMap::new();
+        for (lsn, node) in wal.read_from::<DAGNode>(0)? {
+            index.insert(&node.tx);
+            at.insert(node.tx.id, lsn);
+        }
+        Ok(Self { wal, at })
+    }
+
+    pub fn len(&self) -> usize {
+        self.at.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.at.is_empty()
+    }
+
+    pub fn contains(&self, id: &TxId) -> bool {
+        self.at.contains_key(id)
+    }
+
+    /// Add `nodes` and sync them to disk, so they are held before the
+    /// snapshot that prunes them is written.
+    pub fn append(&mut self, nodes: &[DAGNode]) -> anyhow::Result<()> {
+        for node in nodes {
+            let lsn = self.wal.append(node)?;
+            self.at.insert(node.tx.id, lsn);
+        }
+        self.wal.sync()?;
+        Ok(())
+    }
+
+    /// The archived nodes among `ids`. Ids that were never archived are
+    /// left out.
+    pub fn get(&self, ids: &[TxId]) -> anyhow::Result<HashMap<TxId, DAGNode>> {
+        let lsns: Vec<Lsn> = ids.iter().filter_map(|id| self.at.get(id)).copied().collect();
+        Ok(self
+            .wal
+            .read_many::<DAGNode>(&lsns)?
+            .into_iter()
+            .map(|(_, node)| (node.tx.id, node))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{user::OrgUser, OrgId};
+    use crate::store::dag::{FinalityConfig, TxGraph, TxState};
+    use crate::store::index::TxFilter;
+    use crate::{Amount, Transaction};
+
+    #[test]
+    fn pruned_transactions_stay_queryable() {
+        let dir = std::env::temp_dir().join(format!("cpr-archive-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut graph = TxGraph::with_finality(FinalityConfig {
+            confirmation_threshold: 2,
+            finality_threshold: 3,
+            validator_weighted: false,
+        });
+        let org = OrgId::new("org");
+        let alice = OrgUser::new(org.clone(), "alice".to_string());
+        let bob = OrgUser::new(org, "bob".to_string());
+        let mut parent = None;
+        for nonce in 0..8 {
+            let amt = Amount::from_whole(1, 2).unwrap();
+            let mut tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt, nonce);
+            tx.attach(parent.into_iter().collect()).unwrap();
+            parent = Some(tx.id);
+            graph.insert(tx, 1).unwrap();
+        }
+        let pruned = graph.prune(u64::MAX, |_| false);
+        assert!(!pruned.is_empty());
+        let sent = TxFilter::Sender(alice.id.clone());
+        assert_eq!(graph.index().query(&sent, None, 10).items.len(), 8);
+
+        let mut archive = Archive::open(&dir, WalConfig::default(), &mut TxIndex::new()).unwrap();
+        archive.append(&pruned).unwrap();
+        drop(archive);
+        let mut index = TxIndex::new();
+        let archive = Archive::open(&dir, WalConfig::default(), &mut index).unwrap();
+        assert_eq!(archive.len(), pruned.len());
+        let ids = index.query(&sent, None, 10).items;
+        assert_eq!(ids.len(), pruned.len());
+        let nodes = archive.get(&ids).unwrap();
+        assert!(ids.iter().all(|id| nodes[id].state == TxState::Final && !graph.contains(id)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
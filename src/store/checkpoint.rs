@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use super::dag::{DAGNode, TxGraph, TxState};
+use super::ledger::Ledger;
+use crate::consensus::Commit;
+use crate::{Transaction, TxId};
+
+/// The ledger as the last committed checkpoint left it, kept beside the
+/// live one. Validators agree on a checkpoint's state root whatever order
+/// they saw its transactions in, and a node catching up can start from a
+/// state a quorum of them signed.
+///
+/// It starts from what was minted. Each commit applies every transaction
+/// its tips build on that the one before did not cover, by height and then
+/// by id, as `ConflictTracker::rebuild` replays them. Rejected ones, such as
+/// the loser of a double spend, are covered without being applied, so it
+/// holds the same transactions as the live ledger.
+///
+/// A commit covers everything its tips build on. Only covered transactions
+/// are pruned, so those are the unpruned ancestors of its tips and whatever
+/// is pruned.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CheckpointState {
+    /// The last commit, or `None` before the first.
+    pub commit: Option<Commit>,
+    pub ledger: Ledger,
+}
+
+impl CheckpointState {
+    /// The transactions in `graph` the last commit covers: its tips and
+    /// their unpruned ancestors.
+    pub fn covered(&self, graph: &TxGraph) -> HashSet<TxId> {
+        let mut covered = HashSet::new();
+        let mut stack: Vec<TxId> = self.commit.iter().flat_map(|c| c.checkpoint.tips.iter().copied()).collect();
+        while let Some(id) = stack.pop() {
+            if covered.contains(&id) {
+                continue;
+            }
+            if let Some(node) = graph.get(&id) {
+                stack.extend(node.tx.parents.iter().copied());
+                covered.insert(id);
+            }
+        }
+        covered
+    }
+
+    /// The ledger a checkpoint of `tips` would leave. Fails if they build
+    /// on a transaction `graph` does not hold, or if one that is not
+    /// rejected does not apply.
+    pub fn advance(&self, tips: &[TxId], graph: &TxGraph) -> anyhow::Result<Ledger> {
+        let covered = self.covered(graph);
+        let mut found: HashMap<TxId, &DAGNode> = HashMap::new();
+        let mut stack = tips.to_vec();
+        while let Some(id) = stack.pop() {
+            if covered.contains(&id) || graph.is_pruned(&id) || found.contains_key(&id) {
+                continue;
+            }
+            let Some(node) = graph.get(&id) else {
+                anyhow::bail!("{} is not held here", id);
+            };
+            stack.extend(node.tx.parents.iter().copied());
+            found.insert(id, node);
+        }
+        let mut found: Vec<&DAGNode> = found.into_values().filter(|n| n.state != TxState::Rejected).collect();
+        found.sort_by_key(|n| (n.height, n.tx.id));
+        let txs: Vec<Transaction> = found.into_iter().map(|n| n.tx.clone()).collect();
+        let mut ledger = self.ledger.clone();
+        for tx in txs.iter() {
+            ledger
+                .apply(tx)
+                .map_err(|e| anyhow::anyhow!("{} does not apply: {}", tx.id, e))?;
+        }
+        ledger.settle(&txs);
+        Ok(ledger)
+    }
+}
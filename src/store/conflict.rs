@@ -0,0 +1,501 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::{
+    dag::{TxGraph, TxState},
+    ledger::{Ledger, LedgerError, NonceError},
+};
+use crate::{federation::org::user::OrgUserId, models::AmountError, Transaction, TxId};
+
+/// Transactions from one sender that cannot all stand, of which exactly one
+/// survives.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConflictSet {
+    pub id: u64,
+    pub members: BTreeSet<TxId>,
+    /// The member currently counted in the ledger.
+    pub winner: Option<TxId>,
+    /// Set once the winner is final, after which the set is never
+    /// reconsidered.
+    pub settled: bool,
+}
+
+/// Finds and resolves double spends.
+///
+/// Two transactions conflict when they carry the same sender nonce, or when
+/// the later one could only be applied if an earlier, still unsettled spend
+/// by the same sender (of the same symbol, or with a higher nonce) did not
+/// happen. Conflicting transactions are still inserted into the DAG, so
+/// every node ends up holding the same ones whatever order they arrived in,
+/// and grouped into a `ConflictSet`.
+///
+/// Each set is resolved by preferring a final member, then the highest
+/// cumulative weight, then the lowest id. That only depends on the DAG, so
+/// nodes holding the same DAG pick the same winners. Whenever a winner
+/// changes, the ledger is rewound to the set's earliest member in
+/// `TxGraph::topological` order and replayed from there, skipping the
+/// losers. Replaying only the set's descendants would not be enough: a
+/// recipient can spend what a winner paid them in a transaction that does
+/// not approve it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConflictTracker {
+    by_nonce: HashMap<(OrgUserId, u64), BTreeSet<TxId>>,
+    by_sender: HashMap<OrgUserId, BTreeSet<(u64, TxId)>>,
+    /// Highest nonce of each sender's pruned transactions, which nothing
+    /// may conflict with any more.
+    settled_nonces: HashMap<OrgUserId, u64>,
+    sets: BTreeMap<u64, ConflictSet>,
+    member_of: HashMap<TxId, u64>,
+    next_set: u64,
+    /// Transactions that are not losers but failed to apply on the last
+    /// replay.
+    unapplied: BTreeSet<TxId>,
+}
+
+impl ConflictTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sets(&self) -> impl Iterator<Item = &ConflictSet> {
+        self.sets.values()
+    }
+
+    /// The conflict set `id` belongs to, if any.
+    pub fn set_of(&self, id: &TxId) -> Option<&ConflictSet> {
+        self.member_of.get(id).and_then(|s| self.sets.get(s))
+    }
+
+    /// Transactions that lost their conflict set.
+    pub fn losers(&self) -> BTreeSet<TxId> {
+        self.sets
+            .values()
+            .flat_map(|s| s.members.iter().filter(move |m| Some(**m) != s.winner))
+            .copied()
+            .collect()
+    }
+
+    /// The unsettled transactions in `graph` that `tx` conflicts with,
+    /// given the current `ledger`. Empty if `tx` either applies cleanly or
+    /// fails for a reason no other transaction is to blame for, such as
+    /// reusing the nonce of a final transaction.
+    pub fn rivals(&self, tx: &Transaction, graph: &TxGraph, ledger: &Ledger) -> Vec<TxId> {
+        let sender = &tx.send.id;
+        if self.settled_nonces.get(sender).is_some_and(|n| tx.nonce <= *n) {
+            return Vec::new();
+        }
+        let same_nonce = self
+            .by_nonce
+            .get(&(sender.clone(), tx.nonce))
+            .cloned()
+            .unwrap_or_default();
+        if same_nonce.iter().any(|id| graph.state(id) == Some(TxState::Final)) {
+            return Vec::new();
+        }
+        let mut rivals: BTreeSet<TxId> = same_nonce;
+        let unsettled = |id: &TxId| {
+            !matches!(graph.state(id), Some(TxState::Final) | Some(TxState::Rejected) | None)
+        };
+        let spends = self.by_sender.get(sender);
+        match ledger.check(tx) {
+            Err(LedgerError::Nonce(NonceError::Duplicate { .. } | NonceError::Stale { .. })) => {
+                rivals.extend(
+                    spends
+                        .into_iter()
+                        .flatten()
+                        .filter(|(nonce, id)| *nonce >= tx.nonce && unsettled(id))
+                        .map(|(_, id)| *id),
+                );
+            }
+            Err(LedgerError::Amount(AmountError::InsufficientFunds { .. })) => {
+                rivals.extend(
+                    spends
+                        .into_iter()
+                        .flatten()
+                        .map(|(_, id)| *id)
+                        .filter(|id| {
+                            unsettled(id)
+                                && graph.get_tx(id).map(|t| &t.amt.symbol) == Some(&tx.amt.symbol)
+                        }),
+                );
+            }
+            _ => {}
+        }
+        rivals.remove(&tx.id);
+        rivals.into_iter().collect()
+    }
+
+    /// Remember `tx` so later transactions can be checked against it.
+    pub fn record(&mut self, tx: &Transaction) {
+        self.by_nonce
+            .entry((tx.send.id.clone(), tx.nonce))
+            .or_default()
+            .insert(tx.id);
+        self.by_sender
+            .entry(tx.send.id.clone())
+            .or_default()
+            .insert((tx.nonce, tx.id));
+    }
+
+    /// Forget `txs`, which have been pruned from the DAG. Sets they were in
+    /// are kept until all their members are gone.
+    pub fn prune(&mut self, txs: &[Transaction]) {
+        for tx in txs {
+            let sender = &tx.send.id;
+            let settled = self.settled_nonces.entry(sender.clone()).or_default();
+            *settled = (*settled).max(tx.nonce);
+            let key = (sender.clone(), tx.nonce);
+            if let Some(ids) = self.by_nonce.get_mut(&key) {
+                ids.remove(&tx.id);
+                if ids.is_empty() {
+                    self.by_nonce.remove(&key);
+                }
+            }
+            if let Some(spends) = self.by_sender.get_mut(sender) {
+                spends.remove(&(tx.nonce, tx.id));
+                if spends.is_empty() {
+                    self.by_sender.remove(sender);
+                }
+            }
+            self.unapplied.remove(&tx.id);
+            if let Some(set_id) = self.member_of.remove(&tx.id) {
+                if let Some(set) = self.sets.get_mut(&set_id) {
+                    set.members.remove(&tx.id);
+                    if set.members.is_empty() {
+                        self.sets.remove(&set_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Put `id` in a conflict set with `rivals`, merging any sets they were
+    /// already in, and mark every member in `graph`. Returns the set's id.
+    pub fn add(&mut self, id: TxId, rivals: &[TxId], graph: &mut TxGraph) -> u64 {
+        let existing: BTreeSet<u64> = rivals
+            .iter()
+            .chain(std::iter::once(&id))
+            .filter_map(|r| self.member_of.get(r).copied())
+            .collect();
+        let set_id = match existing.iter().next() {
+            Some(&first) => first,
+            None => {
+                self.next_set += 1;
+                self.next_set
+            }
+        };
+        let mut members: BTreeSet<TxId> = rivals.iter().copied().collect();
+        members.insert(id);
+        let mut winner = None;
+        for other in existing.iter() {
+            if let Some(set) = self.sets.remove(other) {
+                members.extend(set.members);
+                winner = winner.or(set.winner);
+            }
+        }
+        for m in members.iter() {
+            self.member_of.insert(*m, set_id);
+            graph.mark_conflict(m, set_id);
+        }
+        self.sets.insert(
+            set_id,
+            ConflictSet {
+                id: set_id,
+                members,
+                winner,
+                settled: false,
+            },
+        );
+        set_id
+    }
+
+    /// Pick a winner for every unsettled set, rejecting the other members
+    /// in `graph`. Returns the sets whose winner changed, for which the
+    /// ledger needs to be rebuilt.
+    pub fn resolve(&mut self, graph: &mut TxGraph) -> Vec<u64> {
+        let mut changed = Vec::new();
+        let unapplied = &self.unapplied;
+        for set in self.sets.values_mut().filter(|s| !s.settled) {
+            let winner = set
+                .members
+                .iter()
+                .min_by_key(|id| {
+                    let node = graph.get(id);
+                    let is_final = node.is_some_and(|n| n.state == TxState::Final);
+                    let weight = node.map_or(0, |n| n.cumulative_weight);
+                    (!is_final, std::cmp::Reverse(weight), **id)
+                })
+                .copied();
+            if winner != set.winner {
+                changed.push(set.id);
+                set.winner = winner;
+            }
+            for m in set.members.iter() {
+                if Some(*m) == winner {
+                    if !unapplied.contains(m) {
+                        graph.reinstate(m);
+                    }
+                } else {
+                    graph.reject(m);
+                }
+            }
+            if winner.and_then(|w| graph.state(&w)) == Some(TxState::Final) {
+                set.settled = true;
+            }
+        }
+        changed
+    }
+
+    /// Bring `ledger` in line with new winners in the `changed` sets: undo
+    /// everything applied since the first transaction at or after the
+    /// earliest of their members, in topological order, then apply every
+    /// transaction in `graph` from that member on, along with whatever else
+    /// was undone, except the losers. Anything that no longer applies in
+    /// that order is rejected in `graph` until it is replayed again.
+    pub fn rebuild(&mut self, ledger: &mut Ledger, graph: &mut TxGraph, changed: &[u64]) {
+        let key = |id: &TxId| graph.get(id).map(|n| (n.height, n.tx.id));
+        let Some(from) = changed
+            .iter()
+            .filter_map(|s| self.sets.get(s))
+            .flat_map(|s| s.members.iter())
+            .filter_map(key)
+            .min()
+        else {
+            return;
+        };
+        // Transactions no longer in the graph were settled before anything
+        // in it, so are kept.
+        let keep = ledger
+            .unsettled()
+            .position(|id| key(id).is_some_and(|k| k >= from))
+            .unwrap_or(usize::MAX);
+        let undone: HashSet<TxId> = ledger.rewind(keep).into_iter().collect();
+        let retry: Vec<TxId> = self.unapplied.iter().filter(|id| key(id) >= Some(from)).copied().collect();
+        for id in retry {
+            self.unapplied.remove(&id);
+            graph.reinstate(&id);
+        }
+        let losers = self.losers();
+        let mut failed = Vec::new();
+        for node in graph.topological() {
+            let replay = (node.height, node.tx.id) >= from || undone.contains(&node.tx.id);
+            if !replay || losers.contains(&node.tx.id) {
+                continue;
+            }
+            if ledger.apply(&node.tx).is_err() {
+                failed.push(node.tx.id);
+            }
+        }
+        for id in failed {
+            graph.reject(&id);
+            self.unapplied.insert(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{user::OrgUser, OrgId};
+    use crate::models::Amount;
+    use crate::store::dag::FinalityConfig;
+
+    struct Harness {
+        graph: TxGraph,
+        ledger: Ledger,
+        conflicts: ConflictTracker,
+        users: HashMap<&'static str, OrgUser>,
+        nonces: HashMap<&'static str, u64>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let org = OrgId::new("org");
+            let users = ["alice", "bob", "carol", "dave", "erin"]
+                .into_iter()
+                .map(|h| (h, OrgUser::new(org.clone(), h.to_string())))
+                .collect();
+            Self::with_users(users)
+        }
+
+        /// A fresh harness for the same users, standing in for another node.
+        fn with_users(users: HashMap<&'static str, OrgUser>) -> Self {
+            let mut ledger = Ledger::new();
+            let mut harness = Self {
+                graph: TxGraph::with_finality(FinalityConfig {
+                    confirmation_threshold: 2,
+                    finality_threshold: 4,
+                    validator_weighted: false,
+                }),
+                ledger: Ledger::new(),
+                conflicts: ConflictTracker::new(),
+                users,
+                nonces: HashMap::new(),
+            };
+            ledger.mint(&harness.users["alice"].id, "TEST", amt(10)).unwrap();
+            harness.ledger = ledger;
+            harness
+        }
+
+        fn tx(&mut self, from: &'static str, to: &'static str, units: u64, parents: Vec<TxId>) -> Transaction {
+            let nonce = self.nonces.entry(from).or_default();
+            *nonce += 1;
+            let nonce = *nonce;
+            self.tx_at(from, to, units, nonce, parents)
+        }
+
+        fn tx_at(&self, from: &str, to: &str, units: u64, nonce: u64, parents: Vec<TxId>) -> Transaction {
+            let (send, recv) = (self.users[from].clone(), self.users[to].clone());
+            let mut tx = Transaction::new(send, recv, "TEST", amt(units), nonce);
+            tx.attach(parents).unwrap();
+            tx
+        }
+
+        /// What `StreamingDAG::commit_tx` does, without the locks and log.
+        fn commit(&mut self, tx: &Transaction) {
+            let rivals = self.conflicts.rivals(tx, &self.graph, &self.ledger);
+            if rivals.is_empty() {
+                self.ledger.apply(tx).unwrap();
+            }
+            self.graph.insert(tx.clone(), 1).unwrap();
+            if !rivals.is_empty() {
+                self.conflicts.add(tx.id, &rivals, &mut self.graph);
+            }
+            self.conflicts.record(tx);
+            let changed = self.conflicts.resolve(&mut self.graph);
+            if !changed.is_empty() {
+                self.conflicts.rebuild(&mut self.ledger, &mut self.graph, &changed);
+            }
+        }
+
+        /// Approve `id` with `n` empty transactions from erin.
+        fn approve(&mut self, id: TxId, n: usize) {
+            for _ in 0..n {
+                let tx = self.tx("erin", "erin", 0, vec![id]);
+                self.commit(&tx);
+            }
+        }
+
+        fn balance(&self, user: &str) -> Amount {
+            self.ledger.balance(&self.users[user].id, "TEST").unwrap_or(amt(0))
+        }
+
+        fn winner(&self, id: &TxId) -> Option<TxId> {
+            self.conflicts.set_of(id).and_then(|s| s.winner)
+        }
+    }
+
+    fn amt(whole: u64) -> Amount {
+        Amount::from_whole(whole, 2).unwrap()
+    }
+
+    /// A root, then two spends by alice of the same nonce below it.
+    fn double_spend(h: &mut Harness) -> (Transaction, Transaction) {
+        let root = h.tx("dave", "dave", 0, Vec::new());
+        h.commit(&root);
+        let a = h.tx_at("alice", "bob", 6, 1, vec![root.id]);
+        let b = h.tx_at("alice", "carol", 6, 1, vec![root.id]);
+        h.commit(&a);
+        h.commit(&b);
+        (a, b)
+    }
+
+    #[test]
+    fn lowest_id_wins_at_equal_weight() {
+        let mut h = Harness::new();
+        let (a, b) = double_spend(&mut h);
+        let first = a.id.min(b.id);
+        assert_eq!(h.winner(&a.id), Some(first));
+        assert_eq!(h.conflicts.losers(), BTreeSet::from([a.id.max(b.id)]));
+        assert_eq!(h.graph.state(&a.id.max(b.id)), Some(TxState::Rejected));
+        let paid = if first == a.id { "bob" } else { "carol" };
+        assert_eq!(h.balance(paid), amt(6));
+        assert_eq!(h.balance("alice"), amt(4));
+    }
+
+    #[test]
+    fn arrival_order_does_not_change_the_state_root() {
+        let mut first = Harness::new();
+        let mut second = Harness::with_users(first.users.clone());
+        let root = first.tx("dave", "dave", 0, Vec::new());
+        let a = first.tx_at("alice", "bob", 6, 1, vec![root.id]);
+        let b = first.tx_at("alice", "carol", 6, 1, vec![root.id]);
+        for (h, order) in [(&mut first, [&a, &b]), (&mut second, [&b, &a])] {
+            h.commit(&root);
+            for tx in order {
+                h.commit(tx);
+            }
+        }
+        assert_eq!(first.winner(&a.id), second.winner(&a.id));
+        // Whichever node applied the loser first rewound it, and the
+        // account it opened for its recipient is gone again.
+        let loser = if first.winner(&a.id) == Some(a.id) { &b } else { &a };
+        assert_eq!(first.ledger.balance(&loser.recv.id, "TEST"), None);
+        assert_eq!(second.ledger.balance(&loser.recv.id, "TEST"), None);
+        assert_eq!(first.ledger.state_root(), second.ledger.state_root());
+    }
+
+    #[test]
+    fn heavier_wins_over_lower_id() {
+        let mut h = Harness::new();
+        let (a, b) = double_spend(&mut h);
+        let (low, high) = if a.id < b.id { (a, b) } else { (b, a) };
+        h.approve(high.id, 1);
+        assert_eq!(h.winner(&low.id), Some(high.id));
+        assert_eq!(h.graph.state(&low.id), Some(TxState::Rejected));
+        let (paid, unpaid) = if high.recv.id == h.users["bob"].id { ("bob", "carol") } else { ("carol", "bob") };
+        assert_eq!(h.balance(paid), amt(6));
+        assert_eq!(h.balance(unpaid), amt(0));
+        assert_eq!(h.balance("alice"), amt(4));
+    }
+
+    #[test]
+    fn final_wins_over_heavier() {
+        let mut h = Harness::new();
+        let (a, b) = double_spend(&mut h);
+        let (low, high) = if a.id < b.id { (a, b) } else { (b, a) };
+        h.approve(low.id, 3);
+        assert_eq!(h.graph.state(&low.id), Some(TxState::Final));
+        assert!(h.conflicts.set_of(&low.id).unwrap().settled);
+        h.approve(high.id, 5);
+        assert!(h.graph.cumulative_weight(&high.id) > h.graph.cumulative_weight(&low.id));
+        assert_eq!(h.winner(&low.id), Some(low.id));
+        assert_eq!(h.graph.state(&high.id), Some(TxState::Rejected));
+    }
+
+    #[test]
+    fn rebuild_replays_spends_that_do_not_approve_the_winner() {
+        let mut h = Harness::new();
+        let (a, b) = double_spend(&mut h);
+        let (low, high) = if a.id < b.id { (a, b) } else { (b, a) };
+        let paid = if low.recv.id == h.users["bob"].id { "bob" } else { "carol" };
+        // The first winner's recipient passes the money on in a transaction
+        // that does not approve it, but still comes after it in topological
+        // order.
+        let filler = h.tx("dave", "dave", 0, low.parents.clone());
+        h.commit(&filler);
+        let onward = h.tx(paid, "dave", 6, vec![filler.id]);
+        h.commit(&onward);
+        assert_eq!(h.balance("dave"), amt(6));
+        let height = h.ledger.height();
+
+        h.approve(high.id, 2);
+        assert_eq!(h.winner(&low.id), Some(high.id));
+        // Without the first winner's payment, the onward one overdraws.
+        assert_eq!(h.graph.state(&onward.id), Some(TxState::Rejected));
+        assert_eq!(h.balance("dave"), amt(0));
+        assert_eq!(h.balance(paid), amt(0));
+        assert_eq!(h.balance("alice"), amt(4));
+        // Less the first winner and the onward payment, plus the new winner
+        // and two approvals.
+        assert_eq!(h.ledger.height(), height - 2 + 3);
+        assert_eq!(h.ledger.unsettled().count() as u64, h.ledger.height());
+
+        // Back to the first winner, and the onward payment applies again.
+        h.approve(low.id, 3);
+        assert_eq!(h.winner(&low.id), Some(low.id));
+        assert_eq!(h.graph.state(&onward.id), Some(TxState::Pending));
+        assert_eq!(h.balance("dave"), amt(6));
+        assert_eq!(h.balance("alice"), amt(4));
+    }
+}
@@ -1,22 +1,849 @@
 use petgraph::{
-    Directed, Direction, EdgeDirection,
-    algo::{astar, scc},
-    csr::{Csr, Edges, NodeIdentifiers, Neighbors},
-    adj::{List as AdjList, UnweightedList},
-    graph::{Graph, GraphIndex, NodeIndex,}
+    stable_graph::{NodeIndex, StableDiGraph},
+    Direction,
 };
 use std::{
-    hash::Hash, 
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
 };
-use crate::{Transaction, OrgUser, Org, Federation, Balance};
-use tokio::time::Instant;
 
+use super::node::{DAGNode, TxState};
+use crate::{
+    store::index::TxIndex,
+    Transaction, TxId,
+};
+
+/// Most parents a single transaction may reference.
+pub static MAX_PARENTS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DagError {
+    /// A transaction with this id is already in the DAG.
+    Duplicate(TxId),
+    /// A referenced parent is not in the DAG.
+    UnknownParent(TxId),
+    /// A non-genesis transaction did not reference any parent.
+    NoParents,
+    /// More than `MAX_PARENTS` parents were referenced.
+    TooManyParents(usize),
+    /// The same parent was referenced more than once.
+    DuplicateParent(TxId),
+    /// The transaction references itself as a parent.
+    Cycle(TxId),
+}
+
+impl fmt::Display for DagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplicate(id) => write!(f, "transaction {} already in DAG", id),
+            Self::UnknownParent(id) => write!(f, "unknown parent {}", id),
+            Self::NoParents => write!(f, "transaction must reference at least one parent"),
+            Self::TooManyParents(n) => {
+                write!(f, "{} parents referenced, at most {} allowed", n, MAX_PARENTS)
+            }
+            Self::DuplicateParent(id) => write!(f, "parent {} referenced twice", id),
+            Self::Cycle(id) => write!(f, "transaction {} would create a cycle", id),
+        }
+    }
+}
+
+impl std::error::Error for DagError {}
+
+/// Cumulative weight a transaction needs to move out of `Pending`.
+pub static DEFAULT_CONFIRMATION_THRESHOLD: u64 = 3;
+/// Cumulative weight at which a transaction is considered final.
+pub static DEFAULT_FINALITY_THRESHOLD: u64 = 6;
+
+/// When transactions count as confirmed or final, in units of cumulative
+/// approval weight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FinalityConfig {
+    pub confirmation_threshold: u64,
+    pub finality_threshold: u64,
+    /// Whether an approval counts for its org's validator weight, or every
+    /// approval counts as 1.
+    pub validator_weighted: bool,
+}
+
+impl Default for FinalityConfig {
+    fn default() -> Self {
+        Self {
+            confirmation_threshold: DEFAULT_CONFIRMATION_THRESHOLD,
+            finality_threshold: DEFAULT_FINALITY_THRESHOLD,
+            validator_weighted: true,
+        }
+    }
+}
+
+impl FinalityConfig {
+    pub fn state_for(&self, cumulative_weight: u64) -> TxState {
+        if cumulative_weight >= self.finality_threshold {
+            TxState::Final
+        } else if cumulative_weight >= self.confirmation_threshold {
+            TxState::Confirmed
+        } else {
+            TxState::Pending
+        }
+    }
+}
+
+/// Something that happened to the DAG, for anyone following along.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DagEvent {
+    Inserted { id: TxId, height: u64 },
+    StateChanged { id: TxId, state: TxState },
+    /// The transaction was found to conflict with others in `set`.
+    Conflict { id: TxId, set: u64 },
+}
+
+/// The transaction graph. Edges point from a parent to each child that
+/// references it, so a node's incoming neighbours are its parents and its
+/// outgoing neighbours the transactions that approve it.
+///
+/// Nodes can only be added together with edges from parents that already
+/// exist, which means no edge can ever close a cycle: the graph is acyclic by
+/// construction, and `insert` only has to reject self-references.
+///
+/// Each node also tracks its cumulative weight: whenever a transaction is
+/// inserted, its weight is added to every ancestor it approves, and any
+/// ancestor crossing a `FinalityConfig` threshold changes state.
+///
+/// Final transactions can be pruned once they are covered by a snapshot.
+/// Only the ids and heights of those that unpruned transactions reference
+/// are kept, so that those can still be checked and placed; a new
+/// transaction approving anything older is refused for an unknown parent.
+///
+/// A `TxIndex` is kept alongside, maintained on every insert and rebuilt on
+/// every restore. Pruning leaves a transaction's entries in place, so with
+/// the pruned nodes kept in an `Archive` its history can still be queried.
+#[derive(Debug, Default)]
+pub struct TxGraph {
+    graph: StableDiGraph<DAGNode, ()>,
+    tx_indices: HashMap<TxId, NodeIndex>,
+    pruned: HashMap<TxId, u64>,
+    index: TxIndex,
+    tips: BTreeSet<TxId>,
+    /// The most recently inserted final transaction, where tip selection
+    /// walks start from.
+    newest_final: Option<(u64, TxId)>,
+    next_seq: u64,
+    finality: FinalityConfig,
+    events: Vec<DagEvent>,
+}
+
+impl TxGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_finality(finality: FinalityConfig) -> Self {
+        Self {
+            finality,
+            ..Self::default()
+        }
+    }
+
+    pub fn finality(&self) -> FinalityConfig {
+        self.finality
+    }
+
+    /// Take the events produced since the last call.
+    pub fn drain_events(&mut self) -> Vec<DagEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn len(&self) -> usize {
+        self.graph.node_count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graph.node_count() == 0
+    }
+
+    pub fn contains(&self, id: &TxId) -> bool {
+        self.tx_indices.contains_key(id)
+    }
+
+    /// Whether `id` was in the DAG but has been pruned.
+    pub fn is_pruned(&self, id: &TxId) -> bool {
+        self.pruned.contains_key(id)
+    }
+
+    /// Pruned transaction ids that unpruned ones still reference, with the
+    /// height each was at.
+    pub fn pruned(&self) -> &HashMap<TxId, u64> {
+        &self.pruned
+    }
+
+    /// Sequence number the next inserted transaction will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn get(&self, id: &TxId) -> Option<&DAGNode> {
+        self.tx_indices.get(id).map(|&ix| &self.graph[ix])
+    }
+
+    pub fn get_tx(&self, id: &TxId) -> Option<&Transaction> {
+        self.get(id).map(|n| &n.tx)
+    }
+
+    /// Check that `tx` could be inserted without actually inserting it.
+    pub fn check(&self, tx: &Transaction) -> Result<(), DagError> {
+        if self.contains(&tx.id) || self.is_pruned(&tx.id) {
+            return Err(DagError::Duplicate(tx.id));
+        }
+        if tx.parents.is_empty() && !(self.is_empty() && self.pruned.is_empty()) {
+            return Err(DagError::NoParents);
+        }
+        if tx.parents.len() > MAX_PARENTS {
+            return Err(DagError::TooManyParents(tx.parents.len()));
+        }
+        let mut seen = HashSet::new();
+        for p in tx.parents.iter() {
+            if *p == tx.id {
+                return Err(DagError::Cycle(tx.id));
+            }
+            if !seen.insert(p) {
+                return Err(DagError::DuplicateParent(*p));
+            }
+            if !self.contains(p) && !self.is_pruned(p) {
+                return Err(DagError::UnknownParent(*p));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `tx` with an edge from each of its parents, returning its height.
+    /// `weight` is the approval weight the transaction lends to everything
+    /// it approves.
+    pub fn insert(&mut self, tx: Transaction, weight: u64) -> Result<u64, DagError> {
+        self.check(&tx)?;
+        let parent_ixs: Vec<NodeIndex> = tx
+            .parents
+            .iter()
+            .filter_map(|p| self.tx_indices.get(p).copied())
+            .collect();
+        let height = tx
+            .parents
+            .iter()
+            .map(|p| match self.tx_indices.get(p) {
+                Some(&ix) => self.graph[ix].height + 1,
+                None => self.pruned[p] + 1,
+            })
+            .max()
+            .unwrap_or(0);
+        let id = tx.id;
+        for p in tx.parents.iter() {
+            self.tips.remove(p);
+        }
+        self.index.insert(&tx);
+        let ix = self.graph.add_node(DAGNode::new(tx, height, self.next_seq, weight));
+        self.next_seq += 1;
+        for pix in parent_ixs {
+            self.graph.add_edge(pix, ix, ());
+        }
+        self.tx_indices.insert(id, ix);
+        self.tips.insert(id);
+        self.events.push(DagEvent::Inserted { id, height });
+        self.update_state(ix);
+        self.add_weight_to_ancestors(ix, weight);
+        Ok(height)
+    }
+
+    /// Add `weight` to every ancestor of `start`. A final transaction's
+    /// ancestors are all final too, so the walk stops at final nodes and
+    /// their cumulative weight stays frozen at what made them final; this
+    /// keeps insertion cost proportional to the unsettled part of the DAG.
+    fn add_weight_to_ancestors(&mut self, start: NodeIndex, weight: u64) {
+        let mut seen = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(ix) = queue.pop_front() {
+            let parents: Vec<NodeIndex> = self.graph.neighbors_directed(ix, Direction::Incoming).collect();
+            for p in parents {
+                if !seen.insert(p) || self.graph[p].state == TxState::Final {
+                    continue;
+                }
+                self.graph[p].cumulative_weight = self.graph[p].cumulative_weight.saturating_add(weight);
+                self.update_state(p);
+                queue.push_back(p);
+            }
+        }
+    }
+
+    /// Move a node forward to the state its cumulative weight warrants.
+    /// States only ever advance, and rejected nodes are left alone until
+    /// they are reinstated.
+    fn update_state(&mut self, ix: NodeIndex) {
+        let node = &mut self.graph[ix];
+        if node.state == TxState::Rejected {
+            return;
+        }
+        let state = self.finality.state_for(node.cumulative_weight);
+        if Self::rank(state) > Self::rank(node.state) {
+            node.state = state;
+            self.events.push(DagEvent::StateChanged { id: node.tx.id, state });
+            self.note_final(ix);
+        }
+    }
+
+    /// Remember `ix` as the newest final transaction if it is final and
+    /// newer than the one so far.
+    fn note_final(&mut self, ix: NodeIndex) {
+        let node = &self.graph[ix];
+        if node.state == TxState::Final && self.newest_final.is_none_or(|(seq, _)| node.seq > seq) {
+            self.newest_final = Some((node.seq, node.tx.id));
+        }
+    }
+
+    fn rank(state: TxState) -> u8 {
+        match state {
+            TxState::Pending => 0,
+            TxState::Confirmed => 1,
+            TxState::Final => 2,
+            TxState::Rejected => 0,
+        }
+    }
+
+    fn set_state(&mut self, ix: NodeIndex, state: TxState) {
+        let node = &mut self.graph[ix];
+        if node.state != state {
+            node.state = state;
+            self.events.push(DagEvent::StateChanged { id: node.tx.id, state });
+            self.note_final(ix);
+        }
+    }
+
+    /// Record that `id` belongs to conflict set `set`.
+    pub fn mark_conflict(&mut self, id: &TxId, set: u64) {
+        if let Some(&ix) = self.tx_indices.get(id) {
+            let node = &mut self.graph[ix];
+            if node.conflict != Some(set) {
+                node.conflict = Some(set);
+                self.events.push(DagEvent::Conflict { id: *id, set });
+            }
+        }
+    }
+
+    /// Mark `id` as rejected. Final transactions cannot be rejected.
+    pub fn reject(&mut self, id: &TxId) {
+        if let Some(&ix) = self.tx_indices.get(id) {
+            if self.graph[ix].state != TxState::Final {
+                self.set_state(ix, TxState::Rejected);
+            }
+        }
+    }
+
+    /// Undo `reject`, restoring whatever state `id`'s cumulative weight
+    /// warrants.
+    pub fn reinstate(&mut self, id: &TxId) {
+        if let Some(&ix) = self.tx_indices.get(id) {
+            if self.graph[ix].state == TxState::Rejected {
+                let state = self.finality.state_for(self.graph[ix].cumulative_weight);
+                self.set_state(ix, state);
+            }
+        }
+    }
+
+    pub fn state(&self, id: &TxId) -> Option<TxState> {
+        self.get(id).map(|n| n.state)
+    }
+
+    /// All transactions currently in `state`, in topological order.
+    pub fn with_state(&self, state: TxState) -> Vec<TxId> {
+        self.topological()
+            .into_iter()
+            .filter(|n| n.state == state)
+            .map(|n| n.tx.id)
+            .collect()
+    }
+
+    /// Final transactions that no other final transaction references, in
+    /// id order: the frontier the DAG is final up to. Pruned transactions
+    /// are not counted.
+    pub fn final_tips(&self) -> Vec<TxId> {
+        let mut tips: Vec<TxId> = self
+            .with_state(TxState::Final)
+            .into_iter()
+            .filter(|id| !self.children(id).iter().any(|c| self.state(c) == Some(TxState::Final)))
+            .collect();
+        tips.sort();
+        tips
+    }
+
+    /// The most recently inserted final transaction still in the graph.
+    /// Tracked as states change, so this is cheap to ask for.
+    pub fn newest_final(&self) -> Option<TxId> {
+        self.newest_final.map(|(_, id)| id)
+    }
+
+    /// Find the newest final transaction from scratch, after nodes were
+    /// restored or pruned.
+    fn find_newest_final(&mut self) {
+        self.newest_final = None;
+        let ixs: Vec<NodeIndex> = self.graph.node_indices().collect();
+        for ix in ixs {
+            self.note_final(ix);
+        }
+    }
+
+    /// Whether `id` is final, or was pruned, which only final transactions
+    /// are.
+    pub fn is_final(&self, id: &TxId) -> bool {
+        self.is_pruned(id) || self.state(id) == Some(TxState::Final)
+    }
+
+    fn neighbors(&self, id: &TxId, dir: Direction) -> Vec<TxId> {
+        match self.tx_indices.get(id) {
+            Some(&ix) => self
+                .graph
+                .neighbors_directed(ix, dir)
+                .map(|n| self.graph[n].tx.id)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Every node reachable from `start` in direction `dir`, nearest first.
+    fn reachable_ixs(&self, start: NodeIndex, dir: Direction) -> Vec<NodeIndex> {
+        let mut seen = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        let mut out = Vec::new();
+        while let Some(ix) = queue.pop_front() {
+            for n in self.graph.neighbors_directed(ix, dir) {
+                if seen.insert(n) {
+                    out.push(n);
+                    queue.push_back(n);
+                }
+            }
+        }
+        out
+    }
+
+    fn reachable(&self, id: &TxId, dir: Direction) -> Vec<TxId> {
+        match self.tx_indices.get(id) {
+            Some(&ix) => self
+                .reachable_ixs(ix, dir)
+                .into_iter()
+                .map(|n| self.graph[n].tx.id)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Transactions `id` directly references.
+    pub fn parents(&self, id: &TxId) -> Vec<TxId> {
+        self.neighbors(id, Direction::Incoming)
+    }
+
+    /// Transactions that directly reference `id`.
+    pub fn children(&self, id: &TxId) -> Vec<TxId> {
+        self.neighbors(id, Direction::Outgoing)
+    }
+
+    /// Everything `id` approves, directly or indirectly.
+    pub fn ancestors(&self, id: &TxId) -> Vec<TxId> {
+        self.reachable(id, Direction::Incoming)
+    }
+
+    /// Everything that approves `id`, directly or indirectly.
+    pub fn descendants(&self, id: &TxId) -> Vec<TxId> {
+        self.reachable(id, Direction::Outgoing)
+    }
+
+    /// Transactions nothing references yet, in id order.
+    pub fn tips(&self) -> Vec<TxId> {
+        self.tips.iter().copied().collect()
+    }
+
+    /// Weight of `id` plus every transaction approving it, directly or
+    /// indirectly, up to the point it became final. 0 for unknown
+    /// transactions.
+    pub fn cumulative_weight(&self, id: &TxId) -> u64 {
+        self.get(id).map_or(0, |n| n.cumulative_weight)
+    }
+
+    /// Height of the highest transaction, pruned or not, or `None` for an
+    /// empty graph.
+    pub fn max_height(&self) -> Option<u64> {
+        self.graph
+            .node_weights()
+            .map(|n| n.height)
+            .chain(self.pruned.values().copied())
+            .max()
+    }
+
+    /// Unpruned nodes with heights from `from` up to but excluding `to`, in
+    /// topological order. Stops at the first height boundary after `limit`
+    /// nodes, so a height is never split across calls, and returns the
+    /// height to continue from if it stopped short of `to`.
+    pub fn range(&self, from: u64, to: u64, limit: usize) -> (Vec<&DAGNode>, Option<u64>) {
+        let mut nodes: Vec<&DAGNode> = self
+            .graph
+            .node_weights()
+            .filter(|n| n.height >= from && n.height < to)
+            .collect();
+        nodes.sort_by_key(|n| (n.height, n.tx.id));
+        if nodes.len() <= limit {
+            return (nodes, None);
+        }
+        let last = nodes[limit.max(1) - 1].height;
+        let end = nodes.iter().position(|n| n.height > last).unwrap_or(nodes.len());
+        let next = nodes.get(end).map(|n| n.height);
+        nodes.truncate(end);
+        (nodes, next)
+    }
+
+    /// `id` and its unpruned ancestors, nearest first, up to `limit` of
+    /// them, without walking past anything in `known`; returned in
+    /// topological order.
+    pub fn ancestry(&self, id: &TxId, known: &HashSet<TxId>, limit: usize) -> Vec<&DAGNode> {
+        let start = match self.tx_indices.get(id) {
+            Some(&ix) => ix,
+            None => return Vec::new(),
+        };
+        let mut seen = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        let mut nodes = Vec::new();
+        while let Some(ix) = queue.pop_front() {
+            if nodes.len() >= limit {
+                break;
+            }
+            let node = &self.graph[ix];
+            if ix != start && known.contains(&node.tx.id) {
+                continue;
+            }
+            nodes.push(node);
+            for p in self.graph.neighbors_directed(ix, Direction::Incoming) {
+                if seen.insert(p) {
+                    queue.push_back(p);
+                }
+            }
+        }
+        nodes.sort_by_key(|n| (n.height, n.tx.id));
+        nodes
+    }
+
+    /// Transactions that reference no parents.
+    pub fn roots(&self) -> Vec<TxId> {
+        let mut roots: Vec<&DAGNode> = self
+            .graph
+            .node_indices()
+            .filter(|&ix| {
+                self.graph
+                    .neighbors_directed(ix, Direction::Incoming)
+                    .next()
+                    .is_none()
+            })
+            .map(|ix| &self.graph[ix])
+            .collect();
+        roots.sort_by_key(|n| n.seq);
+        roots.into_iter().map(|n| n.tx.id).collect()
+    }
+
+    pub fn index(&self) -> &TxIndex {
+        &self.index
+    }
+
+    pub fn index_mut(&mut self) -> &mut TxIndex {
+        &mut self.index
+    }
+
+    /// Remove every final transaction inserted before `before_seq` for which
+    /// `keep` is false, other than tips, returning their nodes in
+    /// topological order. Their ancestors are final too, so apart from
+    /// rejected ones and those kept, those go as well. They stay in the
+    /// index; pruned ids nothing left in the graph references are otherwise
+    /// forgotten.
+    pub fn prune(&mut self, before_seq: u64, keep: impl Fn(&TxId) -> bool) -> Vec<DAGNode> {
+        let prunable: Vec<TxId> = self
+            .topological()
+            .into_iter()
+            .filter(|n| n.state == TxState::Final && n.seq < before_seq && !self.tips.contains(&n.tx.id))
+            .filter(|n| !keep(&n.tx.id))
+            .map(|n| n.tx.id)
+            .collect();
+        let mut nodes = Vec::with_capacity(prunable.len());
+        for id in prunable {
+            if let Some(ix) = self.tx_indices.remove(&id) {
+                if let Some(node) = self.graph.remove_node(ix) {
+                    self.pruned.insert(id, node.height);
+                    nodes.push(node);
+                }
+            }
+        }
+        if !nodes.is_empty() {
+            let referenced: HashSet<TxId> = self
+                .graph
+                .node_weights()
+                .flat_map(|n| n.tx.parents.iter())
+                .filter(|p| !self.tx_indices.contains_key(*p))
+                .copied()
+                .collect();
+            self.pruned.retain(|id, _| referenced.contains(id));
+        }
+        if self.newest_final.is_some_and(|(_, id)| !self.contains(&id)) {
+            self.find_newest_final();
+        }
+        nodes
+    }
+
+    /// Rebuild a graph from `nodes` as returned by `topological`, keeping
+    /// their recorded weights and states, on top of the `pruned` ids.
+    pub fn restore(
+        finality: FinalityConfig,
+        nodes: Vec<DAGNode>,
+        tips: Vec<TxId>,
+        pruned: HashMap<TxId, u64>,
+        next_seq: u64,
+    ) -> Self {
+        let mut graph = Self {
+            finality,
+            pruned,
+            next_seq,
+            tips: tips.into_iter().collect(),
+            ..Self::default()
+        };
+        for node in nodes {
+            let id = node.tx.id;
+            let parents = node.tx.parents.clone();
+            graph.index.insert(&node.tx);
+            let ix = graph.graph.add_node(node);
+            for p in parents.iter() {
+                if let Some(&pix) = graph.tx_indices.get(p) {
+                    graph.graph.add_edge(pix, ix, ());
+                }
+            }
+            graph.tx_indices.insert(id, ix);
+        }
+        graph.find_newest_final();
+        graph
+    }
+
+    /// All nodes in a deterministic topological order: by height, then id.
+    /// Every node comes after all of its parents, and any two nodes holding
+    /// the same graph produce the same order regardless of arrival order.
+    pub fn topological(&self) -> Vec<&DAGNode> {
+        let mut nodes: Vec<&DAGNode> = self.graph.node_weights().collect();
+        nodes.sort_by_key(|n| (n.height, n.tx.id));
+        nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{user::OrgUser, OrgId};
+    use crate::Amount;
+
+    fn tx(nonce: u64, parents: Vec<TxId>) -> Transaction {
+        let org = OrgId::new("org");
+        let send = OrgUser::new(org.clone(), "alice".to_string());
+        let recv = OrgUser::new(org, "bob".to_string());
+        let mut tx = Transaction::new(send, recv, "TEST", Amount::from_whole(1, 2).unwrap(), nonce);
+        tx.attach(parents).unwrap();
+        tx
+    }
+
+    fn finality() -> FinalityConfig {
+        FinalityConfig {
+            confirmation_threshold: 2,
+            finality_threshold: 4,
+            validator_weighted: false,
+        }
+    }
+
+    /// A root, two transactions on it and one joining them.
+    fn diamond() -> (TxGraph, [TxId; 4]) {
+        let mut graph = TxGraph::new();
+        let root = tx(0, Vec::new());
+        let (a, b) = (tx(1, vec![root.id]), tx(2, vec![root.id]));
+        let join = tx(3, vec![a.id, b.id]);
+        let ids = [root.id, a.id, b.id, join.id];
+        for t in [root, a, b, join] {
+            graph.insert(t, 1).unwrap();
+        }
+        (graph, ids)
+    }
+
+    fn sorted(mut ids: Vec<TxId>) -> Vec<TxId> {
+        ids.sort();
+        ids
+    }
+
+    /// `tx` is refused with `err`, and the graph is left as it was.
+    fn refused(graph: &mut TxGraph, tx: Transaction, err: DagError) {
+        let len = graph.len();
+        assert_eq!(graph.insert(tx, 1), Err(err));
+        assert_eq!(graph.len(), len);
+    }
+
+    #[test]
+    fn parents_are_what_a_transaction_references() {
+        let (graph, [root, a, b, join]) = diamond();
+        assert_eq!(graph.parents(&root), Vec::new());
+        assert_eq!(graph.parents(&a), vec![root]);
+        assert_eq!(sorted(graph.parents(&join)), sorted(vec![a, b]));
+        assert_eq!(graph.parents(&TxId([9; 32])), Vec::new());
+    }
+
+    #[test]
+    fn children_are_what_references_a_transaction() {
+        let (graph, [root, a, b, join]) = diamond();
+        assert_eq!(sorted(graph.children(&root)), sorted(vec![a, b]));
+        assert_eq!(graph.children(&b), vec![join]);
+        assert_eq!(graph.children(&join), Vec::new());
+    }
+
+    #[test]
+    fn ancestors_are_everything_approved() {
+        let (graph, [root, a, b, join]) = diamond();
+        assert_eq!(sorted(graph.ancestors(&join)), sorted(vec![root, a, b]));
+        assert_eq!(graph.ancestors(&a), vec![root]);
+        assert_eq!(graph.ancestors(&root), Vec::new());
+    }
+
+    #[test]
+    fn descendants_are_everything_approving() {
+        let (graph, [root, a, b, join]) = diamond();
+        assert_eq!(sorted(graph.descendants(&root)), sorted(vec![a, b, join]));
+        assert_eq!(graph.descendants(&a), vec![join]);
+        assert_eq!(graph.descendants(&join), Vec::new());
+    }
+
+    #[test]
+    fn tips_are_what_nothing_references_yet() {
+        let mut graph = TxGraph::new();
+        let root = tx(0, Vec::new());
+        graph.insert(root.clone(), 1).unwrap();
+        assert_eq!(graph.tips(), vec![root.id]);
+        let (a, b) = (tx(1, vec![root.id]), tx(2, vec![root.id]));
+        graph.insert(a.clone(), 1).unwrap();
+        graph.insert(b.clone(), 1).unwrap();
+        assert_eq!(graph.tips(), sorted(vec![a.id, b.id]));
+        let join = tx(3, vec![a.id, b.id]);
+        graph.insert(join.clone(), 1).unwrap();
+        assert_eq!(graph.tips(), vec![join.id]);
+    }
+
+    #[test]
+    fn topological_order_is_by_height_then_id() {
+        let (graph, [root, a, b, join]) = diamond();
+        let order: Vec<TxId> = graph.topological().iter().map(|n| n.tx.id).collect();
+        let mut middle = sorted(vec![a, b]);
+        middle.insert(0, root);
+        middle.push(join);
+        assert_eq!(order, middle);
+    }
+
+    #[test]
+    fn unknown_parents_are_refused() {
+        let (mut graph, _) = diamond();
+        let missing = TxId([9; 32]);
+        refused(&mut graph, tx(4, vec![missing]), DagError::UnknownParent(missing));
+    }
+
+    #[test]
+    fn parents_referenced_twice_are_refused() {
+        let (mut graph, [root, ..]) = diamond();
+        let mut twice = tx(4, Vec::new());
+        twice.parents = vec![root, root];
+        refused(&mut graph, twice, DagError::DuplicateParent(root));
+    }
+
+    #[test]
+    fn too_many_parents_are_refused() {
+        let (mut graph, _) = diamond();
+        let mut crowded = tx(4, Vec::new());
+        crowded.parents = (0..=MAX_PARENTS as u8).map(|n| TxId([n; 32])).collect();
+        refused(&mut graph, crowded, DagError::TooManyParents(MAX_PARENTS + 1));
+    }
+
+    #[test]
+    fn only_the_first_transaction_may_have_no_parents() {
+        let (mut graph, _) = diamond();
+        refused(&mut graph, tx(4, Vec::new()), DagError::NoParents);
+    }
+
+    #[test]
+    fn transactions_referencing_themselves_are_refused() {
+        let (mut graph, [root, ..]) = diamond();
+        let mut own = tx(4, Vec::new());
+        own.parents = vec![root, own.id];
+        let id = own.id;
+        refused(&mut graph, own, DagError::Cycle(id));
+    }
+
+    #[test]
+    fn states_follow_the_thresholds() {
+        let config = finality();
+        assert_eq!(config.state_for(1), TxState::Pending);
+        assert_eq!(config.state_for(2), TxState::Confirmed);
+        assert_eq!(config.state_for(3), TxState::Confirmed);
+        assert_eq!(config.state_for(4), TxState::Final);
+    }
+
+    #[test]
+    fn approvals_move_transactions_to_confirmed_then_final() {
+        let mut graph = TxGraph::with_finality(finality());
+        let root = tx(0, Vec::new());
+        graph.insert(root.clone(), 1).unwrap();
+        assert_eq!(graph.state(&root.id), Some(TxState::Pending));
+        let mut chain = vec![root.id];
+        let mut states = Vec::new();
+        for n in 1..=4 {
+            let next = tx(n, vec![*chain.last().unwrap()]);
+            chain.push(next.id);
+            graph.insert(next, 1).unwrap();
+            states.push((graph.cumulative_weight(&root.id), graph.state(&root.id).unwrap()));
+        }
+        // Frozen once final.
+        assert_eq!(
+            states,
+            vec![
+                (2, TxState::Confirmed),
+                (3, TxState::Confirmed),
+                (4, TxState::Final),
+                (4, TxState::Final),
+            ]
+        );
+        let changes: Vec<TxState> = graph
+            .drain_events()
+            .into_iter()
+            .filter_map(|e| match e {
+                DagEvent::StateChanged { id, state } if id == root.id => Some(state),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(changes, vec![TxState::Confirmed, TxState::Final]);
+        // The next one down has four approving it by now too.
+        assert_eq!(graph.newest_final(), Some(chain[1]));
+        assert_eq!(graph.state(&chain[2]), Some(TxState::Confirmed));
+    }
 
-pub type TxGraph = Graph<Instant, Transaction, Directed, usize>;
+    #[test]
+    fn rejected_transactions_stay_rejected_until_reinstated() {
+        let mut graph = TxGraph::with_finality(finality());
+        let root = tx(0, Vec::new());
+        graph.insert(root.clone(), 1).unwrap();
+        graph.reject(&root.id);
+        graph.insert(tx(1, vec![root.id]), 1).unwrap();
+        assert_eq!(graph.state(&root.id), Some(TxState::Rejected));
+        graph.reinstate(&root.id);
+        assert_eq!(graph.state(&root.id), Some(TxState::Confirmed));
+    }
 
-#[derive(Debug, Clone)]
-pub(crate) struct SparseDAG {
-    pub federations: Vec<NodeIdentifiers>,
-    pub users: Vec<NodeIdentifiers>,
+    #[test]
+    fn cumulative_weight_saturates() {
+        let mut graph = TxGraph::with_finality(FinalityConfig {
+            confirmation_threshold: u64::MAX,
+            finality_threshold: u64::MAX,
+            validator_weighted: true,
+        });
+        let root = tx(0, Vec::new());
+        graph.insert(root.clone(), 1).unwrap();
+        graph.insert(tx(1, vec![root.id]), u64::MAX).unwrap();
+        assert_eq!(graph.cumulative_weight(&root.id), u64::MAX);
+        assert_eq!(graph.state(&root.id), Some(TxState::Final));
+    }
 }
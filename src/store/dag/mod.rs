@@ -1,21 +1,36 @@
 pub mod tree;
 pub mod graph;
+pub mod node;
+pub mod tips;
 
-use petgraph::{
-    graph::{Graph, NodeIndex},
-    visit::{EdgeRef, IntoNodeReferences, NodeRef, Reversed},
-    Direction,
-};
+use data_encoding::HEXLOWER;
 use tokio::{
+    sync::broadcast,
     time::{Duration},
 };
-use crate::{Transaction, Federation, federation::org::OrgId};
+use crate::{Transaction, TxId, Federation, federation::org::{rules::day_of, Org, OrgId, RuleContext}};
+use super::{
+    archive::Archive,
+    checkpoint::CheckpointState,
+    conflict::{ConflictSet, ConflictTracker},
+    index::{Cursor, Page, TxFilter},
+    ledger::Ledger,
+    proof::{BalanceProof, Hash},
+    snapshot::{Snapshot, SnapshotConfig, SnapshotStore},
+    wal::{Lsn, Wal, WalConfig, WalRecord, ARCHIVE_DIR, SNAPSHOT_DIR, WAL_DIR},
+};
+use crate::{consensus::Commit, federation::org::user::OrgUserId, models::Amount};
+pub use self::{
+    graph::{DagError, DagEvent, FinalityConfig, TxGraph},
+    node::{DAGNode, TxState},
+    tips::{OldestFirst, TipSelector, UniformRandom, WeightedRandomWalk},
+};
 use std::{
-    thread,
-    collections::{VecDeque, BTreeMap},
-    sync::{Arc, Mutex,  atomic::{Ordering, AtomicUsize, AtomicBool}}, fmt,
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    time::SystemTime,
+    sync::{Arc, Mutex,  atomic::{Ordering, AtomicU64, AtomicUsize, AtomicBool}}, fmt,
 };
-use serde::{Serialize, Deserialize};
 
 pub static MAX_BLOCK_SIZE_BYTES: usize = 1000000;
 pub static MAX_BLOCK_SIZE_TXS: usize = 1000;
@@ -24,31 +39,167 @@ pub static BLOCK_RESPONSE_FIELD_KEY_SIZE: usize = 1;
 pub static MAX_MIN_MSG_SIZE: usize = MAX_BLOCK_SIZE_BYTES
     + BLOCK_RESPONSE_PREFIX_SIZE
     + BLOCK_RESPONSE_FIELD_KEY_SIZE;
+/// How many tips a transaction submitted without parents is attached to.
+pub static DEFAULT_PARENTS: usize = 2;
+
+/// Capacity of the event channel; slow subscribers miss older events.
+pub static DAG_EVENT_CAPACITY: usize = 1024;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Debug)]
 pub struct DAG {
-    pub nodes: Mutex<Vec<Transaction>>,
-    // pub graph: Arc<Mutex<Graph<Transaction, ()>>>,
-    // pub tx_indices: Arc<Mutex<BTreeMap<Transaction, NodeIndex>>>,
+    pub graph: Mutex<TxGraph>,
+    events: broadcast::Sender<DagEvent>,
+}
+impl Default for DAG {
+    fn default() -> Self {
+        Self::with_graph(TxGraph::new())
+    }
 }
 impl fmt::Display for DAG {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        writeln!(f, "{:?}", self.nodes.lock().unwrap())
+        let graph = self.graph.lock().unwrap();
+        for node in graph.topological() {
+            writeln!(
+                f,
+                "{} [{}] {:?} w={} <- {:?}",
+                node.tx.id, node.height, node.state, node.cumulative_weight, node.tx.parents
+            )?;
+        }
+        Ok(())
     }
 }
 impl DAG {
     pub fn new() -> Arc<DAG> {
-        Arc::new(DAG { 
-            nodes: Mutex::new(Vec::new()) ,
-            // graph: Arc::new(Mutex::new(Graph::<Transaction, ()>::new())),
-            // tx_indices: Arc::new(Mutex::new(BTreeMap::new())),
-        })
+        Arc::new(DAG::default())
+    }
+
+    pub fn with_finality(finality: FinalityConfig) -> Arc<DAG> {
+        Arc::new(Self::with_graph(TxGraph::with_finality(finality)))
+    }
+
+    fn with_graph(graph: TxGraph) -> Self {
+        let (events, _) = broadcast::channel(DAG_EVENT_CAPACITY);
+        Self {
+            graph: Mutex::new(graph),
+            events,
+        }
+    }
+
+    /// Follow insertions and state changes from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<DagEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send any events the graph has accumulated to subscribers.
+    pub fn publish(&self, graph: &mut TxGraph) {
+        for event in graph.drain_events() {
+            // Nobody listening is fine.
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Insert `tx` below its parents with approval `weight`, returning its
+    /// height in the DAG.
+    pub fn push_tx(&self, tx: Transaction, weight: u64) -> Result<u64, DagError> {
+        let mut graph = self.graph.lock().unwrap();
+        let height = graph.insert(tx, weight)?;
+        self.publish(&mut graph);
+        Ok(height)
+    }
+
+    pub fn len(&self) -> usize {
+        self.graph.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.graph.lock().unwrap().is_empty()
+    }
+
+    pub fn contains(&self, id: &TxId) -> bool {
+        self.graph.lock().unwrap().contains(id)
+    }
+
+    pub fn get_tx(&self, id: &TxId) -> Option<Transaction> {
+        self.graph.lock().unwrap().get_tx(id).cloned()
+    }
+
+    /// Whether `id` is in the DAG or was pruned from it.
+    pub fn knows(&self, id: &TxId) -> bool {
+        let graph = self.graph.lock().unwrap();
+        graph.contains(id) || graph.is_pruned(id)
+    }
+
+    /// Whether anything has been pruned, so history below the remaining
+    /// nodes is no longer available here.
+    pub fn has_pruned(&self) -> bool {
+        !self.graph.lock().unwrap().pruned().is_empty()
+    }
+
+    pub fn max_height(&self) -> Option<u64> {
+        self.graph.lock().unwrap().max_height()
+    }
+
+    /// Transactions with heights in `from..to`, parents first, cut at a
+    /// height boundary after `limit`; see `TxGraph::range`.
+    pub fn range(&self, from: u64, to: u64, limit: usize) -> (Vec<Transaction>, Option<u64>) {
+        let graph = self.graph.lock().unwrap();
+        let (nodes, next) = graph.range(from, to, limit);
+        (nodes.into_iter().map(|n| n.tx.clone()).collect(), next)
+    }
+
+    /// `id` and up to `limit` of its nearest ancestors, parents first,
+    /// stopping at anything in `known`; see `TxGraph::ancestry`.
+    pub fn ancestry(&self, id: &TxId, known: &HashSet<TxId>, limit: usize) -> Vec<Transaction> {
+        let graph = self.graph.lock().unwrap();
+        graph.ancestry(id, known, limit).into_iter().map(|n| n.tx.clone()).collect()
+    }
+
+    pub fn tips(&self) -> Vec<TxId> {
+        self.graph.lock().unwrap().tips()
+    }
+
+    pub fn state(&self, id: &TxId) -> Option<TxState> {
+        self.graph.lock().unwrap().state(id)
+    }
+
+    pub fn pending(&self) -> Vec<TxId> {
+        self.graph.lock().unwrap().with_state(TxState::Pending)
+    }
+
+    pub fn confirmed(&self) -> Vec<TxId> {
+        self.graph.lock().unwrap().with_state(TxState::Confirmed)
+    }
+
+    pub fn finalized(&self) -> Vec<TxId> {
+        self.graph.lock().unwrap().with_state(TxState::Final)
     }
 
-    pub fn push_tx(&self, tx: Transaction) -> () {
-        let mut nodes = self.nodes.lock().unwrap();
-        nodes.push(tx);
+    /// The frontier the DAG is final up to; see `TxGraph::final_tips`.
+    pub fn final_tips(&self) -> Vec<TxId> {
+        self.graph.lock().unwrap().final_tips()
     }
+
+    pub fn is_final(&self, id: &TxId) -> bool {
+        self.graph.lock().unwrap().is_final(id)
+    }
+
+    pub fn rejected(&self) -> Vec<TxId> {
+        self.graph.lock().unwrap().with_state(TxState::Rejected)
+    }
+}
+
+/// Where a `StreamingDAG` persists its state.
+#[derive(Debug)]
+pub struct Storage {
+    pub wal: Mutex<Wal>,
+    pub snapshots: SnapshotStore,
+    pub archive: Mutex<Archive>,
+    pub config: SnapshotConfig,
+    /// LSN of the last snapshot written or booted from.
+    last_snapshot: AtomicU64,
+    /// Final transactions inserted before this sequence number were in the
+    /// last snapshot and may be pruned by the next.
+    prune_before: AtomicU64,
 }
 
 #[derive(Debug)]
@@ -57,6 +208,14 @@ pub struct StreamingDAG {
     pub window_size: AtomicUsize,
     pub tx_queue: Arc<Mutex<VecDeque<Transaction>>>,
     pub federation: Arc<Federation>,
+    pub ledger: Arc<Mutex<Ledger>>,
+    pub conflicts: Arc<Mutex<ConflictTracker>>,
+    /// The state as of the last committed checkpoint. Always locked after
+    /// the ledger and the graph.
+    pub checkpoint: Arc<Mutex<CheckpointState>>,
+    pub tip_selector: Box<dyn TipSelector>,
+    /// Where changes are persisted, if anywhere.
+    pub storage: Option<Storage>,
 }
 impl fmt::Display for StreamingDAG {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -82,9 +241,582 @@ impl StreamingDAG {
         Arc::new(Self::new_with_federation(window_size, fed))
     }
 
-    pub async fn confirm_tx(&self, tx: &Transaction) -> () {
-        self.dag.push_tx(tx.clone());
+    /// Use `finality` thresholds instead of the defaults. Must be called
+    /// before any transaction is added or `with_storage`, since it starts a
+    /// fresh DAG.
+    pub fn with_finality(self, finality: FinalityConfig) -> Self {
+        Self {
+            dag: DAG::with_finality(finality),
+            ..self
+        }
+    }
+
+    /// Persist every mint and accepted transaction under `dir`: changes go
+    /// to a write-ahead log, and every so often the whole state is written
+    /// to a snapshot and old history pruned into an archive. Boots from the
+    /// newest snapshot there, if any, then replays the log after it.
+    /// Transactions are not validated again on replay, since they were when
+    /// first logged.
+    pub fn with_storage(
+        mut self,
+        dir: impl AsRef<Path>,
+        wal: WalConfig,
+        config: SnapshotConfig,
+    ) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        let snapshots = SnapshotStore::open(dir.join(SNAPSHOT_DIR))?;
+        let mut from = 1;
+        if let Some((lsn, snapshot)) = snapshots.latest::<Snapshot>()? {
+            log::info!("Booting from snapshot at {}", lsn);
+            self.restore(snapshot)?;
+            from = lsn + 1;
+        }
+        let archive = {
+            let mut graph = self.dag.graph.lock().unwrap();
+            Archive::open(dir.join(ARCHIVE_DIR), wal, graph.index_mut())?
+        };
+        let wal = Wal::open(dir.join(WAL_DIR), wal)?;
+        if wal.first_lsn() > from || wal.next_lsn() < from {
+            anyhow::bail!(
+                "write-ahead log holds {} to {}, which does not continue from {}",
+                wal.first_lsn(),
+                wal.last_lsn(),
+                from - 1
+            );
+        }
+        let records: Vec<(Lsn, WalRecord)> = wal.read_from(from)?;
+        for (lsn, record) in records.iter() {
+            self.replay(record)
+                .map_err(|e| anyhow::anyhow!("replaying wal record {}: {}", lsn, e))?;
+        }
+        log::info!("Replayed {} records from {}", records.len(), wal.dir().display());
+        let next_seq = self.dag.graph.lock().unwrap().next_seq();
+        self.storage = Some(Storage {
+            wal: Mutex::new(wal),
+            snapshots,
+            archive: Mutex::new(archive),
+            config,
+            last_snapshot: AtomicU64::new(from - 1),
+            prune_before: AtomicU64::new(next_seq),
+        });
+        Ok(self)
+    }
+
+    /// Replace all state with `snapshot`'s. Orgs it knows that the
+    /// federation does not are registered, which fails, changing nothing, if
+    /// the federation is already shared.
+    fn restore(&mut self, snapshot: Snapshot) -> anyhow::Result<()> {
+        let missing: Vec<Org> = snapshot
+            .orgs
+            .into_iter()
+            .filter(|org| !self.federation.orgs.iter().any(|o| o.id == org.id))
+            .collect();
+        if !missing.is_empty() {
+            let Some(federation) = Arc::get_mut(&mut self.federation) else {
+                let handles: Vec<&str> = missing.iter().map(|o| o.id.handle.as_str()).collect();
+                anyhow::bail!("cannot register orgs {} from the snapshot in a shared federation", handles.join(", "));
+            };
+            for org in missing {
+                federation.register_org(org);
+            }
+        }
+        let mut graph = self.dag.graph.lock().unwrap();
+        *graph = TxGraph::restore(
+            graph.finality(),
+            snapshot.nodes,
+            snapshot.tips,
+            snapshot.pruned,
+            snapshot.next_seq,
+        );
+        *self.ledger.lock().unwrap() = snapshot.ledger;
+        *self.conflicts.lock().unwrap() = snapshot.conflicts;
+        *self.checkpoint.lock().unwrap() = snapshot.checkpoint;
+        Ok(())
+    }
+
+    /// Write a snapshot of the current state, first pruning final
+    /// transactions that were already in the previous one. Once written,
+    /// snapshots beyond the configured number and the log they cover are
+    /// deleted. Returns the LSN the snapshot was taken at, or `None` without
+    /// storage.
+    pub fn snapshot(&self) -> anyhow::Result<Option<Lsn>> {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return Ok(None),
+        };
+        let mut ledger = self.ledger.lock().unwrap();
+        let mut graph = self.dag.graph.lock().unwrap();
+        let mut conflicts = self.conflicts.lock().unwrap();
+        let mut wal = storage.wal.lock().unwrap();
+        let lsn = wal.last_lsn();
+
+        // Only what the last commit covers goes, so a snapshot of it can
+        // still be exported.
+        let covered = self.checkpoint.lock().unwrap().covered(&graph);
+        let before = storage.prune_before.swap(graph.next_seq(), Ordering::Relaxed);
+        let pruned = graph.prune(before, |id| !covered.contains(id));
+        storage.archive.lock().unwrap().append(&pruned)?;
+        let pruned: Vec<Transaction> = pruned.into_iter().map(|n| n.tx).collect();
+        ledger.settle(&pruned);
+        conflicts.prune(&pruned);
+
+        let snapshot = self.capture(&ledger, &graph, &conflicts);
+        storage.snapshots.save(lsn, &snapshot)?;
+        storage.last_snapshot.store(lsn, Ordering::Relaxed);
+        if let Some(oldest) = storage.snapshots.retain(storage.config.keep)? {
+            wal.truncate_before(oldest + 1)?;
+        }
+        log::info!(
+            "Snapshot at {}: pruned {} transactions, state root {}",
+            lsn,
+            pruned.len(),
+            HEXLOWER.encode(&snapshot.state_root)
+        );
+        Ok(Some(lsn))
+    }
+
+    fn capture(&self, ledger: &Ledger, graph: &TxGraph, conflicts: &ConflictTracker) -> Snapshot {
+        Snapshot {
+            orgs: self.federation.orgs.clone(),
+            ledger: ledger.clone(),
+            state_root: ledger.state_root(),
+            tips: graph.tips(),
+            nodes: graph.topological().into_iter().cloned().collect(),
+            pruned: graph.pruned().clone(),
+            next_seq: graph.next_seq(),
+            conflicts: conflicts.clone(),
+            checkpoint: self.checkpoint.lock().unwrap().clone(),
+        }
+    }
+
+    /// A snapshot at the last committed checkpoint, to send to a node that
+    /// is catching up: the checkpoint's ledger, the unpruned transactions it
+    /// covers, and in `checkpoint` only the commit proving a quorum agreed
+    /// on it. Nothing is pruned or written. Fails before the first commit.
+    pub fn export_snapshot(&self) -> anyhow::Result<Snapshot> {
+        let graph = self.dag.graph.lock().unwrap();
+        let checkpoint = self.checkpoint.lock().unwrap();
+        let commit = match &checkpoint.commit {
+            Some(commit) => commit.clone(),
+            None => anyhow::bail!("No checkpoint has been committed yet"),
+        };
+        let covered = checkpoint.covered(&graph);
+        Ok(Snapshot {
+            orgs: self.federation.orgs.clone(),
+            ledger: checkpoint.ledger.clone(),
+            state_root: checkpoint.ledger.state_root(),
+            tips: commit.checkpoint.tips.clone(),
+            nodes: graph
+                .topological()
+                .into_iter()
+                .filter(|n| covered.contains(&n.tx.id))
+                .cloned()
+                .collect(),
+            pruned: graph.pruned().clone(),
+            next_seq: graph.next_seq(),
+            conflicts: ConflictTracker::new(),
+            checkpoint: CheckpointState {
+                commit: Some(commit),
+                ..CheckpointState::default()
+            },
+        })
     }
+
+    /// Replace the state of an empty DAG with `snapshot`, as received from
+    /// a peer. It must carry a commit signed by a quorum of the federation's
+    /// validators, and the ledger is rebuilt from nothing but the balances
+    /// and nonces that commit's state root covers. Every org it mentions
+    /// must already be registered, and its transactions must all be final
+    /// or rejected, have valid ids and signatures, include the commit's tips and build
+    /// only on each other and the pruned ones. With storage, a local
+    /// snapshot is written at once so the installed state survives a
+    /// restart.
+    pub fn install_snapshot(&self, snapshot: Snapshot) -> anyhow::Result<()> {
+        let commit = match snapshot.checkpoint.commit {
+            Some(commit) => commit,
+            None => anyhow::bail!("snapshot is not at a committed checkpoint"),
+        };
+        commit
+            .verify(&self.federation.genesis, &self.federation.validators)
+            .map_err(|e| anyhow::anyhow!("snapshot {}: {}", commit.checkpoint, e))?;
+        let ledger = snapshot.ledger.rebuilt();
+        if ledger.state_root() != commit.checkpoint.state_root {
+            anyhow::bail!("snapshot ledger does not match the state root of {}", commit.checkpoint);
+        }
+        if let Some(org) = snapshot
+            .orgs
+            .iter()
+            .find(|org| !self.federation.orgs.iter().any(|o| o.id == org.id))
+        {
+            anyhow::bail!("snapshot includes unknown org {}", org.id.to_string());
+        }
+        let covered: HashSet<TxId> = snapshot
+            .nodes
+            .iter()
+            .map(|n| n.tx.id)
+            .chain(snapshot.pruned.keys().copied())
+            .collect();
+        if let Some(tip) = commit.checkpoint.tips.iter().find(|id| !covered.contains(id)) {
+            anyhow::bail!("snapshot is missing {} tip {}", commit.checkpoint, tip);
+        }
+        for node in snapshot.nodes.iter() {
+            self.federation
+                .validate_tx(&node.tx, node.tx.send.id.org_id.clone())
+                .map_err(|e| anyhow::anyhow!("snapshot transaction {}: {}", node.tx.id, e))?;
+            if !matches!(node.state, TxState::Final | TxState::Rejected) {
+                anyhow::bail!("snapshot transaction {} is not settled", node.tx.id);
+            }
+            if let Some(parent) = node.tx.parents.iter().find(|p| !covered.contains(p)) {
+                anyhow::bail!("snapshot transaction {} is missing parent {}", node.tx.id, parent);
+            }
+        }
+        {
+            let mut live = self.ledger.lock().unwrap();
+            let mut graph = self.dag.graph.lock().unwrap();
+            let mut conflicts = self.conflicts.lock().unwrap();
+            let mut checkpoint = self.checkpoint.lock().unwrap();
+            if !graph.is_empty() || !graph.pruned().is_empty() {
+                anyhow::bail!("cannot install a snapshot over a non-empty DAG");
+            }
+            // Conflict sets are not sent, and every one these were in is
+            // settled.
+            let nodes = snapshot
+                .nodes
+                .into_iter()
+                .map(|n| DAGNode { conflict: None, ..n })
+                .collect();
+            *graph = TxGraph::restore(
+                graph.finality(),
+                nodes,
+                commit.checkpoint.tips.clone(),
+                snapshot.pruned,
+                snapshot.next_seq,
+            );
+            *live = ledger.clone();
+            *conflicts = ConflictTracker::new();
+            *checkpoint = CheckpointState {
+                commit: Some(commit),
+                ledger,
+            };
+        }
+        self.snapshot()?;
+        Ok(())
+    }
+
+    /// Move the checkpoint state on to `commit`, once it is checked against
+    /// the federation's validators, follows the last commit and leaves the
+    /// state root it claims. Logged first.
+    pub fn record_commit(&self, commit: Commit) -> anyhow::Result<()> {
+        self.advance_checkpoint(commit, true)
+    }
+
+    fn advance_checkpoint(&self, commit: Commit, log: bool) -> anyhow::Result<()> {
+        commit
+            .verify(&self.federation.genesis, &self.federation.validators)
+            .map_err(|e| anyhow::anyhow!("{}: {}", commit.checkpoint, e))?;
+        let graph = self.dag.graph.lock().unwrap();
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        if let Some(last) = &checkpoint.commit {
+            if commit.checkpoint.parent != last.checkpoint.hash() {
+                anyhow::bail!("{} does not follow {}", commit.checkpoint, last.checkpoint);
+            }
+        }
+        let ledger = checkpoint
+            .advance(&commit.checkpoint.tips, &graph)
+            .map_err(|e| anyhow::anyhow!("{}: {}", commit.checkpoint, e))?;
+        if ledger.state_root() != commit.checkpoint.state_root {
+            anyhow::bail!("{} does not match the state here", commit.checkpoint);
+        }
+        if log {
+            self.log(&WalRecord::Checkpoint(Box::new(commit.clone())))?;
+        }
+        checkpoint.ledger = ledger;
+        checkpoint.commit = Some(commit);
+        Ok(())
+    }
+
+    /// The state root a checkpoint of `tips` would commit to, building on
+    /// the last committed one, or `None` if some transaction they build on
+    /// is not held here or does not apply.
+    pub fn checkpoint_root(&self, tips: &[TxId]) -> Option<Hash> {
+        let graph = self.dag.graph.lock().unwrap();
+        let checkpoint = self.checkpoint.lock().unwrap();
+        match checkpoint.advance(tips, &graph) {
+            Ok(ledger) => Some(ledger.state_root()),
+            Err(e) => {
+                log::debug!("No checkpoint of {} tips: {}", tips.len(), e);
+                None
+            }
+        }
+    }
+
+    /// Validate a transaction received from a peer as `check_tx` does and
+    /// confirm it as is. Unlike `push_tx`, a transaction is never attached
+    /// to local tips, since its parents are part of its id.
+    /// History may be older than `MAX_CLOCK_SKEW`, so only timestamps
+    /// ahead of the clock are refused.
+    pub async fn import_tx(&self, tx: &Transaction) -> anyhow::Result<u64> {
+        let org_id = tx.send.id.org_id.clone();
+        self.confirm_checked(tx, &org_id, tx.timestamp.min(SystemTime::now())).await
+    }
+
+    /// Check `tx` as a validator of `org_id` would before confirming it
+    /// without a quorum of votes: the federation must accept its id and
+    /// signature, and it must break none of the org's rules given the
+    /// current state.
+    pub fn check_tx(&self, tx: &Transaction, org_id: &OrgId) -> anyhow::Result<()> {
+        self.check_tx_at(tx, org_id, SystemTime::now())
+    }
+
+    /// `check_tx` with the clock read as `now`.
+    fn check_tx_at(&self, tx: &Transaction, org_id: &OrgId, now: SystemTime) -> anyhow::Result<()> {
+        self.federation.validate_tx(tx, org_id.clone())?;
+        let ledger = self.ledger.lock().unwrap();
+        self.check_rules(tx, org_id, &ledger, self.dag.contains(&tx.id), now)
+    }
+
+    /// Check `tx` against the rules of `org_id` given `ledger`, with the
+    /// clock read as `now`. `known` is whether `tx` is in the DAG already,
+    /// and so counted in the ledger.
+    fn check_rules(
+        &self,
+        tx: &Transaction,
+        org_id: &OrgId,
+        ledger: &Ledger,
+        known: bool,
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        let org = match self.federation.orgs.iter().find(|o| &o.id == org_id) {
+            Some(org) => org,
+            None => anyhow::bail!("Unknown org {}", org_id.handle),
+        };
+        let mut ctx = self.context_in(tx, ledger, known);
+        ctx.now = now;
+        if let Err(reasons) = org.validate_tx(tx, &ctx) {
+            let reasons: Vec<String> = reasons.iter().map(|r| r.to_string()).collect();
+            anyhow::bail!("Transaction {} breaks the rules of {}: {}", tx.id, org_id.handle, reasons.join("; "));
+        }
+        Ok(())
+    }
+
+    /// Root of the Merkle tree over every current balance.
+    pub fn state_root(&self) -> Hash {
+        self.ledger.lock().unwrap().state_root()
+    }
+
+    /// Prove `user`'s current balance of `symbol` against `state_root`.
+    pub fn prove_balance(&self, user: &OrgUserId, symbol: &str) -> BalanceProof {
+        self.ledger.lock().unwrap().prove_balance(user, symbol)
+    }
+
+    /// Take a snapshot if enough has been logged since the last one.
+    fn maybe_snapshot(&self) {
+        let due = match &self.storage {
+            Some(storage) if storage.config.every > 0 => {
+                let last = storage.last_snapshot.load(Ordering::Relaxed);
+                storage.wal.lock().unwrap().last_lsn() >= last + storage.config.every
+            }
+            _ => false,
+        };
+        if due {
+            if let Err(e) = self.snapshot() {
+                log::error!("Could not write snapshot: {}", e);
+            }
+        }
+    }
+
+    fn replay(&self, record: &WalRecord) -> anyhow::Result<()> {
+        match record {
+            WalRecord::Mint { user, symbol, amt } => {
+                self.ledger.lock().unwrap().mint(user, symbol, *amt)?;
+                self.checkpoint.lock().unwrap().ledger.mint(user, symbol, *amt)?;
+            }
+            WalRecord::Tx { tx, approver } => {
+                self.commit_tx(tx, approver, None, false)?;
+            }
+            WalRecord::Checkpoint(commit) => {
+                self.advance_checkpoint(*commit.clone(), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append `record` to the write-ahead log, if there is one.
+    fn log(&self, record: &WalRecord) -> anyhow::Result<()> {
+        if let Some(storage) = &self.storage {
+            storage.wal.lock().unwrap().append(record)?;
+        }
+        Ok(())
+    }
+
+    /// Create `amt` of `symbol` for `user` outside of any transaction,
+    /// logging it first. The checkpoint state is credited too, so nodes
+    /// only agree on checkpoints if they minted the same.
+    pub fn mint(&self, user: &OrgUserId, symbol: &str, amt: Amount) -> anyhow::Result<()> {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger
+            .balance(user, symbol)
+            .unwrap_or(amt.zeroed())
+            .checked_add(amt)?;
+        self.log(&WalRecord::Mint {
+            user: user.clone(),
+            symbol: symbol.to_string(),
+            amt,
+        })?;
+        ledger.mint(user, symbol, amt)?;
+        self.checkpoint.lock().unwrap().ledger.mint(user, symbol, amt)?;
+        drop(ledger);
+        self.maybe_snapshot();
+        Ok(())
+    }
+
+    /// A page of up to `limit` transactions matching `filter`, oldest
+    /// first, with their current state. Pruned transactions are read back
+    /// from the archive. Pass the returned `next` cursor as `after` to get
+    /// the following page.
+    pub fn query(&self, filter: &TxFilter, after: Option<Cursor>, limit: usize) -> anyhow::Result<Page<DAGNode>> {
+        let graph = self.dag.graph.lock().unwrap();
+        let page = graph.index().query(filter, after, limit);
+        let pruned: Vec<TxId> = page.items.iter().filter(|id| !graph.contains(id)).copied().collect();
+        let mut archived = match &self.storage {
+            Some(storage) if !pruned.is_empty() => storage.archive.lock().unwrap().get(&pruned)?,
+            _ => HashMap::new(),
+        };
+        Ok(Page {
+            items: page
+                .items
+                .iter()
+                .filter_map(|id| graph.get(id).cloned().or_else(|| archived.remove(id)))
+                .collect(),
+            next: page.next,
+        })
+    }
+
+    /// What the org rules need to know to check `tx` against the current
+    /// state: the sender's balance and what they sent on the day `tx` was
+    /// made, not counting `tx` itself.
+    pub fn rule_context(&self, tx: &Transaction) -> RuleContext<'_> {
+        let ledger = self.ledger.lock().unwrap();
+        self.context_in(tx, &ledger, self.dag.contains(&tx.id))
+    }
+
+    fn context_in(&self, tx: &Transaction, ledger: &Ledger, known: bool) -> RuleContext<'_> {
+        let mut ctx = RuleContext::new(&self.federation);
+        let (user, symbol) = (&tx.send.id, &tx.amt.symbol);
+        ctx.balance = ledger.balance(user, symbol);
+        ctx.spent_today = ledger.spent_on(user, symbol, day_of(tx.timestamp));
+        if known {
+            ctx.spent_today = ctx.spent_today.and_then(|s| s.checked_sub(tx.amt.amt).ok());
+        }
+        ctx
+    }
+
+    /// The weight an approval by `org_id` lends to the transactions it
+    /// approves.
+    pub fn approval_weight(&self, org_id: &OrgId) -> u64 {
+        let graph = self.dag.graph.lock().unwrap();
+        if graph.finality().validator_weighted {
+            self.federation.approval_weight(org_id)
+        } else {
+            1
+        }
+    }
+
+    /// Replace the strategy used to attach transactions submitted without
+    /// parents.
+    pub fn with_tip_selector(self, tip_selector: impl TipSelector + 'static) -> Self {
+        Self {
+            tip_selector: Box::new(tip_selector),
+            ..self
+        }
+    }
+
+    /// Ask the configured `TipSelector` for up to `count` tips a new
+    /// transaction should approve.
+    pub fn select_tips(&self, count: usize) -> Vec<TxId> {
+        let graph = self.dag.graph.lock().unwrap();
+        self.tip_selector.select(&graph, count)
+    }
+
+    /// Apply a transaction approved by `approver` to the ledger and insert
+    /// it into the DAG with the approver's weight. The ledger, DAG and
+    /// conflict locks are held throughout, and the DAG checks run first, so
+    /// the three always agree.
+    ///
+    /// A transaction that double spends against unsettled transactions is
+    /// inserted without being applied and joins their conflict set. After
+    /// every insertion, weight may have shifted, so conflicts are resolved
+    /// again and the ledger is rebuilt if any winner changed. A transaction
+    /// that fails the ledger checks without conflicting with anything is
+    /// refused outright. Every check runs before the transaction is written
+    /// to the write-ahead log, and nothing changes in memory until it has
+    /// been. Returns the ledger height afterwards.
+    pub async fn confirm_tx(&self, tx: &Transaction, approver: &OrgId) -> anyhow::Result<u64> {
+        let height = self.commit_tx(tx, approver, None, true)?;
+        self.maybe_snapshot();
+        Ok(height)
+    }
+
+    /// `confirm_tx` for a transaction confirmed without a quorum of votes,
+    /// once it passes `check_tx` as of `now`. The org rules are checked
+    /// with the ledger locked, so two transactions from one sender cannot
+    /// both pass a limit only one of them fits under.
+    async fn confirm_checked(&self, tx: &Transaction, org_id: &OrgId, now: SystemTime) -> anyhow::Result<u64> {
+        self.federation.validate_tx(tx, org_id.clone())?;
+        let height = self.commit_tx(tx, org_id, Some(now), true)?;
+        self.maybe_snapshot();
+        Ok(height)
+    }
+
+    /// With `rules_at`, `tx` must also keep the rules of `approver` as of
+    /// that time.
+    fn commit_tx(
+        &self,
+        tx: &Transaction,
+        approver: &OrgId,
+        rules_at: Option<SystemTime>,
+        log: bool,
+    ) -> anyhow::Result<u64> {
+        let weight = self.approval_weight(approver);
+        let mut ledger = self.ledger.lock().unwrap();
+        let mut graph = self.dag.graph.lock().unwrap();
+        let mut conflicts = self.conflicts.lock().unwrap();
+        graph.check(tx)?;
+        if let Some(now) = rules_at {
+            self.check_rules(tx, approver, &ledger, false, now)?;
+        }
+        let rivals = conflicts.rivals(tx, &graph, &ledger);
+        if rivals.is_empty() {
+            ledger.check(tx)?;
+        }
+        if log {
+            self.log(&WalRecord::Tx {
+                tx: Box::new(tx.clone()),
+                approver: approver.clone(),
+            })?;
+        }
+        if rivals.is_empty() {
+            ledger.apply(tx)?;
+            graph.insert(tx.clone(), weight)?;
+        } else {
+            graph.insert(tx.clone(), weight)?;
+            let set = conflicts.add(tx.id, &rivals, &mut graph);
+            log::info!("Transaction {} conflicts with {:?} (set {})", tx.id, rivals, set);
+        }
+        conflicts.record(tx);
+        let changed = conflicts.resolve(&mut graph);
+        if !changed.is_empty() {
+            conflicts.rebuild(&mut ledger, &mut graph, &changed);
+        }
+        self.dag.publish(&mut graph);
+        Ok(ledger.height())
+    }
+
+    /// Every conflict set seen so far.
+    pub fn conflict_sets(&self) -> Vec<ConflictSet> {
+        self.conflicts.lock().unwrap().sets().cloned().collect()
+    }
+
     // pub fn find_tx(&self, tx: &Transaction) -> bool {
     //     let mut nodes = self.dag.nodes.lock().unwrap();
     //     let mut found = false;
@@ -102,7 +834,12 @@ impl StreamingDAG {
             dag: DAG::new(),
             window_size: AtomicUsize::new(window_size),
             tx_queue: Arc::new(Mutex::new(VecDeque::with_capacity(window_size))),
-            federation: Arc::new(Federation::new(""))
+            federation: Arc::new(Federation::new("")),
+            ledger: Arc::new(Mutex::new(Ledger::new())),
+            conflicts: Arc::new(Mutex::new(ConflictTracker::new())),
+            checkpoint: Arc::new(Mutex::new(CheckpointState::default())),
+            tip_selector: Box::new(WeightedRandomWalk::default()),
+            storage: None,
         }
     }
 
@@ -112,34 +849,303 @@ impl StreamingDAG {
             federation: Arc::new(federation),
             window_size: AtomicUsize::new(window_size),
             tx_queue: Arc::new(Mutex::new(VecDeque::with_capacity(window_size))),
+            ledger: Arc::new(Mutex::new(Ledger::new())),
+            conflicts: Arc::new(Mutex::new(ConflictTracker::new())),
+            checkpoint: Arc::new(Mutex::new(CheckpointState::default())),
+            tip_selector: Box::new(WeightedRandomWalk::default()),
+            storage: None,
         }
 
     }
 
-    pub async fn push_tx(&self, tx: Transaction, org_id: OrgId) {
-        if let Some(sig) = self.federation.validate_tx(&tx, org_id) {
-            let mut txn = Transaction::from(tx.clone());
-            txn.sig = Some(sig);
-            self.dag.push_tx(Transaction::from(tx.clone()));
-            let mut txnqueue = self.tx_queue.lock().unwrap();
-            txnqueue.push_back(txn);
-            if txnqueue.len() > self.window_size.load(Ordering::Relaxed) {
-                txnqueue.pop_front();
+    /// Validate `tx` on behalf of `org_id` and confirm it. Transactions the
+    /// federation rejects (e.g. for a bad signature), that break the org's
+    /// rules, that would overdraw the sender, or that reference unknown
+    /// parents are not added. An
+    /// unsigned transaction submitted without parents is first attached to
+    /// tips chosen by the `tip_selector`, which changes its id; the id it
+    /// was stored under is returned. A signed one must already name its
+    /// parents, since they are covered by the signature.
+    pub async fn push_tx(&self, mut tx: Transaction, org_id: OrgId) -> anyhow::Result<TxId> {
+        if tx.parents.is_empty() && tx.sig.is_none() {
+            let tips = self.select_tips(DEFAULT_PARENTS);
+            if !tips.is_empty() {
+                tx.attach(tips)?;
             }
         }
+        self.confirm_checked(&tx, &org_id, SystemTime::now()).await?;
+        let id = tx.id;
+        let mut txnqueue = self.tx_queue.lock().unwrap();
+        txnqueue.push_back(tx);
+        if txnqueue.len() > self.window_size.load(Ordering::Relaxed) {
+            txnqueue.pop_front();
+        }
+        Ok(id)
     }
 
     pub async fn process_tx(&self, stop: &AtomicBool) {
         while !stop.load(Ordering::Relaxed) {
             let mut txnqueue = self.tx_queue.lock().unwrap();
             if let Some(txn) = txnqueue.pop_front() {
-                println!("Processed transaction: {}{} -> {}{}, {}{}",
+                log::debug!("Processed transaction: {}{} -> {}{}, {}{}",
                          txn.send.get_org_id().to_string(), txn.send.id.handle,
                          txn.recv.get_org_id().to_string(), txn.recv.id.handle,
-                         txn.amt.amt.load(Ordering::Relaxed), txn.amt.symbol);
+                         txn.amt.amt, txn.amt.symbol);
             }
             drop(txnqueue);
             tokio::time::sleep(Duration::from_millis(1000)).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{Checkpoint, ConsensusVote, VoteKind};
+    use crate::federation::org::{
+        user::{KeyPair, OrgUser},
+        Org, OrgRules,
+    };
+    use crate::validate::Validator;
+
+    /// A DAG whose one org caps transactions at 5 TEST and each sender's
+    /// day at 10, with alice funded and holding the key to sign with.
+    fn setup() -> (StreamingDAG, OrgUser, OrgUser, KeyPair) {
+        federated(&KeyPair::generate())
+    }
+
+    /// As `setup`, with the org running one validator, v0, signing with
+    /// `validator`, and every transaction final at once.
+    fn federated(validator: &KeyPair) -> (StreamingDAG, OrgUser, OrgUser, KeyPair) {
+        let mut fed = Federation::new("test");
+        let rules = OrgRules {
+            tx_limit: [("TEST".to_string(), Amount::from_whole(5, 2).unwrap())].into(),
+            daily_limit: [("TEST".to_string(), Amount::from_whole(10, 2).unwrap())].into(),
+            ..OrgRules::default()
+        };
+        let mut org = Org::new_from(fed.id.clone(), "testorg", "test", Vec::new()).with_rules(rules);
+        let key = KeyPair::generate();
+        let alice = org.new_user_with_key("alice".to_string(), key.public());
+        let bob = org.new_user_with_key("bob".to_string(), KeyPair::generate().public());
+        fed.register_validator(Validator::new("v0", org.id.clone(), 1, validator.public().to_string()));
+        fed.register_org(org);
+        let dag = StreamingDAG::new_with_federation(10, fed).with_finality(FinalityConfig {
+            confirmation_threshold: 1,
+            finality_threshold: 1,
+            validator_weighted: false,
+        });
+        dag.mint(&alice.id, "TEST", Amount::from_whole(100, 2).unwrap()).unwrap();
+        (dag, alice, bob, key)
+    }
+
+    /// An empty DAG with `dag`'s orgs and validators and alice's mint.
+    fn peer_of(dag: &StreamingDAG, alice: &OrgUser) -> StreamingDAG {
+        let mut fed = Federation::new("test");
+        fed.register_orgs(&dag.federation.orgs);
+        for v in dag.federation.validators.iter() {
+            fed.register_validator(v.clone());
+        }
+        let peer = StreamingDAG::new_with_federation(10, fed).with_finality(dag.dag.graph.lock().unwrap().finality());
+        peer.mint(&alice.id, "TEST", Amount::from_whole(100, 2).unwrap()).unwrap();
+        peer
+    }
+
+    /// The first checkpoint of `dag`'s final tips, precommitted by v0.
+    fn checkpoint(dag: &StreamingDAG, validator: &KeyPair) -> Commit {
+        let tips = dag.dag.final_tips();
+        let root = dag.checkpoint_root(&tips).unwrap();
+        let checkpoint = Checkpoint::new(0, [0; 32], tips, root);
+        let precommit = ConsensusVote::new(
+            &dag.federation.genesis,
+            VoteKind::Precommit,
+            0,
+            0,
+            Some(checkpoint.hash()),
+            "v0",
+            validator,
+        );
+        Commit {
+            round: 0,
+            checkpoint,
+            precommits: vec![precommit],
+        }
+    }
+
+    fn pay(dag: &StreamingDAG, from: &OrgUser, to: &OrgUser, key: &KeyPair, whole: u64) -> Transaction {
+        let nonce = dag.ledger.lock().unwrap().next_nonce(&from.id);
+        let mut tx = Transaction::new(from.clone(), to.clone(), "TEST", Amount::from_whole(whole, 2).unwrap(), nonce);
+        tx.attach(dag.select_tips(DEFAULT_PARENTS)).unwrap();
+        tx.sign(key);
+        tx
+    }
+
+    #[tokio::test]
+    async fn transactions_must_keep_the_org_rules() {
+        let (dag, alice, bob, key) = setup();
+        let org_id = alice.id.org_id.clone();
+        let over = pay(&dag, &alice, &bob, &key, 6);
+        assert!(dag.push_tx(over.clone(), org_id.clone()).await.is_err());
+        assert!(dag.import_tx(&over).await.is_err());
+        assert!(!dag.dag.contains(&over.id));
+        let within = pay(&dag, &alice, &bob, &key, 5);
+        assert_eq!(dag.import_tx(&within).await.unwrap(), 1);
+        assert_eq!(dag.ledger.lock().unwrap().balance(&bob.id, "TEST"), Some(Amount::from_whole(5, 2).unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_transactions_share_the_daily_limit() {
+        let (dag, alice, bob, key) = setup();
+        let dag = Arc::new(dag);
+        // Each fits under the daily limit of 10 alone, but only two fit together.
+        let txs: Vec<Transaction> = (1..=4)
+            .map(|nonce| {
+                let amt = Amount::from_whole(4, 2).unwrap();
+                let mut tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt, nonce);
+                tx.attach(dag.select_tips(DEFAULT_PARENTS)).unwrap();
+                tx.sign(&key);
+                tx
+            })
+            .collect();
+        let tasks: Vec<_> = txs
+            .into_iter()
+            .map(|tx| {
+                let dag = Arc::clone(&dag);
+                tokio::spawn(async move { dag.import_tx(&tx).await.is_ok() })
+            })
+            .collect();
+        let mut accepted = 0;
+        for task in tasks {
+            accepted += task.await.unwrap() as usize;
+        }
+        assert!(accepted <= 2);
+        let spent = dag.ledger.lock().unwrap().spent_on(&alice.id, "TEST", day_of(SystemTime::now()));
+        assert_eq!(spent, Some(Amount::from_whole(4 * accepted as u64, 2).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn snapshots_install_at_quorum_signed_checkpoints() {
+        let validator = KeyPair::generate();
+        let (src, alice, bob, key) = federated(&validator);
+        for _ in 0..3 {
+            src.import_tx(&pay(&src, &alice, &bob, &key, 2)).await.unwrap();
+        }
+        assert!(src.export_snapshot().is_err());
+        src.record_commit(checkpoint(&src, &validator)).unwrap();
+        let late = pay(&src, &alice, &bob, &key, 1);
+        src.import_tx(&late).await.unwrap();
+        let snapshot = src.export_snapshot().unwrap();
+        assert_eq!(snapshot.nodes.len(), 3);
+
+        let mut forged = snapshot.clone();
+        forged.checkpoint.commit = Some(checkpoint(&src, &KeyPair::generate()));
+        assert!(peer_of(&src, &alice).install_snapshot(forged).is_err());
+        let mut inflated = snapshot.clone();
+        inflated.ledger.mint(&bob.id, "TEST", Amount::from_whole(50, 2).unwrap()).unwrap();
+        inflated.state_root = inflated.ledger.state_root();
+        assert!(peer_of(&src, &alice).install_snapshot(inflated).is_err());
+
+        let dst = peer_of(&src, &alice);
+        dst.install_snapshot(snapshot).unwrap();
+        let balance = |dag: &StreamingDAG| dag.ledger.lock().unwrap().balance(&bob.id, "TEST");
+        assert_eq!(balance(&dst), Some(Amount::from_whole(6, 2).unwrap()));
+        assert!(!dst.dag.knows(&late.id));
+        dst.import_tx(&late).await.unwrap();
+        assert_eq!(balance(&dst), balance(&src));
+        assert_eq!(dst.state_root(), src.state_root());
+    }
+
+    #[tokio::test]
+    async fn checkpoints_skip_the_losers_of_double_spends() {
+        let validator = KeyPair::generate();
+        let (dag, alice, bob, key) = federated(&validator);
+        let dag = dag.with_finality(FinalityConfig {
+            confirmation_threshold: 1,
+            finality_threshold: 3,
+            validator_weighted: false,
+        });
+        let tx = |nonce: u64, whole: u64, parents: Vec<TxId>| {
+            let amt = Amount::from_whole(whole, 2).unwrap();
+            let mut tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt, nonce);
+            tx.attach(parents).unwrap();
+            tx.sign(&key);
+            tx
+        };
+        let root = tx(1, 1, Vec::new());
+        dag.import_tx(&root).await.unwrap();
+        let (a, b) = (tx(2, 2, vec![root.id]), tx(2, 3, vec![root.id]));
+        dag.import_tx(&a).await.unwrap();
+        dag.import_tx(&b).await.unwrap();
+        // The higher id wins on weight, so replaying by id alone would apply
+        // the loser.
+        let (winner, loser) = if a.id > b.id { (a, b) } else { (b, a) };
+        let heavier = tx(3, 1, vec![winner.id]);
+        dag.import_tx(&heavier).await.unwrap();
+        let both = tx(4, 1, vec![loser.id, heavier.id]);
+        dag.import_tx(&both).await.unwrap();
+        for nonce in 5..=6 {
+            dag.import_tx(&tx(nonce, 1, dag.select_tips(DEFAULT_PARENTS))).await.unwrap();
+        }
+        assert_eq!(dag.dag.rejected(), vec![loser.id]);
+        assert_eq!(dag.dag.final_tips(), vec![both.id]);
+
+        dag.record_commit(checkpoint(&dag, &validator)).unwrap();
+        let paid = Amount::from_whole(3, 2).unwrap().checked_add(winner.amt.amt).unwrap();
+        let balance = dag.checkpoint.lock().unwrap().ledger.balance(&bob.id, "TEST");
+        assert_eq!(balance, Some(paid));
+    }
+
+    #[tokio::test]
+    async fn daily_limits_count_pruned_transactions() {
+        let dir = std::env::temp_dir().join(format!("cpr-daily-limit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = |dag: StreamingDAG| {
+            let config = SnapshotConfig { every: 0, keep: 2 };
+            dag.with_storage(&dir, WalConfig::default(), config).unwrap()
+        };
+        let validator = KeyPair::generate();
+        let (dag, alice, bob, key) = federated(&validator);
+        let dag = open(dag);
+        for _ in 0..2 {
+            dag.import_tx(&pay(&dag, &alice, &bob, &key, 4)).await.unwrap();
+        }
+        dag.snapshot().unwrap();
+        dag.snapshot().unwrap();
+        assert!(!dag.dag.has_pruned(), "nothing is pruned before a checkpoint covers it");
+        dag.record_commit(checkpoint(&dag, &validator)).unwrap();
+        dag.snapshot().unwrap();
+        assert!(dag.dag.has_pruned());
+        assert!(dag.import_tx(&pay(&dag, &alice, &bob, &key, 3)).await.is_err());
+        dag.import_tx(&pay(&dag, &alice, &bob, &key, 2)).await.unwrap();
+
+        let reopened = peer_of(&dag, &alice);
+        drop(dag);
+        let dag = open(reopened);
+        assert!(dag.import_tx(&pay(&dag, &alice, &bob, &key, 1)).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn booting_registers_snapshot_orgs_only_in_an_unshared_federation() {
+        let dir = std::env::temp_dir().join(format!("cpr-restore-orgs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = |dag: StreamingDAG| {
+            let config = SnapshotConfig { every: 0, keep: 2 };
+            dag.with_storage(&dir, WalConfig::default(), config)
+        };
+        let (dag, alice, bob, key) = setup();
+        let dag = open(dag).unwrap();
+        dag.import_tx(&pay(&dag, &alice, &bob, &key, 1)).await.unwrap();
+        dag.snapshot().unwrap();
+        let org_id = alice.id.org_id.clone();
+        drop(dag);
+
+        let bare = StreamingDAG::new_with_federation(10, Federation::new("test"));
+        let shared = Arc::clone(&bare.federation);
+        assert!(open(bare).is_err());
+        assert!(shared.orgs.is_empty());
+
+        let dag = open(StreamingDAG::new_with_federation(10, Federation::new("test"))).unwrap();
+        assert!(dag.federation.orgs.iter().any(|o| o.id == org_id));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,50 @@
+use serde::{Deserialize, Serialize};
+
 use crate::Transaction;
 
-#[derive(Clone, Debug, PartialEq)]
+/// How settled a transaction is, judged by the weight approving it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TxState {
+    /// Accepted into the DAG but not yet approved by enough weight.
+    Pending,
+    /// Approved by at least the confirmation threshold.
+    Confirmed,
+    /// Approved by at least the finality threshold; will not be revisited.
+    Final,
+    /// Lost a conflict, or could not be applied once conflicts were
+    /// resolved. Stays in the DAG but is not reflected in the ledger, and
+    /// may be reinstated if weight shifts to it before its rivals settle.
+    Rejected,
+}
+
+/// A transaction as stored in the DAG, along with where it sits.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DAGNode {
-    tx: Transaction,
-    children: Box<Vec<DAGNode>>,
+    pub tx: Transaction,
+    /// Length of the longest path from a root to this node; roots are 0 and
+    /// every node is strictly higher than all of its parents.
+    pub height: u64,
+    /// Order the node was inserted in on this node, starting at 0.
+    pub seq: u64,
+    /// Approval weight this transaction itself carries.
+    pub weight: u64,
+    /// Own weight plus the weight of every transaction approving it.
+    pub cumulative_weight: u64,
+    pub state: TxState,
+    /// The conflict set this transaction belongs to, if it double spends.
+    pub conflict: Option<u64>,
 }
 
 impl DAGNode {
-    pub fn new(tx: Transaction) -> Self {
+    pub fn new(tx: Transaction, height: u64, seq: u64, weight: u64) -> Self {
         Self {
             tx,
-            children: Box::new(Vec::new()),
+            height,
+            seq,
+            weight,
+            cumulative_weight: weight,
+            state: TxState::Pending,
+            conflict: None,
         }
     }
 }
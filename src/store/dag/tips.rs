@@ -0,0 +1,272 @@
+use rand::{seq::SliceRandom, Rng};
+use std::fmt;
+
+use super::graph::TxGraph;
+use super::node::TxState;
+use crate::TxId;
+
+/// Strategy for picking which tips a new transaction should approve.
+pub trait TipSelector: fmt::Debug + Send + Sync {
+    /// Choose up to `count` distinct tips of `graph`. Returns fewer only
+    /// when the graph has fewer tips than asked for.
+    fn select(&self, graph: &TxGraph, count: usize) -> Vec<TxId>;
+}
+
+/// Every tip is equally likely to be picked.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UniformRandom;
+
+impl TipSelector for UniformRandom {
+    fn select(&self, graph: &TxGraph, count: usize) -> Vec<TxId> {
+        let tips = graph.tips();
+        tips.choose_multiple(&mut rand::thread_rng(), count)
+            .copied()
+            .collect()
+    }
+}
+
+/// Picks the tips that have waited longest for an approval, so nothing is
+/// left behind. Fully deterministic.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OldestFirst;
+
+impl TipSelector for OldestFirst {
+    fn select(&self, graph: &TxGraph, count: usize) -> Vec<TxId> {
+        let mut tips = graph.tips();
+        tips.sort_by_key(|id| graph.get(id).map(|n| n.seq));
+        tips.truncate(count);
+        tips
+    }
+}
+
+/// Markov chain Monte Carlo walk towards the tips, starting from the newest
+/// final transaction, or from the oldest root while nothing is final yet.
+/// Starting there keeps each walk as long as the unsettled part of the DAG
+/// rather than all of it. At each step the walker moves to a child with
+/// probability proportional to `exp(alpha * cumulative_weight)`, so it
+/// tends to follow the heavily approved part of the DAG and ends on tips
+/// that are unlikely to be orphaned. `alpha == 0` degenerates into an
+/// unbiased random walk; larger values follow the heaviest branch more
+/// strictly. Rejected transactions are never walked into, so a walk whose
+/// every way on is rejected stops short of the tips.
+///
+/// If the walks keep landing on tips already picked, the rest are made up
+/// with other tips that are not rejected, chosen at random, so as many as
+/// asked for are returned whenever the graph has them.
+#[derive(Debug, Clone, Copy)]
+pub struct WeightedRandomWalk {
+    pub alpha: f64,
+}
+
+impl Default for WeightedRandomWalk {
+    fn default() -> Self {
+        Self { alpha: 0.5 }
+    }
+}
+
+impl WeightedRandomWalk {
+    pub fn new(alpha: f64) -> Self {
+        Self { alpha }
+    }
+
+    fn walk(&self, graph: &TxGraph, start: TxId, rng: &mut impl Rng) -> TxId {
+        let mut cur = start;
+        loop {
+            let children: Vec<TxId> = graph
+                .children(&cur)
+                .into_iter()
+                .filter(|c| graph.state(c) != Some(TxState::Rejected))
+                .collect();
+            if children.is_empty() {
+                return cur;
+            }
+            let weights: Vec<f64> = children
+                .iter()
+                .map(|c| graph.cumulative_weight(c) as f64)
+                .collect();
+            let max = weights.iter().cloned().fold(f64::MIN, f64::max);
+            // Subtract the max before exponentiating to stay finite.
+            let scores: Vec<f64> = weights
+                .iter()
+                .map(|w| (self.alpha * (w - max)).exp())
+                .collect();
+            let mut pick = rng.gen::<f64>() * scores.iter().sum::<f64>();
+            cur = children[children.len() - 1];
+            for (child, score) in children.iter().zip(scores) {
+                if pick < score {
+                    cur = *child;
+                    break;
+                }
+                pick -= score;
+            }
+        }
+    }
+}
+
+impl TipSelector for WeightedRandomWalk {
+    fn select(&self, graph: &TxGraph, count: usize) -> Vec<TxId> {
+        let Some(start) = graph.newest_final().or_else(|| graph.roots().first().copied()) else {
+            return Vec::new();
+        };
+        let tips: Vec<TxId> = graph
+            .tips()
+            .into_iter()
+            .filter(|t| graph.state(t) != Some(TxState::Rejected))
+            .collect();
+        let want = count.min(tips.len());
+        let mut rng = rand::thread_rng();
+        let mut picked = Vec::with_capacity(want);
+        // Walks can land on the same tip; give up on duplicates eventually.
+        let mut attempts = count * 4;
+        while picked.len() < want && attempts > 0 {
+            attempts -= 1;
+            let tip = self.walk(graph, start, &mut rng);
+            if !picked.contains(&tip) {
+                picked.push(tip);
+            }
+        }
+        let rest: Vec<TxId> = tips.into_iter().filter(|t| !picked.contains(t)).collect();
+        picked.extend(rest.choose_multiple(&mut rng, want - picked.len()));
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{user::OrgUser, OrgId};
+    use crate::store::dag::FinalityConfig;
+    use crate::{Amount, Transaction};
+
+    fn tx(nonce: u64, parents: Vec<TxId>) -> Transaction {
+        let org = OrgId::new("org");
+        let send = OrgUser::new(org.clone(), "alice".to_string());
+        let recv = OrgUser::new(org, "bob".to_string());
+        let mut tx = Transaction::new(send, recv, "TEST", Amount::from_whole(1, 2).unwrap(), nonce);
+        tx.attach(parents).unwrap();
+        tx
+    }
+
+    /// A chain of `len` transactions, each final once two more approve it,
+    /// with `fan` tips approving its end.
+    fn graph(len: u64, fan: u64) -> (TxGraph, Vec<TxId>) {
+        let mut graph = TxGraph::with_finality(FinalityConfig {
+            confirmation_threshold: 2,
+            finality_threshold: 3,
+            validator_weighted: false,
+        });
+        let mut chain = Vec::new();
+        for n in 0..len {
+            let t = tx(n, chain.last().copied().into_iter().collect());
+            chain.push(t.id);
+            graph.insert(t, 1).unwrap();
+        }
+        for n in 0..fan {
+            graph.insert(tx(len + n, vec![*chain.last().unwrap()]), 1).unwrap();
+        }
+        (graph, chain)
+    }
+
+    #[test]
+    fn walks_start_from_the_newest_final_transaction() {
+        let (mut graph, chain) = graph(10, 1);
+        // The end of the chain has too few approvals to be final.
+        assert_eq!(graph.newest_final(), Some(chain[8]));
+        graph.prune(5, |_| false);
+        // Only the pruned transaction the rest still approve is remembered.
+        assert!(graph.is_pruned(&chain[4]));
+        assert!(!graph.is_pruned(&chain[0]));
+        assert_eq!(graph.pruned().len(), 1);
+        assert_eq!(graph.newest_final(), Some(chain[8]));
+        graph.prune(u64::MAX, |_| false);
+        assert_eq!(graph.newest_final(), None);
+    }
+
+    #[test]
+    fn returns_as_many_tips_as_asked_for() {
+        let (graph, _) = graph(5, 6);
+        // With a huge alpha every walk ends on the same tip, so all but one
+        // of them come from the top-up.
+        let walk = WeightedRandomWalk::new(1e9);
+        let mut picked = walk.select(&graph, 4);
+        assert_eq!(picked.len(), 4);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked.len(), 4);
+        assert!(picked.iter().all(|t| graph.tips().contains(t)));
+        assert_eq!(walk.select(&graph, 10).len(), 6);
+        assert!(walk.select(&TxGraph::new(), 2).is_empty());
+    }
+
+    #[test]
+    fn walks_favour_the_heavier_branch() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut graph = TxGraph::with_finality(FinalityConfig {
+            confirmation_threshold: u64::MAX,
+            finality_threshold: u64::MAX,
+            validator_weighted: false,
+        });
+        let root = tx(0, Vec::new());
+        graph.insert(root.clone(), 1).unwrap();
+        // The heavy branch has three more approving it than the light one.
+        let mut heavy = vec![root.id];
+        for n in 1..=4 {
+            let t = tx(n, vec![*heavy.last().unwrap()]);
+            heavy.push(t.id);
+            graph.insert(t, 1).unwrap();
+        }
+        let light = tx(5, vec![root.id]);
+        graph.insert(light.clone(), 1).unwrap();
+
+        let walk = WeightedRandomWalk::new(0.5);
+        let mut rng = StdRng::seed_from_u64(7);
+        let ends: Vec<TxId> = (0..1000).map(|_| walk.walk(&graph, root.id, &mut rng)).collect();
+        let on_heavy = ends.iter().filter(|t| **t == heavy[4]).count();
+        assert_eq!(on_heavy + ends.iter().filter(|t| **t == light.id).count(), 1000);
+        // exp(0.5 * 4) against exp(0.5 * 1): about 82% of walks.
+        assert!(on_heavy > 750, "{} of 1000 walks took the heavy branch", on_heavy);
+
+        // Once rejected, the heavy branch is never walked into.
+        graph.reject(&heavy[1]);
+        assert!((0..100).all(|_| walk.walk(&graph, root.id, &mut rng) == light.id));
+        // Nor is a rejected tip picked to make up the count.
+        graph.reinstate(&heavy[1]);
+        graph.reject(&light.id);
+        assert_eq!(walk.select(&graph, 2), vec![heavy[4]]);
+    }
+
+    #[test]
+    fn uniform_random_picks_distinct_tips_from_all_of_them() {
+        let (graph, _) = graph(5, 6);
+        let tips = graph.tips();
+        let mut picked = UniformRandom.select(&graph, 4);
+        assert_eq!(picked.len(), 4);
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked.len(), 4);
+        assert!(picked.iter().all(|t| tips.contains(t)));
+        assert_eq!(UniformRandom.select(&graph, 10).len(), 6);
+        assert!(UniformRandom.select(&TxGraph::new(), 2).is_empty());
+        // Every tip turns up eventually.
+        let seen: std::collections::HashSet<TxId> = (0..200).flat_map(|_| UniformRandom.select(&graph, 1)).collect();
+        assert_eq!(seen.len(), tips.len());
+    }
+
+    #[test]
+    fn oldest_first_picks_the_longest_waiting_tips() {
+        let (mut graph, chain) = graph(3, 0);
+        let end = *chain.last().unwrap();
+        let fan: Vec<Transaction> = (3..6).map(|n| tx(n, vec![end])).collect();
+        for t in fan.iter() {
+            graph.insert(t.clone(), 1).unwrap();
+        }
+        assert_eq!(OldestFirst.select(&graph, 2), vec![fan[0].id, fan[1].id]);
+        // Once approved, the oldest is no longer a tip and the newest
+        // waits behind the rest.
+        let approval = tx(6, vec![fan[0].id]);
+        graph.insert(approval.clone(), 1).unwrap();
+        assert_eq!(OldestFirst.select(&graph, 3), vec![fan[1].id, fan[2].id, approval.id]);
+        assert_eq!(OldestFirst.select(&graph, 10).len(), 3);
+    }
+}
@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    ops::Bound,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    federation::org::{user::OrgUserId, OrgId},
+    models::Symbol,
+    Transaction, TxId,
+};
+
+/// Most results a single page may hold.
+pub static MAX_PAGE_SIZE: usize = 500;
+
+/// Where a transaction sorts in every index: by timestamp, then id, so
+/// pages come back in a stable order even when timestamps collide. Also
+/// used as the position to resume a paginated query after.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cursor {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub id: TxId,
+}
+
+impl Cursor {
+    pub fn of(tx: &Transaction) -> Self {
+        Self {
+            timestamp: millis(tx.timestamp),
+            id: tx.id,
+        }
+    }
+
+    /// Sorts before every transaction at or after `time`.
+    fn start(time: SystemTime) -> Self {
+        Self {
+            timestamp: millis(time),
+            id: TxId::default(),
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// `<timestamp>-<id hex>`, the same form `FromStr` reads back.
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.timestamp, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timestamp, id) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("cursor must be <timestamp>-<id>, got {}", s))?;
+        Ok(Self {
+            timestamp: timestamp.parse()?,
+            id: id.parse()?,
+        })
+    }
+}
+
+/// Which transactions a query selects.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TxFilter {
+    All,
+    Sender(OrgUserId),
+    Receiver(OrgUserId),
+    /// Sent or received by the user.
+    User(OrgUserId),
+    /// Sent or received by any user of the org.
+    Org(OrgId),
+    Symbol(Symbol),
+    /// Timestamped at or after `from` and before `to`.
+    Between { from: SystemTime, to: SystemTime },
+}
+
+/// One page of query results, oldest first. `next` is where to resume for
+/// the page after, or `None` if this was the last.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<Cursor>,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next: self.next,
+        }
+    }
+}
+
+static EMPTY: BTreeSet<Cursor> = BTreeSet::new();
+
+/// Secondary indexes over the transactions in the DAG, so a user's, org's
+/// or symbol's history can be found without scanning every node. Pruned
+/// transactions keep their entries, so history does not end at the last
+/// snapshot.
+#[derive(Debug, Default)]
+pub struct TxIndex {
+    by_sender: HashMap<OrgUserId, BTreeSet<Cursor>>,
+    by_receiver: HashMap<OrgUserId, BTreeSet<Cursor>>,
+    by_user: HashMap<OrgUserId, BTreeSet<Cursor>>,
+    by_org: HashMap<OrgId, BTreeSet<Cursor>>,
+    by_symbol: HashMap<Symbol, BTreeSet<Cursor>>,
+    by_time: BTreeSet<Cursor>,
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_time.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_time.is_empty()
+    }
+
+    pub fn insert(&mut self, tx: &Transaction) {
+        let key = Cursor::of(tx);
+        self.by_sender.entry(tx.send.id.clone()).or_default().insert(key);
+        self.by_receiver.entry(tx.recv.id.clone()).or_default().insert(key);
+        self.by_user.entry(tx.send.id.clone()).or_default().insert(key);
+        self.by_user.entry(tx.recv.id.clone()).or_default().insert(key);
+        self.by_org.entry(tx.send.id.org_id.clone()).or_default().insert(key);
+        self.by_org.entry(tx.recv.id.org_id.clone()).or_default().insert(key);
+        self.by_symbol.entry(tx.amt.symbol.clone()).or_default().insert(key);
+        self.by_time.insert(key);
+    }
+
+    pub fn remove(&mut self, tx: &Transaction) {
+        let key = Cursor::of(tx);
+        remove_from(&mut self.by_sender, &tx.send.id, &key);
+        remove_from(&mut self.by_receiver, &tx.recv.id, &key);
+        remove_from(&mut self.by_user, &tx.send.id, &key);
+        remove_from(&mut self.by_user, &tx.recv.id, &key);
+        remove_from(&mut self.by_org, &tx.send.id.org_id, &key);
+        remove_from(&mut self.by_org, &tx.recv.id.org_id, &key);
+        remove_from(&mut self.by_symbol, &tx.amt.symbol, &key);
+        self.by_time.remove(&key);
+    }
+
+    /// Up to `limit` (capped at `MAX_PAGE_SIZE`) ids matching `filter`,
+    /// oldest first, starting after `after` if given.
+    pub fn query(&self, filter: &TxFilter, after: Option<Cursor>, limit: usize) -> Page<TxId> {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE);
+        let (set, from, to) = match filter {
+            TxFilter::All => (&self.by_time, None, None),
+            TxFilter::Sender(u) => (self.by_sender.get(u).unwrap_or(&EMPTY), None, None),
+            TxFilter::Receiver(u) => (self.by_receiver.get(u).unwrap_or(&EMPTY), None, None),
+            TxFilter::User(u) => (self.by_user.get(u).unwrap_or(&EMPTY), None, None),
+            TxFilter::Org(o) => (self.by_org.get(o).unwrap_or(&EMPTY), None, None),
+            TxFilter::Symbol(s) => (self.by_symbol.get(s).unwrap_or(&EMPTY), None, None),
+            TxFilter::Between { from, to } => {
+                (&self.by_time, Some(Cursor::start(*from)), Some(Cursor::start(*to)))
+            }
+        };
+        if matches!((from, to), (Some(from), Some(to)) if from >= to) {
+            return Page { items: Vec::new(), next: None };
+        }
+        let lower = match (after, from) {
+            (Some(after), Some(from)) if after < from => Bound::Included(from),
+            (Some(after), _) => Bound::Excluded(after),
+            (None, Some(from)) => Bound::Included(from),
+            (None, None) => Bound::Unbounded,
+        };
+        let upper = to.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut keys: Vec<Cursor> = set.range((lower, upper)).take(limit + 1).copied().collect();
+        let next = if keys.len() > limit {
+            keys.truncate(limit);
+            keys.last().copied()
+        } else {
+            None
+        };
+        Page {
+            items: keys.into_iter().map(|k| k.id).collect(),
+            next,
+        }
+    }
+}
+
+fn remove_from<K: std::hash::Hash + Eq>(index: &mut HashMap<K, BTreeSet<Cursor>>, key: &K, cursor: &Cursor) {
+    if let Some(set) = index.get_mut(key) {
+        set.remove(cursor);
+        if set.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::user::OrgUser;
+    use crate::Amount;
+    use std::time::Duration;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    fn tx(sender: &str, ms: u64) -> Transaction {
+        let org = OrgId::new("org");
+        let send = OrgUser::new(org.clone(), sender.to_string());
+        let recv = OrgUser::new(org, "carol".to_string());
+        let mut tx = Transaction::new(send, recv, "TEST", Amount::from_whole(1, 2).unwrap(), ms);
+        tx.timestamp = at(ms);
+        tx
+    }
+
+    /// An index over one transaction per timestamp in `times`, with the
+    /// cursors it should hold in order.
+    fn indexed(times: &[u64]) -> (TxIndex, Vec<Cursor>) {
+        let mut index = TxIndex::new();
+        let mut cursors = Vec::new();
+        for ms in times {
+            let tx = tx("alice", *ms);
+            index.insert(&tx);
+            cursors.push(Cursor::of(&tx));
+        }
+        cursors.sort();
+        (index, cursors)
+    }
+
+    fn ids(cursors: &[Cursor]) -> Vec<TxId> {
+        cursors.iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn pages_resume_after_their_cursor() {
+        // Two share a timestamp, so they are told apart by id.
+        let (index, cursors) = indexed(&[5, 1, 4, 2, 4, 3, 7]);
+        let first = index.query(&TxFilter::All, None, 3);
+        assert_eq!(first.items, ids(&cursors[..3]));
+        assert_eq!(first.next, Some(cursors[2]));
+        let second = index.query(&TxFilter::All, first.next, 3);
+        assert_eq!(second.items, ids(&cursors[3..6]));
+        assert_eq!(second.next, Some(cursors[5]));
+        let last = index.query(&TxFilter::All, second.next, 3);
+        assert_eq!(last.items, ids(&cursors[6..]));
+        assert_eq!(last.next, None);
+        // A page that ends exactly at the last entry is the last.
+        let (index, cursors) = indexed(&[1, 2, 3]);
+        assert_eq!(index.query(&TxFilter::All, None, 3).next, None);
+        assert_eq!(index.query(&TxFilter::All, None, 0).items, ids(&cursors[..1]));
+        assert!(index.query(&TxFilter::All, Some(cursors[2]), 3).items.is_empty());
+    }
+
+    #[test]
+    fn between_includes_from_and_excludes_to() {
+        let (index, cursors) = indexed(&[1, 2, 3, 4, 5]);
+        let between = |from, to| TxFilter::Between { from: at(from), to: at(to) };
+        assert_eq!(index.query(&between(2, 4), None, 10).items, ids(&cursors[1..3]));
+        assert!(index.query(&between(4, 4), None, 10).items.is_empty());
+        assert!(index.query(&between(5, 2), None, 10).items.is_empty());
+        // A cursor before the range starts at its beginning, one inside it
+        // resumes after itself.
+        assert_eq!(index.query(&between(2, 5), Some(cursors[0]), 10).items, ids(&cursors[1..4]));
+        let page = index.query(&between(2, 5), None, 2);
+        assert_eq!(page.next, Some(cursors[2]));
+        let rest = index.query(&between(2, 5), page.next, 2);
+        assert_eq!(rest.items, ids(&cursors[3..4]));
+        assert_eq!(rest.next, None);
+    }
+
+    #[test]
+    fn filters_by_sender() {
+        let mut index = TxIndex::new();
+        let (a, b) = (tx("alice", 1), tx("bob", 2));
+        index.insert(&a);
+        index.insert(&b);
+        assert_eq!(index.query(&TxFilter::Sender(b.send.id.clone()), None, 10).items, [b.id]);
+        assert_eq!(index.query(&TxFilter::Receiver(a.recv.id.clone()), None, 10).items, [a.id]);
+        index.remove(&b);
+        assert!(index.query(&TxFilter::Sender(b.send.id.clone()), None, 10).items.is_empty());
+        assert_eq!(index.len(), 1);
+    }
+}
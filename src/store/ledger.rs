@@ -0,0 +1,705 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use super::proof::{account_key, nonce_key, spent_key, BalanceProof, Hash, MerkleTree};
+use crate::{
+    federation::org::{rules::day_of, user::OrgUserId},
+    models::{Amount, AmountError, Symbol},
+    Transaction, TxId,
+};
+
+/// Days before the latest a sender has spent on whose spending is still
+/// counted. Counts for earlier days are dropped.
+pub static SPENT_DAYS_KEPT: u64 = 7;
+
+/// One of the values a ledger's state root commits to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LedgerEntry {
+    Balance(Account, Amount),
+    Nonce(OrgUserId, u64),
+    /// What an account spent on a day.
+    Spent(Account, u64, Amount),
+}
+
+/// A single user's holding of a single symbol.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub user: OrgUserId,
+    pub symbol: Symbol,
+}
+
+impl Account {
+    pub fn new(user: &OrgUserId, symbol: &str) -> Self {
+        Self {
+            user: user.clone(),
+            symbol: symbol.to_string(),
+        }
+    }
+}
+
+/// Current balance of an account plus the balances it has held since the
+/// last transactions were settled, keyed by the ledger height at which it
+/// changed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct AccountState {
+    balance: Amount,
+    history: Vec<(u64, Amount)>,
+}
+
+impl AccountState {
+    fn set(&mut self, height: u64, balance: Amount) {
+        self.balance = balance;
+        match self.history.last_mut() {
+            Some((h, amt)) if *h == height => *amt = balance,
+            _ => self.history.push((height, balance)),
+        }
+    }
+
+    /// Forget balances recorded before `height`, other than the one held
+    /// at it.
+    fn trim(&mut self, height: u64) {
+        let held = self.history.partition_point(|(h, _)| *h <= height);
+        self.history.drain(..held.saturating_sub(1));
+    }
+
+    /// Forget balances recorded above `height`, keeping the current one.
+    fn rewind(&mut self, height: u64) {
+        let keep = self.history.partition_point(|(h, _)| *h <= height);
+        if keep < self.history.len() {
+            self.history.truncate(keep);
+            if self.history.last().map(|(_, b)| *b) != Some(self.balance) {
+                self.set(height, self.balance);
+            }
+        }
+    }
+
+    fn at(&self, height: u64) -> Option<Amount> {
+        let idx = self.history.partition_point(|(h, _)| *h <= height);
+        idx.checked_sub(1).map(|i| self.history[i].1)
+    }
+}
+
+/// What applying a transaction changed, kept until it is settled so that
+/// it can be undone.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Applied {
+    tx: TxId,
+    height: u64,
+    from: Account,
+    to: Account,
+    amt: Amount,
+    /// The sender's last nonce before, if they had sent anything.
+    prev_nonce: Option<u64>,
+    /// The day the transaction was made, its amount counted as spent on.
+    day: u64,
+    /// The latest day the sender had spent on before, if any, and the
+    /// counts that fell out of `SPENT_DAYS_KEPT` when the transaction moved
+    /// it on, put back when it is undone.
+    #[serde(default)]
+    prev_day: Option<u64>,
+    #[serde(default)]
+    dropped: Vec<(u64, Amount)>,
+    /// Accounts the transaction created, removed again when it is undone
+    /// so the state root is as if it never happened.
+    #[serde(default)]
+    opened: Vec<Account>,
+}
+
+/// A transaction reused a nonce its sender has already spent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NonceError {
+    /// The nonce is exactly the last one accepted: a resubmission or replay.
+    Duplicate { nonce: u64 },
+    /// The nonce is lower than the last one accepted.
+    Stale { last: u64, found: u64 },
+    /// The nonce is `u64::MAX`, after which the sender could send nothing
+    /// more.
+    Exhausted,
+}
+
+impl fmt::Display for NonceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplicate { nonce } => write!(f, "nonce {} already used", nonce),
+            Self::Stale { last, found } => {
+                write!(f, "stale nonce {}, last accepted was {}", found, last)
+            }
+            Self::Exhausted => write!(f, "nonce {} is reserved", u64::MAX),
+        }
+    }
+}
+
+impl std::error::Error for NonceError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LedgerError {
+    Amount(AmountError),
+    Nonce(NonceError),
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Amount(e) => write!(f, "{}", e),
+            Self::Nonce(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+impl From<AmountError> for LedgerError {
+    fn from(e: AmountError) -> Self {
+        Self::Amount(e)
+    }
+}
+
+impl From<NonceError> for LedgerError {
+    fn from(e: NonceError) -> Self {
+        Self::Nonce(e)
+    }
+}
+
+/// The balance state machine for a federation. Every confirmed transaction
+/// is applied exactly once, in DAG order, and bumps the ledger height by one;
+/// the height therefore doubles as the position of that transaction in the
+/// confirmed DAG and can be used to ask what a balance was at that point.
+///
+/// The ledger also remembers the last nonce it accepted from each sender and
+/// only applies transactions with a higher one, so a transaction can never
+/// be charged twice however often it is resubmitted.
+///
+/// Transactions applied since the last were settled can be undone with
+/// `rewind`, newest first, so conflict resolution only has to replay what
+/// comes after the first transaction it changed its mind about. Nonces may skip ahead:
+/// a transaction that loses a double-spend, or is dropped for overdrawing,
+/// is never applied, and requiring `last + 1` would leave every later
+/// transaction from its sender waiting forever on the nonce it used.
+///
+/// What each sender spends of each symbol is counted per day the
+/// transactions were made on, for `OrgRules::daily_limit`, back to
+/// `SPENT_DAYS_KEPT` days before the latest that sender has spent on. Each
+/// sender's days are kept apart, so a transaction dated ahead only drops
+/// its own sender's counts.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Ledger {
+    accounts: HashMap<Account, AccountState>,
+    nonces: HashMap<OrgUserId, u64>,
+    spent: HashMap<(Account, u64), Amount>,
+    /// The latest day each account has spent on.
+    days: HashMap<Account, u64>,
+    height: u64,
+    /// Transactions applied and not settled yet, in the order they were
+    /// applied.
+    #[serde(default)]
+    applied: Vec<Applied>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether nothing has been minted or applied yet.
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty() && self.height == 0
+    }
+
+    /// Number of transactions applied so far.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Current balance of `user` in `symbol`, if they have ever held any.
+    pub fn balance(&self, user: &OrgUserId, symbol: &str) -> Option<Amount> {
+        self.accounts
+            .get(&Account::new(user, symbol))
+            .map(|a| a.balance)
+    }
+
+    /// Balance of `user` in `symbol` once the first `height` transactions
+    /// had been applied. History is only kept back to the last settled
+    /// transactions, so asking about heights before then may give `None`.
+    pub fn balance_at(&self, user: &OrgUserId, symbol: &str, height: u64) -> Option<Amount> {
+        self.accounts
+            .get(&Account::new(user, symbol))
+            .and_then(|a| a.at(height))
+    }
+
+    /// The last nonce accepted from `user`, or 0 if they never sent anything.
+    pub fn last_nonce(&self, user: &OrgUserId) -> u64 {
+        self.nonces.get(user).copied().unwrap_or(0)
+    }
+
+    /// What `user` sent in `symbol` in the transactions made on `day`, if
+    /// anything, or `None` once the day is too long ago to be counted.
+    pub fn spent_on(&self, user: &OrgUserId, symbol: &str, day: u64) -> Option<Amount> {
+        self.spent.get(&(Account::new(user, symbol), day)).copied()
+    }
+
+    /// The lowest nonce `user`'s next transaction may carry. Since
+    /// `u64::MAX` is never accepted, this never overflows.
+    pub fn next_nonce(&self, user: &OrgUserId) -> u64 {
+        self.last_nonce(user).saturating_add(1)
+    }
+
+    /// Reject a nonce that is not strictly above the sender's last one, or
+    /// is `u64::MAX`. Gaps are allowed; see `Ledger`.
+    pub fn check_nonce(&self, user: &OrgUserId, nonce: u64) -> Result<(), NonceError> {
+        let last = self.last_nonce(user);
+        match nonce {
+            u64::MAX => Err(NonceError::Exhausted),
+            n if n > last => Ok(()),
+            n if n == last => Err(NonceError::Duplicate { nonce }),
+            _ => Err(NonceError::Stale { last, found: nonce }),
+        }
+    }
+
+    /// Create new funds in an account outside of any transaction, e.g. for
+    /// initial allocations. Does not advance the height.
+    pub fn mint(&mut self, user: &OrgUserId, symbol: &str, amt: Amount) -> Result<(), LedgerError> {
+        self.credit(Account::new(user, symbol), amt)
+    }
+
+    fn credit(&mut self, account: Account, amt: Amount) -> Result<(), LedgerError> {
+        let height = self.height;
+        let acct = self.accounts.entry(account).or_insert_with(|| AccountState {
+            balance: amt.zeroed(),
+            history: Vec::new(),
+        });
+        let balance = acct.balance.checked_add(amt)?;
+        acct.set(height, balance);
+        Ok(())
+    }
+
+    /// Mark `txs`, which have been pruned from the DAG, as never to be
+    /// undone, and drop balance history from before the first transaction
+    /// that still may be.
+    pub fn settle(&mut self, txs: &[Transaction]) {
+        let settled: HashSet<TxId> = txs.iter().map(|t| t.id).collect();
+        self.applied.retain(|a| !settled.contains(&a.tx));
+        let height = self.applied.first().map_or(self.height, |a| a.height - 1);
+        for acct in self.accounts.values_mut() {
+            acct.trim(height);
+        }
+    }
+
+    /// Transactions applied and not settled yet, in the order they were
+    /// applied.
+    pub fn unsettled(&self) -> impl Iterator<Item = &TxId> {
+        self.applied.iter().map(|a| &a.tx)
+    }
+
+    /// Undo every unsettled transaction after the first `keep`, newest
+    /// first, returning their ids in the order they had been applied. Mints
+    /// made since are kept, and accounts the transactions opened are
+    /// removed unless something was minted into them. The height goes back by one per transaction, so
+    /// heights above it will be reused, and balance history above it is
+    /// dropped.
+    pub fn rewind(&mut self, keep: usize) -> Vec<TxId> {
+        let undone: Vec<Applied> = self.applied.drain(keep.min(self.applied.len())..).rev().collect();
+        for a in undone.iter() {
+            self.height -= 1;
+            match a.prev_nonce {
+                Some(n) => self.nonces.insert(a.from.user.clone(), n),
+                None => self.nonces.remove(&a.from.user),
+            };
+            let day = (a.from.clone(), a.day);
+            if let Some(spent) = self.spent.get(&day) {
+                match spent.checked_sub(a.amt) {
+                    Ok(left) if !left.is_zero() => self.spent.insert(day, left),
+                    _ => self.spent.remove(&day),
+                };
+            }
+            for (d, spent) in a.dropped.iter() {
+                self.spent.insert((a.from.clone(), *d), *spent);
+            }
+            match a.prev_day {
+                Some(d) => self.days.insert(a.from.clone(), d),
+                None => self.days.remove(&a.from),
+            };
+            if a.from != a.to {
+                if let Some(from) = self.accounts.get_mut(&a.from) {
+                    from.balance = from.balance.checked_add(a.amt).unwrap_or(from.balance.saturated());
+                }
+                if let Some(to) = self.accounts.get_mut(&a.to) {
+                    // Only short if something spending these funds was settled
+                    // ahead of the transaction that paid them.
+                    to.balance = to.balance.checked_sub(a.amt).unwrap_or_else(|e| {
+                        log::warn!("Undoing {} leaves {} short: {}", a.tx, a.to.user.handle, e);
+                        to.balance.zeroed()
+                    });
+                }
+            }
+            // Unless something was minted into them since.
+            for account in a.opened.iter() {
+                if self.accounts.get(account).is_some_and(|s| s.balance.is_zero()) {
+                    self.accounts.remove(account);
+                }
+            }
+        }
+        if !undone.is_empty() {
+            let height = self.height;
+            for acct in self.accounts.values_mut() {
+                acct.rewind(height);
+            }
+        }
+        undone.into_iter().rev().map(|a| a.tx).collect()
+    }
+
+    /// Whether `tx` could be applied right now, without applying it.
+    pub fn check(&self, tx: &Transaction) -> Result<(), LedgerError> {
+        self.plan(tx).map(|_| ())
+    }
+
+    /// The new sender and receiver balances `tx` would produce.
+    fn plan(&self, tx: &Transaction) -> Result<(Account, Amount, Account, Amount), LedgerError> {
+        self.check_nonce(&tx.send.id, tx.nonce)?;
+        let symbol = &tx.amt.symbol;
+        let amt = tx.amt.amt;
+        let from = Account::new(&tx.send.id, symbol);
+        let to = Account::new(&tx.recv.id, symbol);
+        let zero = amt.zeroed();
+
+        let from_balance = self.accounts.get(&from).map_or(zero, |a| a.balance);
+        let debited = from_balance.checked_sub(amt)?;
+        let credited = if from == to {
+            from_balance
+        } else {
+            let to_balance = self.accounts.get(&to).map_or(zero, |a| a.balance);
+            to_balance.checked_add(amt)?
+        };
+        Ok((from, debited, to, credited))
+    }
+
+    /// Debit the sender and credit the receiver of `tx` in its symbol. Both
+    /// sides are computed before either is written, so a transaction that
+    /// would overdraw the sender, overflow the receiver or reuse a nonce
+    /// leaves the ledger untouched. Returns the height the transaction was
+    /// applied at.
+    pub fn apply(&mut self, tx: &Transaction) -> Result<u64, LedgerError> {
+        let (from, debited, to, credited) = self.plan(tx)?;
+        let mut opened: Vec<Account> = Vec::new();
+        for account in [&from, &to] {
+            if !self.accounts.contains_key(account) && !opened.contains(account) {
+                opened.push(account.clone());
+            }
+        }
+        self.height += 1;
+        let height = self.height;
+        let prev_nonce = self.nonces.insert(tx.send.id.clone(), tx.nonce);
+        let day = day_of(tx.timestamp);
+        let prev_day = self.days.get(&from).copied();
+        let dropped = self.count_spent(&from, day, tx.amt.amt);
+        self.applied.push(Applied {
+            tx: tx.id,
+            height,
+            from: from.clone(),
+            to: to.clone(),
+            amt: tx.amt.amt,
+            prev_nonce,
+            day,
+            prev_day,
+            dropped,
+            opened,
+        });
+        if from != to {
+            self.accounts.entry(from).or_default().set(height, debited);
+        }
+        self.accounts.entry(to).or_default().set(height, credited);
+        Ok(height)
+    }
+
+    /// Add `amt` to what is counted as sent from `account` on `day`. A
+    /// later day than any before for the account drops its counts that
+    /// fall out of `SPENT_DAYS_KEPT`, and days that already have are not
+    /// counted, so the counts do not depend on the order transactions came
+    /// in. Returns the days dropped, with what had been counted on them.
+    fn count_spent(&mut self, account: &Account, day: u64, amt: Amount) -> Vec<(u64, Amount)> {
+        let latest = *self.days.get(account).unwrap_or(&0);
+        let mut dropped = Vec::new();
+        if day > latest {
+            self.days.insert(account.clone(), day);
+            let oldest = day.saturating_sub(SPENT_DAYS_KEPT);
+            self.spent.retain(|(a, d), spent| {
+                let keep = a != account || *d >= oldest;
+                if !keep {
+                    dropped.push((*d, *spent));
+                }
+                keep
+            });
+        }
+        if day < latest.saturating_sub(SPENT_DAYS_KEPT) {
+            return dropped;
+        }
+        let spent = self.spent.entry((account.clone(), day)).or_insert(amt.zeroed());
+        *spent = spent.checked_add(amt).unwrap_or(spent.saturated());
+        dropped
+    }
+
+    /// A Merkle tree committing to every account's current balance, keyed
+    /// by `account_key`, every sender's last nonce, keyed by `nonce_key`,
+    /// and what each has spent per day, keyed by `spent_key`. Built fresh
+    /// on every call.
+    pub fn state_tree(&self) -> MerkleTree {
+        let balances = self.accounts.iter().map(|(a, s)| {
+            let value = bincode::serialize(&s.balance).unwrap_or_default();
+            (account_key(&a.user, &a.symbol), value)
+        });
+        let nonces = self.nonces.iter().map(|(user, nonce)| {
+            let value = bincode::serialize(nonce).unwrap_or_default();
+            (nonce_key(user), value)
+        });
+        let spent = self.spent.iter().map(|((a, day), amt)| {
+            let value = bincode::serialize(amt).unwrap_or_default();
+            (spent_key(&a.user, &a.symbol, *day), value)
+        });
+        MerkleTree::new(balances.chain(nonces).chain(spent).collect())
+    }
+
+    /// A ledger holding only this one's current balances, nonces and daily
+    /// spending, which is all `state_root` commits to, at height 0 with
+    /// nothing to undo.
+    pub fn rebuilt(&self) -> Ledger {
+        Self::from_entries(self.entries())
+    }
+
+    /// Everything `state_root` commits to, one item each, so a ledger can
+    /// be sent in pages.
+    pub fn entries(&self) -> Vec<LedgerEntry> {
+        let balances = self.accounts.iter().map(|(a, s)| LedgerEntry::Balance(a.clone(), s.balance));
+        let nonces = self.nonces.iter().map(|(user, nonce)| LedgerEntry::Nonce(user.clone(), *nonce));
+        let spent = self.spent.iter().map(|((a, day), amt)| LedgerEntry::Spent(a.clone(), *day, *amt));
+        balances.chain(nonces).chain(spent).collect()
+    }
+
+    /// A ledger holding `entries`, as `rebuilt` would.
+    pub fn from_entries(entries: impl IntoIterator<Item = LedgerEntry>) -> Ledger {
+        let mut ledger = Ledger::new();
+        for entry in entries {
+            match entry {
+                LedgerEntry::Balance(account, balance) => ledger.accounts.entry(account).or_default().set(0, balance),
+                LedgerEntry::Nonce(user, nonce) => {
+                    ledger.nonces.insert(user, nonce);
+                }
+                LedgerEntry::Spent(account, day, amt) => {
+                    let latest = ledger.days.entry(account.clone()).or_insert(day);
+                    *latest = (*latest).max(day);
+                    ledger.spent.insert((account, day), amt);
+                }
+            }
+        }
+        ledger
+    }
+
+    /// Root of `state_tree`.
+    pub fn state_root(&self) -> Hash {
+        self.state_tree().root()
+    }
+
+    /// Prove `user`'s balance of `symbol`, or that they have none, against
+    /// `state_root`.
+    pub fn prove_balance(&self, user: &OrgUserId, symbol: &str) -> BalanceProof {
+        BalanceProof {
+            user: user.clone(),
+            symbol: symbol.to_string(),
+            proof: self.state_tree().prove(&account_key(user, symbol)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::{rules::DAILY_LIMIT_WINDOW, user::OrgUser, OrgId};
+
+    fn users() -> (OrgUser, OrgUser) {
+        let org = OrgId::new("org");
+        (OrgUser::new(org.clone(), "alice".to_string()), OrgUser::new(org, "bob".to_string()))
+    }
+
+    fn amt(whole: u64) -> Amount {
+        Amount::from_whole(whole, 2).unwrap()
+    }
+
+    fn funded() -> (Ledger, OrgUser, OrgUser) {
+        let (alice, bob) = users();
+        let mut ledger = Ledger::new();
+        ledger.mint(&alice.id, "TEST", amt(10)).unwrap();
+        (ledger, alice, bob)
+    }
+
+    #[test]
+    fn moves_funds() {
+        let (mut ledger, alice, bob) = funded();
+        let tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(4), 1);
+        assert_eq!(ledger.apply(&tx), Ok(1));
+        assert_eq!(ledger.balance(&alice.id, "TEST"), Some(amt(6)));
+        assert_eq!(ledger.balance(&bob.id, "TEST"), Some(amt(4)));
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 0), Some(amt(10)));
+        assert_eq!(ledger.last_nonce(&alice.id), 1);
+    }
+
+    #[test]
+    fn proves_balances_whatever_the_federation() {
+        let (ledger, alice, _) = funded();
+        let mut elsewhere = alice.id.clone();
+        elsewhere.org_id.fed_id = crate::federation::id::FedId::new("elsewhere".to_string());
+        let proof = ledger.prove_balance(&elsewhere, "TEST");
+        assert_eq!(proof.verify(&ledger.state_root()), Ok(Some(amt(10))));
+    }
+
+    #[test]
+    fn settling_trims_history() {
+        let (mut ledger, alice, bob) = funded();
+        let txs: Vec<Transaction> = (1..=3)
+            .map(|n| Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), n))
+            .collect();
+        for tx in txs.iter() {
+            ledger.apply(tx).unwrap();
+        }
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 0), Some(amt(10)));
+        ledger.settle(&txs[..2]);
+        assert_eq!(ledger.unsettled().collect::<Vec<_>>(), vec![&txs[2].id]);
+        // Back to the balance the last unsettled transaction started from.
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 0), None);
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 2), Some(amt(8)));
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 3), Some(amt(7)));
+        ledger.rewind(0);
+        assert_eq!(ledger.balance(&alice.id, "TEST"), Some(amt(8)));
+        assert_eq!(ledger.height(), 2);
+        ledger.apply(&txs[2]).unwrap();
+        ledger.settle(&txs[2..]);
+        assert_eq!(ledger.unsettled().count(), 0);
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 2), None);
+        assert_eq!(ledger.balance_at(&alice.id, "TEST", 3), Some(amt(7)));
+    }
+
+    #[test]
+    fn counts_spending_per_day() {
+        let (mut ledger, alice, bob) = funded();
+        let day = |n: u64| std::time::UNIX_EPOCH + DAILY_LIMIT_WINDOW * (n as u32);
+        let mut pay = |n: u64, on: u64| {
+            let mut tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), n);
+            tx.timestamp = day(on);
+            ledger.apply(&tx).unwrap();
+        };
+        pay(1, 100);
+        pay(2, 100);
+        pay(3, 101);
+        // Too long before the latest day to be counted.
+        pay(4, 100 - SPENT_DAYS_KEPT);
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100), Some(amt(2)));
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 101), Some(amt(1)));
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100 - SPENT_DAYS_KEPT), None);
+        assert_eq!(ledger.rebuilt().state_root(), ledger.state_root());
+        ledger.rewind(1);
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100), Some(amt(1)));
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 101), None);
+    }
+
+    #[test]
+    fn a_sender_dating_ahead_keeps_others_counted() {
+        let (mut ledger, alice, bob) = funded();
+        ledger.mint(&bob.id, "TEST", amt(10)).unwrap();
+        let day = |n: u64| std::time::UNIX_EPOCH + DAILY_LIMIT_WINDOW * (n as u32);
+        let mut pay = |from: &OrgUser, to: &OrgUser, nonce: u64, on: u64| {
+            let mut tx = Transaction::new(from.clone(), to.clone(), "TEST", amt(1), nonce);
+            tx.timestamp = day(on);
+            ledger.apply(&tx).unwrap();
+        };
+        pay(&alice, &bob, 1, 100);
+        pay(&bob, &alice, 1, 100 + 365);
+        pay(&alice, &bob, 2, 100);
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100), Some(amt(2)));
+        assert_eq!(ledger.rebuilt().spent_on(&alice.id, "TEST", 100), Some(amt(2)));
+    }
+
+    #[test]
+    fn rewinding_across_a_day_boundary_restores_the_counts() {
+        let (mut ledger, alice, bob) = funded();
+        let day = |n: u64| std::time::UNIX_EPOCH + DAILY_LIMIT_WINDOW * (n as u32);
+        let pay = |ledger: &mut Ledger, nonce: u64, on: u64| {
+            let mut tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), nonce);
+            tx.timestamp = day(on);
+            ledger.apply(&tx).unwrap();
+        };
+        pay(&mut ledger, 1, 100);
+        pay(&mut ledger, 2, 101);
+        let root = ledger.state_root();
+        // Far enough ahead to drop day 100.
+        pay(&mut ledger, 3, 100 + SPENT_DAYS_KEPT + 1);
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100), None);
+        ledger.rewind(2);
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100), Some(amt(1)));
+        // Day 100 is counted again, not taken for too long ago.
+        pay(&mut ledger, 3, 100);
+        assert_eq!(ledger.spent_on(&alice.id, "TEST", 100), Some(amt(2)));
+    }
+
+    #[test]
+    fn rewinding_removes_the_accounts_it_opened() {
+        let (mut ledger, alice, bob) = funded();
+        let root = ledger.state_root();
+        let tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(4), 1);
+        ledger.apply(&tx).unwrap();
+        ledger.rewind(0);
+        assert_eq!(ledger.balance(&bob.id, "TEST"), None);
+        assert_eq!(ledger.state_root(), root);
+        // Kept once something else was put in it.
+        ledger.apply(&tx).unwrap();
+        ledger.mint(&bob.id, "TEST", amt(1)).unwrap();
+        ledger.rewind(0);
+        assert_eq!(ledger.balance(&bob.id, "TEST"), Some(amt(1)));
+    }
+
+    #[test]
+    fn overdraw_leaves_the_ledger_untouched() {
+        let (mut ledger, alice, bob) = funded();
+        let root = ledger.state_root();
+        let tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(11), 1);
+        assert!(matches!(
+            ledger.apply(&tx),
+            Err(LedgerError::Amount(AmountError::InsufficientFunds { .. }))
+        ));
+        let unfunded = Transaction::new(bob.clone(), alice.clone(), "TEST", amt(1), 1);
+        assert!(ledger.apply(&unfunded).is_err());
+        assert_eq!(ledger.height(), 0);
+        assert_eq!(ledger.last_nonce(&alice.id), 0);
+        assert_eq!(ledger.balance(&bob.id, "TEST"), None);
+        assert_eq!(ledger.state_root(), root);
+    }
+
+    #[test]
+    fn rejects_replayed_nonces() {
+        let (mut ledger, alice, bob) = funded();
+        let tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), 2);
+        ledger.apply(&tx).unwrap();
+        assert_eq!(ledger.apply(&tx), Err(LedgerError::Nonce(NonceError::Duplicate { nonce: 2 })));
+        let stale = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), 1);
+        assert_eq!(
+            ledger.check(&stale),
+            Err(LedgerError::Nonce(NonceError::Stale { last: 2, found: 1 }))
+        );
+        assert_eq!(ledger.balance(&alice.id, "TEST"), Some(amt(9)));
+        assert_eq!(ledger.height(), 1);
+    }
+
+    #[test]
+    fn nonces_may_skip_ahead() {
+        let (mut ledger, alice, bob) = funded();
+        assert_eq!(ledger.next_nonce(&alice.id), 1);
+        let tx = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), 5);
+        assert_eq!(ledger.apply(&tx), Ok(1));
+        assert_eq!(ledger.next_nonce(&alice.id), 6);
+        let last = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), u64::MAX);
+        assert_eq!(ledger.apply(&last), Err(LedgerError::Nonce(NonceError::Exhausted)));
+        let highest = Transaction::new(alice.clone(), bob.clone(), "TEST", amt(1), u64::MAX - 1);
+        ledger.apply(&highest).unwrap();
+        assert_eq!(ledger.next_nonce(&alice.id), u64::MAX);
+    }
+}
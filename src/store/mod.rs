@@ -1,2 +1,19 @@
+pub mod archive;
+pub mod checkpoint;
+pub mod conflict;
 pub mod dag;
-pub use dag::{DAG, StreamingDAG};
+pub mod index;
+pub mod ledger;
+pub mod proof;
+pub mod snapshot;
+pub mod wal;
+
+pub use archive::Archive;
+pub use checkpoint::CheckpointState;
+pub use conflict::{ConflictSet, ConflictTracker};
+pub use dag::{DagError, DagEvent, FinalityConfig, TipSelector, TxState, DAG, StreamingDAG, TxGraph};
+pub use index::{Cursor, Page, TxFilter, TxIndex};
+pub use ledger::{Ledger, LedgerEntry, LedgerError, NonceError};
+pub use proof::{BalanceProof, ProofError};
+pub use snapshot::{Snapshot, SnapshotConfig, SnapshotHead, SnapshotPage, SnapshotPart};
+pub use wal::{FsyncPolicy, WalConfig, WalRecord};
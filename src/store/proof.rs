@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::{
+    federation::org::user::OrgUserId,
+    models::{Amount, Symbol},
+};
+
+pub use cpr_store::{Hash, MerkleProof, MerkleTree};
+
+/// Domain separators for account, nonce and daily spending keys in the
+/// state tree.
+const ACCOUNT_KEY_DOMAIN: &[u8] = b"cpr/account/v1";
+const NONCE_KEY_DOMAIN: &[u8] = b"cpr/nonce/v1";
+const SPENT_KEY_DOMAIN: &[u8] = b"cpr/spent/v1";
+
+/// The key `user`'s balance of `symbol` is committed under in the ledger's
+/// state tree. Equal ids give the same key whatever they know of their
+/// federation.
+pub fn account_key(user: &OrgUserId, symbol: &str) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(ACCOUNT_KEY_DOMAIN);
+    hasher.update(bincode::serialize(&(user.canonical(), symbol)).unwrap_or_default());
+    hasher.finalize().into()
+}
+
+/// The key `user`'s last accepted nonce is committed under in the ledger's
+/// state tree.
+pub fn nonce_key(user: &OrgUserId) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(NONCE_KEY_DOMAIN);
+    hasher.update(bincode::serialize(&user.canonical()).unwrap_or_default());
+    hasher.finalize().into()
+}
+
+/// The key what `user` sent in `symbol` on `day` is committed under in the
+/// ledger's state tree.
+pub fn spent_key(user: &OrgUserId, symbol: &str, day: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(SPENT_KEY_DOMAIN);
+    hasher.update(bincode::serialize(&(user.canonical(), symbol, day)).unwrap_or_default());
+    hasher.finalize().into()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProofError {
+    /// The proof is for a different account than it claims.
+    WrongAccount,
+    /// The proof does not lead to the given root.
+    Invalid,
+    /// The proven value is not a balance.
+    Malformed,
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongAccount => write!(f, "proof is for a different account"),
+            Self::Invalid => write!(f, "proof does not match the state root"),
+            Self::Malformed => write!(f, "proven value is not a balance"),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+/// A standalone proof of one account's balance, or of it not existing,
+/// against a ledger state root. Auditors can check it knowing only the root.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BalanceProof {
+    pub user: OrgUserId,
+    pub symbol: Symbol,
+    pub proof: MerkleProof,
+}
+
+impl BalanceProof {
+    /// Check the proof against `root`, returning the proven balance, or
+    /// `None` if it proves the account holds nothing.
+    pub fn verify(&self, root: &Hash) -> Result<Option<Amount>, ProofError> {
+        if *self.proof.key() != account_key(&self.user, &self.symbol) {
+            return Err(ProofError::WrongAccount);
+        }
+        if !self.proof.verify(root) {
+            return Err(ProofError::Invalid);
+        }
+        self.proof
+            .value()
+            .map(|v| bincode::deserialize(v).map_err(|_| ProofError::Malformed))
+            .transpose()
+    }
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::{
+    checkpoint::CheckpointState,
+    conflict::ConflictTracker,
+    dag::DAGNode,
+    ledger::{Ledger, LedgerEntry},
+    proof::Hash,
+};
+use crate::consensus::Commit;
+use crate::{Org, TxId};
+
+pub use cpr_store::SnapshotStore;
+
+/// Pruned transaction ids and their heights, as sent to a peer.
+pub type PrunedIds = Vec<(TxId, u64)>;
+
+/// Write a snapshot after this many write-ahead log records by default.
+pub static DEFAULT_SNAPSHOT_EVERY: u64 = 1000;
+/// How many snapshots are kept by default. The log is only truncated up to
+/// the oldest, so an older snapshot can still be booted from if the newest
+/// turns out to be damaged.
+pub static DEFAULT_SNAPSHOTS_KEPT: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotConfig {
+    /// Write-ahead log records between automatic snapshots, or 0 to only
+    /// snapshot when asked to.
+    pub every: u64,
+    pub keep: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            every: DEFAULT_SNAPSHOT_EVERY,
+            keep: DEFAULT_SNAPSHOTS_KEPT,
+        }
+    }
+}
+
+/// The full state of a `StreamingDAG` as of some write-ahead log record:
+/// balances, the org registry, the part of the DAG that has not been
+/// pruned, the conflict sets it is involved in and the state as of the
+/// last committed checkpoint. Booting from a snapshot
+/// and replaying the log after it gives the same state as replaying the
+/// whole log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub orgs: Vec<Org>,
+    pub ledger: Ledger,
+    /// `ledger.state_root()`, so balance proofs can be checked against a
+    /// snapshot without loading it.
+    pub state_root: Hash,
+    pub tips: Vec<TxId>,
+    /// Unpruned transactions, in topological order.
+    pub nodes: Vec<DAGNode>,
+    /// Pruned transaction ids and their heights.
+    pub pruned: HashMap<TxId, u64>,
+    pub next_seq: u64,
+    pub conflicts: ConflictTracker,
+    pub checkpoint: CheckpointState,
+}
+
+impl Snapshot {
+    /// Take a snapshot at a committed checkpoint apart to send it in
+    /// pages: the rest of it, the ledger's entries, the pruned ids by id and
+    /// the transactions in topological order. `None` if it is not at a
+    /// committed checkpoint.
+    pub fn split(self) -> Option<(SnapshotHead, Vec<LedgerEntry>, PrunedIds, Vec<DAGNode>)> {
+        let commit = self.checkpoint.commit?;
+        let entries = self.ledger.entries();
+        let mut pruned: PrunedIds = self.pruned.into_iter().collect();
+        pruned.sort();
+        let head = SnapshotHead {
+            orgs: self.orgs,
+            commit,
+            next_seq: self.next_seq,
+            entries: entries.len() as u64,
+            pruned: pruned.len() as u64,
+            nodes: self.nodes.len() as u64,
+        };
+        Some((head, entries, pruned, self.nodes))
+    }
+
+    /// Put a snapshot taken apart by `split` back together. Nothing is
+    /// checked here; `StreamingDAG::install_snapshot` checks it against the
+    /// commit.
+    pub fn join(head: SnapshotHead, entries: Vec<LedgerEntry>, pruned: PrunedIds, nodes: Vec<DAGNode>) -> Self {
+        let ledger = Ledger::from_entries(entries);
+        Self {
+            orgs: head.orgs,
+            state_root: ledger.state_root(),
+            ledger,
+            tips: head.commit.checkpoint.tips.clone(),
+            nodes,
+            pruned: pruned.into_iter().collect(),
+            next_seq: head.next_seq,
+            conflicts: ConflictTracker::new(),
+            checkpoint: CheckpointState {
+                commit: Some(head.commit),
+                ..CheckpointState::default()
+            },
+        }
+    }
+}
+
+/// What a peer sends first of a snapshot: the commit it is at and how many
+/// of each part are to be fetched page by page.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnapshotHead {
+    pub orgs: Vec<Org>,
+    pub commit: Commit,
+    pub next_seq: u64,
+    pub entries: u64,
+    pub pruned: u64,
+    pub nodes: u64,
+}
+
+/// One of the parts of a snapshot sent in pages.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotPart {
+    Ledger,
+    Pruned,
+    Nodes,
+}
+
+/// A page of one part of a snapshot.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SnapshotPage {
+    Ledger(Vec<LedgerEntry>),
+    Pruned(PrunedIds),
+    Nodes(Vec<DAGNode>),
+}
+
+impl SnapshotPage {
+    pub fn part(&self) -> SnapshotPart {
+        match self {
+            SnapshotPage::Ledger(_) => SnapshotPart::Ledger,
+            SnapshotPage::Pruned(_) => SnapshotPart::Pruned,
+            SnapshotPage::Nodes(_) => SnapshotPart::Nodes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SnapshotPage::Ledger(entries) => entries.len(),
+            SnapshotPage::Pruned(pruned) => pruned.len(),
+            SnapshotPage::Nodes(nodes) => nodes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus::Commit,
+    federation::org::{user::OrgUserId, OrgId},
+    models::{Amount, Symbol},
+    Transaction,
+};
+
+pub use cpr_store::{FsyncPolicy, Lsn, StoreError, Wal, WalConfig};
+
+/// Where `cprd` keeps its data, relative to where it runs.
+pub static DEFAULT_DATA_DIR: &str = "data";
+/// Subdirectories of the data directory for the log, snapshots and the
+/// archive of pruned transactions.
+pub static WAL_DIR: &str = "wal";
+pub static SNAPSHOT_DIR: &str = "snapshots";
+pub static ARCHIVE_DIR: &str = "archive";
+
+/// A change to a `StreamingDAG`, as written to its write-ahead log. Replaying
+/// the records in order rebuilds the DAG, ledger and conflict sets exactly.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum WalRecord {
+    Mint {
+        user: OrgUserId,
+        symbol: Symbol,
+        amt: Amount,
+    },
+    /// A transaction accepted on the word of `approver`, including ones
+    /// that ended up in a conflict set.
+    Tx { tx: Box<Transaction>, approver: OrgId },
+    /// A checkpoint the validators committed, moving the `CheckpointState`
+    /// on.
+    Checkpoint(Box<Commit>),
+}
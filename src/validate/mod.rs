@@ -1,6 +1,81 @@
-#[derive(Debug, Clone, PartialEq)]
+pub mod set;
+pub mod vote;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::federation::org::{
+    user::{KeyError, OrgUserId, PublicKey},
+    OrgId,
+};
+
+pub use set::ValidatorSet;
+pub use vote::{Decision, LocalVoter, ValidationOutcome, Vote, VoteError, Voter, DEFAULT_VOTE_TIMEOUT};
+
+/// A party entitled to approve transactions on behalf of an org, and how
+/// much its approval counts for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Validator {
     name: String,
     weight: usize,
     pub key: String,
+    pub org_id: OrgId,
 }
+
+impl Validator {
+    pub fn new(name: &str, org_id: OrgId, weight: usize, key: String) -> Self {
+        Self {
+            name: name.into(),
+            weight,
+            key,
+            org_id,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+    /// The key the validator's votes must be signed with.
+    pub fn public_key(&self) -> Result<PublicKey, KeyError> {
+        self.key.parse()
+    }
+}
+
+/// Why a federation refused to accept a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValidationError {
+    /// The org asked to validate is not part of the federation.
+    UnknownOrg(Box<OrgId>),
+    /// The sender is not a registered user of their org.
+    UnknownSender(Box<OrgUserId>),
+    /// The sender has no public key registered to verify against.
+    MissingKey(Box<OrgUserId>),
+    /// The transaction's id is not the hash of its contents.
+    IdMismatch,
+    /// The amount has a different number of decimal places than its symbol.
+    WrongPrecision { expected: u8, found: u8 },
+    /// The transaction carries no signature.
+    MissingSignature,
+    /// The signature does not match the transaction and sender's key.
+    BadSignature,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownOrg(o) => write!(f, "unknown org {}", o.to_string()),
+            Self::UnknownSender(u) => write!(f, "unknown sender {}", u.to_string()),
+            Self::MissingKey(u) => write!(f, "no key registered for {}", u.to_string()),
+            Self::IdMismatch => write!(f, "transaction id does not match its contents"),
+            Self::WrongPrecision { expected, found } => {
+                write!(f, "amount has {} decimals, its symbol {}", found, expected)
+            }
+            Self::MissingSignature => write!(f, "transaction is not signed"),
+            Self::BadSignature => write!(f, "invalid transaction signature"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+
+use super::Validator;
+use crate::federation::org::{user::PublicKey, OrgId};
+
+/// The validators whose votes decide whether the federation accepts a
+/// transaction, each counting for its weight. A transaction is approved
+/// once validators holding more than two thirds of the total weight vote
+/// for it, and rejected once that can no longer happen.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ValidatorSet {
+    validators: Vec<Validator>,
+}
+
+impl ValidatorSet {
+    pub fn new(validators: Vec<Validator>) -> Self {
+        let mut set = Self::default();
+        for v in validators {
+            set.insert(v);
+        }
+        set
+    }
+
+    /// Add `validator`, replacing any already registered under its name.
+    pub fn insert(&mut self, validator: Validator) {
+        match self.validators.iter_mut().find(|v| v.name() == validator.name()) {
+            Some(v) => *v = validator,
+            None => self.validators.push(validator),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Validator> {
+        self.validators.iter().find(|v| v.name() == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Validator> {
+        self.validators.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Validators approving on behalf of `org_id`.
+    pub fn for_org<'a>(&'a self, org_id: &'a OrgId) -> impl Iterator<Item = &'a Validator> {
+        self.validators.iter().filter(move |v| &v.org_id == org_id)
+    }
+
+    /// The validator registered with `key`, if any.
+    pub fn by_key(&self, key: &PublicKey) -> Option<&Validator> {
+        self.validators.iter().find(|v| v.public_key().ok().as_ref() == Some(key))
+    }
+
+    pub fn total_weight(&self) -> u64 {
+        self.validators.iter().map(|v| v.weight() as u64).sum()
+    }
+
+    /// Weight needed to approve: the smallest amount strictly greater than
+    /// two thirds of the total.
+    pub fn quorum(&self) -> u64 {
+        self.total_weight() * 2 / 3 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::user::{KeyPair, OrgUser};
+    use crate::federation::org::RejectReason;
+    use crate::validate::{Decision, ValidationOutcome, Vote};
+    use crate::{Amount, Transaction};
+
+    /// A set of validators v0, v1, ... with `weights`, and their keys.
+    fn set(weights: &[usize]) -> (ValidatorSet, Vec<KeyPair>) {
+        let org = OrgId::new("org");
+        let keys: Vec<KeyPair> = weights.iter().map(|_| KeyPair::generate()).collect();
+        let validators = weights
+            .iter()
+            .zip(keys.iter())
+            .enumerate()
+            .map(|(i, (w, k))| Validator::new(&format!("v{}", i), org.clone(), *w, k.public().to_string()))
+            .collect();
+        (ValidatorSet::new(validators), keys)
+    }
+
+    /// The decision once validators `approve` have approved and `reject`
+    /// rejected.
+    fn decide(weights: &[usize], approve: &[usize], reject: &[usize]) -> Decision {
+        let (validators, keys) = set(weights);
+        let org = OrgId::new("org");
+        let send = OrgUser::new(org.clone(), "alice".to_string());
+        let recv = OrgUser::new(org, "bob".to_string());
+        let id = Transaction::new(send, recv, "TEST", Amount::from_whole(1, 2).unwrap(), 0).id;
+        let mut outcome = ValidationOutcome::new(id, [0; 32], &validators);
+        let no = vec![RejectReason::SymbolNotAllowed("TEST".to_string())];
+        for (voters, reasons) in [(approve, Vec::new()), (reject, no)] {
+            for &i in voters {
+                let vote = Vote::new(&[0; 32], &format!("v{}", i), id, reasons.clone(), &keys[i]);
+                outcome.record(&validators, vote).unwrap();
+            }
+        }
+        outcome.decision()
+    }
+
+    #[test]
+    fn quorum_is_more_than_two_thirds() {
+        for (weights, quorum) in [(&[][..], 1), (&[1][..], 1), (&[1, 1, 1][..], 3), (&[1, 1, 1, 1][..], 3), (&[4, 2][..], 5), (&[3, 3, 3][..], 7)] {
+            let (validators, _) = set(weights);
+            assert_eq!(validators.quorum(), quorum, "{:?}", weights);
+            assert!(validators.quorum() * 3 > validators.total_weight() * 2);
+        }
+    }
+
+    #[test]
+    fn decisions_at_the_boundary() {
+        // Exactly two thirds is not enough.
+        assert_eq!(decide(&[4, 2], &[0], &[]), Decision::Undecided);
+        assert_eq!(decide(&[4, 2], &[0, 1], &[]), Decision::Approved);
+        assert_eq!(decide(&[1, 1, 1], &[0, 1], &[]), Decision::Undecided);
+        assert_eq!(decide(&[1, 1, 1, 1], &[0, 1, 2], &[]), Decision::Approved);
+        // Rejected once the rest could not reach quorum.
+        assert_eq!(decide(&[1, 1, 1, 1], &[0], &[1]), Decision::Undecided);
+        assert_eq!(decide(&[1, 1, 1, 1], &[0], &[1, 2]), Decision::Rejected);
+        assert_eq!(decide(&[1, 1, 1], &[0, 1], &[2]), Decision::Rejected);
+        assert_eq!(decide(&[5, 1, 1], &[1, 2], &[]), Decision::Undecided);
+        assert_eq!(decide(&[5, 1, 1], &[0], &[]), Decision::Approved);
+    }
+
+    #[test]
+    fn validators_are_found_by_name_org_and_key() {
+        let (mut validators, keys) = set(&[1, 2]);
+        let (org, other) = (validators.get("v0").unwrap().org_id.clone(), OrgId::new("other"));
+        assert_eq!(validators.by_key(&keys[1].public()).map(|v| v.name()), Some("v1"));
+        assert!(validators.by_key(&KeyPair::generate().public()).is_none());
+        assert_eq!(validators.for_org(&org).count(), 2);
+        assert_eq!(validators.for_org(&other).count(), 0);
+        validators.insert(Validator::new("v1", other.clone(), 5, keys[1].public().to_string()));
+        assert_eq!(validators.for_org(&other).count(), 1);
+        assert_eq!(validators.len(), 2);
+        assert_eq!(validators.total_weight(), 6);
+        assert_eq!(validators.get("v1").map(|v| v.weight()), Some(5));
+    }
+}
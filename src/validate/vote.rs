@@ -0,0 +1,359 @@
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc, time::Duration};
+
+use super::{ValidationError, ValidatorSet};
+use crate::federation::org::{
+    user::{KeyError, KeyPair, PublicKey, Signature},
+    OrgId, RejectReason,
+};
+use crate::store::proof::Hash;
+use crate::{StreamingDAG, Transaction, TxId};
+
+/// How long a validator is given to vote before it is counted as timed
+/// out.
+pub static DEFAULT_VOTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const VOTE_DOMAIN: &[u8] = b"cpr/vote/v1";
+
+/// One validator's signed verdict on one transaction: approval if
+/// `reasons` is empty, rejection for those reasons otherwise. The signature
+/// covers the genesis hash of the federation the vote is cast in, the
+/// validator's name, the transaction id and the reasons, and is made with
+/// the key the validator is registered under. The genesis hash is not
+/// carried in the vote; it is checked against the federation counting it,
+/// so a vote cannot be replayed in another federation sharing the key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vote {
+    pub validator: String,
+    pub key: PublicKey,
+    pub tx_id: TxId,
+    pub reasons: Vec<RejectReason>,
+    pub sig: Signature,
+}
+
+#[derive(Serialize)]
+struct VoteBody<'a> {
+    genesis: &'a Hash,
+    validator: &'a str,
+    tx_id: &'a TxId,
+    reasons: &'a [RejectReason],
+}
+
+impl Vote {
+    pub fn new(genesis: &Hash, validator: &str, tx_id: TxId, reasons: Vec<RejectReason>, key: &KeyPair) -> Self {
+        let sig = key.sign(&Self::signing_bytes(genesis, validator, &tx_id, &reasons));
+        Self {
+            validator: validator.to_string(),
+            key: key.public(),
+            tx_id,
+            reasons,
+            sig,
+        }
+    }
+
+    fn signing_bytes(genesis: &Hash, validator: &str, tx_id: &TxId, reasons: &[RejectReason]) -> Vec<u8> {
+        let body = bincode::serialize(&VoteBody {
+            genesis,
+            validator,
+            tx_id,
+            reasons,
+        })
+        .expect("vote body is always serializable");
+        [VOTE_DOMAIN, &body].concat()
+    }
+
+    pub fn approves(&self) -> bool {
+        self.reasons.is_empty()
+    }
+
+    /// Check the signature against the key the vote carries, as cast in
+    /// the federation started from `genesis`. Whether that key belongs to
+    /// the validator is up to the `ValidatorSet`.
+    pub fn verify(&self, genesis: &Hash) -> Result<(), KeyError> {
+        let bytes = Self::signing_bytes(genesis, &self.validator, &self.tx_id, &self.reasons);
+        self.key.verify(&bytes, &self.sig)
+    }
+}
+
+/// Why a vote was not counted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoteError {
+    /// The vote is for a different transaction.
+    WrongTx(TxId),
+    UnknownValidator(String),
+    /// The vote is not signed with the key the validator is registered
+    /// under.
+    WrongKey(String),
+    BadSignature(String),
+    /// The validator has already voted.
+    Duplicate(String),
+}
+
+impl fmt::Display for VoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongTx(id) => write!(f, "vote is for another transaction {}", id),
+            Self::UnknownValidator(v) => write!(f, "{} is not a validator", v),
+            Self::WrongKey(v) => write!(f, "vote from {} is not signed with its registered key", v),
+            Self::BadSignature(v) => write!(f, "vote from {} has an invalid signature", v),
+            Self::Duplicate(v) => write!(f, "{} has already voted", v),
+        }
+    }
+}
+
+impl std::error::Error for VoteError {}
+
+/// Where the votes counted so far leave a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Approved,
+    Rejected,
+    /// Neither outcome is certain yet.
+    Undecided,
+}
+
+/// The votes collected on one transaction and what they add up to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationOutcome {
+    pub tx_id: TxId,
+    /// Genesis hash of the federation the votes must be cast in.
+    pub genesis: Hash,
+    pub approved: Vec<Vote>,
+    pub rejected: Vec<Vote>,
+    /// Validators that gave no valid vote before counting stopped.
+    pub timed_out: Vec<String>,
+    pub approve_weight: u64,
+    pub reject_weight: u64,
+    pub quorum: u64,
+    pub total_weight: u64,
+}
+
+impl ValidationOutcome {
+    pub fn new(tx_id: TxId, genesis: Hash, validators: &ValidatorSet) -> Self {
+        Self {
+            tx_id,
+            genesis,
+            approved: Vec::new(),
+            rejected: Vec::new(),
+            timed_out: Vec::new(),
+            approve_weight: 0,
+            reject_weight: 0,
+            quorum: validators.quorum(),
+            total_weight: validators.total_weight(),
+        }
+    }
+
+    pub fn has_voted(&self, validator: &str) -> bool {
+        self.approved.iter().chain(self.rejected.iter()).any(|v| v.validator == validator)
+    }
+
+    /// Count `vote` if it comes from a member of `validators`, signed with
+    /// its registered key for this federation, and is its first on this
+    /// transaction.
+    pub fn record(&mut self, validators: &ValidatorSet, vote: Vote) -> Result<Decision, VoteError> {
+        if vote.tx_id != self.tx_id {
+            return Err(VoteError::WrongTx(vote.tx_id));
+        }
+        let validator = validators
+            .get(&vote.validator)
+            .ok_or_else(|| VoteError::UnknownValidator(vote.validator.clone()))?;
+        if validator.public_key().ok() != Some(vote.key) {
+            return Err(VoteError::WrongKey(vote.validator));
+        }
+        if vote.verify(&self.genesis).is_err() {
+            return Err(VoteError::BadSignature(vote.validator));
+        }
+        if self.has_voted(&vote.validator) {
+            return Err(VoteError::Duplicate(vote.validator));
+        }
+        let weight = validator.weight() as u64;
+        if vote.approves() {
+            self.approve_weight += weight;
+            self.approved.push(vote);
+        } else {
+            self.reject_weight += weight;
+            self.rejected.push(vote);
+        }
+        Ok(self.decision())
+    }
+
+    /// Approved once the approving weight reaches the quorum, rejected once
+    /// the rejecting weight leaves too little for it to.
+    pub fn decision(&self) -> Decision {
+        if self.approve_weight >= self.quorum {
+            Decision::Approved
+        } else if self.reject_weight > self.total_weight.saturating_sub(self.quorum) {
+            Decision::Rejected
+        } else {
+            Decision::Undecided
+        }
+    }
+
+    pub fn is_approved(&self) -> bool {
+        self.decision() == Decision::Approved
+    }
+
+    /// `Ok` if approved, otherwise every reason the rejecting validators
+    /// gave, or `NoQuorum` if none did.
+    pub fn verdict(&self) -> Result<(), Vec<RejectReason>> {
+        if self.is_approved() {
+            return Ok(());
+        }
+        let mut reasons: Vec<RejectReason> = Vec::new();
+        for reason in self.rejected.iter().flat_map(|v| v.reasons.iter()) {
+            if !reasons.contains(reason) {
+                reasons.push(reason.clone());
+            }
+        }
+        if reasons.is_empty() {
+            reasons.push(RejectReason::NoQuorum {
+                approve_weight: self.approve_weight,
+                quorum: self.quorum,
+            });
+        }
+        Err(reasons)
+    }
+
+    /// Stop counting: every validator in `validators` that has not voted is
+    /// recorded as timed out.
+    pub fn finish(&mut self, validators: &ValidatorSet) {
+        let missing: Vec<String> = validators
+            .iter()
+            .map(|v| v.name().to_string())
+            .filter(|name| !self.has_voted(name) && !self.timed_out.contains(name))
+            .collect();
+        self.timed_out.extend(missing);
+    }
+}
+
+/// Something that casts a validator's votes, in this process or elsewhere.
+pub trait Voter: Send + Sync {
+    /// Name of the validator the votes come from.
+    fn validator(&self) -> &str;
+
+    fn vote<'a>(&'a self, tx: &'a Transaction) -> BoxFuture<'a, anyhow::Result<Vote>>;
+}
+
+/// A validator run in this process, voting on behalf of its org with a key
+/// held here. Transactions are checked against the federation and the
+/// org's rules, as of the state of `dag`.
+pub struct LocalVoter {
+    pub name: String,
+    pub org_id: OrgId,
+    pub dag: Arc<StreamingDAG>,
+    key: KeyPair,
+}
+
+impl LocalVoter {
+    pub fn new(name: &str, org_id: OrgId, key: KeyPair, dag: Arc<StreamingDAG>) -> Self {
+        Self {
+            name: name.to_string(),
+            org_id,
+            dag,
+            key,
+        }
+    }
+
+    /// Every reason to reject `tx`, or none to approve it.
+    pub fn check(&self, tx: &Transaction) -> Vec<RejectReason> {
+        let fed = &self.dag.federation;
+        if let Err(e) = fed.validate_tx(tx, self.org_id.clone()) {
+            return vec![RejectReason::Invalid(e)];
+        }
+        let org = match fed.orgs.iter().find(|o| o.id == self.org_id) {
+            Some(org) => org,
+            None => return vec![RejectReason::Invalid(ValidationError::UnknownOrg(Box::new(self.org_id.clone())))],
+        };
+        org.validate_tx(tx, &self.dag.rule_context(tx)).err().unwrap_or_default()
+    }
+}
+
+impl Voter for LocalVoter {
+    fn validator(&self) -> &str {
+        &self.name
+    }
+
+    fn vote<'a>(&'a self, tx: &'a Transaction) -> BoxFuture<'a, anyhow::Result<Vote>> {
+        let genesis = &self.dag.federation.genesis;
+        Box::pin(async move { Ok(Vote::new(genesis, &self.name, tx.id, self.check(tx), &self.key)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::org::user::OrgUser;
+    use crate::validate::Validator;
+    use crate::Amount;
+
+    /// Ids of two transactions between the same users.
+    fn tx_ids() -> (TxId, TxId) {
+        let org = OrgId::new("org");
+        let send = OrgUser::new(org.clone(), "alice".to_string());
+        let recv = OrgUser::new(org, "bob".to_string());
+        let tx = |nonce| Transaction::new(send.clone(), recv.clone(), "TEST", Amount::from_whole(1, 2).unwrap(), nonce);
+        (tx(0).id, tx(1).id)
+    }
+
+    #[test]
+    fn votes_verify_only_as_signed_and_in_their_federation() {
+        let (key, genesis) = (KeyPair::generate(), [1; 32]);
+        let (id, other) = tx_ids();
+        let vote = Vote::new(&genesis, "v0", id, Vec::new(), &key);
+        assert!(vote.approves());
+        assert_eq!(vote.verify(&genesis), Ok(()));
+        // Replayed in another federation.
+        assert!(vote.verify(&[2; 32]).is_err());
+        let altered = |f: &dyn Fn(&mut Vote)| {
+            let mut v = vote.clone();
+            f(&mut v);
+            v.verify(&genesis)
+        };
+        assert!(altered(&|v| v.validator = "v1".to_string()).is_err());
+        assert!(altered(&|v| v.tx_id = other).is_err());
+        assert!(altered(&|v| v.reasons = vec![RejectReason::SymbolNotAllowed("TEST".to_string())]).is_err());
+        assert!(altered(&|v| v.key = KeyPair::generate().public()).is_err());
+        // A signature over the body alone, without the type tag, is not one
+        // over a vote.
+        let body = bincode::serialize(&VoteBody {
+            genesis: &genesis,
+            validator: "v0",
+            tx_id: &id,
+            reasons: &[],
+        })
+        .unwrap();
+        let untagged = key.sign(&body);
+        assert!(altered(&|v| v.sig = untagged).is_err());
+    }
+
+    #[test]
+    fn only_valid_first_votes_are_counted() {
+        let genesis = [1; 32];
+        let keys: Vec<KeyPair> = (0..3).map(|_| KeyPair::generate()).collect();
+        let org = OrgId::new("org");
+        let validators = ValidatorSet::new(
+            keys.iter()
+                .enumerate()
+                .map(|(i, k)| Validator::new(&format!("v{}", i), org.clone(), 1, k.public().to_string()))
+                .collect(),
+        );
+        let (id, other) = tx_ids();
+        let mut outcome = ValidationOutcome::new(id, genesis, &validators);
+        let vote = |name: &str, key: &KeyPair| Vote::new(&genesis, name, id, Vec::new(), key);
+        assert_eq!(
+            outcome.record(&validators, Vote::new(&genesis, "v0", other, Vec::new(), &keys[0])),
+            Err(VoteError::WrongTx(other))
+        );
+        assert_eq!(outcome.record(&validators, vote("v9", &keys[0])), Err(VoteError::UnknownValidator("v9".to_string())));
+        assert_eq!(outcome.record(&validators, vote("v0", &keys[1])), Err(VoteError::WrongKey("v0".to_string())));
+        let elsewhere = Vote::new(&[2; 32], "v0", id, Vec::new(), &keys[0]);
+        assert_eq!(outcome.record(&validators, elsewhere), Err(VoteError::BadSignature("v0".to_string())));
+        assert_eq!(outcome.record(&validators, vote("v0", &keys[0])), Ok(Decision::Undecided));
+        assert_eq!(outcome.record(&validators, vote("v0", &keys[0])), Err(VoteError::Duplicate("v0".to_string())));
+        assert_eq!(outcome.record(&validators, vote("v1", &keys[1])), Ok(Decision::Undecided));
+        assert_eq!(outcome.record(&validators, vote("v2", &keys[2])), Ok(Decision::Approved));
+        assert_eq!((outcome.approve_weight, outcome.quorum), (3, 3));
+        assert_eq!(outcome.verdict(), Ok(()));
+    }
+}